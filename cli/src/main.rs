@@ -38,6 +38,7 @@ fn run_subcommand(args: &ArgMatches) -> Result<(), rowdy::Error> {
         ("postgres", Some(args)) => {
             run_diesel::<rowdy_diesel::postgres::Configuration, _, _, _>(args)
         }
+        ("seed", Some(args)) => seed(args),
         _ => unreachable!("Unknown subcommand encountered."),
     }
 }
@@ -167,6 +168,70 @@ where
                 .required(true),
         );
 
+    let seed = SubCommand::with_name("seed")
+        .about(
+            "Hash a password read from STDIN and print the SQL statement to insert the user \
+             into the `users` table of a database backed authenticator.",
+        )
+        .arg(
+            Arg::with_name("database")
+                .index(1)
+                .help("The type of database the statement is for")
+                .takes_value(true)
+                .possible_values(&["mysql", "postgres", "sqlite"])
+                .required(true),
+        )
+        .arg(
+            Arg::with_name("username")
+                .index(2)
+                .help("The username of the user to insert")
+                .takes_value(true)
+                .value_name("username")
+                .empty_values(false)
+                .required(true),
+        )
+        .arg(
+            Arg::with_name("salt_length")
+                .long("salt-length")
+                .help("Length of the randomly generated salt in bytes. Must be at least 8.")
+                .takes_value(true)
+                .value_name("bytes")
+                .default_value("32"),
+        )
+        .arg(
+            Arg::with_name("password_storage")
+                .long("password-storage")
+                .help(
+                    "How the authenticator stores password hashes: `split` stores the hash and \
+                     the salt in their own columns, and `phc` stores the hash in the PHC string \
+                     format in the `hash` column",
+                )
+                .takes_value(true)
+                .possible_values(&["split", "phc"])
+                .default_value("split"),
+        )
+        .arg(
+            Arg::with_name("hash_encoding")
+                .long("hash-encoding")
+                .help(
+                    "The encoding of the hashes stored by the authenticator, if it stores them \
+                     as text. Only applies to the `split` password storage.",
+                )
+                .takes_value(true)
+                .possible_values(&["hex", "base64", "base64url"]),
+        )
+        .arg(
+            Arg::with_name("password_policy")
+                .long("password-policy")
+                .help(
+                    "The password policy of the authenticator as a JSON object, which the \
+                     password must meet. Defaults to the default password policy.",
+                )
+                .takes_value(true)
+                .value_name("json")
+                .empty_values(false),
+        );
+
     App::new("rowdy")
         .bin_name("rowdy")
         .version(crate_version!())
@@ -190,6 +255,9 @@ The `csv` subcommand uses a CSV file as its username database. See
 https://lawliet89.github.io/rowdy/rowdy/auth/simple/index.html for the database format.
 The `mysql`, `postgres`, and `sqlite` subcommands uses a their respective databases for usernames.
 
+The `seed` subcommand reads a password from STDIN and prints the SQL statement to insert a user
+with the hashed password for the `mysql`, `postgres`, and `sqlite` subcommands.
+
 The subcommands will change the format expected by the `basic_authenticator` key of the
 configuration JSON.
   - noop: The key is expected to be simply an empty map: i.e. `{}`
@@ -209,6 +277,7 @@ configuration JSON.
         .subcommand(mysql)
         .subcommand(sqlite)
         .subcommand(postgres)
+        .subcommand(seed)
 }
 
 /// Launch a rocket -- this function will block and never return unless on error
@@ -221,10 +290,11 @@ where
 
     let reader = input_reader(&config)?;
     let config = read_config::<B, _>(reader)?;
-    let rocket = config.ignite()?;
+    // The rocket only serves rowdy, so its catchers can describe every rejected request
+    let rocket = rowdy::rocket(config)?.catch(rowdy::catchers());
 
     // launch() will never return except in error
-    let launch_error = rocket.mount("/", rowdy::routes()).launch();
+    let launch_error = rocket.launch();
     Err(launch_error)?
 }
 
@@ -250,14 +320,117 @@ where
         println!("Migration complete.");
         Ok(())
     } else {
-        let rocket = config.ignite()?;
+        let rocket = rowdy::rocket(config)?.catch(rowdy::catchers());
 
         // launch() will never return except in error
-        let launch_error = rocket.mount("/", rowdy::routes()).launch();
+        let launch_error = rocket.launch();
         Err(launch_error)?
     }
 }
 
+/// Hash a password read from STDIN and print the SQL statement to insert the user
+///
+/// The password is checked against the password policy, and hashed for the password storage and
+/// hash encoding given, as the authenticator would for `create_user`.
+fn seed(args: &ArgMatches) -> Result<(), rowdy::Error> {
+    let database = args.value_of("database")
+        .expect("Required options to be present");
+    let username = args.value_of("username")
+        .expect("Required options to be present");
+    let salt_length = value_t!(args, "salt_length", usize).map_err(|e| e.to_string())?;
+    if salt_length < 8 {
+        Err("Salt length must be at least 8 bytes".to_string())?;
+    }
+    let password_storage = match args.value_of("password_storage") {
+        Some("phc") => rowdy_diesel::PasswordStorage::Phc,
+        _ => rowdy_diesel::PasswordStorage::Split,
+    };
+    let hash_encoding = match args.value_of("hash_encoding") {
+        Some(_) if password_storage == rowdy_diesel::PasswordStorage::Phc => {
+            Err("The hash encoding does not apply to the `phc` password storage".to_string())?
+        }
+        Some(encoding) => Some(parse_hash_encoding(encoding)?),
+        None => None,
+    };
+    let password_policy = match args.value_of("password_policy") {
+        Some(policy) => parse_password_policy(policy)?,
+        None => Default::default(),
+    };
+
+    let mut password = String::new();
+    let _ = io::stdin().read_line(&mut password)?;
+    let password = password.trim_right_matches(|c| c == '\r' || c == '\n');
+    if password.is_empty() {
+        Err("The password read from STDIN is empty".to_string())?;
+    }
+    password_policy.check(password)?;
+
+    let salt = auth::util::generate_salt(salt_length)
+        .map_err(|()| "Unable to generate a salt".to_string())?;
+    let (hash, salt) = match database {
+        "mysql" => rowdy_diesel::mysql::Authenticator::hash_password_for_storage(
+            password,
+            &salt,
+            password_storage,
+            hash_encoding,
+        ),
+        "postgres" => rowdy_diesel::postgres::Authenticator::hash_password_for_storage(
+            password,
+            &salt,
+            password_storage,
+            hash_encoding,
+        ),
+        "sqlite" => rowdy_diesel::sqlite::Authenticator::hash_password_for_storage(
+            password,
+            &salt,
+            password_storage,
+            hash_encoding,
+        ),
+        _ => unreachable!("Unknown database encountered."),
+    };
+
+    println!(
+        "{}",
+        seed_query(
+            database,
+            username,
+            &auth::util::hex_dump(&hash),
+            &auth::util::hex_dump(&salt)
+        )
+    );
+    Ok(())
+}
+
+/// Parse the name of a `HashEncoding`, as it appears in configuration
+fn parse_hash_encoding(encoding: &str) -> Result<auth::util::HashEncoding, rowdy::Error> {
+    Ok(serde_json::from_value(serde_json::Value::String(encoding.to_string()))
+        .map_err(|e| format!("Invalid hash encoding: {}", e))?)
+}
+
+/// Parse a `PasswordPolicy` from a JSON object
+fn parse_password_policy(policy: &str) -> Result<rowdy_diesel::PasswordPolicy, rowdy::Error> {
+    Ok(serde_json::from_str(policy).map_err(|e| format!("Invalid password policy: {}", e))?)
+}
+
+/// Format the SQL statement to insert a user with the hexadecimal bytes of the hash and salt
+fn seed_query(database: &str, username: &str, hash: &str, salt: &str) -> String {
+    let username = username.replace('\'', "''");
+    match database {
+        "postgres" => format!(
+            "INSERT INTO users (username, hash, salt) VALUES ('{}', '\\x{}', '\\x{}');",
+            username,
+            hash,
+            salt
+        ),
+        _ => format!(
+            "INSERT INTO users (username, hash, salt) VALUES ('{}', X'{}', X'{}');",
+            username,
+            hash,
+            salt
+        ),
+    }
+}
+
 fn read_config<B, R: Read>(reader: R) -> Result<rowdy::Configuration<B>, rowdy::Error>
 where
     B: auth::AuthenticatorConfiguration<auth::Basic>,
@@ -314,6 +487,46 @@ mod tests {
         Cursor::new(fixture())
     }
 
+    #[test]
+    fn seed_query_is_formatted_for_postgres() {
+        let query = seed_query("postgres", "mei", "abcd", "ef01");
+        assert_eq!(
+            r"INSERT INTO users (username, hash, salt) VALUES ('mei', '\xabcd', '\xef01');",
+            query
+        );
+    }
+
+    #[test]
+    fn seed_query_is_formatted_for_mysql_and_sqlite() {
+        let expected = "INSERT INTO users (username, hash, salt) VALUES ('mei', X'abcd', X'ef01');";
+        assert_eq!(expected, seed_query("mysql", "mei", "abcd", "ef01"));
+        assert_eq!(expected, seed_query("sqlite", "mei", "abcd", "ef01"));
+    }
+
+    #[test]
+    fn seed_query_escapes_username() {
+        let query = seed_query("mysql", "o'brien", "abcd", "ef01");
+        assert_eq!(
+            "INSERT INTO users (username, hash, salt) VALUES ('o''brien', X'abcd', X'ef01');",
+            query
+        );
+    }
+
+    #[test]
+    fn seed_options_are_parsed() {
+        assert_eq!(
+            auth::util::HashEncoding::Base64Url,
+            parse_hash_encoding("base64url").expect("to succeed")
+        );
+        assert!(parse_hash_encoding("base32").is_err());
+
+        let policy = parse_password_policy(r#"{"min_length": 12}"#).expect("to succeed");
+        assert_eq!(12, policy.min_length);
+        assert!(policy.check("correct horse").is_ok());
+        assert!(policy.check("too short").is_err());
+        assert!(parse_password_policy("12").is_err());
+    }
+
     #[test]
     fn noop_configuration_reading() {
         let config = to_cursor(noop_json);
@@ -0,0 +1,14 @@
+-- MySQL does not support `ADD COLUMN IF NOT EXISTS`, so the column is only added if it is missing
+-- from tables created by `schema::Migration::migrate`
+SET @token_version_exists = (
+    SELECT COUNT(*) FROM information_schema.columns
+    WHERE table_schema = DATABASE() AND table_name = 'users' AND column_name = 'token_version'
+);
+SET @add_token_version = IF(
+    @token_version_exists = 0,
+    'ALTER TABLE `users` ADD COLUMN `token_version` INT NOT NULL DEFAULT 0',
+    'SELECT 1'
+);
+PREPARE add_token_version FROM @add_token_version;
+EXECUTE add_token_version;
+DEALLOCATE PREPARE add_token_version;
@@ -0,0 +1 @@
+ALTER TABLE `users` MODIFY `hash` BINARY(32) NOT NULL;
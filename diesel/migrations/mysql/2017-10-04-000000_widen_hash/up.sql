@@ -0,0 +1,3 @@
+-- `BINARY(32)` only fits raw hashes, and pads shorter values. Hashes in the PHC string format,
+-- or encoded as text, need a wider column.
+ALTER TABLE `users` MODIFY `hash` VARBINARY(255) NOT NULL;
@@ -0,0 +1,6 @@
+CREATE TABLE IF NOT EXISTS users (
+    username VARCHAR(255) UNIQUE NOT NULL,
+    hash BYTEA NOT NULL,
+    salt BYTEA NOT NULL,
+    PRIMARY KEY (username)
+);
@@ -0,0 +1,6 @@
+CREATE TABLE IF NOT EXISTS reference_tokens (
+    reference VARCHAR(36) NOT NULL,
+    token TEXT NOT NULL,
+    expires_at BIGINT NOT NULL,
+    PRIMARY KEY (reference)
+);
@@ -0,0 +1,6 @@
+CREATE TABLE IF NOT EXISTS 'users' (
+    'username' VARCHAR(255) UNIQUE NOT NULL,
+    'hash' BLOB(32) NOT NULL,
+    'salt' BLOB(255) NOT NULL,
+    PRIMARY KEY ('username')
+);
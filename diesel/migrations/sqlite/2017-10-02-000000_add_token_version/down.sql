@@ -0,0 +1,11 @@
+-- SQLite cannot drop columns, so the table is rebuilt without the column
+CREATE TABLE 'users_without_token_version' (
+    'username' VARCHAR(255) UNIQUE NOT NULL,
+    'hash' BLOB(32) NOT NULL,
+    'salt' BLOB(255) NOT NULL,
+    PRIMARY KEY ('username')
+);
+INSERT INTO 'users_without_token_version' (username, hash, salt)
+    SELECT username, hash, salt FROM 'users';
+DROP TABLE 'users';
+ALTER TABLE 'users_without_token_version' RENAME TO 'users';
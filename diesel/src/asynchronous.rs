@@ -0,0 +1,91 @@
+//! Asynchronous authenticator module
+//!
+//! Requires `features = ["async"]` in your `Cargo.toml`
+//!
+//! Verifying credentials involves querying the database and hashing the password with `argon2i`,
+//! both of which block the calling thread. If you are verifying credentials from within an
+//! event loop, use the `Authenticator` in this module to perform the verification on a
+//! thread pool instead.
+use std::sync::Arc;
+
+use futures_cpupool::{CpuFuture, CpuPool};
+use rowdy::auth::AuthenticationResult;
+
+use {Error, Search};
+
+/// Wraps a diesel backed authenticator to verify credentials on a thread pool.
+///
+/// The synchronous authenticator remains usable for Rocket, which handles requests on
+/// blocking worker threads.
+pub struct Authenticator<T>
+where
+    T: Search,
+{
+    authenticator: Arc<::Authenticator<T>>,
+    pool: CpuPool,
+}
+
+impl<T> Authenticator<T>
+where
+    T: Search,
+{
+    /// Wrap an authenticator, performing verifications on the provided thread pool
+    pub fn new(authenticator: ::Authenticator<T>, pool: CpuPool) -> Self {
+        Authenticator {
+            authenticator: Arc::new(authenticator),
+            pool,
+        }
+    }
+
+    /// Wrap an authenticator, performing verifications on a new thread pool with one thread
+    /// per CPU
+    pub fn with_new_pool(authenticator: ::Authenticator<T>) -> Self {
+        Self::new(authenticator, CpuPool::new_num_cpus())
+    }
+
+    /// Verify that some user with the provided password exists in the database, and the password
+    /// is correct. The verification is performed on the thread pool.
+    ///
+    /// See `Authenticator::verify` for details.
+    pub fn verify(
+        &self,
+        username: &str,
+        password: &str,
+        include_refresh_payload: bool,
+    ) -> CpuFuture<AuthenticationResult, Error> {
+        let authenticator = Arc::clone(&self.authenticator);
+        let username = username.to_string();
+        let password = password.to_string();
+
+        self.pool.spawn_fn(move || {
+            authenticator.verify(&username, &password, include_refresh_payload)
+        })
+    }
+}
+
+#[cfg(all(test, feature = "sqlite"))]
+mod tests {
+    use futures::Future;
+
+    use schema::Migration;
+    use super::*;
+
+    fn make_authenticator() -> Authenticator<::diesel::sqlite::SqliteConnection> {
+        let authenticator = ::sqlite::Authenticator::with_path("../target/sqlite.db")
+            .expect("To be constructed successfully");
+        authenticator.migrate().expect("To succeed and be idempotent");
+        Authenticator::with_new_pool(authenticator)
+    }
+
+    #[test]
+    fn verification_of_unknown_user_fails() {
+        let authenticator = make_authenticator();
+
+        let result = authenticator.verify("does not exist", "password", false).wait();
+        match result {
+            Err(Error::AuthenticationFailure) => {}
+            Err(e) => panic!("Unexpected error {:?}", e),
+            Ok(_) => panic!("Verification should have failed"),
+        }
+    }
+}
@@ -0,0 +1,191 @@
+//! Metrics of the connection pools of an authenticator
+//!
+//! With the `metrics` feature enabled, authenticators count the connections checked out of their
+//! pools, how long the checkouts waited, including retries, and how many of them timed out. A
+//! [`PoolMetricsCollector`] renders these, along with the current state of the pools, in the
+//! Prometheus text format. The state of each pool is labelled with `pool="primary"`, or with
+//! `pool="replica"` for the pool to the read replica, if there is one.
+//!
+//! Place the collector in the managed state of Rocket as a
+//! `Box<rowdy::metrics::MetricsCollector>` to append them to the output of the `/metrics` route:
+//!
+//! ```rust,ignore
+//! let collector: Box<MetricsCollector> = Box::new(authenticator.metrics_collector());
+//! let rocket = rocket.manage(collector);
+//! ```
+use std::fmt::Write;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use rowdy::metrics::{Histogram, MetricsCollector};
+
+use {Connection, ConnectionPool, PoolStatistics};
+
+/// Counters of the checkouts of connections from a pool
+#[derive(Debug, Default)]
+pub struct PoolMetrics {
+    checkouts: AtomicUsize,
+    timeouts: AtomicUsize,
+    wait: Histogram,
+}
+
+impl PoolMetrics {
+    /// Record a checkout that waited for `wait`, and whether it timed out after its retries
+    pub(crate) fn record_checkout(&self, wait: Duration, timed_out: bool) {
+        if timed_out {
+            let _ = self.timeouts.fetch_add(1, Ordering::Relaxed);
+        } else {
+            let _ = self.checkouts.fetch_add(1, Ordering::Relaxed);
+        }
+        self.wait.observe(wait);
+    }
+
+    /// Number of connections checked out of the pool
+    pub fn checkouts(&self) -> usize {
+        self.checkouts.load(Ordering::Relaxed)
+    }
+
+    /// Number of checkouts that timed out after exhausting their retries
+    pub fn timeouts(&self) -> usize {
+        self.timeouts.load(Ordering::Relaxed)
+    }
+}
+
+/// Renders the metrics of the connection pools of an authenticator. Clones of the pools and of
+/// their counters are held, so the collector stays valid independently of the authenticator.
+pub struct PoolMetricsCollector<T>
+where
+    T: Connection + 'static,
+{
+    pool: ConnectionPool<T>,
+    read_pool: Option<ConnectionPool<T>>,
+    metrics: Arc<PoolMetrics>,
+}
+
+impl<T> PoolMetricsCollector<T>
+where
+    T: Connection + 'static,
+{
+    pub(crate) fn new(
+        pool: ConnectionPool<T>,
+        read_pool: Option<ConnectionPool<T>>,
+        metrics: Arc<PoolMetrics>,
+    ) -> Self {
+        PoolMetricsCollector {
+            pool,
+            read_pool,
+            metrics,
+        }
+    }
+
+    /// The counters of the checkouts of connections
+    pub fn metrics(&self) -> &PoolMetrics {
+        &self.metrics
+    }
+
+    /// The current state of the pool to the primary database
+    pub fn statistics(&self) -> PoolStatistics {
+        PoolStatistics::of(&self.pool)
+    }
+
+    /// The current state of the pool to the read replica, if there is one
+    pub fn read_statistics(&self) -> Option<PoolStatistics> {
+        self.read_pool.as_ref().map(PoolStatistics::of)
+    }
+
+    /// The current state of each pool, with its label
+    fn labelled_statistics(&self) -> Vec<(&'static str, PoolStatistics)> {
+        let mut statistics = vec![("primary", self.statistics())];
+        if let Some(read_statistics) = self.read_statistics() {
+            statistics.push(("replica", read_statistics));
+        }
+        statistics
+    }
+}
+
+impl<T> MetricsCollector for PoolMetricsCollector<T>
+where
+    T: Connection + Send + 'static,
+{
+    fn render(&self, output: &mut String) {
+        let statistics = self.labelled_statistics();
+
+        let _ = writeln!(
+            output,
+            "# HELP rowdy_pool_connections Connections of the pools by state."
+        );
+        let _ = writeln!(output, "# TYPE rowdy_pool_connections gauge");
+        for &(pool, ref statistics) in &statistics {
+            let _ = writeln!(
+                output,
+                "rowdy_pool_connections{{pool=\"{}\",state=\"idle\"}} {}",
+                pool,
+                statistics.idle_connections
+            );
+            let _ = writeln!(
+                output,
+                "rowdy_pool_connections{{pool=\"{}\",state=\"in_use\"}} {}",
+                pool,
+                statistics.in_use_connections
+            );
+        }
+
+        let _ = writeln!(
+            output,
+            "# HELP rowdy_pool_max_connections Maximum number of connections of the pools."
+        );
+        let _ = writeln!(output, "# TYPE rowdy_pool_max_connections gauge");
+        for &(pool, ref statistics) in &statistics {
+            let _ = writeln!(
+                output,
+                "rowdy_pool_max_connections{{pool=\"{}\"}} {}",
+                pool,
+                statistics.max_size
+            );
+        }
+
+        let _ = writeln!(
+            output,
+            "# HELP rowdy_pool_checkouts_total Connections checked out of the pool."
+        );
+        let _ = writeln!(output, "# TYPE rowdy_pool_checkouts_total counter");
+        let _ = writeln!(output, "rowdy_pool_checkouts_total {}", self.metrics.checkouts());
+
+        let _ = writeln!(
+            output,
+            "# HELP rowdy_pool_checkout_timeouts_total Checkouts that timed out after retrying."
+        );
+        let _ = writeln!(output, "# TYPE rowdy_pool_checkout_timeouts_total counter");
+        let _ = writeln!(
+            output,
+            "rowdy_pool_checkout_timeouts_total {}",
+            self.metrics.timeouts()
+        );
+
+        let _ = writeln!(
+            output,
+            "# HELP rowdy_pool_checkout_wait_seconds Time spent waiting for connections."
+        );
+        let _ = writeln!(output, "# TYPE rowdy_pool_checkout_wait_seconds histogram");
+        self.metrics
+            .wait
+            .render("rowdy_pool_checkout_wait_seconds", output);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checkouts_and_timeouts_are_counted() {
+        let metrics = PoolMetrics::default();
+        metrics.record_checkout(Duration::from_millis(1), false);
+        metrics.record_checkout(Duration::from_millis(2), false);
+        metrics.record_checkout(Duration::from_secs(1), true);
+
+        assert_eq!(metrics.checkouts(), 2);
+        assert_eq!(metrics.timeouts(), 1);
+    }
+}
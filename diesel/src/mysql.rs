@@ -1,33 +1,64 @@
 //! MySQL authenticator module
 //!
 //! Requires `features = ["mysql"]` in your `Cargo.toml`
+use std::path::Path;
+
 use diesel::prelude::*;
+use diesel::dsl::sql;
 use diesel::mysql::MysqlConnection;
-use r2d2::Config;
-use r2d2_diesel::ConnectionManager;
+use diesel::result::ConnectionError;
+use diesel::types::Text;
+use r2d2::CustomizeConnection;
+use r2d2_diesel::{self, ConnectionManager};
 
 use rowdy;
 use rowdy::auth::{AuthenticatorConfiguration, Basic};
 
-use {ConnectionPool, Error, PooledConnection};
+use {instr, pool_config, read_secret_file, Error, PoolConfig, PooledConnection, Search,
+     StoredUsername, User, UserFilter, LIKE_ESCAPE};
 use schema;
 
 /// A rowdy authenticator that uses a MySQL backed database to provide the users
 pub type Authenticator = ::Authenticator<MysqlConnection>;
 
 impl Authenticator {
+    /// Using a database connection string of the form
+    /// `mysql://[user[:password]@]host/database_name`,
+    /// create an authenticator that is backed by a connection pool with the default options.
+    /// Connections are health checked on checkout; see `pool_config`.
+    pub fn new(database_url: &str) -> Result<Self, Error> {
+        Self::with_config(database_url, pool_config(true))
+    }
+
+    /// Like `new`, but with the options of the connection pool in `config`
+    pub fn with_config(
+        database_url: &str,
+        config: PoolConfig<MysqlConnection>,
+    ) -> Result<Self, Error> {
+        Self::connect_pool(database_url, &Default::default(), config)
+    }
+
     /// Using a database connection string of the form
     /// `mysql://[user[:password]@]host/database_name`,
     /// create an authenticator that is backed by a connection pool to a MySQL database
     pub fn with_uri(uri: &str) -> Result<Self, Error> {
-        // Attempt a test connection with diesel
-        let _ = Self::connect(uri)?;
+        Self::new(uri)
+    }
 
-        let config = Config::default();
-        let manager = ConnectionManager::new(uri);
-        debug_!("Creating a connection pool");
-        let pool = ConnectionPool::new(config, manager)?;
-        Ok(Self::new(pool))
+    /// Like `with_uri`, but reads the connection string from a file, so that the password is
+    /// kept out of the configuration, the environment, and the command line.
+    /// See [`read_secret_file`](../fn.read_secret_file.html).
+    pub fn with_uri_file<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        Self::with_uri(&read_secret_file(path)?)
+    }
+
+    /// Like `with_uri`, but with additional connection options such as TLS.
+    ///
+    /// If `require_tls` is set, the test connection is checked to be encrypted, and
+    /// `Error::TlsError` is returned if it is not. The connections of the pool are checked as
+    /// they are established as well. See [`ConnectionOptions`].
+    pub fn with_uri_and_options(uri: &str, options: &ConnectionOptions) -> Result<Self, Error> {
+        Self::connect_pool(uri, options, pool_config(true))
     }
 
     /// Create a new `Authenticator` with a database config
@@ -39,8 +70,40 @@ impl Authenticator {
         user: &str,
         pass: &str,
     ) -> Result<Self, Error> {
-        let database_uri = format!("mysql://{}:{}@{}:{}/{}", user, pass, host, port, database);
-        Self::with_uri(&database_uri)
+        Self::with_configuration_and_options(host, port, database, user, pass, &Default::default())
+    }
+
+    /// Create a new `Authenticator` with a database config and additional connection options
+    pub fn with_configuration_and_options(
+        host: &str,
+        port: u16,
+        database: &str,
+        user: &str,
+        pass: &str,
+        options: &ConnectionOptions,
+    ) -> Result<Self, Error> {
+        let database_uri = Self::configuration_uri(host, port, database, user, pass);
+        Self::with_uri_and_options(&database_uri, options)
+    }
+
+    /// Make a connection string from a database config
+    fn configuration_uri(host: &str, port: u16, database: &str, user: &str, pass: &str) -> String {
+        format!("mysql://{}:{}@{}:{}/{}", user, pass, host, port, database)
+    }
+
+    /// Test the connection, and create the pool with the connection options applied
+    fn connect_pool(
+        uri: &str,
+        options: &ConnectionOptions,
+        config: PoolConfig<MysqlConnection>,
+    ) -> Result<Self, Error> {
+        // Attempt a test connection with diesel
+        let connection = Self::connect(uri)?;
+        if options.require_tls && !Self::is_encrypted(&connection)? {
+            Err(Error::TlsError(NOT_ENCRYPTED.to_string()))?;
+        }
+
+        Self::with_pool_config(uri, options.customize(config))
     }
 
     /// Test connection with the database uri
@@ -48,6 +111,242 @@ impl Authenticator {
         debug_!("Attempting a connection to MySQL database");
         Ok(MysqlConnection::establish(uri)?)
     }
+
+    /// Returns whether the connection is encrypted with TLS
+    fn is_encrypted(connection: &MysqlConnection) -> QueryResult<bool> {
+        debug_!("Verifying that the connection to the MySQL database is encrypted");
+        let status = sql::<(Text, Text)>("SHOW SESSION STATUS LIKE 'Ssl_cipher'")
+            .load::<(String, String)>(connection)?;
+
+        Ok(match status.first() {
+            Some(&(_, ref cipher)) => !cipher.is_empty(),
+            None => false,
+        })
+    }
+
+    /// Run the migrations embedded in this crate to create or update the `users` table, so that
+    /// it matches the schema expected by rowdy. Migrations that have already been run are skipped.
+    pub fn run_migrations(&self) -> Result<(), Error> {
+        let connection = self.get_pooled_connection()?;
+        migrations::embedded_migrations::run(&*connection)?;
+        Ok(())
+    }
+}
+
+/// Additional options for connections to a MySQL database
+///
+/// Diesel connects with the default TLS mode of the MySQL client library, which encrypts the
+/// connection if the server supports it, but does not verify the certificate of the server. Diesel
+/// does not pass any other TLS options to the client library, so neither the mode nor the CA can
+/// be configured here.
+///
+/// # Examples
+/// ```json
+/// {
+///     "require_tls": true
+/// }
+/// ```
+#[derive(Clone, Eq, PartialEq, Serialize, Deserialize, Debug, Default)]
+pub struct ConnectionOptions {
+    /// Whether connections must be encrypted with TLS. Every connection is checked when it is
+    /// established, and rejected if it is not encrypted. The identity of the server is not
+    /// verified. Defaults to `false`.
+    #[serde(default)]
+    pub require_tls: bool,
+}
+
+impl ConnectionOptions {
+    /// Apply the options to the connections of a pool
+    fn customize(&self, config: PoolConfig<MysqlConnection>) -> PoolConfig<MysqlConnection> {
+        if self.require_tls {
+            config.connection_customizer(Box::new(RequireTls))
+        } else {
+            config
+        }
+    }
+}
+
+/// Rejects connections of a pool that are not encrypted with TLS, as they are established
+#[derive(Debug)]
+struct RequireTls;
+
+impl CustomizeConnection<MysqlConnection, r2d2_diesel::Error> for RequireTls {
+    fn on_acquire(&self, connection: &mut MysqlConnection) -> Result<(), r2d2_diesel::Error> {
+        match Authenticator::is_encrypted(connection) {
+            Ok(true) => Ok(()),
+            Ok(false) => Err(r2d2_diesel::Error::ConnectionError(
+                ConnectionError::BadConnection(NOT_ENCRYPTED.to_string()),
+            )),
+            Err(e) => Err(r2d2_diesel::Error::QueryError(e)),
+        }
+    }
+}
+
+/// Reason for rejecting a connection that is not encrypted
+const NOT_ENCRYPTED: &str = "The connection to the MySQL database is not encrypted";
+
+impl Search for MysqlConnection {
+    fn search_users(&self, search_user: &str) -> Result<Vec<User>, Error> {
+        use schema::users::dsl::*;
+
+        Ok(users.filter(username.eq(search_user)).load::<User>(self)?)
+    }
+
+    fn user_exists(&self, search_user: &str) -> Result<bool, Error> {
+        use diesel::dsl::exists;
+        use schema::users::dsl::*;
+
+        Ok(::diesel::select(exists(users.filter(username.eq(search_user))))
+            .get_result::<bool>(self)?)
+    }
+
+    fn list_users(
+        &self,
+        offset: i64,
+        limit: i64,
+        filter: Option<&UserFilter>,
+    ) -> Result<Vec<StoredUsername>, Error> {
+        use schema::users::dsl::*;
+
+        let mut query = users
+            .select(username)
+            .order(username.asc())
+            .offset(offset)
+            .limit(limit)
+            .into_boxed();
+        if let Some(filter) = filter {
+            query = match *filter {
+                UserFilter::Pattern(_) => query.filter(username.like(filter.like_pattern())),
+                UserFilter::Prefix(_) => {
+                    query.filter(username.like(filter.like_pattern()).escape(LIKE_ESCAPE))
+                }
+                UserFilter::Contains(ref string) => {
+                    query.filter(instr(username, string.as_str()).gt(0))
+                }
+            };
+        }
+
+        Ok(query.load::<StoredUsername>(self)?)
+    }
+
+    fn increment_token_version(&self, search_user: &str) -> Result<usize, Error> {
+        use schema::users::dsl::*;
+
+        Ok(::diesel::update(users.filter(username.eq(search_user)))
+            .set(token_version.eq(token_version + 1))
+            .execute(self)?)
+    }
+
+    fn insert_user(
+        &self,
+        new_username: &str,
+        new_hash: &[u8],
+        new_salt: &[u8],
+    ) -> Result<(), Error> {
+        use schema::users::dsl::*;
+
+        let _ = ::diesel::insert_into(users)
+            .values((
+                username.eq(new_username),
+                hash.eq(new_hash),
+                salt.eq(new_salt),
+            ))
+            .execute(self)?;
+        Ok(())
+    }
+
+    fn update_password(
+        &self,
+        search_user: &str,
+        new_hash: &[u8],
+        new_salt: &[u8],
+    ) -> Result<usize, Error> {
+        use schema::users::dsl::*;
+
+        Ok(::diesel::update(users.filter(username.eq(search_user)))
+            .set((hash.eq(new_hash), salt.eq(new_salt)))
+            .execute(self)?)
+    }
+
+    fn replace_password(
+        &self,
+        search_user: &str,
+        old_hash: &[u8],
+        old_salt: &[u8],
+        new_hash: &[u8],
+        new_salt: &[u8],
+    ) -> Result<usize, Error> {
+        use schema::users::dsl::*;
+
+        let user = users
+            .filter(username.eq(search_user))
+            .filter(hash.eq(old_hash))
+            .filter(salt.eq(old_salt));
+        Ok(::diesel::update(user)
+            .set((hash.eq(new_hash), salt.eq(new_salt)))
+            .execute(self)?)
+    }
+
+    fn insert_reference_token(
+        &self,
+        new_reference: &str,
+        new_token: &str,
+        new_expires_at: i64,
+    ) -> Result<(), Error> {
+        use schema::reference_tokens::dsl::*;
+
+        let _ = ::diesel::insert_into(reference_tokens)
+            .values((
+                reference.eq(new_reference),
+                token.eq(new_token),
+                expires_at.eq(new_expires_at),
+            ))
+            .execute(self)?;
+        Ok(())
+    }
+
+    fn find_reference_token(
+        &self,
+        search_reference: &str,
+        now: i64,
+    ) -> Result<Option<String>, Error> {
+        use schema::reference_tokens::dsl::*;
+
+        Ok(reference_tokens
+            .filter(reference.eq(search_reference))
+            .filter(expires_at.gt(now))
+            .select(token)
+            .first::<String>(self)
+            .optional()?)
+    }
+
+    fn delete_reference_token(&self, search_reference: &str) -> Result<usize, Error> {
+        use schema::reference_tokens::dsl::*;
+
+        Ok(::diesel::delete(reference_tokens.filter(reference.eq(search_reference))).execute(self)?)
+    }
+
+    fn delete_expired_reference_tokens(&self, now: i64) -> Result<usize, Error> {
+        use schema::reference_tokens::dsl::*;
+
+        Ok(::diesel::delete(reference_tokens.filter(expires_at.le(now))).execute(self)?)
+    }
+
+    fn check_schema(&self) -> Result<(), Error> {
+        use schema::users::dsl::*;
+
+        let _ = users
+            .limit(1)
+            .load::<User>(self)
+            .map_err(|e| Error::SchemaMismatch(e.to_string()))?;
+        Ok(())
+    }
+}
+
+/// Migrations embedded from `migrations/mysql`
+mod migrations {
+    #![allow(missing_docs, unused_import_braces, unused_qualifications)]
+    embed_migrations!("migrations/mysql");
 }
 
 impl schema::Migration<MysqlConnection> for Authenticator {
@@ -57,13 +356,17 @@ impl schema::Migration<MysqlConnection> for Authenticator {
         self.get_pooled_connection()
     }
 
+    /// Also widens the `hash` column of tables created with `BINARY(32)`, so that it can hold
+    /// hashes in the PHC string format
     fn migration_query(&self) -> &str {
         r#"CREATE TABLE IF NOT EXISTS `users` (
     `username` VARCHAR(255) UNIQUE NOT NULL,
-    `hash` BINARY(32) NOT NULL,
+    `hash` VARBINARY(255) NOT NULL,
     `salt` VARBINARY(255) NOT NULL,
+    `token_version` INT NOT NULL DEFAULT 0,
     PRIMARY KEY (`username`)
-);"#
+);
+ALTER TABLE `users` MODIFY `hash` VARBINARY(255) NOT NULL;"#
     }
 }
 
@@ -90,25 +393,73 @@ pub struct Configuration {
     pub database: String,
     /// MySql user
     pub user: String,
-    /// MySql password
+    /// MySql password. Ignored if `password_file` is set.
+    #[serde(default)]
     pub password: String,
+    /// Path to a file containing the MySql password, such as a secret mounted by the platform.
+    /// It is read when the authenticator is made, and takes precedence over `password`.
+    #[serde(default)]
+    pub password_file: Option<String>,
+    /// Additional connection options, such as TLS
+    #[serde(default)]
+    pub connection_options: ConnectionOptions,
+    /// Whether connections are checked before they are checked out of the pool. Defaults to
+    /// `true`. See [`pool_config`](../fn.pool_config.html).
+    #[serde(default = "::default_health_check")]
+    pub health_check: bool,
+    /// Host of a read replica of the database, to which lookups of users are routed. The port,
+    /// database, and credentials are the same as those of the primary database. By default,
+    /// there is no replica, and all queries go to the primary database. See the section on read
+    /// replicas of [`Authenticator`](../struct.Authenticator.html) for the caveats.
+    #[serde(default)]
+    pub replica_host: Option<String>,
 }
 
 fn default_port() -> u16 {
     3306
 }
 
+impl Configuration {
+    /// Returns the password, reading it from `password_file` if it is set
+    fn password(&self) -> Result<String, Error> {
+        match self.password_file {
+            Some(ref password_file) => read_secret_file(password_file),
+            None => Ok(self.password.clone()),
+        }
+    }
+}
+
 impl AuthenticatorConfiguration<Basic> for Configuration {
     type Authenticator = Authenticator;
 
     fn make_authenticator(&self) -> Result<Self::Authenticator, rowdy::Error> {
-        Ok(Authenticator::with_configuration(
+        let password = self.password()?;
+        let uri = Authenticator::configuration_uri(
             &self.host,
             self.port,
             &self.database,
             &self.user,
-            &self.password,
-        )?)
+            &password,
+        );
+        let mut authenticator = Authenticator::connect_pool(
+            &uri,
+            &self.connection_options,
+            pool_config(self.health_check),
+        )?;
+        if let Some(ref replica_host) = self.replica_host {
+            let replica_uri = Authenticator::configuration_uri(
+                replica_host,
+                self.port,
+                &self.database,
+                &self.user,
+                &password,
+            );
+            let replica_config = self.connection_options
+                .customize(pool_config(self.health_check));
+            authenticator = authenticator.with_read_replica(&replica_uri, replica_config)?;
+        }
+        authenticator.check_schema()?;
+        Ok(authenticator)
     }
 }
 
@@ -175,6 +526,53 @@ mod tests {
             .expect("To succeed and be idempotent")
     }
 
+    /// Embedded migrations should be idempotent, and create the expected schema
+    #[test]
+    fn embedded_migrations_are_idempotent() {
+        let authenticator = make_authenticator();
+        authenticator.run_migrations().expect("To succeed");
+        authenticator
+            .run_migrations()
+            .expect("To succeed and be idempotent");
+        authenticator.check_schema().expect("To match the schema");
+    }
+
+    #[test]
+    fn phc_strings_fit_in_the_hash_column() {
+        use PasswordStorage;
+
+        let mut authenticator = make_authenticator();
+        authenticator.run_migrations().expect("To succeed");
+        authenticator.set_password_storage(PasswordStorage::Phc);
+        authenticator
+            .create_user("phc", "correct horse")
+            .expect("To be created");
+
+        let _ = authenticator
+            .verify("phc", "correct horse", false)
+            .expect("To verify correctly");
+        assert!(authenticator.verify("phc", "wrong", false).is_err());
+    }
+
+    #[test]
+    fn encoded_hashes_fit_in_the_hash_column() {
+        use rowdy::auth::util::HashEncoding;
+
+        let mut authenticator = make_authenticator();
+        authenticator.run_migrations().expect("To succeed");
+        let encodings = [("hex", HashEncoding::Hex), ("base64", HashEncoding::Base64)];
+        for &(username, encoding) in &encodings {
+            authenticator.set_hash_encoding(encoding);
+            authenticator
+                .create_user(username, "correct horse")
+                .expect("To be created");
+            let _ = authenticator
+                .verify(username, "correct horse", false)
+                .expect("To verify correctly");
+            assert!(authenticator.verify(username, "wrong", false).is_err());
+        }
+    }
+
     #[test]
     fn authentication_with_username_and_password() {
         let authenticator = make_authenticator();
@@ -228,6 +626,10 @@ mod tests {
             database: "rowdy".to_string(),
             user: "root".to_string(),
             password: "".to_string(),
+            password_file: None,
+            connection_options: Default::default(),
+            health_check: true,
+            replica_host: None,
         };
         assert_eq!(deserialized, expected_config);
 
@@ -235,4 +637,17 @@ mod tests {
             .make_authenticator()
             .expect("to be constructed correctly");
     }
+
+    #[test]
+    fn connection_options_are_deserialized() {
+        use serde_json;
+
+        let options: ConnectionOptions =
+            serde_json::from_str(r#"{ "require_tls": true }"#).expect("to deserialize");
+        assert!(options.require_tls);
+
+        let options: ConnectionOptions = serde_json::from_str("{}").expect("to deserialize");
+        assert_eq!(options, ConnectionOptions::default());
+        assert!(!options.require_tls);
+    }
 }
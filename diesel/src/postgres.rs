@@ -1,33 +1,56 @@
 //! PostgresSQL authenticator module
 //!
 //! Requires `features = ["postgres"]` in your `Cargo.toml`
+use std::path::Path;
+
 use diesel::prelude::*;
 use diesel::pg::PgConnection;
-use r2d2::Config;
+use diesel::sql_types::{Integer, Text};
 use r2d2_diesel::ConnectionManager;
 
 use rowdy;
 use rowdy::auth::{AuthenticatorConfiguration, Basic};
 
-use {ConnectionPool, Error, PooledConnection};
+use {lower, pool_config, read_secret_file, Error, PoolConfig, PooledConnection, Search,
+     StoredUsername, User, UserFilter, LIKE_ESCAPE};
 use schema;
 
+sql_function!(strpos, strpos_t, (x: Text, y: Text) -> Integer);
+
 /// A rowdy authenticator that uses a PostgresSQL backed database to provide the users
 pub type Authenticator = ::Authenticator<PgConnection>;
 
 impl Authenticator {
+    /// Using a database connection string of the form
+    /// `postgresql://[user[:password]@][host][:port][/database_name]`,
+    /// create an authenticator that is backed by a connection pool with the default options.
+    /// Connections are health checked on checkout; see `pool_config`.
+    pub fn new(database_url: &str) -> Result<Self, Error> {
+        Self::with_config(database_url, pool_config(true))
+    }
+
+    /// Like `new`, but with the options of the connection pool in `config`
+    pub fn with_config(
+        database_url: &str,
+        config: PoolConfig<PgConnection>,
+    ) -> Result<Self, Error> {
+        // Attempt a test connection with diesel
+        let _ = Self::connect(database_url)?;
+        Self::with_pool_config(database_url, config)
+    }
+
     /// Using a database connection string of the form
     /// `postgresql://[user[:password]@][host][:port][/database_name]`,
     /// create an authenticator that is backed by a connection pool to a PostgresSQL database
     pub fn with_uri(uri: &str) -> Result<Self, Error> {
-        // Attempt a test connection with diesel
-        let _ = Self::connect(uri)?;
+        Self::new(uri)
+    }
 
-        let config = Config::default();
-        let manager = ConnectionManager::new(uri);
-        debug_!("Creating a connection pool");
-        let pool = ConnectionPool::new(config, manager)?;
-        Ok(Self::new(pool))
+    /// Like `with_uri`, but reads the connection string from a file, so that the password is
+    /// kept out of the configuration, the environment, and the command line.
+    /// See [`read_secret_file`](../fn.read_secret_file.html).
+    pub fn with_uri_file<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        Self::with_uri(&read_secret_file(path)?)
     }
 
     /// Create a new `Authenticator` with a database config
@@ -39,15 +62,40 @@ impl Authenticator {
         user: &str,
         pass: &str,
     ) -> Result<Self, Error> {
-        let database_uri = format!(
+        Self::with_uri(&Self::configuration_uri(host, port, database, user, pass))
+    }
+
+    /// Make a connection string from a database config
+    fn configuration_uri(host: &str, port: u16, database: &str, user: &str, pass: &str) -> String {
+        format!(
             "postgresql://{}:{}@{}:{}/{}",
             user,
             pass,
             host,
             port,
             database
-        );
-        Self::with_uri(&database_uri)
+        )
+    }
+
+    /// Run the migrations embedded in this crate to create or update the `users` table, so that
+    /// it matches the schema expected by rowdy. Migrations that have already been run are skipped.
+    pub fn run_migrations(&self) -> Result<(), Error> {
+        let connection = self.get_pooled_connection()?;
+        migrations::embedded_migrations::run(&*connection)?;
+        Ok(())
+    }
+
+    /// Run the opt-in migration creating a unique index on `LOWER(username)`, so that the
+    /// case-insensitive lookup of users does not scan the `users` table. It is not run by
+    /// `run_migrations` or `migrate`.
+    ///
+    /// The index also makes usernames unique regardless of case, so that `Mei` can no longer be
+    /// created alongside `mei`. The migration fails, and leaves the table unchanged, if the table
+    /// already has usernames that differ only by case. Rename or remove those users first.
+    pub fn run_username_index_migration(&self) -> Result<(), Error> {
+        let connection = self.get_pooled_connection()?;
+        username_index_migration::embedded_migrations::run(&*connection)?;
+        Ok(())
     }
 
     /// Test connection with the database uri
@@ -57,6 +105,184 @@ impl Authenticator {
     }
 }
 
+/// Usernames are compared case-insensitively. The comparison on `LOWER(username)` scans the table,
+/// unless the functional index created by `Authenticator::run_username_index_migration` exists.
+impl Search for PgConnection {
+    fn search_users(&self, search_user: &str) -> Result<Vec<User>, Error> {
+        use schema::users::dsl::*;
+
+        Ok(users
+            .filter(lower(username).eq(lower(search_user)))
+            .load::<User>(self)?)
+    }
+
+    fn user_exists(&self, search_user: &str) -> Result<bool, Error> {
+        use diesel::dsl::exists;
+        use schema::users::dsl::*;
+
+        Ok(::diesel::select(exists(users.filter(lower(username).eq(lower(search_user)))))
+            .get_result::<bool>(self)?)
+    }
+
+    fn list_users(
+        &self,
+        offset: i64,
+        limit: i64,
+        filter: Option<&UserFilter>,
+    ) -> Result<Vec<StoredUsername>, Error> {
+        use schema::users::dsl::*;
+
+        let mut query = users
+            .select(username)
+            .order(username.asc())
+            .offset(offset)
+            .limit(limit)
+            .into_boxed();
+        if let Some(filter) = filter {
+            query = match *filter {
+                UserFilter::Pattern(_) => {
+                    query.filter(lower(username).like(lower(filter.like_pattern())))
+                }
+                UserFilter::Prefix(_) => query.filter(
+                    lower(username)
+                        .like(lower(filter.like_pattern()))
+                        .escape(LIKE_ESCAPE),
+                ),
+                UserFilter::Contains(ref string) => {
+                    query.filter(strpos(lower(username), lower(string.as_str())).gt(0))
+                }
+            };
+        }
+
+        Ok(query.load::<StoredUsername>(self)?)
+    }
+
+    fn increment_token_version(&self, search_user: &str) -> Result<usize, Error> {
+        use schema::users::dsl::*;
+
+        Ok(::diesel::update(users.filter(lower(username).eq(lower(search_user))))
+            .set(token_version.eq(token_version + 1))
+            .execute(self)?)
+    }
+
+    fn insert_user(
+        &self,
+        new_username: &str,
+        new_hash: &[u8],
+        new_salt: &[u8],
+    ) -> Result<(), Error> {
+        use schema::users::dsl::*;
+
+        let _ = ::diesel::insert_into(users)
+            .values((
+                username.eq(new_username),
+                hash.eq(new_hash),
+                salt.eq(new_salt),
+            ))
+            .execute(self)?;
+        Ok(())
+    }
+
+    fn update_password(
+        &self,
+        search_user: &str,
+        new_hash: &[u8],
+        new_salt: &[u8],
+    ) -> Result<usize, Error> {
+        use schema::users::dsl::*;
+
+        Ok(::diesel::update(users.filter(lower(username).eq(lower(search_user))))
+            .set((hash.eq(new_hash), salt.eq(new_salt)))
+            .execute(self)?)
+    }
+
+    fn replace_password(
+        &self,
+        search_user: &str,
+        old_hash: &[u8],
+        old_salt: &[u8],
+        new_hash: &[u8],
+        new_salt: &[u8],
+    ) -> Result<usize, Error> {
+        use schema::users::dsl::*;
+
+        let user = users
+            .filter(lower(username).eq(lower(search_user)))
+            .filter(hash.eq(old_hash))
+            .filter(salt.eq(old_salt));
+        Ok(::diesel::update(user)
+            .set((hash.eq(new_hash), salt.eq(new_salt)))
+            .execute(self)?)
+    }
+
+    fn insert_reference_token(
+        &self,
+        new_reference: &str,
+        new_token: &str,
+        new_expires_at: i64,
+    ) -> Result<(), Error> {
+        use schema::reference_tokens::dsl::*;
+
+        let _ = ::diesel::insert_into(reference_tokens)
+            .values((
+                reference.eq(new_reference),
+                token.eq(new_token),
+                expires_at.eq(new_expires_at),
+            ))
+            .execute(self)?;
+        Ok(())
+    }
+
+    fn find_reference_token(
+        &self,
+        search_reference: &str,
+        now: i64,
+    ) -> Result<Option<String>, Error> {
+        use schema::reference_tokens::dsl::*;
+
+        Ok(reference_tokens
+            .filter(reference.eq(search_reference))
+            .filter(expires_at.gt(now))
+            .select(token)
+            .first::<String>(self)
+            .optional()?)
+    }
+
+    fn delete_reference_token(&self, search_reference: &str) -> Result<usize, Error> {
+        use schema::reference_tokens::dsl::*;
+
+        Ok(::diesel::delete(reference_tokens.filter(reference.eq(search_reference))).execute(self)?)
+    }
+
+    fn delete_expired_reference_tokens(&self, now: i64) -> Result<usize, Error> {
+        use schema::reference_tokens::dsl::*;
+
+        Ok(::diesel::delete(reference_tokens.filter(expires_at.le(now))).execute(self)?)
+    }
+
+    fn check_schema(&self) -> Result<(), Error> {
+        use schema::users::dsl::*;
+
+        let _ = users
+            .limit(1)
+            .load::<User>(self)
+            .map_err(|e| Error::SchemaMismatch(e.to_string()))?;
+        Ok(())
+    }
+}
+
+/// Migrations embedded from `migrations/postgres`
+mod migrations {
+    #![allow(missing_docs, unused_import_braces, unused_qualifications)]
+    embed_migrations!("migrations/postgres");
+}
+
+/// Opt-in migration embedded from `migrations/postgres_username_index`
+mod username_index_migration {
+    #![allow(missing_docs, unused_import_braces, unused_qualifications)]
+    embed_migrations!("migrations/postgres_username_index");
+}
+
 impl schema::Migration<PgConnection> for Authenticator {
     type Connection = PooledConnection<ConnectionManager<PgConnection>>;
 
@@ -69,6 +295,7 @@ impl schema::Migration<PgConnection> for Authenticator {
     username VARCHAR(255) UNIQUE NOT NULL,
     hash BYTEA NOT NULL,
     salt BYTEA NOT NULL,
+    token_version INTEGER NOT NULL DEFAULT 0,
     PRIMARY KEY (username)
 );"#
     }
@@ -97,25 +324,65 @@ pub struct Configuration {
     pub database: String,
     /// PostgresSQL user
     pub user: String,
-    /// PostgresSQL password
+    /// PostgresSQL password. Ignored if `password_file` is set.
+    #[serde(default)]
     pub password: String,
+    /// Path to a file containing the PostgresSQL password, such as a secret mounted by the
+    /// platform. It is read when the authenticator is made, and takes precedence over `password`.
+    #[serde(default)]
+    pub password_file: Option<String>,
+    /// Whether connections are checked before they are checked out of the pool. Defaults to
+    /// `true`. See [`pool_config`](../fn.pool_config.html).
+    #[serde(default = "::default_health_check")]
+    pub health_check: bool,
+    /// Host of a read replica of the database, to which lookups of users are routed. The port,
+    /// database, and credentials are the same as those of the primary database. By default,
+    /// there is no replica, and all queries go to the primary database. See the section on read
+    /// replicas of [`Authenticator`](../struct.Authenticator.html) for the caveats.
+    #[serde(default)]
+    pub replica_host: Option<String>,
 }
 
 fn default_port() -> u16 {
     5432
 }
 
+impl Configuration {
+    /// Returns the password, reading it from `password_file` if it is set
+    fn password(&self) -> Result<String, Error> {
+        match self.password_file {
+            Some(ref password_file) => read_secret_file(password_file),
+            None => Ok(self.password.clone()),
+        }
+    }
+}
+
 impl AuthenticatorConfiguration<Basic> for Configuration {
     type Authenticator = Authenticator;
 
     fn make_authenticator(&self) -> Result<Self::Authenticator, rowdy::Error> {
-        Ok(Authenticator::with_configuration(
+        let password = self.password()?;
+        let uri = Authenticator::configuration_uri(
             &self.host,
             self.port,
             &self.database,
             &self.user,
-            &self.password,
-        )?)
+            &password,
+        );
+        let mut authenticator = Authenticator::with_config(&uri, pool_config(self.health_check))?;
+        if let Some(ref replica_host) = self.replica_host {
+            let replica_uri = Authenticator::configuration_uri(
+                replica_host,
+                self.port,
+                &self.database,
+                &self.user,
+                &password,
+            );
+            authenticator =
+                authenticator.with_read_replica(&replica_uri, pool_config(self.health_check))?;
+        }
+        authenticator.check_schema()?;
+        Ok(authenticator)
     }
 }
 
@@ -186,6 +453,37 @@ mod tests {
             .expect("To succeed and be idempotent")
     }
 
+    /// Embedded migrations should be idempotent, and create the expected schema
+    #[test]
+    fn embedded_migrations_are_idempotent() {
+        let authenticator = make_authenticator();
+        authenticator.run_migrations().expect("To succeed");
+        authenticator
+            .run_migrations()
+            .expect("To succeed and be idempotent");
+        authenticator.check_schema().expect("To match the schema");
+    }
+
+    /// The username index is not created by the migrations, and cannot be created while usernames
+    /// differ only by case. Changes are rolled back, so that other tests are not affected.
+    #[test]
+    fn username_index_migration_is_opt_in() {
+        let authenticator = make_authenticator();
+        let connection = authenticator.get_pooled_connection().expect("to succeed");
+
+        connection.test_transaction::<_, Error, _>(|| {
+            username_index_migration::embedded_migrations::run(&*connection)?;
+            Ok(())
+        });
+
+        connection.test_transaction::<_, Error, _>(|| {
+            connection.insert_user("index_probe", b"hash", b"salt")?;
+            connection.insert_user("Index_Probe", b"hash", b"salt")?;
+            assert!(username_index_migration::embedded_migrations::run(&*connection).is_err());
+            Ok(())
+        });
+    }
+
     #[test]
     fn authentication_with_username_and_password() {
         let authenticator = make_authenticator();
@@ -202,6 +500,18 @@ mod tests {
         assert!(result.refresh_payload.is_none());
     }
 
+    #[test]
+    fn authentication_is_case_insensitive_for_username() {
+        let authenticator = make_authenticator();
+
+        let result = authenticator
+            .verify("FooBar", "password", false)
+            .expect("To verify correctly");
+
+        // The subject is the username as stored in the database
+        assert_eq!(result.subject, "foobar");
+    }
+
     #[test]
     fn authentication_with_refresh_payload() {
         let authenticator = make_authenticator();
@@ -239,6 +549,9 @@ mod tests {
             database: "rowdy".to_string(),
             user: "postgres".to_string(),
             password: "postgres".to_string(),
+            password_file: None,
+            health_check: true,
+            replica_host: None,
         };
         assert_eq!(deserialized, expected_config);
 
@@ -11,6 +11,7 @@
 //!         username -> Varchar,
 //!         hash -> Binary,
 //!         salt -> Varbinary,
+//!         token_version -> Integer,
 //!     }
 //! }
 //! ```
@@ -20,11 +21,38 @@
 //! ```sql
 //! CREATE TABLE IF NOT EXISTS `users` (
 //!     `username` VARCHAR(255) UNIQUE NOT NULL,
-//!     `hash` BINARY(32) NOT NULL,
+//!     `hash` VARBINARY(255) NOT NULL,
 //!     `salt` VARBINARY(255) NOT NULL,
+//!     `token_version` INT NOT NULL DEFAULT 0,
 //!     PRIMARY KEY (`username`)
 //! );
 //! ```
+//!
+//! Authenticators also implement `rowdy::token::ReferenceTokenStore`, using the
+//! `reference_tokens` table:
+//!
+//! ```sql
+//! CREATE TABLE IF NOT EXISTS `reference_tokens` (
+//!     `reference` VARCHAR(36) NOT NULL,
+//!     `token` TEXT NOT NULL,
+//!     `expires_at` BIGINT NOT NULL,
+//!     PRIMARY KEY (`reference`)
+//! );
+//! ```
+//!
+//! The authenticator of each backend can create the tables with `run_migrations`, which runs the
+//! diesel migrations embedded from the `migrations` directory of this crate. The column types
+//! used for each backend can be found there. The index on `LOWER(username)` that speeds up the
+//! case-insensitive lookups of the PostgreSQL authenticator is opt-in, since it also makes
+//! usernames unique regardless of case. See
+//! `postgres::Authenticator::run_username_index_migration`.
+//!
+//! # Upgrading
+//! `users` tables created before the `token_version` column was introduced have to be upgraded
+//! before the authenticators can load users from them. Either run `run_migrations`, whose
+//! `add_token_version` migration adds the column, or `Migration::migrate`, which adds the column
+//! if it is missing. Existing users start at version `0`, so their outstanding refresh tokens
+//! remain valid. Both ways can be used on the same table, in any order.
 
 /// Diesel table definition inside a module to allow for some lints
 mod table_macro {
@@ -40,6 +68,21 @@ mod table_macro {
             hash -> Binary,
             /// Salt used to generate the password hash
             salt -> Varbinary,
+            /// Version of the user's refresh tokens. Incrementing it invalidates all
+            /// outstanding refresh tokens of the user.
+            token_version -> Integer,
+        }
+    }
+
+    table! {
+        /// Table used to hold the JWTs that opaque reference tokens stand for
+        reference_tokens (reference) {
+            /// The reference token issued to the client. Also the primary key
+            reference -> Varchar,
+            /// The encoded JWT that the reference token stands for
+            token -> Text,
+            /// Expiry of the token, in seconds since the Unix epoch
+            expires_at -> BigInt,
         }
     }
 }
@@ -50,9 +93,23 @@ use std::ops::Deref;
 
 use Connection;
 
+/// Query that fails if there is no `users` table with a `token_version` column
+const TOKEN_VERSION_PROBE: &str = "SELECT token_version FROM users WHERE 1 = 0";
+
+/// Adds the `token_version` column to `users` tables created before it was introduced
+const ADD_TOKEN_VERSION: &str =
+    "ALTER TABLE users ADD COLUMN token_version INTEGER NOT NULL DEFAULT 0";
+
+/// Returns whether the `users` table exists, and has the `token_version` column
+pub(crate) fn has_token_version<T: Connection>(connection: &T) -> bool {
+    connection.batch_execute(TOKEN_VERSION_PROBE).is_ok()
+}
+
 /// Trait to provide idempotent minimal migration to create the table necessary for `rowdy-diesel`
 /// to work. If you have more sophisticated needs, you are able to add more columns to the basic
 /// columns needed for rowdy to work.
+///
+/// Tables created by an earlier version of `migrate` are upgraded with the columns added since.
 // TODO: Look into folding this into the base `Authenticator` struct once const generics
 // is implemented. See https://github.com/rust-lang/rust/issues/44580
 pub trait Migration<T>
@@ -70,12 +127,16 @@ where
     fn migration_query(&self) -> &str;
 
     /// Provide idempotent minimal migration to create the table necessary for `rowdy-diesel`
-    /// to work
+    /// to work, and add the `token_version` column to existing tables that lack it
     fn migrate(&self) -> Result<(), ::Error> {
         let query = self.migration_query();
 
         let connection = self.connection()?;
         connection.batch_execute(&query)?;
+        if !has_token_version(&*connection) {
+            info_!("Adding the token_version column to the users table");
+            connection.batch_execute(ADD_TOKEN_VERSION)?;
+        }
         Ok(())
     }
 }
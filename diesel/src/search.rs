@@ -0,0 +1,101 @@
+//! Database specific queries of the authenticators
+//!
+//! The items of this module are public so that they can bound the public `Authenticator`, but the
+//! module is private, so they are not part of the public API of the crate.
+use ring::digest;
+use rowdy::auth::util::hex_dump;
+
+use {Connection, Error, StoredUsername, UserFilter};
+
+/// A user record in the database.
+///
+/// This is deliberately not serializable, so that the password hash and salt cannot end up in
+/// any externally visible output such as refresh tokens.
+#[derive(Queryable)]
+pub struct User {
+    pub(crate) username: StoredUsername,
+    pub(crate) hash: Vec<u8>,
+    pub(crate) salt: Vec<u8>,
+    pub(crate) token_version: i32,
+}
+
+impl User {
+    /// A nonce derived from the stored credentials of the user. It changes whenever the password
+    /// changes, which invalidates outstanding refresh tokens.
+    pub(crate) fn nonce(&self) -> String {
+        let mut context = digest::Context::new(&digest::SHA256);
+        context.update(&self.hash);
+        context.update(&self.salt);
+        hex_dump(context.finish().as_ref())
+    }
+}
+
+/// Database specific search for users in the `users` table.
+///
+/// This is implemented for the connection types of each database supported by the feature flags.
+/// Backends compare usernames in the manner best supported by the database. For example,
+/// the PostgreSQL backend compares usernames case-insensitively.
+pub trait Search: Connection + 'static {
+    /// Search for users with the provided username
+    fn search_users(&self, username: &str) -> Result<Vec<User>, Error>;
+
+    /// Returns whether a user with the provided username exists, matching usernames in the same
+    /// way as `search_users`, without loading the user
+    fn user_exists(&self, username: &str) -> Result<bool, Error>;
+
+    /// List the usernames of users ordered by username, skipping `offset` users and returning at
+    /// most `limit`. If `filter` is provided, only usernames matching it are returned.
+    fn list_users(
+        &self,
+        offset: i64,
+        limit: i64,
+        filter: Option<&UserFilter>,
+    ) -> Result<Vec<StoredUsername>, Error>;
+
+    /// Insert a user with the password `hash` and `salt`
+    fn insert_user(&self, username: &str, hash: &[u8], salt: &[u8]) -> Result<(), Error>;
+
+    /// Replace the password `hash` and `salt` of the user with the provided username, and return
+    /// the number of users updated
+    fn update_password(&self, username: &str, hash: &[u8], salt: &[u8]) -> Result<usize, Error>;
+
+    /// Replace the password hash and salt of the user with the provided username with `hash` and
+    /// `salt`, only if they are still `old_hash` and `old_salt`, and return the number of users
+    /// updated
+    fn replace_password(
+        &self,
+        username: &str,
+        old_hash: &[u8],
+        old_salt: &[u8],
+        hash: &[u8],
+        salt: &[u8],
+    ) -> Result<usize, Error>;
+
+    /// Increment the token version of the user with the provided username, and return the number
+    /// of users updated
+    fn increment_token_version(&self, username: &str) -> Result<usize, Error>;
+
+    /// Store the encoded JWT `token` under `reference`, until `expires_at`, in seconds since the
+    /// Unix epoch
+    fn insert_reference_token(
+        &self,
+        reference: &str,
+        token: &str,
+        expires_at: i64,
+    ) -> Result<(), Error>;
+
+    /// Find the encoded JWT stored under `reference`, if it has not expired by `now`, in seconds
+    /// since the Unix epoch
+    fn find_reference_token(&self, reference: &str, now: i64) -> Result<Option<String>, Error>;
+
+    /// Delete the token stored under `reference`, and return the number of tokens deleted
+    fn delete_reference_token(&self, reference: &str) -> Result<usize, Error>;
+
+    /// Delete the tokens that have expired by `now`, in seconds since the Unix epoch, and return
+    /// the number of tokens deleted
+    fn delete_expired_reference_tokens(&self, now: i64) -> Result<usize, Error>;
+
+    /// Query the `users` table to check that it exists with the columns expected by rowdy.
+    /// Errors are returned as `Error::SchemaMismatch`.
+    fn check_schema(&self) -> Result<(), Error>;
+}
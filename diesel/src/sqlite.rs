@@ -2,20 +2,38 @@
 //!
 //! Requires `features = ["sqlite"]` in your `Cargo.toml`
 use diesel::prelude::*;
+use diesel::connection::SimpleConnection;
 use diesel::sqlite::SqliteConnection;
-use r2d2::Config;
 use r2d2_diesel::ConnectionManager;
 
 use rowdy;
 use rowdy::auth::{AuthenticatorConfiguration, Basic};
 
-use {ConnectionPool, Error, PooledConnection};
+use {instr, lower, pool_config, Error, PoolConfig, PooledConnection, Search, StoredUsername,
+     User, UserFilter, LIKE_ESCAPE};
 use schema;
 
 /// A rowdy authenticator that uses a SQLite backed database to provide the users
 pub type Authenticator = ::Authenticator<SqliteConnection>;
 
 impl Authenticator {
+    /// Connect to the SQLite database at the path `database_url`, with a connection pool with
+    /// the default options. Connections are health checked on checkout; see `pool_config`.
+    pub fn new(database_url: &str) -> Result<Self, Error> {
+        Self::with_config(database_url, pool_config(true))
+    }
+
+    /// Connect to the SQLite database at the path `database_url`, with a connection pool with
+    /// the options in `config`
+    pub fn with_config(
+        database_url: &str,
+        config: PoolConfig<SqliteConnection>,
+    ) -> Result<Self, Error> {
+        // Attempt a test connection with diesel
+        let _ = Self::connect(database_url)?;
+        Self::with_pool_config(database_url, config)
+    }
+
     /// Connect to a SQLite database at a certain path
     ///
     /// Note: Diesel does not support [URI filenames](https://www.sqlite.org/c3ref/open.html)
@@ -28,14 +46,21 @@ impl Authenticator {
     /// connection in the pool. Since URI filenames are not supported,
     /// `file:memdb1?mode=memory&cache=shared` cannot be used.
     pub fn with_path<S: AsRef<str>>(path: S) -> Result<Self, Error> {
-        // Attempt a test connection with diesel
-        let _ = Self::connect(path.as_ref())?;
+        Self::new(path.as_ref())
+    }
 
-        let config = Config::default();
-        let manager = ConnectionManager::new(path.as_ref());
-        debug_!("Creating a connection pool");
-        let pool = ConnectionPool::new(config, manager)?;
-        Ok(Self::new(pool))
+    /// Run the migrations embedded in this crate to create or update the `users` table, so that
+    /// it matches the schema expected by rowdy. Migrations that have already been run are skipped.
+    pub fn run_migrations(&self) -> Result<(), Error> {
+        let connection = self.get_pooled_connection()?;
+        // SQLite cannot add a column only if it is missing, so the `add_token_version` migration
+        // is recorded as run for tables that already have the column, such as those created by
+        // `schema::Migration::migrate`
+        if schema::has_token_version(&*connection) {
+            connection.batch_execute(MARK_TOKEN_VERSION_MIGRATION)?;
+        }
+        migrations::embedded_migrations::run(&*connection)?;
+        Ok(())
     }
 
     /// Test connection with the database uri
@@ -45,6 +70,178 @@ impl Authenticator {
     }
 }
 
+impl Search for SqliteConnection {
+    fn search_users(&self, search_user: &str) -> Result<Vec<User>, Error> {
+        use schema::users::dsl::*;
+
+        Ok(users.filter(username.eq(search_user)).load::<User>(self)?)
+    }
+
+    fn user_exists(&self, search_user: &str) -> Result<bool, Error> {
+        use diesel::dsl::exists;
+        use schema::users::dsl::*;
+
+        Ok(::diesel::select(exists(users.filter(username.eq(search_user))))
+            .get_result::<bool>(self)?)
+    }
+
+    fn list_users(
+        &self,
+        offset: i64,
+        limit: i64,
+        filter: Option<&UserFilter>,
+    ) -> Result<Vec<StoredUsername>, Error> {
+        use schema::users::dsl::*;
+
+        let mut query = users
+            .select(username)
+            .order(username.asc())
+            .offset(offset)
+            .limit(limit)
+            .into_boxed();
+        if let Some(filter) = filter {
+            query = match *filter {
+                UserFilter::Pattern(_) => query.filter(username.like(filter.like_pattern())),
+                UserFilter::Prefix(_) => {
+                    query.filter(username.like(filter.like_pattern()).escape(LIKE_ESCAPE))
+                }
+                UserFilter::Contains(ref string) => {
+                    query.filter(instr(lower(username), lower(string.as_str())).gt(0))
+                }
+            };
+        }
+
+        Ok(query.load::<StoredUsername>(self)?)
+    }
+
+    fn increment_token_version(&self, search_user: &str) -> Result<usize, Error> {
+        use schema::users::dsl::*;
+
+        Ok(::diesel::update(users.filter(username.eq(search_user)))
+            .set(token_version.eq(token_version + 1))
+            .execute(self)?)
+    }
+
+    fn insert_user(
+        &self,
+        new_username: &str,
+        new_hash: &[u8],
+        new_salt: &[u8],
+    ) -> Result<(), Error> {
+        use schema::users::dsl::*;
+
+        let _ = ::diesel::insert_into(users)
+            .values((
+                username.eq(new_username),
+                hash.eq(new_hash),
+                salt.eq(new_salt),
+            ))
+            .execute(self)?;
+        Ok(())
+    }
+
+    fn update_password(
+        &self,
+        search_user: &str,
+        new_hash: &[u8],
+        new_salt: &[u8],
+    ) -> Result<usize, Error> {
+        use schema::users::dsl::*;
+
+        Ok(::diesel::update(users.filter(username.eq(search_user)))
+            .set((hash.eq(new_hash), salt.eq(new_salt)))
+            .execute(self)?)
+    }
+
+    fn replace_password(
+        &self,
+        search_user: &str,
+        old_hash: &[u8],
+        old_salt: &[u8],
+        new_hash: &[u8],
+        new_salt: &[u8],
+    ) -> Result<usize, Error> {
+        use schema::users::dsl::*;
+
+        let user = users
+            .filter(username.eq(search_user))
+            .filter(hash.eq(old_hash))
+            .filter(salt.eq(old_salt));
+        Ok(::diesel::update(user)
+            .set((hash.eq(new_hash), salt.eq(new_salt)))
+            .execute(self)?)
+    }
+
+    fn insert_reference_token(
+        &self,
+        new_reference: &str,
+        new_token: &str,
+        new_expires_at: i64,
+    ) -> Result<(), Error> {
+        use schema::reference_tokens::dsl::*;
+
+        let _ = ::diesel::insert_into(reference_tokens)
+            .values((
+                reference.eq(new_reference),
+                token.eq(new_token),
+                expires_at.eq(new_expires_at),
+            ))
+            .execute(self)?;
+        Ok(())
+    }
+
+    fn find_reference_token(
+        &self,
+        search_reference: &str,
+        now: i64,
+    ) -> Result<Option<String>, Error> {
+        use schema::reference_tokens::dsl::*;
+
+        Ok(reference_tokens
+            .filter(reference.eq(search_reference))
+            .filter(expires_at.gt(now))
+            .select(token)
+            .first::<String>(self)
+            .optional()?)
+    }
+
+    fn delete_reference_token(&self, search_reference: &str) -> Result<usize, Error> {
+        use schema::reference_tokens::dsl::*;
+
+        Ok(::diesel::delete(reference_tokens.filter(reference.eq(search_reference))).execute(self)?)
+    }
+
+    fn delete_expired_reference_tokens(&self, now: i64) -> Result<usize, Error> {
+        use schema::reference_tokens::dsl::*;
+
+        Ok(::diesel::delete(reference_tokens.filter(expires_at.le(now))).execute(self)?)
+    }
+
+    fn check_schema(&self) -> Result<(), Error> {
+        use schema::users::dsl::*;
+
+        let _ = users
+            .limit(1)
+            .load::<User>(self)
+            .map_err(|e| Error::SchemaMismatch(e.to_string()))?;
+        Ok(())
+    }
+}
+
+/// Records the `add_token_version` migration as run, in the table diesel keeps the migrations in
+const MARK_TOKEN_VERSION_MIGRATION: &str = "
+CREATE TABLE IF NOT EXISTS __diesel_schema_migrations (
+    version VARCHAR(50) PRIMARY KEY NOT NULL,
+    run_on TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+INSERT OR IGNORE INTO __diesel_schema_migrations (version) VALUES ('20171002000000');";
+
+/// Migrations embedded from `migrations/sqlite`
+mod migrations {
+    #![allow(missing_docs, unused_import_braces, unused_qualifications)]
+    embed_migrations!("migrations/sqlite");
+}
+
 impl schema::Migration<SqliteConnection> for Authenticator {
     type Connection = PooledConnection<ConnectionManager<SqliteConnection>>;
 
@@ -57,6 +254,7 @@ impl schema::Migration<SqliteConnection> for Authenticator {
     'username' VARCHAR(255) UNIQUE NOT NULL,
     'hash' BLOB(32) NOT NULL,
     'salt' BLOB(255) NOT NULL,
+    'token_version' INTEGER NOT NULL DEFAULT 0,
     PRIMARY KEY ('username')
 );"#
     }
@@ -84,13 +282,20 @@ pub struct Configuration {
     /// connection in the pool. Since URI filenames are not supported,
     /// `file:memdb1?mode=memory&cache=shared` cannot be used.
     pub path: String,
+    /// Whether connections are checked before they are checked out of the pool. Defaults to
+    /// `true`. See [`pool_config`](../fn.pool_config.html).
+    #[serde(default = "::default_health_check")]
+    pub health_check: bool,
 }
 
 impl AuthenticatorConfiguration<Basic> for Configuration {
     type Authenticator = Authenticator;
 
     fn make_authenticator(&self) -> Result<Self::Authenticator, rowdy::Error> {
-        Ok(Authenticator::with_path(&self.path)?)
+        let authenticator =
+            Authenticator::with_config(&self.path, pool_config(self.health_check))?;
+        authenticator.check_schema()?;
+        Ok(authenticator)
     }
 }
 
@@ -98,11 +303,13 @@ impl AuthenticatorConfiguration<Basic> for Configuration {
 #[cfg(test)]
 mod tests {
     use std::sync::{Once, ONCE_INIT};
+    use std::time::{Duration, Instant};
 
     use diesel::connection::SimpleConnection;
-    use rowdy::auth::Authenticator;
+    use rowdy::auth::{Authenticator, Authorization};
 
     use schema::Migration;
+    use {ConnectionPool, InvalidUsernamePolicy, PasswordStorage, RetryPolicy, UserSummary};
     use super::*;
 
     static SEED: Once = ONCE_INIT;
@@ -147,6 +354,175 @@ mod tests {
         );
     }
 
+    #[test]
+    fn authenticator_is_constructed_with_pool_options() {
+        let config = ConnectionPool::builder().max_size(2);
+        let authenticator = super::Authenticator::with_config("../target/sqlite.db", config)
+            .expect("To be constructed successfully");
+        migrate_and_seed(&authenticator);
+
+        let _ = authenticator
+            .verify("foobar", "password", false)
+            .expect("To verify correctly");
+    }
+
+    #[test]
+    fn retry_delays_double() {
+        let policy = RetryPolicy {
+            max_retries: 3,
+            base_delay: Duration::from_millis(10),
+        };
+        assert_eq!(policy.delay(0), Duration::from_millis(10));
+        assert_eq!(policy.delay(2), Duration::from_millis(40));
+        assert_eq!(RetryPolicy::default().max_retries, 0);
+    }
+
+    #[test]
+    fn max_checkout_duration_includes_every_timeout_and_delay() {
+        let policy = RetryPolicy {
+            max_retries: 2,
+            base_delay: Duration::from_millis(20),
+        };
+        // Three timeouts, with delays of 20ms and 40ms between them
+        assert_eq!(
+            policy.max_checkout_duration(Duration::from_millis(10)),
+            Duration::from_millis(90)
+        );
+        assert_eq!(
+            RetryPolicy::default().max_checkout_duration(Duration::from_secs(30)),
+            Duration::from_secs(30)
+        );
+    }
+
+    #[test]
+    fn exhausted_pool_is_retried_before_timing_out() {
+        let config = ConnectionPool::builder()
+            .max_size(1)
+            .connection_timeout(Duration::from_millis(10));
+        let mut authenticator = super::Authenticator::with_config("../target/sqlite.db", config)
+            .expect("To be constructed successfully");
+        authenticator.set_retry_policy(RetryPolicy {
+            max_retries: 2,
+            base_delay: Duration::from_millis(20),
+        });
+
+        let _held = authenticator
+            .get_pooled_connection()
+            .expect("To retrieve a connection");
+        let started = Instant::now();
+        match authenticator.get_pooled_connection() {
+            Err(Error::ConnectionTimeout) => {}
+            Err(e) => panic!("Unexpected error {:?}", e),
+            Ok(_) => panic!("The pool should be exhausted"),
+        }
+        // Three attempts, with delays of 20ms and 40ms between them
+        assert!(started.elapsed() >= Duration::from_millis(90));
+    }
+
+    #[test]
+    fn connections_failing_to_open_are_not_reported_as_timeouts() {
+        let manager = ConnectionManager::<SqliteConnection>::new("../target/missing/sqlite.db");
+        let pool = ConnectionPool::builder()
+            .max_size(1)
+            .connection_timeout(Duration::from_millis(50))
+            .build_unchecked(manager);
+
+        let error = pool.get().err().expect("The database cannot be opened");
+        match ::checkout_error(&error) {
+            Error::ConnectionError(_) => {}
+            e => panic!("Unexpected error {:?}", e),
+        }
+    }
+
+    #[test]
+    fn shutdown_reports_connections_in_use() {
+        let authenticator = make_authenticator();
+        assert_eq!(0, authenticator.shutdown());
+
+        let config = ConnectionPool::builder().max_size(1);
+        let authenticator = super::Authenticator::with_config("../target/sqlite.db", config)
+            .expect("To be constructed successfully");
+        let held = authenticator
+            .get_pooled_connection()
+            .expect("To retrieve a connection");
+        assert_eq!(1, authenticator.shutdown());
+
+        // Connections in use are not interrupted
+        held.batch_execute("SELECT 1").expect("to work");
+    }
+
+    #[test]
+    fn pool_statistics_count_connections_in_use() {
+        let config = ConnectionPool::builder().max_size(2).min_idle(Some(2));
+        let authenticator = super::Authenticator::with_config("../target/sqlite.db", config)
+            .expect("To be constructed successfully");
+        let held = authenticator
+            .get_pooled_connection()
+            .expect("To retrieve a connection");
+
+        let statistics = authenticator.pool_statistics();
+        assert_eq!(statistics.max_size, 2);
+        assert_eq!(statistics.in_use_connections, 1);
+        assert_eq!(
+            statistics.connections,
+            statistics.idle_connections + statistics.in_use_connections
+        );
+
+        drop(held);
+        assert_eq!(authenticator.pool_statistics().in_use_connections, 0);
+    }
+
+    #[test]
+    #[cfg(feature = "metrics")]
+    fn pool_metrics_are_collected() {
+        use rowdy::metrics::MetricsCollector;
+
+        let config = ConnectionPool::builder()
+            .max_size(1)
+            .connection_timeout(Duration::from_millis(10));
+        let authenticator = super::Authenticator::with_config("../target/sqlite.db", config)
+            .expect("To be constructed successfully");
+        let collector = authenticator.metrics_collector();
+
+        let _held = authenticator
+            .get_pooled_connection()
+            .expect("To retrieve a connection");
+        assert!(authenticator.get_pooled_connection().is_err());
+        assert_eq!(collector.metrics().checkouts(), 1);
+        assert_eq!(collector.metrics().timeouts(), 1);
+
+        let mut output = String::new();
+        collector.render(&mut output);
+        assert!(output.contains("rowdy_pool_connections{pool=\"primary\",state=\"in_use\"} 1\n"));
+        assert!(output.contains("rowdy_pool_max_connections{pool=\"primary\"} 1\n"));
+        assert!(!output.contains("pool=\"replica\""));
+        assert!(output.contains("rowdy_pool_checkout_timeouts_total 1\n"));
+        assert!(output.contains("rowdy_pool_checkout_wait_seconds_count 2\n"));
+    }
+
+    #[test]
+    fn invalid_database_url_is_a_connection_error() {
+        match super::Authenticator::new("../target/does/not/exist/sqlite.db") {
+            Err(Error::ConnectionError(_)) => {}
+            Err(e) => panic!("Unexpected error {:?}", e),
+            Ok(_) => panic!("Connecting to an invalid path should fail"),
+        }
+    }
+
+    #[test]
+    fn schema_is_checked() {
+        let authenticator = make_authenticator();
+        authenticator.check_schema().expect("The schema to match");
+
+        let authenticator = super::Authenticator::new("../target/sqlite_without_users.db")
+            .expect("To be constructed successfully");
+        match authenticator.check_schema() {
+            Err(Error::SchemaMismatch(_)) => {}
+            Err(e) => panic!("Unexpected error {:?}", e),
+            Ok(_) => panic!("A missing users table should not match the schema"),
+        }
+    }
+
     /// Migration should be idempotent
     #[test]
     fn migration_is_idempotent() {
@@ -156,6 +532,54 @@ mod tests {
             .expect("To succeed and be idempotent")
     }
 
+    /// Embedded migrations should be idempotent, and create the expected schema
+    #[test]
+    fn embedded_migrations_are_idempotent() {
+        let path = "../target/sqlite_embedded_migrations.db";
+        let _ = ::std::fs::remove_file(path);
+        let authenticator =
+            super::Authenticator::new(path).expect("To be constructed successfully");
+        authenticator.run_migrations().expect("To succeed");
+        authenticator
+            .run_migrations()
+            .expect("To succeed and be idempotent");
+        authenticator.check_schema().expect("To match the schema");
+    }
+
+    #[test]
+    fn tables_without_token_versions_are_upgraded() {
+        let legacy_table = "CREATE TABLE 'users' (
+    'username' VARCHAR(255) UNIQUE NOT NULL,
+    'hash' BLOB(32) NOT NULL,
+    'salt' BLOB(255) NOT NULL,
+    PRIMARY KEY ('username')
+);";
+
+        // Upgraded by `migrate`, after which the embedded migrations can still be run
+        let path = "../target/sqlite_legacy_migrate.db";
+        let _ = ::std::fs::remove_file(path);
+        let authenticator =
+            super::Authenticator::new(path).expect("To be constructed successfully");
+        let connection = authenticator.get_pooled_connection().expect("to succeed");
+        connection.batch_execute(legacy_table).expect("to work");
+        assert!(authenticator.check_schema().is_err());
+        authenticator.migrate().expect("To succeed");
+        authenticator.check_schema().expect("To match the schema");
+        authenticator.run_migrations().expect("To succeed");
+        authenticator.check_schema().expect("To match the schema");
+
+        // Upgraded by the embedded migrations
+        let path = "../target/sqlite_legacy_run_migrations.db";
+        let _ = ::std::fs::remove_file(path);
+        let authenticator =
+            super::Authenticator::new(path).expect("To be constructed successfully");
+        let connection = authenticator.get_pooled_connection().expect("to succeed");
+        connection.batch_execute(legacy_table).expect("to work");
+        authenticator.run_migrations().expect("To succeed");
+        authenticator.check_schema().expect("To match the schema");
+        authenticator.migrate().expect("To succeed");
+    }
+
     #[test]
     fn authentication_with_username_and_password() {
         let authenticator = make_authenticator();
@@ -172,6 +596,360 @@ mod tests {
         assert!(result.refresh_payload.is_none());
     }
 
+    #[test]
+    fn authentication_with_empty_or_missing_password_fails() {
+        let authenticator = make_authenticator();
+
+        match authenticator.verify("foobar", "", false) {
+            Err(Error::AuthenticationFailure) => {}
+            Err(e) => panic!("Unexpected error {:?}", e),
+            Ok(_) => panic!("Empty password should be rejected"),
+        }
+
+        for header in &["Basic Zm9vYmFy", "Basic Zm9vYmFyOg=="] {
+            let authorization = Authorization::<Basic>::new(header).expect("to parse");
+            let result = authenticator.authenticate(&authorization, false);
+            match result {
+                Err(rowdy::Error::Auth(rowdy::auth::Error::AuthenticationFailure)) => {}
+                Err(e) => panic!("Unexpected error {:?}", e),
+                Ok(_) => panic!("Missing password should be rejected"),
+            }
+        }
+    }
+
+    #[test]
+    fn batch_verification_reports_each_result() {
+        let authenticator = make_authenticator();
+
+        let credentials = vec![
+            ("foobar".to_string(), "password".to_string()),
+            ("mei".to_string(), "wrong".to_string()),
+            ("not_found".to_string(), "password".to_string()),
+            ("mei".to_string(), "".to_string()),
+            ("mei".to_string(), "冻住，不许走!".to_string()),
+        ];
+        let results = authenticator.verify_batch(&credentials);
+        assert_eq!(results.len(), credentials.len());
+
+        for &index in &[0, 4] {
+            let result = results[index].as_ref().expect("to be verified");
+            assert_eq!(result.subject, credentials[index].0);
+            assert!(result.refresh_payload.is_none());
+        }
+        for result in &results[1..4] {
+            match *result {
+                Err(Error::AuthenticationFailure) => {}
+                Err(ref e) => panic!("Unexpected error {:?}", e),
+                Ok(_) => panic!("Invalid credentials should be rejected"),
+            }
+        }
+    }
+
+    #[test]
+    fn reference_tokens_are_stored_until_expiry_or_revocation() {
+        use chrono::{self, Utc};
+        use rowdy::token::ReferenceTokenStore;
+
+        let path = "../target/sqlite_reference_tokens.db";
+        let _ = ::std::fs::remove_file(path);
+        let authenticator =
+            super::Authenticator::new(path).expect("To be constructed successfully");
+        authenticator.run_migrations().expect("To succeed");
+
+        let now = Utc::now();
+        let expires_at = now + chrono::Duration::hours(1);
+        authenticator
+            .store("reference", "token", expires_at, now)
+            .expect("To be stored");
+        authenticator
+            .store("expired", "token", now - chrono::Duration::hours(1), now)
+            .expect("To be stored");
+
+        let resolved = authenticator.resolve("reference", now).expect("To resolve");
+        assert_eq!(Some("token".to_string()), resolved);
+        assert_eq!(None, authenticator.resolve("expired", now).expect("To resolve"));
+        assert_eq!(None, authenticator.resolve("unknown", now).expect("To resolve"));
+        // Expiry is judged by the time passed in, not the system clock
+        assert_eq!(None, authenticator.resolve("reference", expires_at).expect("To resolve"));
+        let earlier = now - chrono::Duration::hours(2);
+        let resolved = authenticator.resolve("expired", earlier).expect("To resolve");
+        assert_eq!(Some("token".to_string()), resolved);
+
+        authenticator.revoke("reference").expect("To be revoked");
+        assert_eq!(None, authenticator.resolve("reference", now).expect("To resolve"));
+    }
+
+    #[test]
+    fn lookups_are_routed_to_the_read_replica() {
+        let primary_path = "../target/sqlite_primary.db";
+        let replica_path = "../target/sqlite_replica.db";
+        for path in &[primary_path, replica_path] {
+            let _ = ::std::fs::remove_file(path);
+            let authenticator =
+                super::Authenticator::new(path).expect("To be constructed successfully");
+            authenticator.run_migrations().expect("To succeed");
+            authenticator
+                .create_user("alice", "correct horse")
+                .expect("To be created");
+        }
+
+        let authenticator = super::Authenticator::new(primary_path)
+            .expect("To be constructed successfully")
+            .with_read_replica(replica_path, pool_config(true))
+            .expect("To be constructed successfully");
+        assert!(authenticator.read_pool_statistics().is_some());
+
+        // Writes go to the primary database, and have not been replicated
+        authenticator
+            .create_user("bob", "battery staple")
+            .expect("To be created");
+        assert!(!authenticator.user_exists("bob").expect("To succeed"));
+        assert!(authenticator.verify("bob", "battery staple", false).is_err());
+
+        // Refresh tokens are checked against the primary database, where alice has another salt
+        let refresh_payload = authenticator
+            .verify("alice", "correct horse", true)
+            .expect("To verify correctly")
+            .refresh_payload
+            .expect("A refresh payload to be present");
+        assert!(authenticator.verify_refresh_payload(&refresh_payload).is_err());
+    }
+
+    #[test]
+    fn logins_on_a_lagging_replica_do_not_undo_password_changes() {
+        let primary_path = "../target/sqlite_rehash_primary.db";
+        let replica_path = "../target/sqlite_rehash_replica.db";
+        for path in &[primary_path, replica_path] {
+            let _ = ::std::fs::remove_file(path);
+            let authenticator =
+                super::Authenticator::new(path).expect("To be constructed successfully");
+            authenticator.run_migrations().expect("To succeed");
+            authenticator
+                .create_user("alice", "correct horse")
+                .expect("To be created");
+        }
+
+        // The change has not been replicated
+        let primary = super::Authenticator::new(primary_path).expect("To be constructed");
+        primary
+            .change_password("alice", "battery staple")
+            .expect("To be changed");
+
+        let mut authenticator = super::Authenticator::new(primary_path)
+            .expect("To be constructed successfully")
+            .with_read_replica(replica_path, pool_config(true))
+            .expect("To be constructed successfully");
+        authenticator.set_password_storage(PasswordStorage::Phc);
+        authenticator.set_rehash_on_login(true);
+        let _ = authenticator
+            .verify("alice", "correct horse", false)
+            .expect("To verify against the replica");
+
+        // The hash of the old password was not written to the primary database
+        let _ = primary
+            .verify("alice", "battery staple", false)
+            .expect("To verify correctly");
+        assert!(primary.verify("alice", "correct horse", false).is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "metrics")]
+    fn replica_pool_metrics_are_collected() {
+        use rowdy::metrics::MetricsCollector;
+
+        let authenticator = super::Authenticator::new("../target/sqlite.db")
+            .expect("To be constructed successfully")
+            .with_read_replica("../target/sqlite.db", pool_config(true))
+            .expect("To be constructed successfully");
+        let collector = authenticator.metrics_collector();
+        assert!(collector.read_statistics().is_some());
+
+        let mut output = String::new();
+        collector.render(&mut output);
+        assert!(output.contains("rowdy_pool_max_connections{pool=\"replica\"} 10\n"));
+    }
+
+    #[test]
+    fn users_are_created_and_their_passwords_changed() {
+        let path = "../target/sqlite_password_changes.db";
+        let _ = ::std::fs::remove_file(path);
+        let authenticator =
+            super::Authenticator::new(path).expect("To be constructed successfully");
+        authenticator.run_migrations().expect("To succeed");
+
+        authenticator
+            .create_user("alice", "correct horse")
+            .expect("To be created");
+        let _ = authenticator
+            .verify("alice", "correct horse", false)
+            .expect("To verify correctly");
+
+        authenticator
+            .change_password("alice", "battery staple")
+            .expect("To be changed");
+        assert!(authenticator.verify("alice", "correct horse", false).is_err());
+        let _ = authenticator
+            .verify("alice", "battery staple", false)
+            .expect("To verify correctly");
+
+        match authenticator.change_password("alice", "short") {
+            Err(Error::WeakPassword(_)) => {}
+            other => panic!("Unexpected result {:?}", other),
+        }
+        match authenticator.change_password("bob", "battery staple") {
+            Err(Error::AuthenticationFailure) => {}
+            other => panic!("Unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn hashes_are_stored_in_the_configured_encoding() {
+        use rowdy::auth::util::HashEncoding;
+
+        let path = "../target/sqlite_hash_encoding.db";
+        let _ = ::std::fs::remove_file(path);
+        let mut authenticator =
+            super::Authenticator::new(path).expect("To be constructed successfully");
+        authenticator.run_migrations().expect("To succeed");
+        authenticator.set_hash_encoding(HashEncoding::Base64);
+
+        authenticator
+            .create_user("alice", "correct horse")
+            .expect("To be created");
+        let _ = authenticator
+            .verify("alice", "correct horse", false)
+            .expect("To verify correctly");
+        assert!(authenticator.verify("alice", "battery staple", false).is_err());
+
+        let connection = authenticator.get_pooled_connection().expect("A connection");
+        let user = connection
+            .search_users("alice")
+            .expect("To search")
+            .pop()
+            .expect("The user to exist");
+        let expected = super::Authenticator::hash_password_with_encoding(
+            "correct horse",
+            &user.salt,
+            HashEncoding::Base64,
+        ).expect("to hash successfully");
+        assert_eq!(user.hash, expected.into_bytes());
+    }
+
+    #[test]
+    fn password_changes_are_audited() {
+        use std::sync::mpsc;
+        use rowdy::audit::{AuditEventKind, AuditOutcome, ChannelAuditSink};
+
+        let path = "../target/sqlite_password_audit.db";
+        let _ = ::std::fs::remove_file(path);
+        let mut authenticator =
+            super::Authenticator::new(path).expect("To be constructed successfully");
+        authenticator.run_migrations().expect("To succeed");
+        let (sender, receiver) = mpsc::channel();
+        authenticator.set_audit_sink(ChannelAuditSink::new(sender));
+
+        authenticator
+            .create_user("alice", "correct horse")
+            .expect("To be created");
+        authenticator
+            .change_password("alice", "battery staple")
+            .expect("To be changed");
+        assert!(authenticator.change_password("alice", "short").is_err());
+
+        let event = receiver.try_recv().expect("An event to be recorded");
+        assert_eq!(event.event, AuditEventKind::PasswordChange);
+        assert_eq!(event.subject, Some("alice".to_string()));
+        assert_eq!(event.outcome, AuditOutcome::Success);
+        let event = receiver.try_recv().expect("An event to be recorded");
+        assert_eq!(event.outcome, AuditOutcome::Failure);
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn invalidated_sessions_reject_refresh_tokens() {
+        let path = "../target/sqlite_invalidated_sessions.db";
+        let _ = ::std::fs::remove_file(path);
+        let authenticator =
+            super::Authenticator::new(path).expect("To be constructed successfully");
+        authenticator.run_migrations().expect("To succeed");
+        authenticator
+            .create_user("mei", "冻住，不许走!")
+            .expect("To be created");
+        let refresh_payload = authenticator
+            .verify("mei", "冻住，不许走!", true)
+            .expect("to be verified")
+            .refresh_payload
+            .expect("A refresh payload to be present");
+        let _ = authenticator
+            .verify_refresh_payload(&refresh_payload)
+            .expect("to be valid");
+
+        authenticator.invalidate_sessions("mei").expect("to succeed");
+        match authenticator.verify_refresh_payload(&refresh_payload) {
+            Err(Error::AuthenticationFailure) => {}
+            Err(e) => panic!("Unexpected error {:?}", e),
+            Ok(_) => panic!("Revoked refresh token should be rejected"),
+        }
+
+        // Refresh tokens issued afterwards are valid
+        let refresh_payload = authenticator
+            .verify("mei", "冻住，不许走!", true)
+            .expect("to be verified")
+            .refresh_payload
+            .expect("A refresh payload to be present");
+        let _ = authenticator
+            .verify_refresh_payload(&refresh_payload)
+            .expect("to be valid");
+
+        match authenticator.invalidate_sessions("not_found") {
+            Err(Error::AuthenticationFailure) => {}
+            Err(e) => panic!("Unexpected error {:?}", e),
+            Ok(_) => panic!("Unknown user should not be found"),
+        }
+    }
+
+    #[test]
+    fn claims_are_enriched_after_authentication() {
+        use rowdy::{JsonMap, JsonValue};
+        use ClaimsEnricher;
+
+        struct Entitlements;
+
+        impl ClaimsEnricher for Entitlements {
+            fn enrich(&self, subject: &str) -> Result<JsonMap, Error> {
+                let mut claims = JsonMap::new();
+                let _ = claims.insert("entitled".to_string(), From::from(subject == "mei"));
+                Ok(claims)
+            }
+        }
+
+        let path = "../target/sqlite_enriched_claims.db";
+        let _ = ::std::fs::remove_file(path);
+        let mut authenticator =
+            super::Authenticator::new(path).expect("To be constructed successfully");
+        authenticator.run_migrations().expect("To succeed");
+        authenticator
+            .create_user("mei", "冻住，不许走!")
+            .expect("To be created");
+
+        let result = authenticator
+            .verify("mei", "冻住，不许走!", true)
+            .expect("To verify correctly");
+        assert_eq!(result.private_claims, JsonValue::Object(JsonMap::new()));
+
+        authenticator.set_claims_enricher(Entitlements);
+        let result = authenticator
+            .verify("mei", "冻住，不许走!", true)
+            .expect("To verify correctly");
+        assert_eq!(result.private_claims["entitled"], true);
+
+        let refresh_payload = result.refresh_payload.expect("A refresh payload");
+        let result = authenticator
+            .authenticate_refresh_token(&refresh_payload)
+            .expect("To refresh correctly");
+        assert_eq!(result.private_claims["entitled"], true);
+    }
+
     #[test]
     fn authentication_with_refresh_payload() {
         let authenticator = make_authenticator();
@@ -188,6 +966,260 @@ mod tests {
         assert!(result.refresh_payload.is_none());
     }
 
+    #[test]
+    fn refresh_payload_excludes_credentials() {
+        let authenticator = make_authenticator();
+
+        let result = authenticator
+            .verify("foobar", "password", true)
+            .expect("To verify correctly");
+        let refresh_payload = result.refresh_payload.expect("to be present");
+
+        let user = &refresh_payload["user"];
+        assert_eq!(user["username"], "foobar");
+        assert!(user.get("hash").is_none());
+        assert!(user.get("salt").is_none());
+    }
+
+    #[test]
+    fn refresh_payload_with_wrong_nonce_is_rejected() {
+        let authenticator = make_authenticator();
+
+        let result = authenticator
+            .verify("foobar", "password", true)
+            .expect("To verify correctly");
+        let mut refresh_payload = result.refresh_payload.expect("to be present");
+        refresh_payload["user"]["nonce"] = From::from("tampered");
+
+        let result = authenticator.authenticate_refresh_token(&refresh_payload);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn user_existence_is_checked_without_a_password() {
+        let authenticator = make_authenticator();
+
+        assert!(authenticator.user_exists("mei").expect("To query"));
+        assert!(!authenticator.user_exists("nobody").expect("To query"));
+    }
+
+    #[test]
+    fn users_are_listed_with_pagination_and_filter() {
+        let authenticator = make_authenticator();
+
+        let all_users = authenticator
+            .list_users(0, 10, None)
+            .expect("to list users");
+        let usernames: Vec<_> = all_users.iter().map(|user| user.username.as_str()).collect();
+        assert_eq!(usernames, vec!["foobar", "mei"]);
+
+        let page = authenticator
+            .list_users(1, 1, None)
+            .expect("to list users");
+        assert_eq!(page, vec![UserSummary::from("mei".to_string())]);
+
+        let filtered = authenticator
+            .list_users(0, 10, Some("foo%"))
+            .expect("to list users");
+        assert_eq!(filtered, vec![UserSummary::from("foobar".to_string())]);
+    }
+
+    #[test]
+    fn users_are_listed_by_prefix_or_substring() {
+        let authenticator = make_authenticator();
+        let list = |filter: UserFilter| -> Vec<String> {
+            authenticator
+                .list_users_matching(0, 10, &filter)
+                .expect("to list users")
+                .into_iter()
+                .map(|user| user.username)
+                .collect()
+        };
+
+        assert_eq!(list(UserFilter::Prefix("foo".to_string())), vec!["foobar"]);
+        assert!(list(UserFilter::Prefix("bar".to_string())).is_empty());
+        assert_eq!(list(UserFilter::Prefix("FOO".to_string())), vec!["foobar"]);
+        assert_eq!(list(UserFilter::Contains("bar".to_string())), vec!["foobar"]);
+        assert_eq!(list(UserFilter::Contains("e".to_string())), vec!["mei"]);
+        // Wildcards are matched literally
+        assert!(list(UserFilter::Prefix("%".to_string())).is_empty());
+        assert!(list(UserFilter::Contains("_".to_string())).is_empty());
+    }
+
+    #[test]
+    fn wildcards_in_prefixes_are_matched_literally() {
+        let path = "../target/sqlite_wildcard_prefixes.db";
+        let _ = ::std::fs::remove_file(path);
+        let authenticator =
+            super::Authenticator::new(path).expect("To be constructed successfully");
+        authenticator.run_migrations().expect("To succeed");
+        for username in &["mei", "_mei", "m_ei", "%mei", "\\mei"] {
+            authenticator
+                .create_user(username, "correct horse")
+                .expect("To be created");
+        }
+        let list = |prefix: &str| -> Vec<String> {
+            authenticator
+                .list_users_matching(0, 10, &UserFilter::Prefix(prefix.to_string()))
+                .expect("to list users")
+                .into_iter()
+                .map(|user| user.username)
+                .collect()
+        };
+
+        assert_eq!(list("_"), vec!["_mei"]);
+        assert_eq!(list("%"), vec!["%mei"]);
+        assert_eq!(list("\\"), vec!["\\mei"]);
+        assert_eq!(list("m_"), vec!["m_ei"]);
+        assert_eq!(list("_m"), vec!["_mei"]);
+        assert!(list("_x").is_empty());
+    }
+
+    #[test]
+    fn invalid_unicode_in_usernames_is_handled_by_policy() {
+        let path = "../target/sqlite_invalid_usernames.db";
+        let _ = ::std::fs::remove_file(path);
+        let mut authenticator =
+            super::Authenticator::new(path).expect("To be constructed successfully");
+        authenticator.run_migrations().expect("To succeed");
+        authenticator
+            .create_user("alice", "correct horse")
+            .expect("To be created");
+        // `mei` followed by a byte that is never valid in UTF-8
+        authenticator
+            .get_pooled_connection()
+            .expect("A connection")
+            .batch_execute(
+                "INSERT INTO users (username, hash, salt) \
+                 VALUES (CAST(X'6D6569FF' AS TEXT), X'00', X'00');",
+            )
+            .expect("To insert");
+
+        match authenticator.list_users(0, 10, None) {
+            Err(Error::InvalidUnicodeInUsername(ref username)) => {
+                assert_eq!(username, "mei\u{FFFD}")
+            }
+            other => panic!("Unexpected result {:?}", other),
+        }
+
+        authenticator.set_invalid_username_policy(InvalidUsernamePolicy::Lossy);
+        let usernames: Vec<_> = authenticator
+            .list_users(0, 10, None)
+            .expect("to list users")
+            .into_iter()
+            .map(|user| user.username)
+            .collect();
+        assert_eq!(usernames, vec!["alice", "mei\u{FFFD}"]);
+
+        authenticator.set_invalid_username_policy(InvalidUsernamePolicy::Skip);
+        let users = authenticator
+            .list_users(0, 10, None)
+            .expect("to list users");
+        assert_eq!(users, vec![UserSummary::from("alice".to_string())]);
+
+        // Valid users are unaffected by the policy
+        let _ = authenticator
+            .verify("alice", "correct horse", false)
+            .expect("To verify correctly");
+    }
+
+    #[test]
+    fn hashes_are_stored_in_the_phc_string_format() {
+        let path = "../target/sqlite_phc.db";
+        let _ = ::std::fs::remove_file(path);
+        let mut authenticator =
+            super::Authenticator::new(path).expect("To be constructed successfully");
+        authenticator.run_migrations().expect("To succeed");
+        authenticator
+            .create_user("alice", "correct horse")
+            .expect("To be created");
+        authenticator.set_password_storage(PasswordStorage::Phc);
+        authenticator
+            .create_user("bob", "battery staple")
+            .expect("To be created");
+
+        let connection = authenticator.get_pooled_connection().expect("A connection");
+        let user = connection
+            .search_users("bob")
+            .expect("To search")
+            .pop()
+            .expect("The user to exist");
+        assert!(user.hash.starts_with(b"$argon2i$v=19$m=4096,t=3,p=1$"));
+        assert!(user.salt.is_empty());
+
+        // Both formats are verified, whatever the storage of new hashes
+        for &storage in &[PasswordStorage::Phc, PasswordStorage::Split] {
+            authenticator.set_password_storage(storage);
+            for &(username, password) in &[("alice", "correct horse"), ("bob", "battery staple")] {
+                let _ = authenticator
+                    .verify(username, password, false)
+                    .expect("To verify correctly");
+                assert!(authenticator.verify(username, "wrong", false).is_err());
+            }
+        }
+    }
+
+    #[test]
+    fn weak_hashes_are_upgraded_on_login() {
+        use rowdy::auth::util::PhcHash;
+
+        let path = "../target/sqlite_rehash.db";
+        let _ = ::std::fs::remove_file(path);
+        let mut authenticator =
+            super::Authenticator::new(path).expect("To be constructed successfully");
+        authenticator.run_migrations().expect("To succeed");
+        authenticator.set_password_storage(PasswordStorage::Phc);
+
+        let mut weak = PhcHash {
+            data_dependent: false,
+            memory_cost: 8,
+            time_cost: 1,
+            parallelism: 1,
+            salt: vec![1; 16],
+            hash: vec![0; 32],
+        };
+        weak.hash = weak.hash_password("correct horse").expect("To hash");
+        let weak = weak.encode();
+        let connection = authenticator.get_pooled_connection().expect("A connection");
+        connection
+            .insert_user("alice", weak.as_bytes(), &[])
+            .expect("To insert");
+        let stored_hash = || {
+            connection
+                .search_users("alice")
+                .expect("To search")
+                .pop()
+                .expect("The user to exist")
+                .hash
+        };
+
+        // Hashes are left alone unless enabled
+        let _ = authenticator
+            .verify("alice", "correct horse", false)
+            .expect("To verify correctly");
+        assert_eq!(stored_hash(), weak.as_bytes());
+
+        authenticator.set_rehash_on_login(true);
+        assert!(authenticator.verify("alice", "wrong", false).is_err());
+        assert_eq!(stored_hash(), weak.as_bytes());
+
+        let result = authenticator
+            .verify("alice", "correct horse", true)
+            .expect("To verify correctly");
+        let upgraded = stored_hash();
+        assert!(upgraded.starts_with(b"$argon2i$v=19$m=4096,t=3,p=1$"));
+
+        // The refresh token issued with the login matches the upgraded hash
+        let refresh_payload = result.refresh_payload.expect("A refresh payload");
+        let _ = authenticator
+            .verify_refresh_payload(&refresh_payload)
+            .expect("To verify the refresh payload");
+        let _ = authenticator
+            .verify("alice", "correct horse", false)
+            .expect("To verify correctly");
+        assert_eq!(stored_hash(), upgraded);
+    }
+
     #[test]
     fn sqlite_authenticator_configuration_deserialization() {
         use serde_json;
@@ -201,6 +1233,7 @@ mod tests {
             serde_json::from_str(json).expect("to deserialize successfully");
         let expected_config = Configuration {
             path: From::from("../target/test.db"),
+            health_check: true,
         };
         assert_eq!(deserialized, expected_config);
 
@@ -208,4 +1241,24 @@ mod tests {
             .make_authenticator()
             .expect("to be constructed correctly");
     }
+
+    #[test]
+    fn health_checks_can_be_turned_off() {
+        use serde_json;
+
+        let json = r#"{
+            "path": "../target/test.db",
+            "health_check": false
+        }"#;
+        let deserialized: Configuration =
+            serde_json::from_str(json).expect("to deserialize successfully");
+        assert!(!deserialized.health_check);
+
+        let authenticator =
+            super::Authenticator::with_config(&deserialized.path, pool_config(false))
+                .expect("to be constructed correctly");
+        let _ = authenticator
+            .get_pooled_connection()
+            .expect("a connection without a health check");
+    }
 }
@@ -0,0 +1,320 @@
+//! Audit events for security-relevant actions
+//!
+//! Unlike logging, audit events are structured and machine-readable, for deployments that must
+//! keep a record of logins, password changes, lockouts and token revocations. Events are recorded
+//! in an [`AuditSink`].
+//!
+//! To record the events of the routes provided by rowdy, manage a sink in the Rocket state as a
+//! `Box<AuditSink>`. The routes record logins, including the issuance of tokens in exchange for
+//! refresh tokens and client credentials, and the revocation of sessions. Authenticators that
+//! change passwords or lock out users, like the diesel authenticator, can be given a sink too.
+//!
+//! ```rust,ignore
+//! let sink: Box<AuditSink> = Box::new(JsonLinesAuditSink::open("audit.jsonl")?);
+//! let rocket = rowdy::rocket(config)?.manage(sink);
+//! ```
+//!
+//! Use an `Arc` to share one sink between the routes and the authenticator.
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::Sender;
+
+use chrono::{DateTime, Utc};
+use serde_json;
+
+use Error;
+
+/// Kinds of audit events
+#[derive(Serialize, Deserialize, Clone, Copy, Eq, PartialEq, Hash, Debug)]
+pub enum AuditEventKind {
+    /// A user or client authenticated to be issued a token
+    #[serde(rename = "login")]
+    Login,
+    /// The password of a user was changed
+    #[serde(rename = "password_change")]
+    PasswordChange,
+    /// A user was locked out after repeated authentication failures
+    #[serde(rename = "lockout")]
+    Lockout,
+    /// Tokens or sessions of a user were revoked
+    #[serde(rename = "token_revocation")]
+    TokenRevocation,
+}
+
+/// Outcome of the action recorded by an audit event
+#[derive(Serialize, Deserialize, Clone, Copy, Eq, PartialEq, Hash, Debug)]
+pub enum AuditOutcome {
+    /// The action succeeded
+    #[serde(rename = "success")]
+    Success,
+    /// The action failed
+    #[serde(rename = "failure")]
+    Failure,
+}
+
+impl From<bool> for AuditOutcome {
+    fn from(success: bool) -> Self {
+        if success {
+            AuditOutcome::Success
+        } else {
+            AuditOutcome::Failure
+        }
+    }
+}
+
+/// An audit event. Serializes to JSON as
+///
+/// ```json
+/// {
+///     "timestamp": "2017-10-11T12:34:56.789Z",
+///     "event": "login",
+///     "subject": "mei",
+///     "client_ip": "192.0.2.1",
+///     "outcome": "failure"
+/// }
+/// ```
+#[derive(Serialize, Deserialize, Clone, Eq, PartialEq, Debug)]
+pub struct AuditEvent {
+    /// When the event happened
+    pub timestamp: DateTime<Utc>,
+    /// The kind of event
+    pub event: AuditEventKind,
+    /// The user or client the event is about, if known. The subject of a failed login is the
+    /// username presented, which may not exist.
+    pub subject: Option<String>,
+    /// The IP address of the client, if the event was caused by a request
+    pub client_ip: Option<IpAddr>,
+    /// The outcome of the action
+    pub outcome: AuditOutcome,
+}
+
+impl AuditEvent {
+    /// Create an event that happened now
+    pub fn new<S: Into<String>>(
+        event: AuditEventKind,
+        subject: Option<S>,
+        client_ip: Option<IpAddr>,
+        outcome: AuditOutcome,
+    ) -> Self {
+        AuditEvent {
+            timestamp: Utc::now(),
+            event,
+            subject: subject.map(Into::into),
+            client_ip,
+            outcome,
+        }
+    }
+
+    /// Record the event in the sink, with the method for its kind
+    pub fn record_in(&self, sink: &AuditSink) {
+        match self.event {
+            AuditEventKind::Login => sink.login(self),
+            AuditEventKind::PasswordChange => sink.password_change(self),
+            AuditEventKind::Lockout => sink.lockout(self),
+            AuditEventKind::TokenRevocation => sink.token_revocation(self),
+        }
+    }
+}
+
+/// A destination for audit events.
+///
+/// Each kind of event has its own method, which passes the event to `record` by default. `record`
+/// does nothing by default, so sinks can implement `record` to receive every event, or only the
+/// methods of the events they are interested in.
+///
+/// Recording an event cannot fail the action it records, so sinks should report their own errors,
+/// for example by logging them.
+pub trait AuditSink: Send + Sync {
+    /// Record an event of any kind
+    fn record(&self, event: &AuditEvent) {
+        let _ = event;
+    }
+
+    /// Record a login
+    fn login(&self, event: &AuditEvent) {
+        self.record(event)
+    }
+
+    /// Record a password change
+    fn password_change(&self, event: &AuditEvent) {
+        self.record(event)
+    }
+
+    /// Record a lockout
+    fn lockout(&self, event: &AuditEvent) {
+        self.record(event)
+    }
+
+    /// Record a token revocation
+    fn token_revocation(&self, event: &AuditEvent) {
+        self.record(event)
+    }
+}
+
+impl<T: AuditSink + ?Sized> AuditSink for Arc<T> {
+    fn record(&self, event: &AuditEvent) {
+        (**self).record(event)
+    }
+
+    fn login(&self, event: &AuditEvent) {
+        (**self).login(event)
+    }
+
+    fn password_change(&self, event: &AuditEvent) {
+        (**self).password_change(event)
+    }
+
+    fn lockout(&self, event: &AuditEvent) {
+        (**self).lockout(event)
+    }
+
+    fn token_revocation(&self, event: &AuditEvent) {
+        (**self).token_revocation(event)
+    }
+}
+
+/// A sink that discards every event
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NoOpAuditSink;
+
+impl AuditSink for NoOpAuditSink {}
+
+/// A sink that appends every event to a file, as one line of JSON per event
+#[derive(Debug)]
+pub struct JsonLinesAuditSink {
+    file: Mutex<File>,
+}
+
+impl JsonLinesAuditSink {
+    /// Open the file at `path` for appending, creating it if it does not exist
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(JsonLinesAuditSink {
+            file: Mutex::new(file),
+        })
+    }
+}
+
+impl AuditSink for JsonLinesAuditSink {
+    fn record(&self, event: &AuditEvent) {
+        let line = match serde_json::to_string(event) {
+            Ok(line) => line,
+            Err(e) => {
+                error_!("Unable to serialize audit event {:?}: {}", event, e);
+                return;
+            }
+        };
+        let mut file = match self.file.lock() {
+            Ok(file) => file,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        if let Err(e) = writeln!(file, "{}", line).and_then(|_| file.flush()) {
+            error_!("Unable to write audit event {}: {}", line, e);
+        }
+    }
+}
+
+/// A sink that sends every event to a channel, for processing on another thread
+#[derive(Debug)]
+pub struct ChannelAuditSink {
+    sender: Mutex<Sender<AuditEvent>>,
+}
+
+impl ChannelAuditSink {
+    /// Create a sink that sends events with `sender`
+    pub fn new(sender: Sender<AuditEvent>) -> Self {
+        ChannelAuditSink {
+            sender: Mutex::new(sender),
+        }
+    }
+}
+
+impl AuditSink for ChannelAuditSink {
+    fn record(&self, event: &AuditEvent) {
+        let sender = match self.sender.lock() {
+            Ok(sender) => sender,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        if sender.send(event.clone()).is_err() {
+            error_!("Unable to send audit event {:?}: the receiver was dropped", event);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::Read;
+    use std::sync::mpsc;
+
+    use super::*;
+
+    fn event(kind: AuditEventKind) -> AuditEvent {
+        AuditEvent::new(
+            kind,
+            Some("mei"),
+            Some(not_err!("192.0.2.1".parse())),
+            AuditOutcome::Success,
+        )
+    }
+
+    #[test]
+    fn events_are_sent_to_channels() {
+        let (sender, receiver) = mpsc::channel();
+        let sink = ChannelAuditSink::new(sender);
+
+        let login = event(AuditEventKind::Login);
+        let revocation = event(AuditEventKind::TokenRevocation);
+        login.record_in(&sink);
+        revocation.record_in(&sink);
+
+        assert_eq!(not_err!(receiver.try_recv()), login);
+        assert_eq!(not_err!(receiver.try_recv()), revocation);
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn events_are_appended_to_json_lines_files() {
+        let path = "target/audit_events.jsonl";
+        let _ = fs::remove_file(path);
+
+        let sink = not_err!(JsonLinesAuditSink::open(path));
+        let events = vec![
+            event(AuditEventKind::Login),
+            event(AuditEventKind::PasswordChange),
+        ];
+        for event in &events {
+            event.record_in(&sink);
+        }
+
+        let mut contents = String::new();
+        let _ = not_err!(not_err!(File::open(path)).read_to_string(&mut contents));
+        let recorded: Vec<AuditEvent> = contents
+            .lines()
+            .map(|line| not_err!(serde_json::from_str(line)))
+            .collect();
+        assert_eq!(recorded, events);
+
+        let line = not_none!(contents.lines().next());
+        let value: ::JsonValue = not_err!(serde_json::from_str(line));
+        assert_eq!(value["event"], "login");
+        assert_eq!(value["subject"], "mei");
+        assert_eq!(value["client_ip"], "192.0.2.1");
+        assert_eq!(value["outcome"], "success");
+    }
+
+    #[test]
+    fn sinks_can_be_shared() {
+        let (sender, receiver) = mpsc::channel();
+        let sink = Arc::new(ChannelAuditSink::new(sender));
+        let shared: Box<AuditSink> = Box::new(sink.clone());
+
+        event(AuditEventKind::Lockout).record_in(&*shared);
+        event(AuditEventKind::Lockout).record_in(&NoOpAuditSink);
+        assert_eq!(not_err!(receiver.try_recv()).event, AuditEventKind::Lockout);
+        assert!(receiver.try_recv().is_err());
+    }
+}
@@ -0,0 +1,286 @@
+//! An authenticator that tries several authenticators in order
+//!
+//! This is useful to check a local database before falling back to an external source, or to
+//! migrate gradually between authentication backends.
+//!
+//! A `Vec` of authenticator configurations of the same type configures a `Chain`. So does a pair
+//! of different authenticator configurations, which deserializes from an array of two. For
+//! example, a `rowdy::Configuration<(SimpleAuthenticatorConfiguration, LdapAuthenticator)>`
+//! checks a CSV file before an LDAP server:
+//!
+//! ```json
+//! {
+//!     "basic_authenticator": [
+//!         { "csv_path": "users.csv", "has_headers": true },
+//!         { "address": "ldap://ldap.example.com", "bind_dn": "...", "bind_password": "...",
+//!           "search_base": "..." }
+//!     ]
+//! }
+//! ```
+use hyper::header;
+
+use {Error, JsonMap, JsonValue};
+use super::{AuthenticationResult, Authenticator, AuthenticatorConfiguration, Authorization};
+
+/// Key in refresh token payloads holding the index of the authenticator that issued them
+const AUTHENTICATOR_KEY: &'static str = "authenticator";
+/// Key in refresh token payloads holding the payload of the authenticator that issued them
+const PAYLOAD_KEY: &'static str = "payload";
+
+/// Authenticator that tries each of its authenticators in order, until one authenticates
+/// successfully. The first success supplies the subject and private claims.
+///
+/// An authenticator that returns `auth::Error::AuthenticationFailure` does not know the
+/// credentials, and the next one is tried. Any other error, such as a database that is down, is a
+/// genuine error. The remaining authenticators are still tried, but if none of them succeeds,
+/// the first genuine error is returned instead of `AuthenticationFailure`. This allows clients
+/// to tell that the server is unavailable, rather than that their credentials are wrong.
+///
+/// Refresh tokens are handled by the authenticator that issued them.
+pub struct Chain<S: header::Scheme + 'static> {
+    authenticators: Vec<Box<Authenticator<S>>>,
+}
+
+impl<S: header::Scheme + 'static> Chain<S> {
+    /// Create a chain that tries the authenticators in order
+    pub fn new(authenticators: Vec<Box<Authenticator<S>>>) -> Self {
+        Chain { authenticators }
+    }
+
+    /// Add an authenticator to the end of the chain
+    pub fn with<A: Authenticator<S> + 'static>(mut self, authenticator: A) -> Self {
+        self.authenticators.push(Box::new(authenticator));
+        self
+    }
+
+    /// Wrap the refresh token payload of the authenticator at `index`
+    fn wrap_refresh_payload(index: usize, payload: JsonValue) -> JsonValue {
+        let mut map = JsonMap::with_capacity(2);
+        let _ = map.insert(AUTHENTICATOR_KEY.to_string(), From::from(index));
+        let _ = map.insert(PAYLOAD_KEY.to_string(), payload);
+        JsonValue::Object(map)
+    }
+
+    /// Retrieve the index of the authenticator, and its refresh token payload
+    fn unwrap_refresh_payload(payload: &JsonValue) -> Result<(usize, &JsonValue), Error> {
+        let index = payload
+            .get(AUTHENTICATOR_KEY)
+            .and_then(JsonValue::as_u64)
+            .ok_or_else(|| Error::Auth(super::Error::AuthenticationFailure))?;
+        let payload = payload
+            .get(PAYLOAD_KEY)
+            .ok_or_else(|| Error::Auth(super::Error::AuthenticationFailure))?;
+        Ok((index as usize, payload))
+    }
+}
+
+/// Returns whether the error only means that the credentials are unknown to an authenticator
+fn is_authentication_failure(error: &Error) -> bool {
+    match *error {
+        Error::Auth(super::Error::AuthenticationFailure) => true,
+        _ => false,
+    }
+}
+
+impl<S: header::Scheme + 'static> Authenticator<S> for Chain<S> {
+    fn authenticate(
+        &self,
+        authorization: &Authorization<S>,
+        include_refresh_payload: bool,
+    ) -> Result<AuthenticationResult, Error> {
+        let mut genuine_error = None;
+
+        for (index, authenticator) in self.authenticators.iter().enumerate() {
+            match authenticator.authenticate(authorization, include_refresh_payload) {
+                Ok(mut result) => {
+                    result.refresh_payload = result
+                        .refresh_payload
+                        .map(|payload| Self::wrap_refresh_payload(index, payload));
+                    return Ok(result);
+                }
+                Err(ref e) if is_authentication_failure(e) => {}
+                Err(e) => {
+                    warn_!("Authenticator {} in the chain has failed: {}", index, e);
+                    if genuine_error.is_none() {
+                        genuine_error = Some(e);
+                    }
+                }
+            }
+        }
+
+        match genuine_error {
+            Some(e) => Err(e),
+            None => Err(super::Error::AuthenticationFailure)?,
+        }
+    }
+
+    fn authenticate_refresh_token(
+        &self,
+        refresh_payload: &JsonValue,
+    ) -> Result<AuthenticationResult, Error> {
+        let (index, payload) = Self::unwrap_refresh_payload(refresh_payload)?;
+        match self.authenticators.get(index) {
+            Some(authenticator) => authenticator.authenticate_refresh_token(payload),
+            None => Err(super::Error::AuthenticationFailure)?,
+        }
+    }
+
+    /// Invalidates the sessions with every authenticator that supports it. Fails with
+    /// `UnsupportedOperation` if none of them do.
+    fn invalidate_sessions(&self, username: &str) -> Result<(), Error> {
+        let mut supported = false;
+
+        for authenticator in &self.authenticators {
+            match authenticator.invalidate_sessions(username) {
+                Ok(()) => supported = true,
+                Err(Error::UnsupportedOperation) => {}
+                Err(ref e) if is_authentication_failure(e) => supported = true,
+                Err(e) => Err(e)?,
+            }
+        }
+
+        if supported {
+            Ok(())
+        } else {
+            Err(Error::UnsupportedOperation)
+        }
+    }
+}
+
+/// An ordered list of authenticator configurations of the same type configures a `Chain`
+impl<S, B> AuthenticatorConfiguration<S> for Vec<B>
+where
+    S: header::Scheme + 'static,
+    B: AuthenticatorConfiguration<S>,
+{
+    type Authenticator = Chain<S>;
+
+    fn make_authenticator(&self) -> Result<Self::Authenticator, Error> {
+        let mut chain = Chain::new(Vec::with_capacity(self.len()));
+        for configuration in self {
+            chain = chain.with(configuration.make_authenticator()?);
+        }
+        Ok(chain)
+    }
+}
+
+/// A pair of authenticator configurations configures a `Chain` trying the first, then the second.
+/// Nest pairs to chain more than two types of authenticators.
+impl<S, A, B> AuthenticatorConfiguration<S> for (A, B)
+where
+    S: header::Scheme + 'static,
+    A: AuthenticatorConfiguration<S>,
+    B: AuthenticatorConfiguration<S>,
+{
+    type Authenticator = Chain<S>;
+
+    fn make_authenticator(&self) -> Result<Self::Authenticator, Error> {
+        Ok(Chain::new(vec![])
+            .with(self.0.make_authenticator()?)
+            .with(self.1.make_authenticator()?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use auth::tests::MockAuthenticator;
+    use auth::{Basic, NoOp};
+    use super::*;
+
+    /// Authenticator that fails with a genuine error, like a database that is down
+    struct Unavailable;
+
+    impl Authenticator<Basic> for Unavailable {
+        fn authenticate(
+            &self,
+            _authorization: &Authorization<Basic>,
+            _include_refresh_payload: bool,
+        ) -> Result<AuthenticationResult, Error> {
+            Err(Error::GenericError("Database is down".to_string()))
+        }
+    }
+
+    fn authorization(username: &str, password: &str) -> Authorization<Basic> {
+        Authorization(header::Authorization(Basic {
+            username: username.to_string(),
+            password: Some(password.to_string()),
+        }))
+    }
+
+    #[test]
+    fn first_success_wins() {
+        let chain = Chain::<Basic>::new(vec![]).with(MockAuthenticator {}).with(NoOp {});
+
+        // Known to the mock authenticator
+        let result = not_err!(chain.authenticate(&authorization("mei", "冻住，不许走!"), true));
+        assert_eq!("mei", result.subject);
+        let payload = not_none!(result.refresh_payload);
+        assert_eq!(payload[AUTHENTICATOR_KEY], 0);
+
+        // Falls through to the no-op authenticator
+        let result = not_err!(chain.authenticate(&authorization("foo", "bar"), true));
+        assert_eq!("foo", result.subject);
+        let payload = not_none!(result.refresh_payload);
+        assert_eq!(payload[AUTHENTICATOR_KEY], 1);
+    }
+
+    #[test]
+    fn refresh_tokens_are_handled_by_their_issuer() {
+        let chain = Chain::<Basic>::new(vec![]).with(MockAuthenticator {}).with(NoOp {});
+
+        let result = not_err!(chain.authenticate(&authorization("foo", "bar"), true));
+        let payload = not_none!(result.refresh_payload);
+        let refreshed = not_err!(chain.prepare_refresh_response(&payload));
+        assert_eq!("foo", refreshed.subject);
+
+        let mut forged = payload.clone();
+        forged[AUTHENTICATOR_KEY] = From::from(5);
+        let error = chain.authenticate_refresh_token(&forged).err();
+        assert!(is_authentication_failure(&not_none!(error)));
+    }
+
+    #[test]
+    fn configurations_make_chains() {
+        use auth::tests::MockAuthenticatorConfiguration;
+        use auth::NoOpConfiguration;
+
+        let configuration = vec![MockAuthenticatorConfiguration {}];
+        let chain = not_err!(AuthenticatorConfiguration::<Basic>::make_authenticator(
+            &configuration
+        ));
+        assert_eq!(1, chain.authenticators.len());
+
+        let configuration = (MockAuthenticatorConfiguration {}, NoOpConfiguration {});
+        let chain = not_err!(AuthenticatorConfiguration::<Basic>::make_authenticator(
+            &configuration
+        ));
+        let result = not_err!(chain.authenticate(&authorization("foo", "bar"), false));
+        assert_eq!("foo", result.subject);
+    }
+
+    #[test]
+    fn authentication_failures_are_aggregated() {
+        let chain = Chain::<Basic>::new(vec![])
+            .with(MockAuthenticator {})
+            .with(MockAuthenticator {});
+
+        let error = chain.authenticate(&authorization("foo", "bar"), false).err();
+        assert!(is_authentication_failure(&not_none!(error)));
+    }
+
+    #[test]
+    fn genuine_errors_are_distinguishable_from_authentication_failures() {
+        let chain = Chain::<Basic>::new(vec![])
+            .with(Unavailable)
+            .with(MockAuthenticator {});
+
+        // Another authenticator can still authenticate the user
+        let result = not_err!(chain.authenticate(&authorization("mei", "冻住，不许走!"), false));
+        assert_eq!("mei", result.subject);
+
+        // But unknown credentials report the genuine error
+        let error = not_none!(chain.authenticate(&authorization("foo", "bar"), false).err());
+        assert!(!is_authentication_failure(&error));
+        assert_eq!("Database is down", error.to_string());
+    }
+}
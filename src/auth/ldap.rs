@@ -159,10 +159,10 @@ impl LdapAuthenticator {
         search_attrs_vec.sort();
         search_attrs_vec.dedup();
 
+        // The filter is not logged because it contains the username
         debug_!(
-            "Searching base {} with filter {} and attributes {:?}",
+            "Searching base {} with attributes {:?}",
             search_base,
-            search_filter,
             search_attrs_vec
         );
 
@@ -281,27 +281,38 @@ impl LdapAuthenticator {
     }
 
     /// Authenticate the user with the username/password
+    ///
+    /// Empty passwords are rejected before connecting to the server, since many servers treat a
+    /// simple bind with an empty password as an unauthenticated, anonymous, bind that succeeds.
     pub fn verify(
         &self,
         username: &str,
         password: &str,
         include_refresh_payload: bool,
     ) -> Result<AuthenticationResult, Error> {
+        if password.is_empty() {
+            debug_!("Empty password provided");
+            Err(super::Error::AuthenticationFailure)?;
+        }
+
         let user = {
             // First, we search for the user
             let connection = self.connect()?;
             self.searcher_bind(&connection)?;
             let mut user = self.search(&connection, username).map_err(|e| {
-                error_!("Error searching for username `{}`: {}", username, e);
+                error_!("Error searching for the user: {}", e);
                 super::Error::AuthenticationFailure
             })?;
-            if user.len() != 1 {
-                error_!(
-                    "{} users were returned for the username {}",
-                    user.len(),
-                    username
-                );
-                Err(super::Error::AuthenticationFailure)?;
+            match user.len() {
+                0 => {
+                    debug_!("No user was returned for the username {}", username);
+                    Err(super::Error::AuthenticationFailure)?;
+                }
+                1 => {}
+                count => {
+                    error_!("{} users were returned for the same username", count);
+                    Err(super::Error::AuthenticationFailure)?;
+                }
             }
 
             user.pop().unwrap() // safe to unwrap
@@ -313,7 +324,7 @@ impl LdapAuthenticator {
             // Attempt a bind with the user's DN and password
             let connection = self.connect()?;
             self.bind(&connection, &user_dn, password).map_err(|e| {
-                error_!(
+                debug_!(
                     "Error binding DN {} with user supplied password: {}",
                     user_dn,
                     e
@@ -340,7 +351,8 @@ impl super::Authenticator<Basic> for LdapAuthenticator {
         include_refresh_payload: bool,
     ) -> Result<AuthenticationResult, Error> {
         let username = authorization.username();
-        let password = authorization.password().unwrap_or_else(|| "".to_string());
+        // A missing password is rejected by `verify`, like an empty one
+        let password = authorization.password().unwrap_or_default();
         self.verify(&username, &password, include_refresh_payload)
     }
 
@@ -568,6 +580,28 @@ mod tests {
             .unwrap();
     }
 
+    #[test]
+    fn authentication_with_empty_or_missing_password_fails() {
+        // Rejected without connecting, so the server is never bound anonymously
+        let mut authenticator = make_authenticator();
+        authenticator.address = "ldap://127.0.0.1:1".to_string();
+
+        match authenticator.verify("euler", "", false) {
+            Err(Error::Auth(::auth::Error::AuthenticationFailure)) => {}
+            Err(e) => panic!("Unexpected error {:?}", e),
+            Ok(_) => panic!("Empty password should be rejected"),
+        }
+
+        for header in &["Basic ZXVsZXI=", "Basic ZXVsZXI6"] {
+            let authorization = not_err!(::auth::Authorization::<Basic>::new(header));
+            match authenticator.authenticate(&authorization, false) {
+                Err(Error::Auth(::auth::Error::AuthenticationFailure)) => {}
+                Err(e) => panic!("Unexpected error {:?}", e),
+                Ok(_) => panic!("Missing password should be rejected"),
+            }
+        }
+    }
+
     #[test]
     #[should_panic(expected = "AuthenticationFailure")]
     fn authentication_invalid_password() {
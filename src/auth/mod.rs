@@ -2,20 +2,27 @@
 //! authentication.
 use std::error;
 use std::fmt;
+use std::io::Cursor;
+use std::marker::PhantomData;
 use std::ops::Deref;
 
 use hyper;
 use hyper::header;
+use jwt;
 use rocket;
-use rocket::http::Status;
-use rocket::request::{self, FromRequest, Request};
+use rocket::http::{ContentType, Status};
+use rocket::request::{self, FormItems, FromRequest, Request};
 use rocket::response;
-use rocket::Outcome;
+use rocket::{Outcome, State};
 use serde::Serialize;
 use serde::de::DeserializeOwned;
+use serde_json;
 
 pub mod util;
 
+mod chain;
+pub use self::chain::Chain;
+
 mod noop;
 pub use self::noop::NoOp;
 pub use self::noop::NoOpConfiguration;
@@ -33,6 +40,10 @@ mod ldap;
 pub use self::ldap::LdapAuthenticator;
 
 use JsonValue;
+use reload::CurrentConfiguration;
+use request_id;
+use scope;
+use token;
 
 /// Re-exported [`hyper::header::Scheme`]
 pub type Scheme = hyper::header::Scheme<Err = hyper::error::Error>;
@@ -65,6 +76,12 @@ pub enum Error {
         /// The HTTP basic authentication realm
         realm: String,
     },
+    /// The token is valid, but does not have the scope required by the route. This variant will
+    /// `respond` with `403 Forbidden`.
+    InsufficientScope {
+        /// The scope required by the route
+        required: String,
+    },
 }
 
 impl_from_error!(String, Error::GenericError);
@@ -77,6 +94,7 @@ impl error::Error for Error {
             Error::MissingAuthorization { .. } => {
                 "The request header `Authorization` is required but is missing"
             }
+            Error::InsufficientScope { .. } => "The token does not have the required scope",
             Error::GenericError(ref e) => &**e,
             Error::HyperError(ref e) => e.description(),
         }
@@ -100,25 +118,70 @@ impl fmt::Display for Error {
 }
 
 impl<'r> response::Responder<'r> for Error {
-    fn respond_to(self, _: &Request) -> Result<response::Response<'r>, Status> {
-        error_!("Authentication Error: {:?}", self);
-        match self {
+    fn respond_to(self, request: &Request) -> Result<response::Response<'r>, Status> {
+        let message = format!("Authentication Error: {:?}", self);
+        let response = match self {
             Error::MissingAuthorization { ref realm } => {
-                // TODO: Support other schemes!
-                let www_header =
-                    rocket::http::Header::new("WWW-Authenticate", format!("Basic realm={}", realm));
-
-                Ok(
-                    response::Response::build()
-                        .status(Status::Unauthorized)
-                        .header(www_header)
-                        .finalize(),
-                )
+                let message = match request.guard::<CurrentConfiguration>() {
+                    Outcome::Success(configuration) => failure_message(&configuration, request),
+                    _ => None,
+                };
+                Ok(challenge(realm, message))
+            }
+            Error::AuthenticationFailure => {
+                // The realm is only known if the token configuration is managed by Rocket
+                match request.guard::<CurrentConfiguration>() {
+                    Outcome::Success(configuration) => {
+                        let realm = match requested_service(request) {
+                            Some(service) => configuration.realm_for(&service),
+                            None => configuration.realm(),
+                        };
+                        Ok(challenge(&realm, failure_message(&configuration, request)))
+                    }
+                    _ => Err(Status::Unauthorized),
+                }
             }
-            Error::AuthenticationFailure => Err(Status::Unauthorized),
+            Error::InsufficientScope { .. } => Err(Status::Forbidden),
             Error::HyperError(_) => Err(Status::BadRequest),
             _ => Err(Status::InternalServerError),
-        }
+        };
+        request_id::log_error_response(request, &message, response)
+    }
+}
+
+/// Build a `401 Unauthorized` response with a `WWW-Authenticate` challenge for the realm, and
+/// the message explaining the failure, if any, as the body
+fn challenge<'r>(realm: &str, message: Option<String>) -> response::Response<'r> {
+    // TODO: Support other schemes!
+    let www_header =
+        rocket::http::Header::new("WWW-Authenticate", format!("Basic realm=\"{}\"", realm));
+
+    let mut response = response::Response::build();
+    let _ = response.status(Status::Unauthorized).header(www_header);
+    if let Some(message) = message {
+        let _ = response
+            .header(ContentType::Plain)
+            .sized_body(Cursor::new(message));
+    }
+    response.finalize()
+}
+
+/// The `service` request parameter in the query string of the request, if any
+fn requested_service(request: &Request) -> Option<String> {
+    let query = match request.uri().query() {
+        Some(query) => query,
+        None => return None,
+    };
+    FormItems::from(query)
+        .find(|&(key, _)| key.as_str() == "service")
+        .and_then(|(_, value)| value.url_decode().ok())
+}
+
+/// The message explaining authentication failures to clients of the requested service
+fn failure_message(configuration: &token::Configuration, request: &Request) -> Option<String> {
+    match requested_service(request) {
+        Some(service) => configuration.failure_message_for(&service),
+        None => configuration.authentication_failure_message.clone(),
     }
 }
 
@@ -129,10 +192,18 @@ pub struct Authorization<S: header::Scheme + 'static>(pub header::Authorization<
 impl<'a, 'r, S: header::Scheme + 'static> FromRequest<'a, 'r> for Authorization<S> {
     type Error = Error;
 
+    /// Requests without the header, or with a header of another scheme, are forwarded. A header
+    /// of the scheme that cannot be parsed fails with `400 Bad Request`.
     fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Error> {
         match request.headers().get_one("Authorization") {
             Some(authorization) => match Self::new(authorization) {
-                Err(_) => Outcome::Forward(()),
+                Err(e) => {
+                    if has_scheme::<S>(authorization) {
+                        Outcome::Failure((Status::BadRequest, e))
+                    } else {
+                        Outcome::Forward(())
+                    }
+                }
                 Ok(parsed) => Outcome::Success(parsed),
             },
             None => Outcome::Forward(()),
@@ -140,6 +211,31 @@ impl<'a, 'r, S: header::Scheme + 'static> FromRequest<'a, 'r> for Authorization<
     }
 }
 
+/// Returns whether the `Authorization` header is of the scheme `S`
+fn has_scheme<S: header::Scheme + 'static>(authorization: &str) -> bool {
+    match S::scheme() {
+        Some(scheme) => authorization.split_whitespace().next() == Some(scheme),
+        None => false,
+    }
+}
+
+/// Describe why the `Authorization` header of the request cannot be parsed as a `Basic` or
+/// `Bearer` header. Returns `None` if the header is missing, or can be parsed.
+pub(crate) fn malformed_authorization(request: &Request) -> Option<String> {
+    let authorization = match request.headers().get_one("Authorization") {
+        Some(authorization) => authorization,
+        None => return None,
+    };
+    let error = if has_scheme::<Basic>(authorization) {
+        Authorization::<Basic>::new(authorization).err()
+    } else if has_scheme::<Bearer>(authorization) {
+        Authorization::<Bearer>::new(authorization).err()
+    } else {
+        None
+    };
+    error.map(|e| format!("The `Authorization` header is malformed: {}", e))
+}
+
 impl<S: header::Scheme + 'static> Authorization<S> {
     /// Create a new Authorization header
     pub fn new<'a>(header: &'a str) -> Result<Self, Error> {
@@ -212,6 +308,256 @@ impl<S: header::Scheme + 'static> Deref for Authorization<S> {
     }
 }
 
+/// Request guard for routes that require an access token issued by rowdy.
+///
+/// The token is extracted from the `Authorization: Bearer` request header, and validated against
+/// the current `token::Configuration` and its prepared `Keys` with `Configuration::validate_token`,
+/// so that key files are not read for every request. See `reload::CurrentConfiguration`. The
+/// private claims of the token must deserialize into `T`.
+///
+/// The guard fails with `401 Unauthorized` if the header is missing, or the token is invalid.
+///
+/// # Examples
+/// ```rust,ignore
+/// #[get("/admin")]
+/// fn admin(claims: AuthenticatedClaims<PrivateClaim>) -> String {
+///     format!("Hello {:?}", claims.registered.subject)
+/// }
+/// ```
+#[derive(Debug)]
+pub struct AuthenticatedClaims<T>(pub jwt::ClaimsSet<T>);
+
+impl<T> Deref for AuthenticatedClaims<T> {
+    type Target = jwt::ClaimsSet<T>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl<'a, 'r, T> FromRequest<'a, 'r> for AuthenticatedClaims<T>
+where
+    T: Serialize + DeserializeOwned + 'static,
+{
+    type Error = Error;
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Error> {
+        validated_claims(request).map(AuthenticatedClaims)
+    }
+}
+
+/// A scope that can be required by `ScopedClaims`
+///
+/// # Examples
+/// ```rust,ignore
+/// struct Admin;
+///
+/// impl Scope for Admin {
+///     fn scope() -> &'static str {
+///         "admin"
+///     }
+/// }
+///
+/// #[get("/admin")]
+/// fn admin(claims: ScopedClaims<PrivateClaim, Admin>) -> String {
+///     format!("Hello {:?}", claims.registered.subject)
+/// }
+/// ```
+pub trait Scope {
+    /// The required scope
+    fn scope() -> &'static str;
+
+    /// Whether granted scopes are matched hierarchically, so that `repo:admin` satisfies a
+    /// required `repo:read`. See the [`scope`](::scope) module for the rules.
+    ///
+    /// Defaults to `false`, and the required scope must then be granted exactly.
+    fn hierarchical() -> bool {
+        false
+    }
+}
+
+/// Request guard for routes that require an access token issued by rowdy with the scope `S`.
+///
+/// Like `AuthenticatedClaims`, but the private claims of the token must also be an object with a
+/// `scope` field, which is either a space-delimited string of scopes, or an array of scopes, with
+/// a scope that satisfies `S::scope()`. By default, the scope must be granted exactly. If
+/// `S::hierarchical()` is `true`, scopes are hierarchical, and `repo:admin` satisfies a required
+/// `repo:read`.
+///
+/// The guard fails with `401 Unauthorized` if the header is missing or the token is invalid, and
+/// with `403 Forbidden` if the token is valid but does not have the required scope.
+#[derive(Debug)]
+pub struct ScopedClaims<T, S: Scope> {
+    claims: jwt::ClaimsSet<T>,
+    scope: PhantomData<S>,
+}
+
+impl<T, S: Scope> ScopedClaims<T, S> {
+    /// Consume the guard, and return the claims of the token
+    pub fn into_inner(self) -> jwt::ClaimsSet<T> {
+        self.claims
+    }
+}
+
+impl<T, S: Scope> Deref for ScopedClaims<T, S> {
+    type Target = jwt::ClaimsSet<T>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.claims
+    }
+}
+
+impl<'a, 'r, T, S> FromRequest<'a, 'r> for ScopedClaims<T, S>
+where
+    T: Serialize + DeserializeOwned + 'static,
+    S: Scope,
+{
+    type Error = Error;
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Error> {
+        let claims: jwt::ClaimsSet<JsonValue> = match validated_claims(request) {
+            Outcome::Success(claims) => claims,
+            Outcome::Failure(failure) => return Outcome::Failure(failure),
+            Outcome::Forward(()) => return Outcome::Forward(()),
+        };
+
+        if !has_scope(&claims.private, S::scope(), S::hierarchical()) {
+            return Outcome::Failure((
+                Status::Forbidden,
+                Error::InsufficientScope {
+                    required: S::scope().to_string(),
+                },
+            ));
+        }
+
+        match serde_json::from_value(claims.private) {
+            Ok(private) => Outcome::Success(ScopedClaims {
+                claims: jwt::ClaimsSet {
+                    registered: claims.registered,
+                    private,
+                },
+                scope: PhantomData,
+            }),
+            Err(e) => {
+                debug_!("Private claims of the token could not be deserialized: {}", e);
+                Outcome::Failure((Status::Unauthorized, Error::AuthenticationFailure))
+            }
+        }
+    }
+}
+
+/// Returns whether the `scope` field of the private claims includes the required scope, or, if
+/// `hierarchical`, any scope that satisfies it. See `scope::Scope::satisfies` for the rules.
+/// Granted scopes that cannot be parsed are then ignored.
+fn has_scope(private_claims: &JsonValue, required: &str, hierarchical: bool) -> bool {
+    let granted: Vec<&str> = match private_claims.get("scope") {
+        Some(&JsonValue::String(ref scopes)) => scopes.split_whitespace().collect(),
+        Some(&JsonValue::Array(ref scopes)) => {
+            scopes.iter().filter_map(JsonValue::as_str).collect()
+        }
+        _ => vec![],
+    };
+    if !hierarchical {
+        return granted.into_iter().any(|granted| granted == required);
+    }
+
+    let required: scope::Scope = match required.parse() {
+        Ok(required) => required,
+        Err(e) => {
+            error_!("The required scope is invalid: {}", e);
+            return false;
+        }
+    };
+    granted
+        .into_iter()
+        .filter_map(|granted| granted.parse::<scope::Scope>().ok())
+        .any(|granted| granted.satisfies(&required))
+}
+
+/// Extract the bearer token from the request, and validate it against the current
+/// `token::Configuration`. See `reload::CurrentConfiguration`.
+fn validated_claims<T>(request: &Request) -> request::Outcome<jwt::ClaimsSet<T>, Error>
+where
+    T: Serialize + DeserializeOwned + 'static,
+{
+    validated_token(request).map(|(_, claims)| claims)
+}
+
+/// Like `validated_claims`, but also returns the header of the token, such as to tell whether it
+/// is a service token with `token::HeaderFields::is_service_token`
+pub(crate) fn validated_token<T>(
+    request: &Request,
+) -> request::Outcome<(jwt::jws::Header<token::HeaderFields>, jwt::ClaimsSet<T>), Error>
+where
+    T: Serialize + DeserializeOwned + 'static,
+{
+    let configuration = match request.guard::<CurrentConfiguration>() {
+        Outcome::Success(configuration) => configuration,
+        _ => {
+            return Outcome::Failure((
+                Status::InternalServerError,
+                Error::GenericError("Token configuration is not managed".to_string()),
+            ))
+        }
+    };
+
+    let authorization = match request.guard::<Authorization<Bearer>>() {
+        Outcome::Success(authorization) => authorization,
+        _ => {
+            return Outcome::Failure((
+                Status::Unauthorized,
+                Error::MissingAuthorization {
+                    realm: configuration.realm(),
+                },
+            ))
+        }
+    };
+
+    // Reference tokens are resolved into the JWT they stand for, if they are enabled. The store is
+    // not queried otherwise, even if one is managed.
+    let mut token = authorization.token();
+    let store = if configuration.reference_tokens {
+        request.guard::<State<Box<token::ReferenceTokenStore>>>().succeeded()
+    } else {
+        None
+    };
+    if let Some(store) = store {
+        match store.resolve(&token, configuration.clock.now()) {
+            Ok(Some(resolved)) => token = resolved,
+            Ok(None) => {}
+            Err(e) => {
+                return Outcome::Failure((
+                    Status::InternalServerError,
+                    Error::GenericError(e.to_string()),
+                ))
+            }
+        }
+    }
+
+    let keys = match configuration.keys() {
+        Some(keys) => keys,
+        None => {
+            return Outcome::Failure((
+                Status::InternalServerError,
+                Error::GenericError("Token keys are not managed".to_string()),
+            ))
+        }
+    };
+    match configuration.validate_token::<T>(keys, &token) {
+        Ok(token) => match token.token {
+            jwt::jws::Compact::Decoded { header, payload } => Outcome::Success((header, payload)),
+            jwt::jws::Compact::Encoded(_) => Outcome::Failure((
+                Status::InternalServerError,
+                Error::GenericError("Validated token was not decoded".to_string()),
+            )),
+        },
+        Err(e) => {
+            debug_!("Token validation failed: {}", e);
+            Outcome::Failure((Status::Unauthorized, Error::AuthenticationFailure))
+        }
+    }
+}
+
 /// Authenticator trait to be implemented by identity provider (idp) adapters to
 /// provide authentication.
 /// Each idp may support all the
@@ -262,6 +608,14 @@ pub trait Authenticator<S: header::Scheme + 'static>: Send + Sync {
         Err(::Error::UnsupportedOperation)
     }
 
+    /// Invalidate all outstanding refresh tokens of the user with `username`, if supported by the
+    /// authenticator. This signs the user out everywhere, once their access tokens expire.
+    ///
+    /// A default implementation that returns an `Err(::Error::UnsupportedOperation)` is provided.
+    fn invalidate_sessions(&self, _username: &str) -> Result<(), ::Error> {
+        Err(::Error::UnsupportedOperation)
+    }
+
     /// Prepare a response to an authentication request
     /// by first verifying credentials. If validation fails, will return an `Err` with the response
     /// to be sent. Otherwise, the unwrapped authentication result will be returned in an `Ok`.
@@ -326,6 +680,21 @@ pub trait AuthenticatorConfiguration<S: header::Scheme + 'static>
     fn make_authenticator(&self) -> Result<Self::Authenticator, ::Error>;
 }
 
+/// Authenticator for machine-to-machine clients using the client credentials grant. Clients
+/// pass their client ID and secret with HTTP Basic Authentication, and receive a service token
+/// without a refresh token.
+///
+/// The grant is enabled by managing this in the Rocket state.
+pub struct ClientCredentialsAuthenticator(Box<BasicAuthenticator>);
+impl_deref!(ClientCredentialsAuthenticator, Box<BasicAuthenticator>);
+
+impl ClientCredentialsAuthenticator {
+    /// Wrap an authenticator that verifies client credentials
+    pub fn new<A: Authenticator<Basic> + 'static>(authenticator: A) -> Self {
+        ClientCredentialsAuthenticator(Box::new(authenticator))
+    }
+}
+
 /// Result from a successful authentication operation
 #[derive(Clone, PartialEq, Debug)]
 pub struct AuthenticationResult {
@@ -337,6 +706,44 @@ pub struct AuthenticationResult {
     pub refresh_payload: Option<JsonValue>,
 }
 
+impl AuthenticationResult {
+    /// Serialize a strongly-typed private claims structure into `private_claims`.
+    /// The type should match the claims type that rowdy was launched with.
+    pub fn set_private_claims<T: Serialize>(&mut self, private_claims: &T) -> Result<(), ::Error> {
+        self.private_claims = serde_json::to_value(private_claims).map_err(token::Error::from)?;
+        Ok(())
+    }
+}
+
+/// Hook invoked with the `AuthenticationResult` before a token is issued. It can rewrite the
+/// `subject`, for example to a stable user ID, or augment the `private_claims`.
+///
+/// To use a transform, manage it in the Rocket state as a `Box<ClaimsTransform>`. Closures with
+/// the right signature implement this trait. When no transform is managed, the result is used
+/// as is.
+///
+/// ```rust,ignore
+/// let transform = |mut result: AuthenticationResult| -> Result<_, rowdy::Error> {
+///     result.subject = format!("user:{}", result.subject);
+///     Ok(result)
+/// };
+/// let transform: Box<ClaimsTransform> = Box::new(transform);
+/// let rocket = rowdy::rocket(config)?.manage(transform);
+/// ```
+pub trait ClaimsTransform: Send + Sync {
+    /// Transform the result of an authentication before a token is issued with it
+    fn transform(&self, result: AuthenticationResult) -> Result<AuthenticationResult, ::Error>;
+}
+
+impl<F> ClaimsTransform for F
+where
+    F: Fn(AuthenticationResult) -> Result<AuthenticationResult, ::Error> + Send + Sync,
+{
+    fn transform(&self, result: AuthenticationResult) -> Result<AuthenticationResult, ::Error> {
+        self(result)
+    }
+}
+
 #[cfg(test)]
 pub mod tests {
     #[allow(deprecated)]
@@ -344,6 +751,7 @@ pub mod tests {
     use rocket::{self, Rocket, State};
     use rocket::http;
     use rocket::local::Client;
+    use std::cell::RefCell;
 
     use {Error, JsonMap};
     use super::*;
@@ -355,6 +763,17 @@ pub mod tests {
     /// - String: 哦，对不起啦。
     pub struct MockAuthenticator {}
 
+    thread_local! {
+        /// Usernames whose sessions the `MockAuthenticator` was asked to invalidate on this thread
+        static INVALIDATED_SESSIONS: RefCell<Vec<String>> = RefCell::new(vec![]);
+    }
+
+    /// Returns the usernames whose sessions the `MockAuthenticator` was asked to invalidate on the
+    /// current thread, in order. Local clients dispatch requests on the thread of the test.
+    pub fn invalidated_sessions() -> Vec<String> {
+        INVALIDATED_SESSIONS.with(|invalidated| invalidated.borrow().clone())
+    }
+
     /// Payload for the `MockAuthenticator` Refresh Token
     #[derive(Serialize, Deserialize, Debug)]
     struct RefreshTokenPayload {
@@ -405,9 +824,9 @@ pub mod tests {
             include_refresh_payload: bool,
         ) -> Result<AuthenticationResult, Error> {
             let username = authorization.username();
-            let password = authorization.password().unwrap_or_else(|| "".to_string());
+            let password = authorization.password().unwrap_or_default();
 
-            if username == "mei" && password == "冻住，不许走!" {
+            if !password.is_empty() && username == "mei" && password == "冻住，不许走!" {
                 let refresh_payload = if include_refresh_payload {
                     Some(Self::serialize_refresh_token_payload(authorization))
                 } else {
@@ -431,6 +850,17 @@ pub mod tests {
                 Self::deserialize_refresh_token_payload(refresh_payload);
             self.authenticate(&Authorization(header), false)
         }
+
+        fn invalidate_sessions(&self, username: &str) -> Result<(), ::Error> {
+            INVALIDATED_SESSIONS.with(|invalidated| {
+                invalidated.borrow_mut().push(username.to_string())
+            });
+            if username == "mei" {
+                Ok(())
+            } else {
+                Err(super::Error::AuthenticationFailure)?
+            }
+        }
     }
 
     impl Authenticator<Bearer> for MockAuthenticator {
@@ -761,6 +1191,6 @@ pub mod tests {
         assert_eq!(response.status(), Status::Unauthorized);
 
         let www_header: Vec<_> = response.headers().get("WWW-Authenticate").collect();
-        assert_eq!(www_header, vec!["Basic realm=https://www.acme.com"]);
+        assert_eq!(www_header, vec![r#"Basic realm="https://www.acme.com""#]);
     }
 }
@@ -103,7 +103,7 @@ impl SimpleAuthenticator {
     }
 
     /// Verify that some user with the provided password exists in the CSV database,
-    /// and the password is correct.
+    /// and the password is correct. Empty passwords are rejected.
     ///
     /// Returns the payload to be included in a refresh token if successful
     pub fn verify(
@@ -112,6 +112,11 @@ impl SimpleAuthenticator {
         password: &str,
         include_refresh_payload: bool,
     ) -> Result<AuthenticationResult, Error> {
+        if password.is_empty() {
+            debug_!("Empty password provided");
+            Err(super::Error::AuthenticationFailure)?;
+        }
+
         match self.users.get(username) {
             None => Err(Error::Auth(super::Error::AuthenticationFailure)),
             Some(&(ref hash, ref salt)) => {
@@ -147,7 +152,8 @@ impl super::Authenticator<Basic> for SimpleAuthenticator {
     ) -> Result<AuthenticationResult, Error> {
         warn_!("Do not use the Simple authenticator in production");
         let username = authorization.username();
-        let password = authorization.password().unwrap_or_else(|| "".to_string());
+        // A missing password is rejected by `verify`, like an empty one
+        let password = authorization.password().unwrap_or_default();
         self.verify(&username, &password, include_refresh_payload)
     }
 
@@ -331,6 +337,26 @@ mod tests {
         assert!(result.refresh_payload.is_none());
     }
 
+    #[test]
+    fn authentication_with_empty_or_missing_password_fails() {
+        let authenticator = make_authenticator();
+
+        match authenticator.verify("foobar", "", false) {
+            Err(Error::Auth(::auth::Error::AuthenticationFailure)) => {}
+            Err(e) => panic!("Unexpected error {:?}", e),
+            Ok(_) => panic!("Empty password should be rejected"),
+        }
+
+        for header in &["Basic Zm9vYmFy", "Basic Zm9vYmFyOg=="] {
+            let authorization = not_err!(::auth::Authorization::<Basic>::new(header));
+            match authenticator.authenticate(&authorization, false) {
+                Err(Error::Auth(::auth::Error::AuthenticationFailure)) => {}
+                Err(e) => panic!("Unexpected error {:?}", e),
+                Ok(_) => panic!("Missing password should be rejected"),
+            }
+        }
+    }
+
     #[test]
     fn authentication_with_refresh_payload() {
         let authenticator = make_authenticator();
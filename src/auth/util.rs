@@ -4,7 +4,15 @@
 //! salt to produce a hash with [Argon2i](https://en.wikipedia.org/wiki/Argon2).
 //!
 //! The hash produced will be 32 bytes long.
+//!
+//! Hashes can also be encoded in the [PHC string format](
+//! https://github.com/P-H-C/phc-string-format/blob/master/phc-sf-spec.md) with [`PhcHash`], which
+//! keeps the parameters and salt of the hash alongside it, as in
+//! `$argon2i$v=19$m=4096,t=3,p=1$c2FsdHNhbHQ$aGFzaA`.
+use std::str;
+
 use argon2rs;
+use data_encoding;
 use jwt::jwa::{self, SecureRandom};
 
 static CHARS: &'static [u8] = b"0123456789abcdef";
@@ -20,6 +28,172 @@ pub fn hash_password_digest(password: &str, salt: &[u8]) -> Vec<u8> {
     out
 }
 
+/// Returns whether a stored hash is in the PHC string format of an argon2 hash
+pub fn is_phc_string(stored: &[u8]) -> bool {
+    stored.starts_with(b"$argon2")
+}
+
+/// An argon2 hash in the [PHC string format](
+/// https://github.com/P-H-C/phc-string-format/blob/master/phc-sf-spec.md), with the parameters
+/// and salt that it was computed with
+///
+/// Only version 19 (`0x13`) of argon2, and the `argon2i` and `argon2d` variants are supported.
+/// The version segment (`v=19`) may be left out, in which case version 19 is assumed. The salt
+/// and hash are encoded in base64 without padding.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct PhcHash {
+    /// Whether the variant is `argon2d`, rather than `argon2i`
+    pub data_dependent: bool,
+    /// The memory cost, in KiB (`m`)
+    pub memory_cost: u32,
+    /// The number of passes (`t`)
+    pub time_cost: u32,
+    /// The degree of parallelism (`p`)
+    pub parallelism: u32,
+    /// The salt
+    pub salt: Vec<u8>,
+    /// The hash
+    pub hash: Vec<u8>,
+}
+
+/// The version of argon2 implemented by `argon2rs`
+const ARGON2_VERSION: &'static str = "19";
+
+impl PhcHash {
+    /// Hash a password with the salt, with the same parameters as `hash_password_digest`
+    ///
+    /// Note that a salt between 8 and 2^32-1 bytes must be provided.
+    pub fn new(password: &str, salt: &[u8]) -> Self {
+        PhcHash {
+            data_dependent: false,
+            memory_cost: argon2rs::defaults::KIB,
+            time_cost: argon2rs::defaults::PASSES,
+            parallelism: argon2rs::defaults::LANES,
+            salt: salt.to_vec(),
+            hash: hash_password_digest(password, salt),
+        }
+    }
+
+    /// Parse a hash in the PHC string format. Surrounding whitespace, such as the padding of
+    /// fixed-width columns, is ignored.
+    pub fn parse(encoded: &[u8]) -> Result<Self, String> {
+        let encoded = str::from_utf8(encoded)
+            .map_err(|e| format!("PHC string is not valid UTF-8: {}", e))?
+            .trim();
+        let mut fields: Vec<&str> = encoded.split('$').collect();
+        let versioned = fields.len() != 5 || fields[2].starts_with("v=");
+        if !versioned {
+            fields.insert(2, "");
+        }
+        if fields.len() != 6 || !fields[0].is_empty() {
+            Err(format!("Malformed PHC string: {}", encoded))?;
+        }
+
+        let data_dependent = match fields[1] {
+            "argon2i" => false,
+            "argon2d" => true,
+            "argon2id" => Err(
+                "argon2id hashes are not supported, only argon2i and argon2d".to_string(),
+            )?,
+            variant => Err(format!(
+                "Unsupported hash algorithm {}, only argon2i and argon2d are supported",
+                variant
+            ))?,
+        };
+        if versioned && fields[2] != format!("v={}", ARGON2_VERSION) {
+            Err(format!(
+                "Unsupported argon2 version {}, only v={} is supported",
+                fields[2], ARGON2_VERSION
+            ))?;
+        }
+
+        let (mut memory_cost, mut time_cost, mut parallelism) = (None, None, None);
+        for parameter in fields[3].split(',') {
+            let mut parameter = parameter.splitn(2, '=');
+            let (name, value) = match (parameter.next(), parameter.next()) {
+                (Some(name), Some(value)) => (name, value),
+                _ => Err(format!("Malformed argon2 parameters {}", fields[3]))?,
+            };
+            let value = value
+                .parse::<u32>()
+                .map_err(|e| format!("Invalid argon2 parameter {}: {}", name, e))?;
+            match name {
+                "m" => memory_cost = Some(value),
+                "t" => time_cost = Some(value),
+                "p" => parallelism = Some(value),
+                name => Err(format!("Unsupported argon2 parameter {}", name))?,
+            }
+        }
+
+        let salt = data_encoding::base64::decode_nopad(fields[4].as_bytes())
+            .map_err(|e| format!("Invalid salt in PHC string: {}", e))?;
+        let hash = data_encoding::base64::decode_nopad(fields[5].as_bytes())
+            .map_err(|e| format!("Invalid hash in PHC string: {}", e))?;
+        if salt.len() < 8 || hash.len() < 4 {
+            Err("The salt or hash of the PHC string is too short".to_string())?;
+        }
+
+        match (memory_cost, time_cost, parallelism) {
+            (Some(memory_cost), Some(time_cost), Some(parallelism)) => Ok(PhcHash {
+                data_dependent,
+                memory_cost,
+                time_cost,
+                parallelism,
+                salt,
+                hash,
+            }),
+            _ => Err(format!("Missing argon2 parameters in {}", fields[3])),
+        }
+    }
+
+    /// Encode the hash in the PHC string format
+    pub fn encode(&self) -> String {
+        format!(
+            "${}$v={}$m={},t={},p={}${}${}",
+            self.variant_name(),
+            ARGON2_VERSION,
+            self.memory_cost,
+            self.time_cost,
+            self.parallelism,
+            data_encoding::base64::encode_nopad(&self.salt),
+            data_encoding::base64::encode_nopad(&self.hash)
+        )
+    }
+
+    /// Hash the password with the parameters and salt, and return the hash, which has the same
+    /// length as the stored one. Fails if the parameters are out of the range supported by
+    /// argon2.
+    pub fn hash_password(&self, password: &str) -> Result<Vec<u8>, String> {
+        let variant = if self.data_dependent {
+            argon2rs::Variant::Argon2d
+        } else {
+            argon2rs::Variant::Argon2i
+        };
+        let argon2 =
+            argon2rs::Argon2::new(self.time_cost, self.parallelism, self.memory_cost, variant)
+                .map_err(|e| format!("Invalid argon2 parameters: {:?}", e))?;
+        let mut out = vec![0; self.hash.len()];
+        argon2.hash(&mut out, password.as_bytes(), &self.salt, &[], &[]);
+        Ok(out)
+    }
+
+    /// Returns whether the hash was computed with weaker parameters than those used by `new`, or
+    /// with the `argon2d` variant, so that it should be upgraded by hashing the password again
+    pub fn needs_rehash(&self) -> bool {
+        self.data_dependent || self.memory_cost < argon2rs::defaults::KIB
+            || self.time_cost < argon2rs::defaults::PASSES
+            || self.hash.len() < argon2rs::defaults::LENGTH
+    }
+
+    fn variant_name(&self) -> &'static str {
+        if self.data_dependent {
+            "argon2d"
+        } else {
+            "argon2i"
+        }
+    }
+}
+
 /// Generate a new random salt based on the configured salt length
 ///
 /// For argon2i, you should use a salt between 8 and 2^32-1 bytes
@@ -42,3 +216,49 @@ pub fn hex_dump(bytes: &[u8]) -> String {
 
     unsafe { String::from_utf8_unchecked(v) }
 }
+
+/// Encoding of password hashes represented as text, such as hashes stored in the text columns of
+/// legacy databases
+#[derive(Clone, Copy, Eq, PartialEq, Serialize, Deserialize, Debug)]
+pub enum HashEncoding {
+    /// Lowercase hexadecimal, as produced by `hex_dump`. Uppercase is accepted when decoding.
+    #[serde(rename = "hex")]
+    Hex,
+    /// Standard base64, with padding
+    #[serde(rename = "base64")]
+    Base64,
+    /// URL-safe base64, with padding
+    #[serde(rename = "base64url")]
+    Base64Url,
+}
+
+impl HashEncoding {
+    /// Encode the bytes of a hash
+    pub fn encode(&self, bytes: &[u8]) -> String {
+        match *self {
+            HashEncoding::Hex => hex_dump(bytes),
+            HashEncoding::Base64 => data_encoding::base64::encode(bytes),
+            HashEncoding::Base64Url => data_encoding::base64url::encode(bytes),
+        }
+    }
+
+    /// Decode an encoded hash. Surrounding whitespace, such as the padding of fixed-width
+    /// columns, is ignored.
+    pub fn decode(&self, encoded: &[u8]) -> Result<Vec<u8>, String> {
+        let encoded = str::from_utf8(encoded)
+            .map_err(|e| format!("Encoded hash is not valid UTF-8: {}", e))?
+            .trim();
+        let decoded = match *self {
+            HashEncoding::Hex => data_encoding::hex::decode(encoded.to_uppercase().as_bytes()),
+            HashEncoding::Base64 => data_encoding::base64::decode(encoded.as_bytes()),
+            HashEncoding::Base64Url => data_encoding::base64url::decode(encoded.as_bytes()),
+        };
+        decoded.map_err(|e| format!("Invalid {:?} encoded hash: {}", self, e))
+    }
+}
+
+impl Default for HashEncoding {
+    fn default() -> Self {
+        HashEncoding::Hex
+    }
+}
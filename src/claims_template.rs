@@ -0,0 +1,316 @@
+//! Templates of private claims
+//!
+//! A [`ClaimsTemplate`] is a JSON object whose string values may contain placeholders, like
+//! `{{subject}}` or `{{claims.roles}}`. The template is rendered against the
+//! [`AuthenticationResult`](::auth::AuthenticationResult) of each authentication to produce the
+//! private claims of the token, so that the shape of the claims can be changed without code
+//! changes.
+//!
+//! The following placeholders are supported:
+//!
+//! - `{{subject}}`, or its alias `{{username}}`: the subject of the authentication
+//! - `{{claims.<name>}}`: the private claim `<name>` returned by the authenticator
+//!
+//! A string that consists of a single placeholder is replaced by the value it refers to, which
+//! can be of any JSON type. Placeholders embedded in a longer string are replaced by the value as
+//! a string.
+//!
+//! The template is parsed when the configuration is loaded, and placeholders that are malformed,
+//! or that refer to fields that the authentication result does not have, are errors. Private
+//! claims that are missing from an authentication result are rendered as `null`, or as an empty
+//! string when embedded, with a warning.
+//!
+//! # Examples
+//! ```json
+//! {
+//!     "user": "{{username}}",
+//!     "roles": "{{claims.roles}}",
+//!     "profile": "https://www.example.com/users/{{subject}}"
+//! }
+//! ```
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde::de;
+
+use {Error, JsonMap, JsonValue};
+use auth::AuthenticationResult;
+
+/// Prefix of placeholders that refer to the private claims returned by the authenticator
+const CLAIMS_PREFIX: &str = "claims.";
+
+/// A parsed template of private claims. See the [module documentation](index.html).
+///
+/// The template (de)serializes as the JSON object it was parsed from, and is parsed when it is
+/// deserialized.
+#[derive(Clone, Debug)]
+pub struct ClaimsTemplate {
+    /// The object the template was parsed from
+    source: JsonMap,
+    /// The parsed template
+    template: Template,
+}
+
+impl ClaimsTemplate {
+    /// Parse a template from a JSON object
+    pub fn parse(source: JsonMap) -> Result<Self, Error> {
+        let template = Template::parse(&JsonValue::Object(source.clone()))?;
+        Ok(ClaimsTemplate {
+            source: source,
+            template: template,
+        })
+    }
+
+    /// Render the template against the result of an authentication, into private claims
+    pub fn render(&self, result: &AuthenticationResult) -> JsonValue {
+        self.template.render(result)
+    }
+}
+
+impl PartialEq for ClaimsTemplate {
+    fn eq(&self, other: &Self) -> bool {
+        self.source == other.source
+    }
+}
+
+impl Serialize for ClaimsTemplate {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        self.source.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for ClaimsTemplate {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let source = JsonMap::deserialize(deserializer)?;
+        ClaimsTemplate::parse(source).map_err(de::Error::custom)
+    }
+}
+
+/// A node of a parsed template
+#[derive(Clone, Debug)]
+enum Template {
+    /// An object whose values are templates
+    Object(Vec<(String, Template)>),
+    /// An array whose elements are templates
+    Array(Vec<Template>),
+    /// A value without placeholders
+    Literal(JsonValue),
+    /// A string that consists of a single placeholder, which is replaced by the value of the field
+    Placeholder(Field),
+    /// A string with embedded placeholders
+    Interpolated(Vec<Segment>),
+}
+
+impl Template {
+    fn parse(value: &JsonValue) -> Result<Self, Error> {
+        match *value {
+            JsonValue::Object(ref map) => {
+                let entries = map.iter()
+                    .map(|(key, value)| Ok((key.clone(), Template::parse(value)?)))
+                    .collect::<Result<_, Error>>()?;
+                Ok(Template::Object(entries))
+            }
+            JsonValue::Array(ref values) => {
+                let values = values
+                    .iter()
+                    .map(Template::parse)
+                    .collect::<Result<_, Error>>()?;
+                Ok(Template::Array(values))
+            }
+            JsonValue::String(ref string) => Template::parse_string(string),
+            ref value => Ok(Template::Literal(value.clone())),
+        }
+    }
+
+    fn parse_string(string: &str) -> Result<Self, Error> {
+        let mut segments = vec![];
+        let mut rest = string;
+        while let Some(start) = rest.find("{{") {
+            if start > 0 {
+                segments.push(Segment::Text(rest[..start].to_string()));
+            }
+            let placeholder = &rest[start + 2..];
+            let end = placeholder.find("}}").ok_or_else(|| {
+                Error::GenericError(format!(
+                    "The claims template has an unclosed placeholder in `{}`",
+                    string
+                ))
+            })?;
+            segments.push(Segment::Field(Field::parse(&placeholder[..end])?));
+            rest = &placeholder[end + 2..];
+        }
+
+        if segments.is_empty() {
+            return Ok(Template::Literal(JsonValue::String(string.to_string())));
+        }
+        if !rest.is_empty() {
+            segments.push(Segment::Text(rest.to_string()));
+        }
+        if segments.len() == 1 {
+            if let Segment::Field(ref field) = segments[0] {
+                return Ok(Template::Placeholder(field.clone()));
+            }
+        }
+        Ok(Template::Interpolated(segments))
+    }
+
+    fn render(&self, result: &AuthenticationResult) -> JsonValue {
+        match *self {
+            Template::Object(ref entries) => JsonValue::Object(
+                entries
+                    .iter()
+                    .map(|&(ref key, ref template)| (key.clone(), template.render(result)))
+                    .collect(),
+            ),
+            Template::Array(ref templates) => JsonValue::Array(
+                templates
+                    .iter()
+                    .map(|template| template.render(result))
+                    .collect(),
+            ),
+            Template::Literal(ref value) => value.clone(),
+            Template::Placeholder(ref field) => field.resolve(result).unwrap_or(JsonValue::Null),
+            Template::Interpolated(ref segments) => {
+                let mut rendered = String::new();
+                for segment in segments {
+                    match *segment {
+                        Segment::Text(ref text) => rendered.push_str(text),
+                        Segment::Field(ref field) => match field.resolve(result) {
+                            Some(JsonValue::String(ref string)) => rendered.push_str(string),
+                            Some(value) => rendered.push_str(&value.to_string()),
+                            None => {}
+                        },
+                    }
+                }
+                JsonValue::String(rendered)
+            }
+        }
+    }
+}
+
+/// A part of a string with embedded placeholders
+#[derive(Clone, Debug)]
+enum Segment {
+    Text(String),
+    Field(Field),
+}
+
+/// A field of the authentication result referred to by a placeholder
+#[derive(Clone, Eq, PartialEq, Debug)]
+enum Field {
+    /// The subject of the authentication
+    Subject,
+    /// A private claim returned by the authenticator
+    Claim(String),
+}
+
+impl Field {
+    fn parse(placeholder: &str) -> Result<Self, Error> {
+        let name = placeholder.trim();
+        match name {
+            "subject" | "username" => Ok(Field::Subject),
+            _ if name.starts_with(CLAIMS_PREFIX) && name.len() > CLAIMS_PREFIX.len() => {
+                Ok(Field::Claim(name[CLAIMS_PREFIX.len()..].to_string()))
+            }
+            _ => Err(Error::GenericError(format!(
+                "The placeholder `{{{{{}}}}}` in the claims template does not refer to a field of \
+                 the authentication result. Use `{{{{subject}}}}`, or `{{{{claims.<name>}}}}` for \
+                 the private claims returned by the authenticator",
+                placeholder
+            ))),
+        }
+    }
+
+    /// Resolve the value of the field, warning if it is missing
+    fn resolve(&self, result: &AuthenticationResult) -> Option<JsonValue> {
+        match *self {
+            Field::Subject => Some(JsonValue::String(result.subject.clone())),
+            Field::Claim(ref name) => {
+                let value = result.private_claims.get(name).cloned();
+                if value.is_none() {
+                    warn_!(
+                        "The private claim `{}` referred to by the claims template is missing \
+                         for `{}`, and was rendered empty",
+                        name,
+                        result.subject
+                    );
+                }
+                value
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json;
+
+    use super::*;
+
+    fn template(json: &str) -> Result<ClaimsTemplate, serde_json::Error> {
+        serde_json::from_str(json)
+    }
+
+    fn result() -> AuthenticationResult {
+        AuthenticationResult {
+            subject: "mei".to_string(),
+            private_claims: not_err!(serde_json::from_str(
+                r#"{ "roles": ["admin", "user"], "department": "weather" }"#
+            )),
+            refresh_payload: None,
+        }
+    }
+
+    #[test]
+    fn placeholders_are_rendered() {
+        let template = not_err!(template(
+            r#"{
+                "user": "{{username}}",
+                "roles": "{{ claims.roles }}",
+                "profile": "https://www.example.com/{{claims.department}}/{{subject}}",
+                "nested": { "tags": ["{{subject}}", 42] },
+                "constant": true
+            }"#
+        ));
+        let expected: JsonValue = not_err!(serde_json::from_str(
+            r#"{
+                "user": "mei",
+                "roles": ["admin", "user"],
+                "profile": "https://www.example.com/weather/mei",
+                "nested": { "tags": ["mei", 42] },
+                "constant": true
+            }"#
+        ));
+        assert_eq!(template.render(&result()), expected);
+    }
+
+    #[test]
+    fn missing_claims_are_rendered_empty() {
+        let template = not_err!(template(
+            r#"{ "team": "{{claims.team}}", "label": "team: {{claims.team}}" }"#
+        ));
+        let expected: JsonValue =
+            not_err!(serde_json::from_str(r#"{ "team": null, "label": "team: " }"#));
+        assert_eq!(template.render(&result()), expected);
+    }
+
+    #[test]
+    fn invalid_placeholders_are_rejected_at_load() {
+        assert!(template(r#"{ "roles": "{{roles}}" }"#).is_err());
+        assert!(template(r#"{ "roles": "{{claims.}}" }"#).is_err());
+        assert!(template(r#"{ "user": "{{subject" }"#).is_err());
+        assert!(template(r#"{ "nested": ["{{password}}"] }"#).is_err());
+        assert!(template(r#"["{{subject}}"]"#).is_err());
+    }
+
+    #[test]
+    fn template_serializes_as_its_source() {
+        let json = r#"{"user":"{{username}}"}"#;
+        let template = not_err!(template(json));
+        assert_eq!(not_err!(serde_json::to_string(&template)), json);
+    }
+}
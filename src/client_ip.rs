@@ -0,0 +1,251 @@
+//! Client IP addresses of requests
+//!
+//! Rowdy is usually deployed behind a reverse proxy, so the peer of the connection is the proxy,
+//! and not the client. When `trust_proxy` is set in the token [`Configuration`], the client IP
+//! address is recovered from the `Forwarded` or `X-Forwarded-For` headers added by the proxy.
+//! Otherwise, these headers are ignored, because any client can send them.
+//!
+//! `trust_proxy` also governs whether the scheme of requests is recovered from the proxy headers
+//! by the [`https::RequireHttps`](::https::RequireHttps) guard.
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::ops::Deref;
+
+use rocket::{Outcome, Request};
+use rocket::request::{self, FromRequest};
+
+use reload::CurrentConfiguration;
+
+/// Name of the standard header carrying proxy information. See RFC 7239.
+pub const FORWARDED_HEADER: &'static str = "Forwarded";
+/// Name of the de-facto standard header carrying the addresses a request was forwarded for
+pub const X_FORWARDED_FOR_HEADER: &'static str = "X-Forwarded-For";
+
+/// The IP address of the client of a request. Can be used as a request guard in your own routes.
+///
+/// When proxies are trusted, the address is taken from the last element of the `Forwarded`
+/// header, or, if there is no `Forwarded` header, the last address of the `X-Forwarded-For`
+/// header. These are the entries appended by the proxy directly in front of rowdy, so clients
+/// cannot spoof them by sending the headers themselves. Otherwise, or if the headers are missing
+/// or cannot be parsed, the address of the peer of the connection is used. In particular, the
+/// `X-Forwarded-For` header is ignored if the `Forwarded` header is present, but its last element
+/// has no `for` parameter that can be parsed, since it might have been sent by the client.
+///
+/// The guard forwards if no address can be determined.
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub struct ClientIp(IpAddr);
+
+impl ClientIp {
+    /// Determine the client IP address of a request, trusting the proxy headers if `trust_proxy`
+    /// is set.
+    pub fn for_request(request: &Request, trust_proxy: bool) -> Option<Self> {
+        let forwarded = if trust_proxy {
+            forwarded_for(request)
+        } else {
+            None
+        };
+
+        forwarded
+            .or_else(|| request.remote().map(|remote| remote.ip()))
+            .map(ClientIp)
+    }
+}
+
+impl Deref for ClientIp {
+    type Target = IpAddr;
+
+    fn deref(&self) -> &IpAddr {
+        &self.0
+    }
+}
+
+impl fmt::Display for ClientIp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for ClientIp {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, ()> {
+        let trust_proxy = request
+            .guard::<CurrentConfiguration>()
+            .succeeded()
+            .map(|configuration| configuration.trust_proxy)
+            .unwrap_or(false);
+
+        match ClientIp::for_request(request, trust_proxy) {
+            Some(client_ip) => Outcome::Success(client_ip),
+            None => Outcome::Forward(()),
+        }
+    }
+}
+
+/// Retrieve the address added by the last proxy from the `Forwarded` header, or, if there is
+/// none, from the `X-Forwarded-For` header
+fn forwarded_for(request: &Request) -> Option<IpAddr> {
+    let headers = request.headers();
+
+    match last_element(headers.get(FORWARDED_HEADER)) {
+        Some(element) => element
+            .split(';')
+            .filter_map(|pair| {
+                let mut pair = pair.splitn(2, '=');
+                match (pair.next(), pair.next()) {
+                    (Some(name), Some(value)) if name.trim().to_lowercase() == "for" => {
+                        Some(value)
+                    }
+                    _ => None,
+                }
+            })
+            .next()
+            .and_then(parse_node),
+        None => last_element(headers.get(X_FORWARDED_FOR_HEADER)).and_then(parse_node),
+    }
+}
+
+/// Return the last element of comma-separated header values, which may be split across several
+/// instances of the header
+pub(crate) fn last_element<'h, I: Iterator<Item = &'h str>>(values: I) -> Option<&'h str> {
+    values
+        .flat_map(|value| value.split(','))
+        .last()
+        .map(|element| element.trim())
+}
+
+/// Parse a node, which is an IP address, optionally quoted, bracketed, and with a port
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+    if let Ok(ip) = node.parse::<IpAddr>() {
+        return Some(ip);
+    }
+    if let Ok(socket) = node.parse::<SocketAddr>() {
+        return Some(socket.ip());
+    }
+    node.trim_left_matches('[')
+        .trim_right_matches(']')
+        .parse::<IpAddr>()
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use rocket;
+    use rocket::http::Header;
+    use rocket::local::Client;
+
+    use super::*;
+    use token::Configuration;
+
+    #[get("/")]
+    fn echo(client_ip: ClientIp) -> String {
+        client_ip.to_string()
+    }
+
+    fn client(trust_proxy: bool) -> Client {
+        let mut configuration: Configuration = not_err!(::serde_json::from_str(
+            r#"{ "issuer": "https://www.acme.com", "allowed_origins": "All", "audience": [] }"#
+        ));
+        configuration.trust_proxy = trust_proxy;
+
+        let rocket = rocket::ignite()
+            .manage(configuration)
+            .mount("/", routes![echo]);
+        not_err!(Client::new(rocket))
+    }
+
+    fn get(client: &Client, headers: &[(&'static str, &'static str)]) -> String {
+        let mut request = client
+            .get("/")
+            .remote(not_err!(SocketAddr::from_str("192.0.2.1:8000")));
+        for &(name, value) in headers {
+            request = request.header(Header::new(name, value));
+        }
+        let mut response = request.dispatch();
+        not_none!(response.body().and_then(|body| body.into_string()))
+    }
+
+    #[test]
+    fn nodes_are_parsed() {
+        let expected_v4 = not_err!(IpAddr::from_str("198.51.100.17"));
+        let expected_v6 = not_err!(IpAddr::from_str("2001:db8:cafe::17"));
+
+        assert_eq!(Some(expected_v4), parse_node("198.51.100.17"));
+        assert_eq!(Some(expected_v4), parse_node(" \"198.51.100.17:4711\" "));
+        assert_eq!(Some(expected_v6), parse_node("2001:db8:cafe::17"));
+        assert_eq!(Some(expected_v6), parse_node("\"[2001:db8:cafe::17]\""));
+        assert_eq!(Some(expected_v6), parse_node("\"[2001:db8:cafe::17]:4711\""));
+        assert_eq!(None, parse_node("unknown"));
+        assert_eq!(None, parse_node("_hidden"));
+    }
+
+    #[test]
+    fn remote_address_is_used_by_default() {
+        let client = client(false);
+        let body = get(
+            &client,
+            &[
+                (X_FORWARDED_FOR_HEADER, "203.0.113.43"),
+                (FORWARDED_HEADER, "for=203.0.113.43"),
+            ],
+        );
+        assert_eq!("192.0.2.1", body);
+    }
+
+    #[test]
+    fn forwarded_header_is_trusted_when_configured() {
+        let client = client(true);
+        let body = get(
+            &client,
+            &[
+                (FORWARDED_HEADER, "for=198.51.100.1, for=\"[2001:db8:cafe::17]:4711\";proto=http"),
+                (X_FORWARDED_FOR_HEADER, "203.0.113.43"),
+            ],
+        );
+        assert_eq!("2001:db8:cafe::17", body);
+    }
+
+    #[test]
+    fn x_forwarded_for_header_is_trusted_when_configured() {
+        let client = client(true);
+        let body = get(
+            &client,
+            &[(X_FORWARDED_FOR_HEADER, "198.51.100.1, 203.0.113.43")],
+        );
+        assert_eq!("203.0.113.43", body);
+    }
+
+    #[test]
+    fn unparseable_headers_fall_back_to_remote_address() {
+        let client = client(true);
+        let body = get(
+            &client,
+            &[
+                (FORWARDED_HEADER, "for=unknown"),
+                (X_FORWARDED_FOR_HEADER, "garbage"),
+            ],
+        );
+        assert_eq!("192.0.2.1", body);
+
+        let body = get(&client, &[(X_FORWARDED_FOR_HEADER, "garbage")]);
+        assert_eq!("192.0.2.1", body);
+    }
+
+    #[test]
+    fn x_forwarded_for_header_is_ignored_if_forwarded_header_cannot_be_parsed() {
+        let client = client(true);
+        for forwarded in &["for=unknown", "for=198.51.100.1, proto=https", "for=_hidden"] {
+            let body = get(
+                &client,
+                &[
+                    (FORWARDED_HEADER, *forwarded),
+                    (X_FORWARDED_FOR_HEADER, "203.0.113.43"),
+                ],
+            );
+            assert_eq!("192.0.2.1", body);
+        }
+    }
+}
@@ -0,0 +1,129 @@
+//! Sources of the current time
+//!
+//! Tokens are issued and validated with the time from the [`Clock`] of the token
+//! [`Configuration`](::token::Configuration), which is the system clock by default. Tests can
+//! replace it with a [`FixedClock`] to freeze time, and assert the exact `iat`, `nbf` and `exp` of
+//! tokens, or expire tokens without sleeping.
+//!
+//! ```rust,ignore
+//! let clock = Arc::new(FixedClock::new(issued_at));
+//! configuration.clock = ClockHandle::new(clock.clone());
+//! // ... issue a token
+//! clock.advance(Duration::days(2));
+//! // ... the token has now expired
+//! ```
+use std::fmt;
+use std::sync::{Arc, RwLock};
+
+use chrono::{DateTime, Duration, Utc};
+
+/// A source of the current time
+pub trait Clock: Send + Sync {
+    /// Returns the current time
+    fn now(&self) -> DateTime<Utc>;
+}
+
+impl<T: Clock + ?Sized> Clock for Arc<T> {
+    fn now(&self) -> DateTime<Utc> {
+        (**self).now()
+    }
+}
+
+/// The system clock
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+/// A clock that stands still until it is set or advanced. Share it with an `Arc` to move the time
+/// of a configuration that it has been placed in.
+#[derive(Debug)]
+pub struct FixedClock {
+    time: RwLock<DateTime<Utc>>,
+}
+
+impl FixedClock {
+    /// Create a clock frozen at `time`
+    pub fn new(time: DateTime<Utc>) -> Self {
+        FixedClock {
+            time: RwLock::new(time),
+        }
+    }
+
+    /// Set the time of the clock
+    pub fn set(&self, time: DateTime<Utc>) {
+        let mut current = match self.time.write() {
+            Ok(current) => current,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        *current = time;
+    }
+
+    /// Move the time of the clock by `duration`, which can be negative
+    pub fn advance(&self, duration: Duration) {
+        let time = self.now() + duration;
+        self.set(time);
+    }
+}
+
+impl Clock for FixedClock {
+    fn now(&self) -> DateTime<Utc> {
+        match self.time.read() {
+            Ok(time) => *time,
+            Err(poisoned) => *poisoned.into_inner(),
+        }
+    }
+}
+
+/// A shareable handle to a `Clock`, to place in configurations. Defaults to the `SystemClock`.
+#[derive(Clone)]
+pub struct ClockHandle(Arc<Clock>);
+
+impl ClockHandle {
+    /// Create a handle to the clock
+    pub fn new<C: Clock + 'static>(clock: C) -> Self {
+        ClockHandle(Arc::new(clock))
+    }
+
+    /// Returns the current time of the clock
+    pub fn now(&self) -> DateTime<Utc> {
+        self.0.now()
+    }
+}
+
+impl Default for ClockHandle {
+    fn default() -> Self {
+        ClockHandle::new(SystemClock)
+    }
+}
+
+impl fmt::Debug for ClockHandle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ClockHandle({})", self.now().to_rfc3339())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    #[test]
+    fn fixed_clocks_are_set_and_advanced() {
+        let time = Utc.ymd(2017, 10, 11).and_hms(12, 0, 0);
+        let clock = Arc::new(FixedClock::new(time));
+        let handle = ClockHandle::new(clock.clone());
+        assert_eq!(handle.now(), time);
+
+        clock.advance(Duration::seconds(90));
+        assert_eq!(handle.clone().now(), time + Duration::seconds(90));
+
+        clock.set(time);
+        assert_eq!(handle.now(), time);
+    }
+}
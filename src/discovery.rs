@@ -0,0 +1,133 @@
+//! Server metadata for discovery
+//!
+//! The routes provided by rowdy include `GET /.well-known/openid-configuration`, which responds
+//! with a discovery document describing the server, in the style of
+//! [OpenID Connect Discovery](https://openid.net/specs/openid-connect-discovery-1_0.html) and
+//! [RFC 8414](https://tools.ietf.org/html/rfc8414). Clients and resource servers can use it to
+//! find the token and introspection endpoints, and the algorithm that tokens are signed with.
+//!
+//! Rowdy does not issue ID tokens, and does not serve its keys as a JWK set, so the fields of the
+//! specifications about them, such as `jwks_uri`, are omitted.
+//!
+//! The document is served to any origin, without credentials.
+//!
+//! # Examples
+//! ```json
+//! {
+//!     "issuer": "https://www.acme.com",
+//!     "token_endpoint": "https://www.acme.com/",
+//!     "introspection_endpoint": "https://www.acme.com/introspect",
+//!     "token_endpoint_auth_methods_supported": ["client_secret_basic"],
+//!     "grant_types_supported": ["client_credentials", "refresh_token"],
+//!     "access_token_signing_alg_values_supported": ["RS256"]
+//! }
+//! ```
+use hyper::Url;
+use jwt::jwa::SignatureAlgorithm;
+
+use join_path;
+use token::Configuration;
+
+/// Path of the discovery document, relative to the base path of the routes
+pub const DISCOVERY_PATH: &str = ".well-known/openid-configuration";
+
+/// The discovery document of the server
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct ServerMetadata {
+    /// The issuer of tokens
+    pub issuer: String,
+    /// URL of the route that issues tokens
+    pub token_endpoint: String,
+    /// URL of the route that introspects tokens
+    pub introspection_endpoint: String,
+    /// How clients authenticate to the token endpoint. Rowdy only supports HTTP Basic
+    /// authentication.
+    pub token_endpoint_auth_methods_supported: Vec<String>,
+    /// The OAuth2 grant types supported in addition to rowdy's own flow. `refresh_token` is only
+    /// listed if refresh tokens are enabled.
+    pub grant_types_supported: Vec<String>,
+    /// The algorithm that access tokens are signed with. This field is specific to rowdy.
+    pub access_token_signing_alg_values_supported: Vec<SignatureAlgorithm>,
+}
+
+impl ServerMetadata {
+    /// Describe a server with the token configuration, whose routes are mounted under
+    /// `base_path`.
+    ///
+    /// If the issuer is a URL, the endpoints are absolute URLs on the host of the issuer.
+    /// Otherwise, they are paths.
+    pub fn new(configuration: &Configuration, base_path: &str) -> Self {
+        let issuer = configuration.issuer.to_string();
+        let endpoint = |segment: &str| {
+            let path = join_path(base_path, segment);
+            let url = Url::parse(&issuer).and_then(|issuer| issuer.join(&path));
+            match url {
+                Ok(url) => url.to_string(),
+                Err(_) => path,
+            }
+        };
+
+        let mut grant_types_supported = vec!["client_credentials".to_string()];
+        if configuration.refresh_token_enabled() {
+            grant_types_supported.push("refresh_token".to_string());
+        }
+
+        ServerMetadata {
+            token_endpoint: endpoint(""),
+            introspection_endpoint: endpoint("introspect"),
+            issuer: issuer.clone(),
+            token_endpoint_auth_methods_supported: vec!["client_secret_basic".to_string()],
+            grant_types_supported: grant_types_supported,
+            access_token_signing_alg_values_supported: vec![
+                configuration
+                    .signature_algorithm
+                    .unwrap_or(SignatureAlgorithm::None),
+            ],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use serde_json;
+
+    use super::*;
+
+    fn configuration(issuer: &str) -> Configuration {
+        let mut configuration: Configuration = not_err!(serde_json::from_str(
+            r#"{
+                "issuer": "https://www.acme.com",
+                "allowed_origins": "All",
+                "audience": [],
+                "signature_algorithm": "RS256"
+            }"#
+        ));
+        configuration.issuer = not_err!(FromStr::from_str(issuer));
+        configuration
+    }
+
+    #[test]
+    fn endpoints_are_urls_on_the_issuer() {
+        let metadata = ServerMetadata::new(&configuration("https://www.acme.com/"), "/auth");
+        assert_eq!(metadata.issuer, "https://www.acme.com/");
+        assert_eq!(metadata.token_endpoint, "https://www.acme.com/auth/");
+        assert_eq!(
+            metadata.introspection_endpoint,
+            "https://www.acme.com/auth/introspect"
+        );
+        assert_eq!(metadata.grant_types_supported, vec!["client_credentials"]);
+        assert_eq!(
+            metadata.access_token_signing_alg_values_supported,
+            vec![SignatureAlgorithm::RS256]
+        );
+    }
+
+    #[test]
+    fn endpoints_are_paths_unless_the_issuer_is_a_url() {
+        let metadata = ServerMetadata::new(&configuration("acme"), "/");
+        assert_eq!(metadata.token_endpoint, "/");
+        assert_eq!(metadata.introspection_endpoint, "/introspect");
+    }
+}
@@ -0,0 +1,376 @@
+//! Fetch metadata request headers
+//!
+//! Modern browsers describe the context of each request with the `Sec-Fetch-*` headers. Unlike
+//! `Origin`, scripts cannot set these headers, so they can be used to reject cross-site requests
+//! that a browser made on behalf of another site. See the
+//! [specification](https://w3c.github.io/webappsec-fetch-metadata/).
+//!
+//! The [`SecFetchSite`] and [`SecFetchMode`] request guards can be used in your own routes. The
+//! routes provided by rowdy are protected by the [`FetchMetadataPolicy`] in the token
+//! `Configuration`, which is disabled by default.
+use std::fmt;
+use std::str::FromStr;
+
+use rocket::{Outcome, Request};
+use rocket::http::Status;
+use rocket::request::{self, FromRequest};
+
+use Error;
+
+/// Name of the header describing the relationship between the initiator and the target of a
+/// request
+pub const SEC_FETCH_SITE_HEADER: &'static str = "Sec-Fetch-Site";
+/// Name of the header describing the mode of a request
+pub const SEC_FETCH_MODE_HEADER: &'static str = "Sec-Fetch-Mode";
+
+/// The value of the `Sec-Fetch-Site` header.
+///
+/// As a request guard, this forwards if the header is missing, and fails with
+/// `400 Bad Request` if the header cannot be parsed. Use `Option<SecFetchSite>` to handle
+/// requests from clients that do not send the header.
+#[derive(Clone, Copy, Eq, PartialEq, Hash, Serialize, Deserialize, Debug)]
+pub enum SecFetchSite {
+    /// The request was initiated by the same origin as the target
+    #[serde(rename = "same-origin")]
+    SameOrigin,
+    /// The request was initiated by another origin of the same site as the target
+    #[serde(rename = "same-site")]
+    SameSite,
+    /// The request was initiated by another site
+    #[serde(rename = "cross-site")]
+    CrossSite,
+    /// The request was initiated by the user, such as by entering a URL
+    #[serde(rename = "none")]
+    None,
+}
+
+impl SecFetchSite {
+    fn as_str(&self) -> &'static str {
+        match *self {
+            SecFetchSite::SameOrigin => "same-origin",
+            SecFetchSite::SameSite => "same-site",
+            SecFetchSite::CrossSite => "cross-site",
+            SecFetchSite::None => "none",
+        }
+    }
+}
+
+impl FromStr for SecFetchSite {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s.trim() {
+            "same-origin" => Ok(SecFetchSite::SameOrigin),
+            "same-site" => Ok(SecFetchSite::SameSite),
+            "cross-site" => Ok(SecFetchSite::CrossSite),
+            "none" => Ok(SecFetchSite::None),
+            other => Err(Error::BadRequest(format!(
+                "Invalid {} header `{}`",
+                SEC_FETCH_SITE_HEADER,
+                other
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for SecFetchSite {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for SecFetchSite {
+    type Error = Error;
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Error> {
+        from_header(request, SEC_FETCH_SITE_HEADER)
+    }
+}
+
+/// The value of the `Sec-Fetch-Mode` header.
+///
+/// As a request guard, this forwards if the header is missing, and fails with
+/// `400 Bad Request` if the header cannot be parsed.
+#[derive(Clone, Copy, Eq, PartialEq, Hash, Serialize, Deserialize, Debug)]
+pub enum SecFetchMode {
+    /// A CORS request
+    #[serde(rename = "cors")]
+    Cors,
+    /// A navigation between documents
+    #[serde(rename = "navigate")]
+    Navigate,
+    /// A request whose response cannot be read by the initiator
+    #[serde(rename = "no-cors")]
+    NoCors,
+    /// A request that must be made to the same origin
+    #[serde(rename = "same-origin")]
+    SameOrigin,
+    /// A WebSocket connection
+    #[serde(rename = "websocket")]
+    Websocket,
+}
+
+impl SecFetchMode {
+    fn as_str(&self) -> &'static str {
+        match *self {
+            SecFetchMode::Cors => "cors",
+            SecFetchMode::Navigate => "navigate",
+            SecFetchMode::NoCors => "no-cors",
+            SecFetchMode::SameOrigin => "same-origin",
+            SecFetchMode::Websocket => "websocket",
+        }
+    }
+}
+
+impl FromStr for SecFetchMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s.trim() {
+            "cors" => Ok(SecFetchMode::Cors),
+            "navigate" => Ok(SecFetchMode::Navigate),
+            "no-cors" => Ok(SecFetchMode::NoCors),
+            "same-origin" => Ok(SecFetchMode::SameOrigin),
+            "websocket" => Ok(SecFetchMode::Websocket),
+            other => Err(Error::BadRequest(format!(
+                "Invalid {} header `{}`",
+                SEC_FETCH_MODE_HEADER,
+                other
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for SecFetchMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for SecFetchMode {
+    type Error = Error;
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Error> {
+        from_header(request, SEC_FETCH_MODE_HEADER)
+    }
+}
+
+/// Parse a header into a request guard, forwarding if it is missing
+fn from_header<T>(request: &Request, name: &str) -> request::Outcome<T, Error>
+where
+    T: FromStr<Err = Error>,
+{
+    match request.headers().get_one(name) {
+        Some(value) => match value.parse() {
+            Ok(parsed) => Outcome::Success(parsed),
+            Err(e) => Outcome::Failure((Status::BadRequest, e)),
+        },
+        None => Outcome::Forward(()),
+    }
+}
+
+/// Policy on the `Sec-Fetch-Site` and `Sec-Fetch-Mode` headers of requests to the routes provided
+/// by rowdy.
+///
+/// When `allowed_sites` is set, requests are rejected with `403 Forbidden` unless their
+/// `Sec-Fetch-Site` header is one of the allowed values. Likewise, when `allowed_modes` is set,
+/// requests are rejected unless their `Sec-Fetch-Mode` header is one of the allowed values. This
+/// stops a browser from making requests on behalf of another site, even if the `Origin` is
+/// allowed. The headers are checked whether or not the request carries an `Origin` header,
+/// because browsers leave it out of navigations and `no-cors` requests, such as those of images
+/// and forms. Add `none` to the `allowed_sites` to allow users to navigate to the routes
+/// themselves.
+///
+/// Non-browser clients, and older browsers, do not send `Sec-Fetch-*` headers. Requests without
+/// them are allowed by default, because the headers cannot be relied on to be present. Set
+/// `allow_missing` to `false` to reject CORS requests, which carry an `Origin` header, without
+/// them, if every client is a modern browser. Requests without an `Origin` header and without the
+/// headers are always allowed, since they are not distinguishable from those of non-browser
+/// clients.
+///
+/// # Examples
+/// ```json
+/// {
+///     "allowed_sites": ["same-origin", "same-site"],
+///     "allowed_modes": ["cors", "same-origin"],
+///     "allow_missing": true
+/// }
+/// ```
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct FetchMetadataPolicy {
+    /// The values of `Sec-Fetch-Site` allowed on CORS requests. Set to `null` to disable the
+    /// policy.
+    ///
+    /// Defaults to `null` when left unfilled.
+    #[serde(default)]
+    pub allowed_sites: Option<Vec<SecFetchSite>>,
+    /// The values of `Sec-Fetch-Mode` allowed on requests. Set to `null` to allow every mode.
+    ///
+    /// Defaults to `null` when left unfilled.
+    #[serde(default)]
+    pub allowed_modes: Option<Vec<SecFetchMode>>,
+    /// Whether CORS requests without the `Sec-Fetch-Site` or `Sec-Fetch-Mode` header checked by
+    /// the policy are allowed when the policy is enabled.
+    ///
+    /// Defaults to `true` when left unfilled.
+    #[serde(default = "FetchMetadataPolicy::default_allow_missing")]
+    pub allow_missing: bool,
+}
+
+impl FetchMetadataPolicy {
+    fn default_allow_missing() -> bool {
+        true
+    }
+
+    /// Returns whether the policy is enabled
+    pub fn is_enabled(&self) -> bool {
+        self.allowed_sites.is_some() || self.allowed_modes.is_some()
+    }
+
+    /// Check the `Sec-Fetch-Site` and `Sec-Fetch-Mode` headers of a request against the policy.
+    /// All requests are allowed when the policy is disabled.
+    pub fn check(&self, request: &Request) -> Result<(), Error> {
+        if let Some(ref allowed_sites) = self.allowed_sites {
+            self.check_header(request, SEC_FETCH_SITE_HEADER, allowed_sites)?;
+        }
+        if let Some(ref allowed_modes) = self.allowed_modes {
+            self.check_header(request, SEC_FETCH_MODE_HEADER, allowed_modes)?;
+        }
+        Ok(())
+    }
+
+    /// Check that the header `name` of a request is one of the `allowed` values
+    fn check_header<T>(&self, request: &Request, name: &str, allowed: &[T]) -> Result<(), Error>
+    where
+        T: FromStr<Err = Error> + PartialEq + fmt::Display,
+    {
+        match request.headers().get_one(name) {
+            Some(value) => {
+                let value = value.parse::<T>()?;
+                if allowed.contains(&value) {
+                    Ok(())
+                } else {
+                    Err(Error::GenericError(
+                        format!("{} `{}` is not allowed", name, value),
+                    ))
+                }
+            }
+            None if self.allow_missing || !request.headers().contains("Origin") => Ok(()),
+            None => Err(Error::GenericError(format!("{} is required", name))),
+        }
+    }
+}
+
+impl Default for FetchMetadataPolicy {
+    fn default() -> Self {
+        FetchMetadataPolicy {
+            allowed_sites: None,
+            allowed_modes: None,
+            allow_missing: Self::default_allow_missing(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rocket::http::Header;
+    use rocket::local::Client;
+
+    use super::*;
+
+    fn check(policy: &FetchMetadataPolicy, headers: &[(&'static str, &'static str)]) -> bool {
+        let client = not_err!(Client::new(::rocket::ignite()));
+        let mut request = client.get("/");
+        for &(name, value) in headers {
+            request = request.header(Header::new(name, value));
+        }
+        policy.check(request.inner()).is_ok()
+    }
+
+    #[test]
+    fn headers_are_parsed() {
+        assert_eq!(SecFetchSite::SameSite, not_err!("same-site".parse::<SecFetchSite>()));
+        assert_eq!(SecFetchSite::None, not_err!("none".parse::<SecFetchSite>()));
+        assert!("elsewhere".parse::<SecFetchSite>().is_err());
+        assert_eq!(SecFetchMode::NoCors, not_err!("no-cors".parse::<SecFetchMode>()));
+        assert!("teleport".parse::<SecFetchMode>().is_err());
+    }
+
+    #[test]
+    fn policy_is_disabled_by_default() {
+        let policy: FetchMetadataPolicy = not_err!(::serde_json::from_str("{}"));
+        assert_eq!(FetchMetadataPolicy::default(), policy);
+        assert!(!policy.is_enabled());
+        assert!(check(
+            &policy,
+            &[
+                ("Origin", "https://www.example.com"),
+                (SEC_FETCH_SITE_HEADER, "cross-site"),
+            ]
+        ));
+    }
+
+    #[test]
+    fn policy_rejects_disallowed_sites() {
+        let policy: FetchMetadataPolicy = not_err!(::serde_json::from_str(
+            r#"{ "allowed_sites": ["same-origin", "same-site"] }"#
+        ));
+        let origin = ("Origin", "https://www.example.com");
+
+        assert!(check(&policy, &[origin, (SEC_FETCH_SITE_HEADER, "same-site")]));
+        assert!(!check(&policy, &[origin, (SEC_FETCH_SITE_HEADER, "cross-site")]));
+        assert!(!check(&policy, &[origin, (SEC_FETCH_SITE_HEADER, "garbage")]));
+        // Navigations and `no-cors` requests do not carry an `Origin` header
+        assert!(!check(&policy, &[(SEC_FETCH_SITE_HEADER, "cross-site")]));
+        assert!(!check(&policy, &[(SEC_FETCH_SITE_HEADER, "none")]));
+        assert!(check(&policy, &[(SEC_FETCH_SITE_HEADER, "same-origin")]));
+    }
+
+    #[test]
+    fn policy_rejects_disallowed_modes() {
+        let policy: FetchMetadataPolicy = not_err!(::serde_json::from_str(
+            r#"{ "allowed_modes": ["cors", "same-origin"] }"#
+        ));
+        assert!(policy.is_enabled());
+        let origin = ("Origin", "https://www.example.com");
+
+        assert!(check(&policy, &[origin, (SEC_FETCH_MODE_HEADER, "cors")]));
+        assert!(!check(&policy, &[origin, (SEC_FETCH_MODE_HEADER, "no-cors")]));
+        assert!(!check(&policy, &[(SEC_FETCH_MODE_HEADER, "navigate")]));
+        assert!(!check(&policy, &[(SEC_FETCH_MODE_HEADER, "garbage")]));
+        // Any site is allowed
+        assert!(check(
+            &policy,
+            &[
+                (SEC_FETCH_SITE_HEADER, "cross-site"),
+                (SEC_FETCH_MODE_HEADER, "same-origin"),
+            ]
+        ));
+    }
+
+    #[test]
+    fn missing_headers_are_handled_by_policy() {
+        let mut policy = FetchMetadataPolicy {
+            allowed_sites: Some(vec![SecFetchSite::SameOrigin]),
+            ..Default::default()
+        };
+        let origin = ("Origin", "https://www.example.com");
+        assert!(check(&policy, &[origin]));
+
+        policy.allow_missing = false;
+        assert!(!check(&policy, &[origin]));
+        // Requests of non-browser clients
+        assert!(check(&policy, &[]));
+
+        policy.allowed_modes = Some(vec![SecFetchMode::Cors]);
+        assert!(!check(&policy, &[origin, (SEC_FETCH_SITE_HEADER, "same-origin")]));
+        assert!(check(
+            &policy,
+            &[
+                origin,
+                (SEC_FETCH_SITE_HEADER, "same-origin"),
+                (SEC_FETCH_MODE_HEADER, "cors"),
+            ]
+        ));
+    }
+}
@@ -0,0 +1,249 @@
+//! Transport security of requests
+//!
+//! Credentials and tokens must not be sent over plaintext HTTP. The routes that issue tokens,
+//! revoke sessions, or introspect tokens are guarded by [`RequireHttps`], which applies the
+//! [`HttpsPolicy`] set by `https` in the token [`Configuration`]. The policy can enforce HTTPS,
+//! only warn about plaintext requests, or be turned off for local development.
+//!
+//! Rowdy is usually deployed behind a reverse proxy that terminates TLS. Like the client IP
+//! address of [`client_ip::ClientIp`](::client_ip::ClientIp), the scheme of the original request
+//! is only recovered from the headers added by the proxy when `trust_proxy` is set: the `proto`
+//! of the last element of the `Forwarded` header, or, if there is no `Forwarded` header, the last
+//! value of the `X-Forwarded-Proto` header. Otherwise, these headers are ignored, because any
+//! client can send them, and every request is considered to be plaintext. `trust_proxy` therefore
+//! governs both features, and should only be set if every request reaches rowdy through the
+//! proxy.
+//!
+//! Rocket does not expose whether it terminated TLS itself. If rowdy serves HTTPS directly, every
+//! request is secure, and the policy should be `off`.
+use std::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
+
+use rocket::{Outcome, Request};
+use rocket::http::Status;
+use rocket::request::{self, FromRequest};
+
+use Error;
+use client_ip::{last_element, FORWARDED_HEADER};
+use reload::CurrentConfiguration;
+
+/// Name of the de-facto standard header carrying the scheme of a request forwarded by a proxy
+pub const X_FORWARDED_PROTO_HEADER: &'static str = "X-Forwarded-Proto";
+
+/// Policy on requests made over plaintext HTTP
+#[derive(Clone, Copy, Eq, PartialEq, Serialize, Deserialize, Debug)]
+pub enum HttpsPolicy {
+    /// Reject plaintext requests with `403 Forbidden`
+    #[serde(rename = "enforce")]
+    Enforce,
+    /// Allow plaintext requests, but log a warning on the first one. Without `trust_proxy`,
+    /// every request is considered to be plaintext, so only one warning is logged to avoid
+    /// flooding the logs.
+    #[serde(rename = "warn")]
+    Warn,
+    /// Allow plaintext requests silently, for example for local development
+    #[serde(rename = "off")]
+    Off,
+}
+
+impl HttpsPolicy {
+    /// Check a request against the policy. The scheme of the request is taken from the proxy
+    /// headers if `trust_proxy` is set.
+    pub fn check(&self, request: &Request, trust_proxy: bool) -> Result<(), Error> {
+        if *self == HttpsPolicy::Off || is_https(request, trust_proxy) {
+            return Ok(());
+        }
+
+        match *self {
+            HttpsPolicy::Enforce => Err(Error::GenericError(
+                "HTTPS is required. Tokens are not issued over plaintext HTTP".to_string(),
+            )),
+            _ => {
+                if !PLAINTEXT_WARNED.swap(true, Ordering::Relaxed) {
+                    warn_!(
+                        "Request to {} was made over plaintext HTTP. Tokens and credentials can \
+                         be intercepted. Further plaintext requests are not logged",
+                        request.uri()
+                    );
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Whether a plaintext request has been logged by the `Warn` policy
+static PLAINTEXT_WARNED: AtomicBool = ATOMIC_BOOL_INIT;
+
+impl Default for HttpsPolicy {
+    fn default() -> Self {
+        HttpsPolicy::Warn
+    }
+}
+
+/// Request guard that applies the `https` policy of the managed token `Configuration`. Fails
+/// with `403 Forbidden` if the policy is enforced, and the request was made over plaintext HTTP.
+/// Can be used in your own routes.
+///
+/// Requests are allowed if there is no managed `Configuration`.
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub struct RequireHttps;
+
+impl RequireHttps {
+    /// Returns why the request was rejected by the guard, if it was
+    pub fn rejection_reason(request: &Request) -> Option<String> {
+        check(request).err().map(|e| e.to_string())
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for RequireHttps {
+    type Error = Error;
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Error> {
+        match check(request) {
+            Ok(()) => Outcome::Success(RequireHttps),
+            Err(e) => Outcome::Failure((Status::Forbidden, e)),
+        }
+    }
+}
+
+/// Check the request against the policy of the managed `Configuration`, if any
+fn check(request: &Request) -> Result<(), Error> {
+    match request.guard::<CurrentConfiguration>().succeeded() {
+        Some(configuration) => configuration
+            .https
+            .check(request, configuration.trust_proxy),
+        None => Ok(()),
+    }
+}
+
+/// Returns whether the request was made over HTTPS, according to the proxy headers, if they are
+/// trusted
+fn is_https(request: &Request, trust_proxy: bool) -> bool {
+    if !trust_proxy {
+        return false;
+    }
+
+    forwarded_proto(request)
+        .map(|proto| proto.trim_matches('"').to_lowercase() == "https")
+        .unwrap_or(false)
+}
+
+/// Retrieve the scheme added by the last proxy from the `Forwarded` header, or, if there is none,
+/// from the `X-Forwarded-Proto` header
+fn forwarded_proto<'r>(request: &'r Request) -> Option<&'r str> {
+    let headers = request.headers();
+
+    match last_element(headers.get(FORWARDED_HEADER)) {
+        Some(element) => element
+            .split(';')
+            .filter_map(|pair| {
+                let mut pair = pair.splitn(2, '=');
+                match (pair.next(), pair.next()) {
+                    (Some(name), Some(value)) if name.trim().to_lowercase() == "proto" => {
+                        Some(value.trim())
+                    }
+                    _ => None,
+                }
+            })
+            .next(),
+        None => last_element(headers.get(X_FORWARDED_PROTO_HEADER)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rocket;
+    use rocket::http::Header;
+    use rocket::local::Client;
+
+    use super::*;
+    use token::Configuration;
+
+    #[get("/")]
+    fn guarded(_https: RequireHttps) -> &'static str {
+        "Secure"
+    }
+
+    fn client(https: HttpsPolicy, trust_proxy: bool) -> Client {
+        let mut configuration: Configuration = not_err!(::serde_json::from_str(
+            r#"{ "issuer": "https://www.acme.com", "allowed_origins": "All", "audience": [] }"#
+        ));
+        configuration.https = https;
+        configuration.trust_proxy = trust_proxy;
+
+        let rocket = rocket::ignite()
+            .manage(configuration)
+            .mount("/", routes![guarded]);
+        not_err!(Client::new(rocket))
+    }
+
+    fn get(client: &Client, headers: &[(&'static str, &'static str)]) -> Status {
+        let mut request = client.get("/");
+        for &(name, value) in headers {
+            request = request.header(Header::new(name, value));
+        }
+        request.dispatch().status()
+    }
+
+    #[test]
+    fn policy_is_deserialized() {
+        let policy: HttpsPolicy = not_err!(::serde_json::from_str(r#""enforce""#));
+        assert_eq!(HttpsPolicy::Enforce, policy);
+        assert_eq!(HttpsPolicy::Warn, HttpsPolicy::default());
+    }
+
+    #[test]
+    fn plaintext_requests_are_rejected_when_enforced() {
+        let client = client(HttpsPolicy::Enforce, true);
+        assert_eq!(Status::Forbidden, get(&client, &[]));
+        assert_eq!(
+            Status::Forbidden,
+            get(&client, &[(X_FORWARDED_PROTO_HEADER, "http")])
+        );
+        assert_eq!(
+            Status::Ok,
+            get(&client, &[(X_FORWARDED_PROTO_HEADER, "https")])
+        );
+        assert_eq!(
+            Status::Ok,
+            get(
+                &client,
+                &[(FORWARDED_HEADER, "for=192.0.2.60;proto=http, for=192.0.2.43;proto=HTTPS")]
+            )
+        );
+    }
+
+    #[test]
+    fn x_forwarded_proto_is_ignored_if_forwarded_header_is_present() {
+        let client = client(HttpsPolicy::Enforce, true);
+        for forwarded in &["for=192.0.2.43;proto=http", "for=192.0.2.43"] {
+            assert_eq!(
+                Status::Forbidden,
+                get(
+                    &client,
+                    &[
+                        (FORWARDED_HEADER, *forwarded),
+                        (X_FORWARDED_PROTO_HEADER, "https"),
+                    ]
+                )
+            );
+        }
+    }
+
+    #[test]
+    fn proxy_headers_are_ignored_unless_trusted() {
+        let client = client(HttpsPolicy::Enforce, false);
+        assert_eq!(
+            Status::Forbidden,
+            get(&client, &[(X_FORWARDED_PROTO_HEADER, "https")])
+        );
+    }
+
+    #[test]
+    fn plaintext_requests_are_allowed_unless_enforced() {
+        for policy in vec![HttpsPolicy::Warn, HttpsPolicy::Off] {
+            let client = client(policy, false);
+            assert_eq!(Status::Ok, get(&client, &[]));
+        }
+    }
+}
@@ -14,6 +14,7 @@
 //!
 //! - `simple_authenticator`: A simple CSV based authenticator
 //! - `ldap_authenticator`: An LDAP based authenticator
+//! - `test-util`: Helpers for testing code that depends on rowdy, in the `test_support` module
 //!
 //! By default, the `simple_authenticator` feature is turned on.
 //!
@@ -83,6 +84,8 @@
 //! This should be the same as the `scope` value in the previous step.
 //! - `offline_token`: Set to `true` if a refresh token is also required. Defaults to `false`.
 //! Cannot be set to `true` when using a refresh token to retrieve a new access token.
+//! - `nonce`: An opaque value of at most 256 bytes, which is echoed in the `nonce` claim of the
+//! access token so that clients can detect replayed tokens. Optional.
 //!
 //! When authenticating for the first time, clients should send the user's username and passwords
 //! in the form of `Basic` authentication. If the client already has a prior refresh token and
@@ -124,6 +127,30 @@
 //! Also, `offline_token` cannot be requested for when requesting for a new access token using a
 //! refresh token. (HTTP 401 will be returned if this happens.)
 //!
+//! ### Signing Out Everywhere
+//!
+//! A user can invalidate all of their outstanding refresh tokens by making a `POST` request to
+//! `/sessions/revoke` with their username and password as `Basic` authentication. This is only
+//! supported by authenticators that implement `Authenticator::invalidate_sessions`.
+//!
+//! ### Token Introspection
+//!
+//! Services that cannot validate JWTs can make a `POST` request to `/introspect` with the form
+//! parameter `token`, as described in [RFC 7662](https://tools.ietf.org/html/rfc7662). The
+//! response contains the claims of the token, and whether it is `active`.
+//!
+//! Services must authenticate to introspect tokens, either with their client credentials as
+//! `Basic` authentication, or with a service token issued by the client credentials grant as
+//! `Bearer` authentication. Introspection is therefore only available when a
+//! `auth::ClientCredentialsAuthenticator` is managed. Requests without credentials are refused
+//! with `401 Unauthorized`, and requests with the access token of a user with `403 Forbidden`.
+//!
+//! With `reference_tokens` enabled in the token configuration, rowdy issues opaque reference
+//! tokens instead of JWTs. The JWTs are stored in a managed `Box<token::ReferenceTokenStore>`,
+//! and resolved by `/introspect`, and by the `AuthenticatedClaims` and `ScopedClaims` request
+//! guards. Reference tokens can be revoked by removing them from the store. The store is only
+//! queried when `reference_tokens` is enabled.
+//!
 //! ### Example
 //!
 //! This example uses `curl` to make request to the some (hypothetical) protected endpoint.
@@ -192,6 +219,7 @@
 
 extern crate biscuit as jwt;
 extern crate chrono;
+extern crate data_encoding;
 extern crate hyper;
 #[macro_use]
 extern crate log;
@@ -199,10 +227,12 @@ extern crate log;
 extern crate rocket;
 // we are using the "log_!" macros which are redefined from `log`'s
 extern crate rocket_cors as cors;
+extern crate ring;
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
 extern crate serde_json;
+extern crate untrusted;
 extern crate uuid;
 
 #[cfg(feature = "simple_authenticator")]
@@ -211,8 +241,6 @@ extern crate argon2rs;
 extern crate csv;
 #[cfg(feature = "ldap_authenticator")]
 extern crate ldap3;
-#[cfg(feature = "simple_authenticator")]
-extern crate ring;
 #[cfg(feature = "ldap_authenticator")]
 extern crate strfmt;
 
@@ -224,12 +252,28 @@ mod macros;
 #[cfg(test)]
 #[macro_use]
 mod test;
+pub mod audit;
 pub mod auth;
+pub mod claims_template;
+pub mod client_ip;
+pub mod clock;
+pub mod discovery;
+pub mod fetch_metadata;
+pub mod https;
+pub mod logger;
+pub mod metrics;
+pub mod origins;
+pub mod reload;
+pub mod request_id;
+pub mod response_headers;
 mod routes;
+pub mod scope;
 pub mod serde_custom;
+#[cfg(any(test, feature = "test-util"))]
+pub mod test_support;
 pub mod token;
 
-pub use self::routes::routes;
+pub use self::routes::{catchers, routes};
 
 use std::error;
 use std::fmt;
@@ -241,7 +285,7 @@ use rocket::Request;
 use rocket::http::Status;
 use rocket::response::{Responder, Response};
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
-use serde::de;
+use serde::de::{self, DeserializeOwned};
 
 pub use serde_json::Value as JsonValue;
 pub use serde_json::Map as JsonMap;
@@ -319,17 +363,33 @@ impl fmt::Display for Error {
 
 impl<'r> Responder<'r> for Error {
     fn respond_to(self, request: &Request) -> Result<Response<'r>, Status> {
+        // Errors of authentication, tokens and `rocket_cors` are logged by their own responders
         match self {
             Error::Auth(e) => e.respond_to(request),
-            Error::CORS(e) => e.respond_to(request),
+            Error::CORS(e) => match e {
+                cors::Error::OriginNotAllowed |
+                cors::Error::MethodNotAllowed |
+                cors::Error::HeadersNotAllowed => {
+                    let reason = request
+                        .guard::<rocket::State<token::CorsOptionsHandle>>()
+                        .succeeded()
+                        .and_then(|cors_options| cors_options.rejection_reason(request))
+                        .unwrap_or_else(|| e.to_string());
+                    let response = Response::build()
+                        .status(Status::Forbidden)
+                        .sized_body(io::Cursor::new(reason))
+                        .ok();
+                    request_id::log_error_response(request, &e, response)
+                }
+                e => e.respond_to(request),
+            },
             Error::Token(e) => e.respond_to(request),
-            Error::BadRequest(e) => {
-                error_!("{}", e);
-                Err(Status::BadRequest)
-            }
             e => {
-                error_!("{}", e);
-                Err(Status::InternalServerError)
+                let response = match e {
+                    Error::BadRequest(_) => Err(Status::BadRequest),
+                    _ => Err(Status::InternalServerError),
+                };
+                request_id::log_error_response(request, &e, response)
             }
         }
     }
@@ -459,33 +519,348 @@ pub struct Configuration<B> {
     pub token: token::Configuration,
     /// The configuration for the authenticator that will handle HTTP Basic Authentication.
     pub basic_authenticator: B,
+    /// Logging configuration. When left unfilled, Rocket's own logger is used.
+    #[serde(default)]
+    pub logging: Option<logger::Configuration>,
+    /// The path under which `rocket` and the `launch` functions mount the routes provided by
+    /// rowdy, such as `/auth/token`. The CORS error routes are mounted under `<base_path>/cors`.
+    /// This must start with `/`.
+    ///
+    /// Defaults to `/` when left unfilled.
+    #[serde(default = "default_base_path")]
+    pub base_path: String,
+}
+
+fn default_base_path() -> String {
+    "/".to_string()
 }
 
 impl<B: auth::AuthenticatorConfiguration<auth::Basic>> Configuration<B> {
     /// Ignites the rocket with various configuration objects, but does not mount any routes.
     /// Remember to mount routes and call `launch` on the returned Rocket object.
     /// See the struct documentation for an example.
+    ///
+    /// The error catchers provided by rowdy are not registered. See `catchers`.
     pub fn ignite(&self) -> Result<rocket::Rocket, Error> {
-        let token_getter_cors_options = self.token.cors_option();
+        self.ignite_with_claims::<token::PrivateClaim>()
+    }
+
+    /// Ignites the rocket like `ignite`, but issues tokens with private claims of type `T`.
+    /// Private claims returned by the authenticator must deserialize into `T`, or the token
+    /// request will fail.
+    ///
+    /// `T` is a schema that the private claims are validated against at runtime, when tokens are
+    /// issued. Authenticators still return, and the routes still issue, untyped `PrivateClaim`s,
+    /// since Rocket routes cannot be generic, so a mismatch is not caught at compile time.
+    pub fn ignite_with_claims<T>(&self) -> Result<rocket::Rocket, Error>
+    where
+        T: Serialize + DeserializeOwned,
+    {
+        self.init_logging();
+        self.manage::<T>(rocket::ignite())
+    }
+
+    /// Ignites the rocket like `ignite`, and also returns a handle to the token configuration
+    /// managed by the rocket. Use the handle to swap in a new configuration while the server is
+    /// running. See the `reload` module.
+    pub fn ignite_reloadable(&self) -> Result<(rocket::Rocket, reload::ReloadHandle), Error> {
+        self.ignite_reloadable_with_claims::<token::PrivateClaim>()
+    }
+
+    /// Ignites the rocket like `ignite_reloadable`, but issues tokens with private claims of type
+    /// `T`. See `ignite_with_claims`. Reloads do not change the type of private claims.
+    pub fn ignite_reloadable_with_claims<T>(
+        &self,
+    ) -> Result<(rocket::Rocket, reload::ReloadHandle), Error>
+    where
+        T: Serialize + DeserializeOwned,
+    {
+        self.init_logging();
+        self.manage_reloadable::<T>(rocket::ignite())
+    }
+
+    /// Ignites the rocket like `ignite`, but binds to the provided address and port instead of
+    /// those from Rocket's own configuration. The rest of Rocket's configuration is still honoured.
+    /// Remember to mount routes and call `launch` on the returned Rocket object.
+    pub fn ignite_on(&self, address: &str, port: u16) -> Result<rocket::Rocket, Error> {
+        self.ignite_on_with_claims::<token::PrivateClaim>(address, port)
+    }
+
+    /// Ignites the rocket like `ignite_on`, but issues tokens with private claims of type `T`.
+    /// See `ignite_with_claims`.
+    pub fn ignite_on_with_claims<T>(
+        &self,
+        address: &str,
+        port: u16,
+    ) -> Result<rocket::Rocket, Error>
+    where
+        T: Serialize + DeserializeOwned,
+    {
+        self.init_logging();
+        let mut rocket_config = Self::rocket_config()?;
+        rocket_config
+            .set_address(address)
+            .map_err(|e| e.to_string())?;
+        rocket_config.set_port(port);
+
+        self.manage::<T>(rocket::custom(rocket_config, true))
+    }
+
+    /// Read Rocket's configuration for the active environment from `Rocket.toml`, or use the
+    /// defaults of the environment if there is no `Rocket.toml`, like `rocket::ignite` does.
+    fn rocket_config() -> Result<rocket::Config, Error> {
+        use rocket::config::{ConfigError, RocketConfig};
+
+        match RocketConfig::read() {
+            Ok(rocket_config) => Ok(rocket_config.active().clone()),
+            Err(ConfigError::NotFound) => {
+                rocket::Config::active().map_err(|e| Error::GenericError(e.to_string()))
+            }
+            Err(e) => Err(Error::GenericError(format!(
+                "Unable to read the Rocket configuration: {}",
+                e
+            ))),
+        }
+    }
+
+    /// Initialize the logger from the logging configuration, if any. This has to happen before
+    /// Rocket is ignited, otherwise Rocket's own logger takes precedence.
+    fn init_logging(&self) {
+        if let Some(ref logging) = self.logging {
+            if !logging.init() {
+                warn_!("A logger has already been initialized");
+            }
+        }
+    }
+
+    /// Returns the validated base path, without any trailing `/` unless it is the root
+    fn base_path(&self) -> Result<&str, Error> {
+        if !self.base_path.starts_with('/') {
+            Err(Error::GenericError(format!(
+                "The base path `{}` must start with `/`",
+                self.base_path
+            )))?;
+        }
+        match self.base_path.trim_right_matches('/') {
+            "" => Ok("/"),
+            base_path => Ok(base_path),
+        }
+    }
+
+    /// Run the startup validations of the configuration without launching the server, and report
+    /// every problem found rather than just the first. This is useful to check configurations in
+    /// CI or deployment pipelines. See [`token::Configuration::validate_all`] for the validations
+    /// of the token configuration. The base path is validated as well.
+    ///
+    /// The authenticator is not made, so credentials and connections to external services such
+    /// as databases are not checked.
+    pub fn validate_all(&self) -> Result<(), Vec<Error>> {
+        let mut errors: Vec<Error> = vec![];
+        errors.extend(self.base_path().err());
+        if let Err(token_errors) = self.token.validate_all() {
+            errors.extend(token_errors.into_iter().map(From::from));
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Place the various configuration objects into the managed state of the rocket
+    fn manage<T>(&self, rocket: rocket::Rocket) -> Result<rocket::Rocket, Error>
+    where
+        T: Serialize + DeserializeOwned,
+    {
+        self.manage_reloadable::<T>(rocket).map(|(rocket, _)| rocket)
+    }
+
+    /// Place the various configuration objects into the managed state of the rocket, and return
+    /// the handle to the managed token configuration
+    fn manage_reloadable<T>(
+        &self,
+        rocket: rocket::Rocket,
+    ) -> Result<(rocket::Rocket, reload::ReloadHandle), Error>
+    where
+        T: Serialize + DeserializeOwned,
+    {
+        let base_path = self.base_path()?;
+        let mut cors_options = self.token.cors_option();
+        cors_options.fairing_route_base = join_path(base_path, "cors");
+        let token_getter_cors_options = token::CorsOptionsHandle::new(cors_options)
+            .with_request_headers_limit(self.token.cors_request_headers_limit.clone())
+            .with_fetch_metadata_policy(self.token.fetch_metadata.clone())
+            .with_endpoint(
+                &join_path(base_path, "introspect"),
+                self.token.introspection_cors(),
+            )
+            .with_endpoint(
+                &join_path(base_path, discovery::DISCOVERY_PATH),
+                self.token.discovery_cors(),
+            );
+
+        let response_headers = response_headers::ResponseHeadersFairing::new()
+            .with_token_path(base_path)
+            .with_token_path(&join_path(base_path, ""))
+            .with_token_path(&join_path(base_path, "introspect"))
+            .with_json_path(&join_path(base_path, "health"))
+            .with_json_path(&join_path(base_path, discovery::DISCOVERY_PATH));
 
         let basic_authenticator = self.basic_authenticator.make_authenticator()?;
         let basic_authenticator: Box<auth::BasicAuthenticator> = Box::new(basic_authenticator);
 
         // Prepare the keys
-        let keys = self.token.keys()?;
+        let reload_handle = reload::ReloadHandle::new(self.token.clone())?
+            .with_cors_options(token_getter_cors_options.clone());
+        if let Some(ref fingerprint) = reload_handle.keys().signing_key_fingerprint {
+            info_!("Signing tokens with the key {}", fingerprint);
+        }
 
-        Ok(
-            rocket::ignite()
-                .manage(self.token.clone())
-                .manage(basic_authenticator)
-                .manage(keys)
-                .attach(token_getter_cors_options),
-        )
+        // Only the handle is managed, so that no route can validate tokens with keys that a
+        // reload has replaced. See the `reload` module.
+        let rocket = rocket
+            .manage(reload_handle.clone())
+            .manage(basic_authenticator)
+            .manage(discovery::ServerMetadata::new(&self.token, base_path))
+            .manage(token::PrivateClaimsType::new::<T>())
+            .manage(token_getter_cors_options.clone())
+            .attach(token_getter_cors_options)
+            .attach(request_id::RequestIdFairing)
+            .attach(response_headers);
+        Ok((rocket, reload_handle))
     }
 }
 
+/// Ignites a rocket with the configuration, and mounts the routes provided by rowdy at `/`.
+/// The returned Rocket can be further customised with your own routes, managed state, or fairings
+/// before calling `launch` on it.
+///
+/// Rocket's error catchers apply to every route of the rocket, so the catchers provided by rowdy
+/// are not registered, and the error responses of your own routes are left alone. If the rocket
+/// only serves rowdy, register them with `rocket.catch(rowdy::catchers())`.
+///
+/// # Example
+/// ```rust
+/// extern crate rowdy;
+/// extern crate serde_json;
+///
+/// use rowdy::Configuration;
+/// use rowdy::auth::NoOpConfiguration;
+///
+/// # fn main() {
+/// // We are using the `NoOp` authenticator
+/// let json = r#"{
+///     "token" : {
+///         "issuer": "https://www.acme.com",
+///         "allowed_origins": { "Some": ["https://www.example.com", "https://www.foobar.com"] },
+///         "audience": ["https://www.example.com", "https://www.foobar.com"],
+///         "signature_algorithm": "RS256",
+///         "secret": {
+///                     "rsa_private": "test/fixtures/rsa_private_key.der",
+///                     "rsa_public": "test/fixtures/rsa_public_key.der"
+///                    },
+///         "expiry_duration": 86400
+///        },
+///        "basic_authenticator": {}
+/// }"#;
+/// let config: Configuration<NoOpConfiguration> = serde_json::from_str(json).unwrap();
+/// let rocket = rowdy::rocket(config).unwrap();
+/// // then `rocket.launch()`!
+/// # }
+/// ```
+pub fn rocket<B: auth::AuthenticatorConfiguration<auth::Basic>>(
+    config: Configuration<B>,
+) -> Result<rocket::Rocket, Error> {
+    let rocket = config.ignite()?;
+    Ok(mount_routes(rocket, config.base_path()?))
+}
+
+/// Ignites a rocket like `rocket`, but issues tokens with private claims of type `T` instead of
+/// the untyped `PrivateClaim`. The authenticator's private claims must deserialize into `T`, which
+/// is checked at runtime. See [`Configuration::ignite_with_claims`].
+pub fn rocket_with_claims<T, B>(config: Configuration<B>) -> Result<rocket::Rocket, Error>
+where
+    T: Serialize + DeserializeOwned,
+    B: auth::AuthenticatorConfiguration<auth::Basic>,
+{
+    let rocket = config.ignite_with_claims::<T>()?;
+    Ok(mount_routes(rocket, config.base_path()?))
+}
+
+/// Ignites a rocket like `rocket`, and also returns a handle to the token configuration managed
+/// by the rocket, to swap in a new configuration while the server is running. See the `reload`
+/// module for an example that reloads the configuration on `SIGHUP`.
+pub fn rocket_reloadable<B: auth::AuthenticatorConfiguration<auth::Basic>>(
+    config: Configuration<B>,
+) -> Result<(rocket::Rocket, reload::ReloadHandle), Error> {
+    rocket_reloadable_with_claims::<token::PrivateClaim, B>(config)
+}
+
+/// Ignites a rocket like `rocket_reloadable`, but issues tokens with private claims of type `T`.
+/// See [`Configuration::ignite_with_claims`].
+pub fn rocket_reloadable_with_claims<T, B>(
+    config: Configuration<B>,
+) -> Result<(rocket::Rocket, reload::ReloadHandle), Error>
+where
+    T: Serialize + DeserializeOwned,
+    B: auth::AuthenticatorConfiguration<auth::Basic>,
+{
+    let (rocket, reload_handle) = config.ignite_reloadable_with_claims::<T>()?;
+    Ok((mount_routes(rocket, config.base_path()?), reload_handle))
+}
+
+/// Mount the routes provided by rowdy under `base_path`
+fn mount_routes(rocket: rocket::Rocket, base_path: &str) -> rocket::Rocket {
+    rocket.mount(base_path, routes())
+}
+
+/// Manage the `metrics` registry, in which the routes provided by rowdy record their outcomes,
+/// and mount the `/metrics` route exposing it under `base_path`. See the `metrics` module.
+///
+/// # Example
+/// ```rust,ignore
+/// let rocket = rowdy::rocket(config)?;
+/// let rocket = rowdy::mount_metrics(rocket, rowdy::metrics::Metrics::default(), "/");
+/// ```
+#[cfg(feature = "metrics")]
+pub fn mount_metrics(
+    rocket: rocket::Rocket,
+    metrics: metrics::Metrics,
+    base_path: &str,
+) -> rocket::Rocket {
+    rocket
+        .manage(metrics)
+        .mount(base_path, routes::metrics_routes())
+}
+
+/// Mount the routes provided by rowdy, and register its catchers, for the `launch` functions. With
+/// the `metrics` feature enabled, also mount the route exposing a new `Metrics` registry.
+fn mount_for_launch(rocket: rocket::Rocket, base_path: &str) -> rocket::Rocket {
+    let rocket = mount_routes(rocket, base_path).catch(catchers());
+    mount_default_metrics(rocket, base_path)
+}
+
+#[cfg(feature = "metrics")]
+fn mount_default_metrics(rocket: rocket::Rocket, base_path: &str) -> rocket::Rocket {
+    mount_metrics(rocket, metrics::Metrics::default(), base_path)
+}
+
+#[cfg(not(feature = "metrics"))]
+fn mount_default_metrics(rocket: rocket::Rocket, _base_path: &str) -> rocket::Rocket {
+    rocket
+}
+
+/// Join a segment to a base path
+fn join_path(base_path: &str, segment: &str) -> String {
+    format!("{}/{}", base_path.trim_right_matches('/'), segment)
+}
+
 /// Convenience function to ignite and launch rowdy. This function will never return
 ///
+/// Unlike `rocket`, the error catchers provided by rowdy are registered. With the `metrics`
+/// feature enabled, a new `Metrics` registry is exposed as well. See `mount_metrics`.
+///
 /// # Panics
 /// Panics if during the Rocket igition, something goes wrong.
 ///
@@ -521,14 +896,81 @@ impl<B: auth::AuthenticatorConfiguration<auth::Basic>> Configuration<B> {
 pub fn launch<B: auth::AuthenticatorConfiguration<auth::Basic>>(
     config: Configuration<B>,
 ) -> rocket::error::LaunchError {
+    let base_path = config.base_path().unwrap_or_else(|e| panic!("{}", e));
     let rocket = config.ignite().unwrap_or_else(|e| panic!("{}", e));
-    rocket.mount("/", routes()).launch()
+    mount_for_launch(rocket, base_path).launch()
+}
+
+/// Convenience function to ignite and launch rowdy, issuing tokens with private claims of type
+/// `T`. This function will never return.
+///
+/// # Panics
+/// Panics if during the Rocket igition, something goes wrong.
+pub fn launch_with_claims<T, B>(config: Configuration<B>) -> rocket::error::LaunchError
+where
+    T: Serialize + DeserializeOwned,
+    B: auth::AuthenticatorConfiguration<auth::Basic>,
+{
+    let base_path = config.base_path().unwrap_or_else(|e| panic!("{}", e));
+    let rocket = config
+        .ignite_with_claims::<T>()
+        .unwrap_or_else(|e| panic!("{}", e));
+    mount_for_launch(rocket, base_path).launch()
+}
+
+/// Convenience function to ignite and launch rowdy bound to the provided address and port.
+/// The rest of Rocket's configuration is still read from the usual sources.
+/// This function will never return.
+///
+/// # Panics
+/// Panics if during the Rocket igition, something goes wrong.
+///
+/// # Example
+/// ```rust,no_run
+/// extern crate rowdy;
+/// extern crate serde_json;
+///
+/// use rowdy::Configuration;
+/// use rowdy::auth::NoOpConfiguration;
+///
+/// # fn main() {
+/// // We are using the `NoOp` authenticator
+/// let json = r#"{
+///     "token" : {
+///         "issuer": "https://www.acme.com",
+///         "allowed_origins": { "Some": ["https://www.example.com", "https://www.foobar.com"] },
+///         "audience": ["https://www.example.com", "https://www.foobar.com"],
+///         "signature_algorithm": "RS256",
+///         "secret": {
+///                     "rsa_private": "test/fixtures/rsa_private_key.der",
+///                     "rsa_public": "test/fixtures/rsa_public_key.der"
+///                    },
+///         "expiry_duration": 86400
+///        },
+///        "basic_authenticator": {}
+/// }"#;
+/// let config: Configuration<NoOpConfiguration> = serde_json::from_str(json).unwrap();
+///
+/// rowdy::launch_on(config, "0.0.0.0", 8000);
+/// # }
+/// ```
+pub fn launch_on<B: auth::AuthenticatorConfiguration<auth::Basic>>(
+    config: Configuration<B>,
+    address: &str,
+    port: u16,
+) -> rocket::error::LaunchError {
+    let rocket = config
+        .ignite_on(address, port)
+        .unwrap_or_else(|e| panic!("{}", e));
+    let base_path = config.base_path().unwrap_or_else(|e| panic!("{}", e));
+    mount_for_launch(rocket, base_path).launch()
 }
 
 #[cfg(test)]
 mod tests {
     use std::str::FromStr;
 
+    use serde_json;
     use serde_test::{assert_tokens, Token};
 
     use super::*;
@@ -553,6 +995,164 @@ mod tests {
         }
     }
 
+    fn make_configuration() -> Configuration<auth::NoOpConfiguration> {
+        let json = r#"{
+            "token" : {
+                "issuer": "https://www.acme.com",
+                "allowed_origins": { "Some": ["https://www.example.com"] },
+                "audience": ["https://www.example.com"],
+                "signature_algorithm": "HS512",
+                "secret": "secret",
+                "expiry_duration": 86400
+            },
+            "basic_authenticator": {}
+        }"#;
+        not_err!(serde_json::from_str(json))
+    }
+
+    #[test]
+    fn ignite_on_binds_to_address_and_port() {
+        let configuration = make_configuration();
+        let rocket = not_err!(configuration.ignite_on("127.0.0.1", 1337));
+
+        assert_eq!(rocket.config().address, "127.0.0.1");
+        assert_eq!(rocket.config().port, 1337);
+    }
+
+    #[test]
+    fn rocket_is_built_with_routes_mounted() {
+        use rocket::local::Client;
+
+        let rocket = not_err!(::rocket(make_configuration()));
+        let client = not_err!(Client::new(rocket));
+
+        let mut response = client.get("/ping").dispatch();
+        let body_str = not_none!(response.body().and_then(|body| body.into_string()));
+        assert_eq!("Pong", body_str);
+    }
+
+    #[test]
+    #[cfg(feature = "metrics")]
+    fn metrics_are_managed_only_when_passed() {
+        use rocket::http::Status;
+        use rocket::local::Client;
+
+        let rocket = not_err!(::rocket(make_configuration()));
+        let rocket = rocket.manage(metrics::Metrics::default());
+        let client = not_err!(Client::new(rocket));
+        let response = client.get("/metrics").dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+
+        let metrics = metrics::Metrics::default();
+        metrics.record_token_issued();
+        let rocket = not_err!(::rocket(make_configuration()));
+        let client = not_err!(Client::new(mount_metrics(rocket, metrics, "/")));
+        let mut response = client.get("/metrics").dispatch();
+        let body_str = not_none!(response.body().and_then(|body| body.into_string()));
+        assert!(body_str.contains("rowdy_tokens_issued_total 1"), "{}", body_str);
+    }
+
+    #[test]
+    fn routes_are_mounted_under_base_path() {
+        use rocket::http::{Header, Status};
+        use rocket::local::Client;
+
+        let mut configuration = make_configuration();
+        assert_eq!("/", configuration.base_path);
+        configuration.base_path = "/auth/token/".to_string();
+
+        let rocket = not_err!(::rocket(configuration));
+        let client = not_err!(Client::new(rocket));
+
+        let mut response = client.get("/auth/token/ping").dispatch();
+        let body_str = not_none!(response.body().and_then(|body| body.into_string()));
+        assert_eq!("Pong", body_str);
+
+        let response = client.get("/ping").dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+
+        // Preflight requests to the token getter are handled under the base path
+        let response = client
+            .options("/auth/token?service=https://www.example.com&scope=all")
+            .header(Header::new("Origin", "https://www.example.com"))
+            .header(Header::new("Access-Control-Request-Method", "GET"))
+            .header(Header::new("Access-Control-Request-Headers", "Authorization"))
+            .dispatch();
+        assert!(response.status().class().is_success());
+        let allowed_origin = not_none!(response.headers().get_one("Access-Control-Allow-Origin"));
+        assert_eq!("https://www.example.com", allowed_origin);
+
+        // And so are requests rejected by CORS
+        let response = client
+            .get("/auth/token?service=https://www.example.com&scope=all")
+            .header(Header::new("Origin", "https://www.evil.com"))
+            .dispatch();
+        assert_eq!(response.status(), Status::Forbidden);
+    }
+
+    #[test]
+    fn base_path_must_be_absolute() {
+        let mut configuration = make_configuration();
+        configuration.base_path = "auth".to_string();
+
+        let error = ::rocket(configuration).err();
+        let error = not_none!(error);
+        assert_eq!("The base path `auth` must start with `/`", error.to_string());
+    }
+
+    #[test]
+    fn validation_reports_every_problem_without_launching() {
+        let configuration = make_configuration();
+        not_err!(configuration.validate_all());
+
+        let mut configuration = make_configuration();
+        configuration.base_path = "auth".to_string();
+        configuration.token.secret = token::Secret::RSAKeyPair {
+            rsa_private: "test/fixtures/does_not_exist.der".to_string(),
+            rsa_public: "test/fixtures/rsa_public_key.der".to_string(),
+        };
+
+        let errors = not_none!(configuration.validate_all().err());
+        let messages: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+        assert_eq!(3, messages.len(), "{:?}", messages);
+        assert_eq!("The base path `auth` must start with `/`", messages[0]);
+        assert_eq!(
+            "The signature algorithm `HS512` requires a byte sequence secret",
+            messages[1]
+        );
+        match errors[2] {
+            Error::Token(token::Error::IOError(ref e)) => {
+                assert_eq!(e.kind(), ::std::io::ErrorKind::NotFound)
+            }
+            ref other => panic!("Unexpected error {:?}", other),
+        }
+    }
+
+    #[test]
+    fn logging_configuration_is_optional() {
+        let configuration = make_configuration();
+        assert!(configuration.logging.is_none());
+
+        let json = r#"{
+            "token" : {
+                "issuer": "https://www.acme.com",
+                "allowed_origins": { "Some": ["https://www.example.com"] },
+                "audience": ["https://www.example.com"]
+            },
+            "basic_authenticator": {},
+            "logging": { "level": "warn", "format": "json" }
+        }"#;
+        let configuration: Configuration<auth::NoOpConfiguration> =
+            not_err!(serde_json::from_str(json));
+        assert_eq!(
+            configuration.logging,
+            Some(logger::Configuration {
+                level: logger::LogLevel::Warn,
+                format: logger::LogFormat::Json,
+            })
+        );
+    }
+
     #[test]
     fn url_serialization_token_round_trip() {
         let test = TestUrl {
@@ -0,0 +1,174 @@
+//! Logging configuration
+//!
+//! By default, Rocket initializes its own logger based on its configuration. When a logging
+//! configuration is provided in [`rowdy::Configuration`], rowdy initializes a logger with the
+//! configured level filter and format instead, before Rocket is ignited.
+//!
+//! Rowdy never logs passwords, secrets, or tokens, at any level.
+//!
+//! # Examples
+//! ```json
+//! {
+//!     "level": "info",
+//!     "format": "json"
+//! }
+//! ```
+use std::io::{self, Write};
+
+use chrono::Utc;
+use log::{self, LogLevelFilter, LogMetadata, LogRecord};
+use serde_json;
+
+/// Level filter for log records
+#[derive(Clone, Copy, Eq, PartialEq, Serialize, Deserialize, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    /// Log nothing
+    Off,
+    /// Log errors only
+    Error,
+    /// Log warnings and above
+    Warn,
+    /// Log informational messages and above
+    Info,
+    /// Log debug messages and above
+    Debug,
+    /// Log everything
+    Trace,
+}
+
+impl Default for LogLevel {
+    fn default() -> Self {
+        LogLevel::Info
+    }
+}
+
+impl From<LogLevel> for LogLevelFilter {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Off => LogLevelFilter::Off,
+            LogLevel::Error => LogLevelFilter::Error,
+            LogLevel::Warn => LogLevelFilter::Warn,
+            LogLevel::Info => LogLevelFilter::Info,
+            LogLevel::Debug => LogLevelFilter::Debug,
+            LogLevel::Trace => LogLevelFilter::Trace,
+        }
+    }
+}
+
+/// Format of the log records
+#[derive(Clone, Copy, Eq, PartialEq, Serialize, Deserialize, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human readable lines of text
+    Text,
+    /// One JSON object per line, for ingestion into log aggregators
+    Json,
+}
+
+impl Default for LogFormat {
+    fn default() -> Self {
+        LogFormat::Text
+    }
+}
+
+/// Logging configuration. Both fields default when left unfilled: `level` to `info` and `format`
+/// to `text`.
+#[derive(Clone, Copy, Eq, PartialEq, Serialize, Deserialize, Debug, Default)]
+pub struct Configuration {
+    /// Records below this level are discarded
+    #[serde(default)]
+    pub level: LogLevel,
+    /// Format of the log records
+    #[serde(default)]
+    pub format: LogFormat,
+}
+
+impl Configuration {
+    /// Initialize the global logger. A logger can only be initialized once per process, so
+    /// subsequent calls have no effect and return `false`.
+    pub fn init(&self) -> bool {
+        let logger = Logger {
+            level: From::from(self.level),
+            format: self.format,
+        };
+        log::set_logger(|max_log_level| {
+            max_log_level.set(logger.level);
+            Box::new(logger)
+        }).is_ok()
+    }
+}
+
+/// A log record serialized in the JSON format
+#[derive(Serialize)]
+struct JsonRecord<'a> {
+    timestamp: String,
+    level: String,
+    target: &'a str,
+    message: String,
+}
+
+struct Logger {
+    level: LogLevelFilter,
+    format: LogFormat,
+}
+
+impl Logger {
+    fn format(&self, record: &LogRecord) -> String {
+        let timestamp = Utc::now().to_rfc3339();
+        let message = record.args().to_string();
+        let message = message.trim();
+
+        match self.format {
+            LogFormat::Text => format!(
+                "{} {:<5} {}: {}",
+                timestamp,
+                record.level(),
+                record.target(),
+                message
+            ),
+            LogFormat::Json => {
+                let record = JsonRecord {
+                    timestamp: timestamp,
+                    level: record.level().to_string(),
+                    target: record.target(),
+                    message: message.to_string(),
+                };
+                serde_json::to_string(&record).unwrap_or_else(|e| e.to_string())
+            }
+        }
+    }
+}
+
+impl log::Log for Logger {
+    fn enabled(&self, metadata: &LogMetadata) -> bool {
+        // Like Rocket, only show the very chatty hyper logs at the most verbose levels
+        let is_hyper = metadata.target().starts_with("hyper");
+        metadata.level() <= self.level && (!is_hyper || self.level >= LogLevelFilter::Debug)
+    }
+
+    fn log(&self, record: &LogRecord) {
+        if self.enabled(record.metadata()) {
+            let _ = writeln!(io::stdout(), "{}", self.format(record));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json;
+
+    use super::*;
+
+    #[test]
+    fn configuration_defaults_when_unfilled() {
+        let deserialized: Configuration = not_err!(serde_json::from_str("{}"));
+        assert_eq!(deserialized.level, LogLevel::Info);
+        assert_eq!(deserialized.format, LogFormat::Text);
+
+        let deserialized: Configuration =
+            not_err!(serde_json::from_str(r#"{ "level": "debug", "format": "json" }"#));
+        assert_eq!(deserialized.level, LogLevel::Debug);
+        assert_eq!(deserialized.format, LogFormat::Json);
+    }
+}
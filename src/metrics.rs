@@ -0,0 +1,215 @@
+//! Operational metrics for authentication outcomes
+//!
+//! When a `Metrics` registry is placed in the managed state of Rocket, the routes provided by
+//! rowdy record the outcome and latency of authentications, and the number of tokens issued and
+//! refreshed. The latency includes the time taken by the authenticator to query its backend and
+//! to hash the password.
+//!
+//! With the `metrics` feature enabled, [`rowdy::mount_metrics`] manages the registry passed to it,
+//! and mounts a `/metrics` route that exposes the metrics in the
+//! [Prometheus text format](https://prometheus.io/docs/instrumenting/exposition_formats/). The
+//! `launch` functions do so with a new registry. [`rowdy::rocket`] does not manage a registry,
+//! so that you can manage your own.
+//!
+//! Other metrics, such as those of the connection pool of an authenticator, are appended to the
+//! route's output by a [`MetricsCollector`] placed in the managed state as a
+//! `Box<MetricsCollector>`.
+use std::fmt::Write;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+/// Upper bounds, in seconds, of the buckets of latency histograms
+const LATENCY_BUCKETS: &[f64] = &[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+/// Registry of metrics recorded by rowdy
+#[derive(Debug, Default)]
+pub struct Metrics {
+    authentication_successes: AtomicUsize,
+    authentication_failures: AtomicUsize,
+    tokens_issued: AtomicUsize,
+    token_refreshes: AtomicUsize,
+    latency: Histogram,
+}
+
+impl Metrics {
+    /// Record the outcome and latency of an authentication
+    pub fn record_authentication(&self, success: bool, latency: Duration) {
+        if success {
+            let _ = self.authentication_successes.fetch_add(1, Ordering::Relaxed);
+        } else {
+            let _ = self.authentication_failures.fetch_add(1, Ordering::Relaxed);
+        }
+        self.latency.observe(latency);
+    }
+
+    /// Record that a token was issued
+    pub fn record_token_issued(&self) {
+        let _ = self.tokens_issued.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record that a token was issued in exchange for a refresh token
+    pub fn record_token_refresh(&self) {
+        let _ = self.token_refreshes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Number of successful authentications
+    pub fn authentication_successes(&self) -> usize {
+        self.authentication_successes.load(Ordering::Relaxed)
+    }
+
+    /// Number of failed authentications
+    pub fn authentication_failures(&self) -> usize {
+        self.authentication_failures.load(Ordering::Relaxed)
+    }
+
+    /// Number of tokens issued, including those issued in exchange for refresh tokens
+    pub fn tokens_issued(&self) -> usize {
+        self.tokens_issued.load(Ordering::Relaxed)
+    }
+
+    /// Number of tokens issued in exchange for refresh tokens
+    pub fn token_refreshes(&self) -> usize {
+        self.token_refreshes.load(Ordering::Relaxed)
+    }
+
+    /// Render the metrics in the Prometheus text format
+    pub fn render(&self) -> String {
+        let mut output = String::new();
+
+        let _ = writeln!(
+            output,
+            "# HELP rowdy_authentications_total Authentication attempts by outcome."
+        );
+        let _ = writeln!(output, "# TYPE rowdy_authentications_total counter");
+        let _ = writeln!(
+            output,
+            "rowdy_authentications_total{{outcome=\"success\"}} {}",
+            self.authentication_successes()
+        );
+        let _ = writeln!(
+            output,
+            "rowdy_authentications_total{{outcome=\"failure\"}} {}",
+            self.authentication_failures()
+        );
+
+        let _ = writeln!(output, "# HELP rowdy_tokens_issued_total Tokens issued.");
+        let _ = writeln!(output, "# TYPE rowdy_tokens_issued_total counter");
+        let _ = writeln!(output, "rowdy_tokens_issued_total {}", self.tokens_issued());
+
+        let _ = writeln!(
+            output,
+            "# HELP rowdy_token_refreshes_total Tokens issued in exchange for refresh tokens."
+        );
+        let _ = writeln!(output, "# TYPE rowdy_token_refreshes_total counter");
+        let _ = writeln!(output, "rowdy_token_refreshes_total {}", self.token_refreshes());
+
+        let _ = writeln!(
+            output,
+            "# HELP rowdy_authentication_duration_seconds Latency of authentications."
+        );
+        let _ = writeln!(output, "# TYPE rowdy_authentication_duration_seconds histogram");
+        self.latency
+            .render("rowdy_authentication_duration_seconds", &mut output);
+
+        output
+    }
+}
+
+/// Source of metrics rendered by the `/metrics` route after those of the `Metrics` registry
+pub trait MetricsCollector: Send + Sync {
+    /// Append the metrics, in the Prometheus text format, to `output`
+    fn render(&self, output: &mut String);
+}
+
+/// A histogram of latencies, with fixed buckets from 5 milliseconds to 10 seconds
+#[derive(Debug)]
+pub struct Histogram {
+    /// Non-cumulative count of observations in each bucket, and the `+Inf` bucket last
+    buckets: Vec<AtomicUsize>,
+    /// Sum of the observations, in microseconds
+    sum: AtomicUsize,
+    count: AtomicUsize,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Histogram {
+            buckets: (0..LATENCY_BUCKETS.len() + 1)
+                .map(|_| AtomicUsize::new(0))
+                .collect(),
+            sum: AtomicUsize::new(0),
+            count: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl Histogram {
+    /// Record an observation
+    pub fn observe(&self, value: Duration) {
+        let seconds = value.as_secs() as f64 + f64::from(value.subsec_nanos()) / 1e9;
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|bound| seconds <= *bound)
+            .unwrap_or_else(|| LATENCY_BUCKETS.len());
+
+        let _ = self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        let _ = self.sum
+            .fetch_add((seconds * 1e6) as usize, Ordering::Relaxed);
+        let _ = self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Append the buckets, sum and count of the histogram named `name` to `output`. The `HELP`
+    /// and `TYPE` lines are left to the caller.
+    pub fn render(&self, name: &str, output: &mut String) {
+        let mut cumulative = 0;
+        for (bound, bucket) in LATENCY_BUCKETS.iter().zip(self.buckets.iter()) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let _ = writeln!(output, "{}_bucket{{le=\"{}\"}} {}", name, bound, cumulative);
+        }
+        let _ = writeln!(
+            output,
+            "{}_bucket{{le=\"+Inf\"}} {}",
+            name,
+            self.count.load(Ordering::Relaxed)
+        );
+        let _ = writeln!(
+            output,
+            "{}_sum {}",
+            name,
+            self.sum.load(Ordering::Relaxed) as f64 / 1e6
+        );
+        let _ = writeln!(output, "{}_count {}", name, self.count.load(Ordering::Relaxed));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn authentications_are_counted_and_timed() {
+        let metrics = Metrics::default();
+        metrics.record_authentication(true, Duration::from_millis(20));
+        metrics.record_authentication(false, Duration::from_secs(20));
+        metrics.record_token_issued();
+
+        assert_eq!(metrics.authentication_successes(), 1);
+        assert_eq!(metrics.authentication_failures(), 1);
+        assert_eq!(metrics.tokens_issued(), 1);
+        assert_eq!(metrics.token_refreshes(), 0);
+
+        let rendered = metrics.render();
+        assert!(rendered.contains("rowdy_authentications_total{outcome=\"success\"} 1\n"));
+        for line in &[
+            "rowdy_authentication_duration_seconds_bucket{le=\"0.01\"} 0",
+            "rowdy_authentication_duration_seconds_bucket{le=\"0.025\"} 1",
+            "rowdy_authentication_duration_seconds_bucket{le=\"10\"} 1",
+            "rowdy_authentication_duration_seconds_bucket{le=\"+Inf\"} 2",
+            "rowdy_authentication_duration_seconds_count 2",
+        ] {
+            assert!(rendered.lines().any(|rendered| rendered == *line), "{}", line);
+        }
+    }
+}
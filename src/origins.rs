@@ -0,0 +1,247 @@
+//! Helpers for working with [`cors::AllowedOrigins`] programmatically
+//!
+//! `AllowedOrigins` is defined by `rocket_cors`, so the helpers are provided by the
+//! `AllowedOriginsExt` extension trait. The serde representation is unchanged.
+//!
+//! Origins are compared by their scheme, host, and port only. Use `normalize_origin` and
+//! `AllowedOriginsExt::normalized` to strip any path, so that `https://www.example.com` and
+//! `https://www.example.com/` compare equal.
+//!
+//! IPv6 hosts are compared in their bracketed, compressed form, so `http://[0:0:0:0:0:0:0:1]:3000`
+//! is the same origin as `http://[::1]:3000`. Explicit default ports are dropped, so
+//! `http://127.0.0.1:80` is the same origin as `http://127.0.0.1`, but other ports are kept, and
+//! must match exactly.
+//!
+//! # Examples
+//! ```
+//! extern crate rocket_cors;
+//! extern crate rowdy;
+//!
+//! use std::str::FromStr;
+//!
+//! use rocket_cors::AllowedOrigins;
+//! use rocket_cors::headers::Url;
+//! use rowdy::origins::AllowedOriginsExt;
+//!
+//! # fn main() {
+//! let (mut allowed_origins, _) = AllowedOrigins::some(&["https://www.example.com"]);
+//! let origin = Url::from_str("https://www.foobar.com").unwrap();
+//!
+//! assert!(!allowed_origins.contains(&origin));
+//! assert!(allowed_origins.insert(origin.clone()));
+//! assert!(allowed_origins.contains(&origin));
+//! assert_eq!(allowed_origins.origins().count(), 2);
+//! # }
+//! ```
+use std::collections::hash_set;
+use std::str::FromStr;
+
+use cors::{AllOrSome, AllowedOrigins};
+use cors::headers::Url;
+
+/// Helper methods for `AllowedOrigins`
+pub trait AllowedOriginsExt {
+    /// Returns whether the origin is allowed. All origins are allowed by `All`. The origin is
+    /// also looked up normalized with `normalize_origin`, so it may have a path.
+    fn contains(&self, origin: &Url) -> bool;
+
+    /// Allow the origin. Returns whether the origin was newly allowed, so inserting into `All`
+    /// returns `false`.
+    fn insert(&mut self, origin: Url) -> bool;
+
+    /// Stop allowing the origin. Returns whether the origin was allowed before. Origins cannot be
+    /// removed from `All`, which returns `false`.
+    fn remove(&mut self, origin: &Url) -> bool;
+
+    /// Iterate over the allowed origins. The iterator is empty for `All`.
+    fn origins(&self) -> Origins;
+
+    /// Returns the allowed origins with each origin normalized with `normalize_origin`
+    fn normalized(&self) -> AllowedOrigins;
+}
+
+impl AllowedOriginsExt for AllowedOrigins {
+    fn contains(&self, origin: &Url) -> bool {
+        match *self {
+            AllOrSome::All => true,
+            AllOrSome::Some(ref origins) => {
+                origins.contains(origin) || origins.contains(&normalize_origin(origin))
+            }
+        }
+    }
+
+    fn insert(&mut self, origin: Url) -> bool {
+        match *self {
+            AllOrSome::All => false,
+            AllOrSome::Some(ref mut origins) => origins.insert(origin),
+        }
+    }
+
+    fn remove(&mut self, origin: &Url) -> bool {
+        match *self {
+            AllOrSome::All => false,
+            AllOrSome::Some(ref mut origins) => origins.remove(origin),
+        }
+    }
+
+    fn origins(&self) -> Origins {
+        match *self {
+            AllOrSome::All => Origins(None),
+            AllOrSome::Some(ref origins) => Origins(Some(origins.iter())),
+        }
+    }
+
+    fn normalized(&self) -> AllowedOrigins {
+        match *self {
+            AllOrSome::All => AllOrSome::All,
+            AllOrSome::Some(ref origins) => {
+                AllOrSome::Some(origins.iter().map(normalize_origin).collect())
+            }
+        }
+    }
+}
+
+/// Normalize an origin to its scheme, host, and port, discarding any user information, path,
+/// query, or fragment. Default ports are omitted, and IPv6 hosts are kept in brackets. Origins
+/// without a host, such as those of `file:` URLs, are returned as is.
+pub fn normalize_origin(origin: &Url) -> Url {
+    // The host is taken from the serialization of the URL, which already has IPv6 addresses
+    // compressed and bracketed, and default ports removed
+    let host = match origin.host_str() {
+        Some(host) if !host.is_empty() => host,
+        _ => return origin.clone(),
+    };
+    let normalized = match origin.port() {
+        Some(port) => format!("{}://{}:{}", origin.scheme(), host, port),
+        None => format!("{}://{}", origin.scheme(), host),
+    };
+    Url::from_str(&normalized).unwrap_or_else(|_| origin.clone())
+}
+
+/// Iterator over the allowed origins, returned by `AllowedOriginsExt::origins`
+#[derive(Clone, Debug)]
+pub struct Origins<'a>(Option<hash_set::Iter<'a, Url>>);
+
+impl<'a> Iterator for Origins<'a> {
+    type Item = &'a Url;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.as_mut().and_then(Iterator::next)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use cors::{AllOrSome, AllowedOrigins};
+    use cors::headers::Url;
+
+    use super::*;
+
+    #[test]
+    fn origins_are_inserted_and_removed() {
+        let (mut allowed_origins, _) = AllowedOrigins::some(&["https://www.example.com"]);
+        let example = not_err!(Url::from_str("https://www.example.com"));
+        let foobar = not_err!(Url::from_str("https://www.foobar.com"));
+
+        assert!(allowed_origins.contains(&example));
+        assert!(!allowed_origins.contains(&foobar));
+
+        assert!(allowed_origins.insert(foobar.clone()));
+        assert!(!allowed_origins.insert(foobar.clone()));
+        assert!(allowed_origins.contains(&foobar));
+        assert_eq!(allowed_origins.origins().count(), 2);
+
+        assert!(allowed_origins.remove(&example));
+        assert!(!allowed_origins.remove(&example));
+        assert_eq!(allowed_origins.origins().collect::<Vec<_>>(), vec![&foobar]);
+    }
+
+    #[test]
+    fn origins_are_normalized_to_scheme_host_and_port() {
+        let expected = not_err!(Url::from_str("https://www.example.com"));
+        for origin in &[
+            "https://www.example.com",
+            "https://www.example.com/",
+            "https://www.example.com:443/app/?query#fragment",
+        ] {
+            let origin = not_err!(Url::from_str(origin));
+            assert_eq!(normalize_origin(&origin), expected);
+        }
+
+        let origin = not_err!(Url::from_str("https://www.example.com:8443/app"));
+        assert_eq!(
+            normalize_origin(&origin),
+            not_err!(Url::from_str("https://www.example.com:8443"))
+        );
+
+        let (allowed_origins, _) = AllowedOrigins::some(&["https://www.example.com/app"]);
+        assert!(allowed_origins.normalized().contains(&expected));
+    }
+
+    #[test]
+    fn ipv6_hosts_and_ports_are_normalized() {
+        let loopback = not_err!(Url::from_str("http://[::1]:3000"));
+        for origin in &[
+            "http://[::1]:3000",
+            "http://[::1]:3000/",
+            "HTTP://[0:0:0:0:0:0:0:1]:3000/app?query",
+            "http://user:password@[::1]:3000",
+        ] {
+            let origin = not_err!(Url::from_str(origin));
+            assert_eq!(normalize_origin(&origin), loopback);
+            assert_eq!(normalize_origin(&origin).as_str(), "http://[::1]:3000/");
+        }
+
+        for &(origin, expected) in &[
+            ("http://127.0.0.1:3000/app", "http://127.0.0.1:3000/"),
+            ("http://127.0.0.1:80/", "http://127.0.0.1/"),
+            ("https://[::1]:443/", "https://[::1]/"),
+            ("https://[::1]:80/", "https://[::1]:80/"),
+            ("http://[2001:DB8::1]:8080", "http://[2001:db8::1]:8080/"),
+        ] {
+            let origin = not_err!(Url::from_str(origin));
+            assert_eq!(normalize_origin(&origin).as_str(), expected);
+        }
+    }
+
+    #[test]
+    fn ipv6_and_ported_origins_are_matched_exactly() {
+        let (allowed_origins, _) =
+            AllowedOrigins::some(&["http://[::1]:3000/", "http://127.0.0.1:3000", "http://[::1]"]);
+        let allowed_origins = allowed_origins.normalized();
+
+        for origin in &[
+            "http://[::1]:3000",
+            "http://[0::1]:3000/",
+            "http://127.0.0.1:3000",
+            "http://[::1]",
+            "http://[::1]:80",
+        ] {
+            let origin = not_err!(Url::from_str(origin));
+            assert!(allowed_origins.contains(&origin), "{}", origin);
+        }
+        for origin in &[
+            "http://[::1]:8080",
+            "https://[::1]:3000",
+            "http://127.0.0.1",
+            "http://localhost:3000",
+            "http://[::2]:3000",
+        ] {
+            let origin = not_err!(Url::from_str(origin));
+            assert!(!allowed_origins.contains(&origin), "{}", origin);
+        }
+    }
+
+    #[test]
+    fn all_contains_every_origin_and_iterates_nothing() {
+        let mut allowed_origins: AllowedOrigins = AllOrSome::All;
+        let foobar = not_err!(Url::from_str("https://www.foobar.com"));
+
+        assert!(allowed_origins.contains(&foobar));
+        assert!(!allowed_origins.insert(foobar.clone()));
+        assert!(!allowed_origins.remove(&foobar));
+        assert_eq!(allowed_origins.origins().count(), 0);
+    }
+}
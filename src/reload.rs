@@ -0,0 +1,326 @@
+//! Reloading the token configuration and keys of a running server
+//!
+//! The token [`Configuration`] and the [`Keys`] prepared from it are placed in the managed state
+//! of Rocket behind a [`ReloadHandle`]. Swapping in a new configuration through the handle takes
+//! effect from the next request, so rotated secrets and keys are picked up without restarting the
+//! server. The allowed origins of the token retrieval route are replaced as well.
+//!
+//! The handle is returned by
+//! [`Configuration::ignite_reloadable`](::Configuration::ignite_reloadable) and
+//! [`rocket_reloadable`](::rocket_reloadable). Keep a clone of it to reload from a signal handler,
+//! an admin route, or a file watcher.
+//!
+//! # Reloading on `SIGHUP`
+//! rowdy does not install signal handlers itself. With a crate like
+//! [`signal-hook`](https://crates.io/crates/signal-hook), a thread can re-read the configuration
+//! file whenever the process receives `SIGHUP`:
+//!
+//! ```rust,ignore
+//! extern crate signal_hook;
+//!
+//! use std::thread;
+//!
+//! let (rocket, handle) = rowdy::rocket_reloadable(config)?;
+//! let signals = signal_hook::iterator::Signals::new(&[signal_hook::SIGHUP])?;
+//! let _ = thread::spawn(move || {
+//!     for _ in signals.forever() {
+//!         if let Err(e) = handle.reload_from_file("config.json") {
+//!             eprintln!("Configuration was not reloaded: {}", e);
+//!         }
+//!     }
+//! });
+//! rocket.launch();
+//! ```
+//!
+//! A configuration that fails [`Configuration::validate_all`] or whose keys cannot be read is
+//! rejected, and the previous configuration stays in effect.
+//!
+//! Only the token configuration is reloaded. The authenticator, the base path, the discovery
+//! document, and CORS options other than the allowed origins are fixed when Rocket is ignited.
+//!
+//! # Migrating from `State<Configuration>` and `State<Keys>`
+//! Rocket manages only the `ReloadHandle`, and not the `token::Configuration` and `Keys` the
+//! server was ignited with, since routes validating tokens with them would keep accepting tokens
+//! signed with a key that a reload has rotated out. Routes taking them as `State` take a
+//! [`Snapshot`] or a [`CurrentConfiguration`] instead.
+use std::fs::File;
+use std::io::Read;
+use std::ops::Deref;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+
+use rocket::{Outcome, Request, State};
+use rocket::http::Status;
+use rocket::request::{self, FromRequest};
+use serde_json;
+
+use Error;
+use token::{Configuration, CorsOptionsHandle, Keys};
+
+/// The token configuration, and the keys prepared from it, in effect at some point in time. Can
+/// be used as a request guard in your own routes, if Rocket manages a `ReloadHandle`.
+///
+/// The configuration and keys always come from the same reload, so they are consistent with each
+/// other for the whole request even if the configuration is reloaded concurrently.
+#[derive(Clone)]
+pub struct Snapshot {
+    /// The token configuration
+    pub configuration: Arc<Configuration>,
+    /// The keys prepared from `configuration`
+    pub keys: Arc<Keys>,
+}
+
+impl Snapshot {
+    /// Prepare the keys of the configuration
+    fn new(configuration: Configuration) -> Result<Self, Error> {
+        let keys = configuration.keys()?;
+        Ok(Snapshot {
+            configuration: Arc::new(configuration),
+            keys: Arc::new(keys),
+        })
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for Snapshot {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, ()> {
+        match request.guard::<State<ReloadHandle>>() {
+            Outcome::Success(handle) => Outcome::Success(handle.snapshot()),
+            _ => Outcome::Failure((Status::InternalServerError, ())),
+        }
+    }
+}
+
+/// The token configuration in effect for a request, and the keys prepared from it. Can be used as
+/// a request guard in your own routes.
+///
+/// The configuration is taken from the managed `ReloadHandle`, which Rockets ignited by rowdy
+/// always manage. If there is none, a managed `token::Configuration` and `Keys` are borrowed
+/// instead, so guards relying on the configuration keep working in Rockets that manage them
+/// directly, without rowdy. Such configurations cannot be reloaded. The guard fails if neither is
+/// managed.
+pub struct CurrentConfiguration<'r>(Current<'r>);
+
+/// Where the configuration of a `CurrentConfiguration` comes from
+enum Current<'r> {
+    Reloadable(Snapshot),
+    Managed(&'r Configuration, Option<&'r Keys>),
+}
+
+impl<'r> CurrentConfiguration<'r> {
+    /// The keys prepared from the configuration. This is `None` if neither a `ReloadHandle` nor
+    /// `Keys` are managed.
+    pub fn keys(&self) -> Option<&Keys> {
+        match self.0 {
+            Current::Reloadable(ref snapshot) => Some(&snapshot.keys),
+            Current::Managed(_, keys) => keys,
+        }
+    }
+}
+
+impl<'r> Deref for CurrentConfiguration<'r> {
+    type Target = Configuration;
+
+    fn deref(&self) -> &Configuration {
+        match self.0 {
+            Current::Reloadable(ref snapshot) => &snapshot.configuration,
+            Current::Managed(configuration, _) => configuration,
+        }
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for CurrentConfiguration<'r> {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, ()> {
+        if let Outcome::Success(handle) = request.guard::<State<ReloadHandle>>() {
+            return Outcome::Success(CurrentConfiguration(Current::Reloadable(handle.snapshot())));
+        }
+        let keys = request
+            .guard::<State<Keys>>()
+            .succeeded()
+            .map(|keys| keys.inner());
+        match request.guard::<State<Configuration>>() {
+            Outcome::Success(configuration) => Outcome::Success(CurrentConfiguration(
+                Current::Managed(configuration.inner(), keys),
+            )),
+            _ => Outcome::Failure((Status::InternalServerError, ())),
+        }
+    }
+}
+
+/// Handle to the token configuration and keys of a running server, which can be replaced at
+/// runtime. Clones of the handle share the same configuration.
+///
+/// Rocket manages the handle when ignited with any of the functions of this crate. Use
+/// [`Configuration::ignite_reloadable`](::Configuration::ignite_reloadable) to keep a clone.
+#[derive(Clone)]
+pub struct ReloadHandle {
+    current: Arc<RwLock<Snapshot>>,
+    cors_options: Option<CorsOptionsHandle>,
+}
+
+impl ReloadHandle {
+    /// Create a handle with the initial configuration, preparing its keys
+    pub fn new(configuration: Configuration) -> Result<Self, Error> {
+        Ok(ReloadHandle {
+            current: Arc::new(RwLock::new(Snapshot::new(configuration)?)),
+            cors_options: None,
+        })
+    }
+
+    /// Replace the allowed origins of the token retrieval route on every reload
+    pub(crate) fn with_cors_options(mut self, cors_options: CorsOptionsHandle) -> Self {
+        self.cors_options = Some(cors_options);
+        self
+    }
+
+    /// Returns the configuration and keys currently in effect
+    pub fn snapshot(&self) -> Snapshot {
+        self.current
+            .read()
+            .expect("Reload handle lock to not be poisoned")
+            .clone()
+    }
+
+    /// Returns the configuration currently in effect
+    pub fn configuration(&self) -> Arc<Configuration> {
+        self.snapshot().configuration
+    }
+
+    /// Returns the keys currently in effect
+    pub fn keys(&self) -> Arc<Keys> {
+        self.snapshot().keys
+    }
+
+    /// Validate the configuration, prepare its keys, and swap it in. Requests handled after this
+    /// returns use the new configuration and keys. If any validation fails, all the problems
+    /// found are reported, and the previous configuration stays in effect.
+    pub fn reload(&self, configuration: Configuration) -> Result<(), Error> {
+        if let Err(errors) = configuration.validate_all() {
+            let errors: Vec<String> = errors.iter().map(ToString::to_string).collect();
+            Err(Error::GenericError(format!(
+                "The configuration is invalid: {}",
+                errors.join("; ")
+            )))?;
+        }
+
+        // Read the keys before taking the lock, so that requests are not held up
+        let allowed_origins = configuration.allowed_origins.clone();
+        let snapshot = Snapshot::new(configuration)?;
+        if let Some(ref fingerprint) = snapshot.keys.signing_key_fingerprint {
+            info_!("Reloaded configuration, signing tokens with the key {}", fingerprint);
+        }
+
+        *self.current
+            .write()
+            .expect("Reload handle lock to not be poisoned") = snapshot;
+        if let Some(ref cors_options) = self.cors_options {
+            cors_options.set_allowed_origins(allowed_origins);
+        }
+        Ok(())
+    }
+
+    /// Read the token configuration from the `token` key of an application configuration in JSON,
+    /// in the same format as the configuration the server was started with, and swap it in. The
+    /// other keys are ignored.
+    pub fn reload_from_reader<R: Read>(&self, reader: R) -> Result<(), Error> {
+        let file: ConfigurationFile = serde_json::from_reader(reader).map_err(|e| e.to_string())?;
+        self.reload(file.token)
+    }
+
+    /// Read the application configuration file at `path`, and swap in its token configuration.
+    /// See `reload_from_reader`.
+    pub fn reload_from_file<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        let file = File::open(path)?;
+        self.reload_from_reader(file)
+    }
+}
+
+/// The token configuration of an application configuration file
+#[derive(Deserialize)]
+struct ConfigurationFile {
+    token: Configuration,
+}
+
+#[cfg(test)]
+mod tests {
+    use ByteSequence;
+    use super::*;
+    use test_support;
+    use token::Secret;
+
+    fn rotated_configuration() -> Configuration {
+        let mut configuration = test_support::configuration();
+        configuration.secret = Secret::ByteSequence(ByteSequence::String("rotated".to_string()));
+        configuration
+    }
+
+    #[test]
+    fn reloading_swaps_the_configuration_and_keys() {
+        let handle = not_err!(ReloadHandle::new(test_support::configuration()));
+        let clone = handle.clone();
+        let fingerprint = handle.keys().signing_key_fingerprint.clone();
+
+        not_err!(clone.reload(rotated_configuration()));
+
+        let snapshot = handle.snapshot();
+        assert_ne!(snapshot.keys.signing_key_fingerprint, fingerprint);
+        assert_eq!(
+            snapshot.keys.signing_key_fingerprint,
+            not_err!(rotated_configuration().signing_key_fingerprint())
+        );
+        match snapshot.configuration.secret {
+            Secret::ByteSequence(ByteSequence::String(ref secret)) => assert_eq!(secret, "rotated"),
+            ref secret => panic!("Unexpected secret {:?}", secret),
+        }
+    }
+
+    #[test]
+    fn invalid_configurations_are_not_swapped_in() {
+        let handle = not_err!(ReloadHandle::new(test_support::configuration()));
+        let fingerprint = handle.keys().signing_key_fingerprint.clone();
+
+        let mut configuration = rotated_configuration();
+        configuration.refresh_soon_threshold = 2.0;
+        let error = handle.reload(configuration).unwrap_err();
+        assert!(error.to_string().contains("refresh_soon_threshold"));
+
+        assert_eq!(handle.keys().signing_key_fingerprint, fingerprint);
+        assert_eq!(
+            handle.configuration().refresh_soon_threshold,
+            test_support::configuration().refresh_soon_threshold
+        );
+    }
+
+    #[test]
+    fn token_configuration_is_reloaded_from_the_application_configuration() {
+        let handle = not_err!(ReloadHandle::new(test_support::configuration()));
+        let json = r#"{
+            "token": {
+                "issuer": "https://rowdy.test",
+                "allowed_origins": "All",
+                "audience": "https://service.rowdy.test",
+                "signature_algorithm": "HS256",
+                "secret": "rotated"
+            },
+            "basic_authenticator": {}
+        }"#;
+
+        not_err!(handle.reload_from_reader(json.as_bytes()));
+        assert_eq!(
+            handle.keys().signing_key_fingerprint,
+            not_err!(rotated_configuration().signing_key_fingerprint())
+        );
+    }
+
+    #[test]
+    fn malformed_files_are_rejected() {
+        let handle = not_err!(ReloadHandle::new(test_support::configuration()));
+        let fingerprint = handle.keys().signing_key_fingerprint.clone();
+
+        assert!(handle.reload_from_reader(&b"{\"token\": {}}"[..]).is_err());
+        assert_eq!(handle.keys().signing_key_fingerprint, fingerprint);
+    }
+}
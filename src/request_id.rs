@@ -0,0 +1,221 @@
+//! Correlation IDs for requests
+//!
+//! Every request handled by rowdy is assigned a request ID, which is echoed back to the client in
+//! the `X-Request-Id` response header. If the client
+//! (or a gateway in front of rowdy) provides an `X-Request-Id` header, its value is used instead
+//! of generating a new one, provided that it is between 1 and 128 characters long and only
+//! consists of ASCII letters, digits, `.`, `_`, and `-`. Other values are replaced with a newly
+//! generated ID, so that clients cannot inject arbitrary text into the logs.
+//!
+//! The request ID is included in the log lines of the request and its response, written by
+//! `RequestIdFairing`, and in the log lines of errors that rowdy responds with, which are written
+//! by `log_error_response`. Other log lines, such as those of authenticators, do not carry it,
+//! since they are not given the request. Match them up by the time between the request and
+//! response log lines, and use `RequestId` as a request guard to include the ID in the logs of
+//! your own routes.
+use std::fmt;
+use std::ops::Deref;
+
+use rocket::{Data, Outcome, Request, Response};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::{Header, Status};
+use rocket::request::{self, FromRequest};
+
+use token;
+
+/// Name of the header carrying the request ID
+pub const REQUEST_ID_HEADER: &'static str = "X-Request-Id";
+
+/// Maximum length of request IDs provided by clients
+const MAX_REQUEST_ID_LENGTH: usize = 128;
+
+/// Returns whether a request ID provided by a client is acceptable
+fn is_valid(request_id: &str) -> bool {
+    !request_id.is_empty() && request_id.len() <= MAX_REQUEST_ID_LENGTH
+        && request_id.bytes().all(|byte| match byte {
+            b'A'...b'Z' | b'a'...b'z' | b'0'...b'9' | b'.' | b'_' | b'-' => true,
+            _ => false,
+        })
+}
+
+/// The correlation ID of a request. Can be used as a request guard in your own routes.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct RequestId(String);
+
+impl RequestId {
+    /// Retrieve the request ID of a request. Returns an empty ID if the request has not been
+    /// processed by `RequestIdFairing`.
+    pub fn from_request(request: &Request) -> Self {
+        RequestId(
+            request
+                .headers()
+                .get_one(REQUEST_ID_HEADER)
+                .unwrap_or_default()
+                .to_string(),
+        )
+    }
+}
+
+impl Deref for RequestId {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for RequestId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for RequestId {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, ()> {
+        Outcome::Success(RequestId::from_request(request))
+    }
+}
+
+/// Log an error that the request is responded to with, tagged with the ID of the request, and
+/// pass the response through. Errors with a `4xx` status are caused by clients, who can cause them
+/// at will, so they are only logged at the debug level. Other errors are logged as errors.
+///
+/// Responders should log each error once, from the responder that decides on its status.
+pub fn log_error_response<'r>(
+    request: &Request,
+    error: &fmt::Display,
+    response: Result<Response<'r>, Status>,
+) -> Result<Response<'r>, Status> {
+    let status = match response {
+        Ok(ref response) => response.status(),
+        Err(status) => status,
+    };
+    let request_id = RequestId::from_request(request);
+    if status.class().is_client_error() {
+        debug_!("[{}] {}", request_id, error);
+    } else {
+        error_!("[{}] {}", request_id, error);
+    }
+    response
+}
+
+/// Fairing that assigns a request ID to every request, logs the request and response with it,
+/// and echoes it back in the `X-Request-Id` response header.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct RequestIdFairing;
+
+impl Fairing for RequestIdFairing {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request ID",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    fn on_request(&self, request: &mut Request, _: &Data) {
+        let has_request_id = {
+            let mut request_ids = request.headers().get(REQUEST_ID_HEADER);
+            match (request_ids.next(), request_ids.next()) {
+                (Some(request_id), None) => is_valid(request_id),
+                _ => false,
+            }
+        };
+
+        if !has_request_id {
+            let request_id = match token::make_uuid() {
+                Ok(uuid) => uuid.to_string(),
+                Err(e) => {
+                    error_!("Unable to generate a request ID: {}", e);
+                    String::new()
+                }
+            };
+            request.replace_header(Header::new(REQUEST_ID_HEADER, request_id));
+        }
+
+        info_!(
+            "[{}] {} {}",
+            RequestId::from_request(request),
+            request.method(),
+            request.uri()
+        );
+    }
+
+    fn on_response(&self, request: &Request, response: &mut Response) {
+        let request_id = RequestId::from_request(request);
+        info_!("[{}] {}", request_id, response.status());
+        response.set_header(Header::new(REQUEST_ID_HEADER, request_id.0));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rocket;
+    use rocket::http::Header;
+    use rocket::local::Client;
+
+    use super::*;
+
+    #[get("/")]
+    fn echo(request_id: RequestId) -> String {
+        request_id.to_string()
+    }
+
+    fn client() -> Client {
+        let rocket = rocket::ignite()
+            .attach(RequestIdFairing)
+            .mount("/", routes![echo]);
+        not_err!(Client::new(rocket))
+    }
+
+    #[test]
+    fn request_id_is_generated() {
+        let client = client();
+        let mut response = client.get("/").dispatch();
+
+        let header = not_none!(response.headers().get_one(REQUEST_ID_HEADER)).to_string();
+        assert_eq!(36, header.len());
+
+        let body = not_none!(response.body().and_then(|body| body.into_string()));
+        assert_eq!(header, body);
+    }
+
+    #[test]
+    fn inbound_request_id_is_honoured() {
+        let client = client();
+        let mut response = client
+            .get("/")
+            .header(Header::new(REQUEST_ID_HEADER, "abc-123"))
+            .dispatch();
+
+        let header = not_none!(response.headers().get_one(REQUEST_ID_HEADER)).to_string();
+        assert_eq!("abc-123", header);
+
+        let body = not_none!(response.body().and_then(|body| body.into_string()));
+        assert_eq!("abc-123", body);
+    }
+
+    #[test]
+    fn invalid_inbound_request_ids_are_replaced() {
+        let client = client();
+        let too_long = "a".repeat(129);
+        let invalids = ["", " ", "abc 123", "abc\r\nINFO", "abc/123", "冻住", too_long.as_str()];
+        for invalid in &invalids {
+            let response = client
+                .get("/")
+                .header(Header::new(REQUEST_ID_HEADER, invalid.to_string()))
+                .dispatch();
+            let header = not_none!(response.headers().get_one(REQUEST_ID_HEADER)).to_string();
+            assert_ne!(*invalid, header);
+            assert_eq!(36, header.len());
+        }
+
+        let longest = "a.b_c-".repeat(21) + "ab";
+        let response = client
+            .get("/")
+            .header(Header::new(REQUEST_ID_HEADER, longest.clone()))
+            .dispatch();
+        assert_eq!(Some(&*longest), response.headers().get_one(REQUEST_ID_HEADER));
+    }
+}
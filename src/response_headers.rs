@@ -0,0 +1,174 @@
+//! Headers added to the responses of rowdy
+//!
+//! Responses bearing tokens must not be stored by browsers or shared caches, as required by
+//! [RFC 6749 §5.1](https://tools.ietf.org/html/rfc6749#section-5.1). The
+//! [`ResponseHeadersFairing`] sets `Cache-Control: no-store` and `Pragma: no-cache` on every
+//! response to the token retrieval and introspection routes, including error responses, and
+//! `Content-Type: application/json` on their successful responses, along with those of the other
+//! routes responding with JSON. Every response is sent with `X-Content-Type-Options: nosniff`.
+//!
+//! The headers are applied centrally, so that routes do not have to set them individually. Rocket
+//! ignited by this crate attaches the fairing with the paths of the routes provided by rowdy.
+use rocket::{Request, Response};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::{ContentType, Header};
+
+/// Name of the header directing caches not to store responses
+pub const CACHE_CONTROL_HEADER: &'static str = "Cache-Control";
+/// Name of the HTTP/1.0 header directing caches not to store responses
+pub const PRAGMA_HEADER: &'static str = "Pragma";
+/// Name of the header directing browsers not to guess the type of responses
+pub const CONTENT_TYPE_OPTIONS_HEADER: &'static str = "X-Content-Type-Options";
+
+/// Fairing that sets the caching, content type, and content sniffing headers of responses. The
+/// paths of routes are registered with their treatment, and must match the path of the request
+/// exactly.
+#[derive(Clone, Default, Debug)]
+pub struct ResponseHeadersFairing {
+    token_paths: Vec<String>,
+    json_paths: Vec<String>,
+}
+
+impl ResponseHeadersFairing {
+    /// Create a fairing that only sets `X-Content-Type-Options: nosniff`
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Mark the responses to `path` as bearing tokens. They are never cached, and their successful
+    /// responses are JSON.
+    pub fn with_token_path(mut self, path: &str) -> Self {
+        self.token_paths.push(path.to_string());
+        self
+    }
+
+    /// Mark the successful responses to `path` as JSON
+    pub fn with_json_path(mut self, path: &str) -> Self {
+        self.json_paths.push(path.to_string());
+        self
+    }
+
+    /// Returns whether responses to the request bear tokens
+    fn is_token_path(&self, request: &Request) -> bool {
+        let path = request.uri().path();
+        self.token_paths.iter().any(|token_path| token_path == path)
+    }
+
+    /// Returns whether successful responses to the request are JSON
+    fn is_json_path(&self, request: &Request) -> bool {
+        let path = request.uri().path();
+        self.is_token_path(request) || self.json_paths.iter().any(|json_path| json_path == path)
+    }
+}
+
+impl Fairing for ResponseHeadersFairing {
+    fn info(&self) -> Info {
+        Info {
+            name: "Response Headers",
+            kind: Kind::Response,
+        }
+    }
+
+    fn on_response(&self, request: &Request, response: &mut Response) {
+        response.set_header(Header::new(CONTENT_TYPE_OPTIONS_HEADER, "nosniff"));
+
+        if self.is_token_path(request) {
+            response.set_header(Header::new(CACHE_CONTROL_HEADER, "no-store"));
+            response.set_header(Header::new(PRAGMA_HEADER, "no-cache"));
+        }
+
+        // Preflight requests and errors without a body are left alone
+        if self.is_json_path(request) && response.status().class().is_success()
+            && response.body().is_some()
+        {
+            response.set_header(ContentType::JSON);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rocket;
+    use rocket::http::Status;
+    use rocket::local::Client;
+    use rocket::response::Failure;
+
+    use super::*;
+
+    #[get("/token")]
+    fn token() -> &'static str {
+        "{}"
+    }
+
+    #[get("/health")]
+    fn health() -> &'static str {
+        "{}"
+    }
+
+    #[get("/ping")]
+    fn ping() -> &'static str {
+        "Pong"
+    }
+
+    #[get("/forbidden")]
+    fn forbidden() -> Failure {
+        Failure(Status::Forbidden)
+    }
+
+    fn client() -> Client {
+        let fairing = ResponseHeadersFairing::new()
+            .with_token_path("/token")
+            .with_token_path("/forbidden")
+            .with_json_path("/health");
+        let rocket = rocket::ignite()
+            .attach(fairing)
+            .mount("/", routes![token, health, ping, forbidden]);
+        not_err!(Client::new(rocket))
+    }
+
+    #[test]
+    fn token_responses_are_not_cached() {
+        let client = client();
+        let response = client.get("/token").dispatch();
+
+        let headers = response.headers();
+        assert_eq!(headers.get_one(CACHE_CONTROL_HEADER), Some("no-store"));
+        assert_eq!(headers.get_one(PRAGMA_HEADER), Some("no-cache"));
+        assert_eq!(headers.get_one("Content-Type"), Some("application/json"));
+        assert_eq!(headers.get_one(CONTENT_TYPE_OPTIONS_HEADER), Some("nosniff"));
+    }
+
+    #[test]
+    fn token_errors_are_not_cached_nor_json() {
+        let client = client();
+        let response = client.get("/forbidden").dispatch();
+        assert_eq!(response.status(), Status::Forbidden);
+
+        let headers = response.headers();
+        assert_eq!(headers.get_one(CACHE_CONTROL_HEADER), Some("no-store"));
+        assert_eq!(headers.get_one(PRAGMA_HEADER), Some("no-cache"));
+        assert_ne!(headers.get_one("Content-Type"), Some("application/json"));
+    }
+
+    #[test]
+    fn json_responses_can_be_cached() {
+        let client = client();
+        let response = client.get("/health").dispatch();
+
+        let headers = response.headers();
+        assert_eq!(headers.get_one("Content-Type"), Some("application/json"));
+        assert!(headers.get_one(CACHE_CONTROL_HEADER).is_none());
+        assert!(headers.get_one(PRAGMA_HEADER).is_none());
+    }
+
+    #[test]
+    fn other_responses_are_only_marked_nosniff() {
+        let client = client();
+        let response = client.get("/ping").dispatch();
+
+        let headers = response.headers();
+        assert_eq!(headers.get_one("Content-Type"), Some("text/plain; charset=utf-8"));
+        assert!(headers.get_one(CACHE_CONTROL_HEADER).is_none());
+        assert_eq!(headers.get_one(CONTENT_TYPE_OPTIONS_HEADER), Some("nosniff"));
+    }
+}
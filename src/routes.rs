@@ -3,17 +3,37 @@
 // mounted via `::launch()`
 #![allow(unmounted_route)]
 
+use std::io::Read;
+use std::str::FromStr;
+use std::time::Instant;
+
 use hyper;
-use rocket::{Route, State};
+use rocket::{Catcher, Data, Outcome, Request, Route, State};
+use rocket::data::{self, FromData};
+use rocket::http::{RawStr, Status};
+use rocket::request::{self, FormItems, FromForm, FromFormValue, FromRequest};
+use rocket::response::{content, status};
+use serde_json;
 
+use audit::{AuditEvent, AuditEventKind, AuditSink};
 use auth;
-use token::{Configuration, Keys, PrivateClaim, RefreshToken, Token};
+use client_ip::ClientIp;
+use discovery::ServerMetadata;
+use https::RequireHttps;
+use metrics::Metrics;
+use reload::{CurrentConfiguration, Snapshot};
+use token::{self, Configuration, CorsOptionsHandle, PrivateClaim, PrivateClaimsType,
+            ReferenceTokenStore, RefreshToken, Token};
 
 #[derive(FromForm, Default, Clone, Debug)]
 struct AuthParam {
     service: String,
     scope: String,
     offline_token: Option<bool>,
+    /// Space-separated audiences requested in addition to the configured audience
+    audience: Option<String>,
+    /// Opaque value to echo in the `nonce` claim of the access token
+    nonce: Option<String>,
 }
 
 impl AuthParam {
@@ -31,30 +51,191 @@ impl AuthParam {
         }
         Ok(())
     }
+
+    /// Parse the additional audiences requested, if any
+    fn additional_audiences(&self) -> Result<Vec<::jwt::StringOrUri>, ::Error> {
+        match self.audience {
+            Some(ref audience) => audience
+                .split_whitespace()
+                .map(|audience| Ok(FromStr::from_str(audience).map_err(token::Error::from)?))
+                .collect(),
+            None => Ok(vec![]),
+        }
+    }
+
+    /// Returns the nonce requested, if any
+    fn nonce(&self) -> Result<Option<&str>, ::Error> {
+        check_nonce(&self.nonce)
+    }
+}
+
+/// OAuth2 grant types supported by rowdy in addition to the default Basic authentication flow
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+enum GrantType {
+    /// Machine-to-machine clients authenticating with a client ID and secret
+    ClientCredentials,
+}
+
+impl<'v> FromFormValue<'v> for GrantType {
+    type Error = &'v RawStr;
+
+    fn from_form_value(value: &'v RawStr) -> Result<Self, Self::Error> {
+        match value.as_str() {
+            "client_credentials" => Ok(GrantType::ClientCredentials),
+            _ => Err(value),
+        }
+    }
+}
+
+#[derive(FromForm, Clone, Debug)]
+struct ClientCredentialsParam {
+    grant_type: GrantType,
+    service: String,
+    /// Space-delimited scopes requested. The `scope` claim of the service token, if any, is
+    /// narrowed down to these. See `restrict_scope`.
+    scope: String,
+    /// Opaque value to echo in the `nonce` claim of the access token
+    nonce: Option<String>,
+}
+
+/// Check that the nonce of a token request, if any, is not empty and is no longer than
+/// `token::MAX_NONCE_LENGTH`. The nonce is otherwise opaque.
+fn check_nonce(nonce: &Option<String>) -> Result<Option<&str>, ::Error> {
+    match *nonce {
+        Some(ref nonce) if nonce.is_empty() => {
+            Err(::Error::BadRequest("The nonce must not be empty".to_string()))
+        }
+        Some(ref nonce) if nonce.len() > token::MAX_NONCE_LENGTH => Err(::Error::BadRequest(
+            format!(
+                "The nonce is longer than the maximum of {} bytes",
+                token::MAX_NONCE_LENGTH
+            ),
+        )),
+        Some(ref nonce) => Ok(Some(nonce)),
+        None => Ok(None),
+    }
+}
+
+/// Render the private claims of the authentication result from the configured claims template,
+/// if any
+fn render_claims_template(
+    configuration: &Configuration,
+    mut result: auth::AuthenticationResult,
+) -> auth::AuthenticationResult {
+    if let Some(ref claims_template) = configuration.claims_template {
+        result.private_claims = claims_template.render(&result);
+    }
+    result
+}
+
+/// Apply the managed `ClaimsTransform`, if any, to the authentication result
+fn transform_claims(
+    claims_transform: Option<State<Box<auth::ClaimsTransform>>>,
+    result: auth::AuthenticationResult,
+) -> Result<auth::AuthenticationResult, ::Error> {
+    match claims_transform {
+        Some(claims_transform) => claims_transform.transform(result),
+        None => Ok(result),
+    }
+}
+
+/// Run the authentication, and record its outcome and latency in the managed `Metrics`, if any
+fn authenticate_with_metrics<F>(
+    metrics: &Option<State<Metrics>>,
+    authenticate: F,
+) -> Result<auth::AuthenticationResult, ::Error>
+where
+    F: FnOnce() -> Result<auth::AuthenticationResult, ::Error>,
+{
+    let started = Instant::now();
+    let result = authenticate();
+    if let Some(ref metrics) = *metrics {
+        metrics.record_authentication(result.is_ok(), started.elapsed());
+    }
+    result
+}
+
+/// Record an audit event in the managed `AuditSink`, if any
+fn audit(
+    audit_sink: &Option<State<Box<AuditSink>>>,
+    event: AuditEventKind,
+    subject: Option<String>,
+    client_ip: Option<ClientIp>,
+    success: bool,
+) {
+    if let Some(ref audit_sink) = *audit_sink {
+        let event = AuditEvent::new(event, subject, client_ip.map(|ip| *ip), success.into());
+        event.record_in(&***audit_sink);
+    }
+}
+
+/// Record the outcome of an authentication as a login in the managed `AuditSink`, if any. The
+/// subject of a failed login is the `username` presented, if any.
+fn audit_login(
+    audit_sink: &Option<State<Box<AuditSink>>>,
+    client_ip: Option<ClientIp>,
+    username: Option<String>,
+    result: &Result<auth::AuthenticationResult, ::Error>,
+) {
+    let subject = match *result {
+        Ok(ref result) => Some(result.subject.clone()),
+        Err(_) => username,
+    };
+    audit(audit_sink, AuditEventKind::Login, subject, client_ip, result.is_ok());
+}
+
+/// Record the issuance of a token in the managed `Metrics`, if any
+fn record_token_issued(metrics: &Option<State<Metrics>>, refreshed: bool) {
+    if let Some(ref metrics) = *metrics {
+        metrics.record_token_issued();
+        if refreshed {
+            metrics.record_token_refresh();
+        }
+    }
 }
 
 /// Access token retrieval via initial authentication route
 #[get("/?<auth_param>", rank = 1)]
 fn token_getter(
+    _https: RequireHttps,
     authorization: auth::Authorization<auth::Basic>,
     auth_param: AuthParam,
-    configuration: State<Configuration>,
-    keys: State<Keys>,
+    snapshot: Snapshot,
     authenticator: State<Box<auth::BasicAuthenticator>>,
+    private_claims_type: State<PrivateClaimsType>,
+    claims_transform: Option<State<Box<auth::ClaimsTransform>>>,
+    metrics: Option<State<Metrics>>,
+    reference_store: Option<State<Box<ReferenceTokenStore>>>,
+    audit_sink: Option<State<Box<AuditSink>>>,
+    client_ip: Option<ClientIp>,
 ) -> Result<Token<PrivateClaim>, ::Error> {
+    let Snapshot { configuration, keys } = snapshot;
     auth_param.verify(&authorization)?;
-    authenticator
-        .prepare_authentication_response(&authorization, auth_param.offline_token.unwrap_or(false))
+    let additional_audiences = auth_param.additional_audiences()?;
+    let nonce = auth_param.nonce()?;
+    let offline_token = auth_param.offline_token.unwrap_or(false);
+    let result = authenticate_with_metrics(&metrics, || {
+        authenticator.prepare_authentication_response(&authorization, offline_token)
+    });
+    audit_login(&audit_sink, client_ip, Some(authorization.username()), &result);
+    result
+        .map(|result| render_claims_template(&configuration, result))
+        .and_then(|result| transform_claims(claims_transform, result))
         .and_then(|result| {
-            let token = Token::<PrivateClaim>::with_configuration(
+            let private_claims = private_claims_type.conform(result.private_claims.clone())?;
+            let private_claims = strip_token_type(private_claims);
+            let private_claims = add_nonce(private_claims, nonce)?;
+            let token = Token::<PrivateClaim>::with_additional_audiences(
                 &configuration,
                 &result.subject,
                 &auth_param.service,
-                result.private_claims.clone(),
+                &additional_audiences,
+                private_claims,
                 result.refresh_payload.as_ref(),
             )?;
             let signing_key = &keys.signing;
             let token = token.encode(signing_key)?;
+            token.check_size(&configuration.token_size_limit)?;
 
             let token = if configuration.refresh_token_enabled() && token.has_refresh_token() {
                 let refresh_token_key = keys.encryption
@@ -64,20 +245,215 @@ fn token_getter(
             } else {
                 token
             };
+            let token = into_reference_if_enabled(token, &configuration, &reference_store)?;
+
+            record_token_issued(&metrics, false);
+            Ok(token)
+        })
+}
+
+/// Replace the encoded token with a reference token, if reference tokens are enabled
+fn into_reference_if_enabled(
+    token: Token<PrivateClaim>,
+    configuration: &Configuration,
+    reference_store: &Option<State<Box<ReferenceTokenStore>>>,
+) -> Result<Token<PrivateClaim>, ::Error> {
+    if !configuration.reference_tokens {
+        return Ok(token);
+    }
+    match *reference_store {
+        Some(ref store) => token.into_reference(&***store, configuration.clock.now()),
+        None => Err(::Error::GenericError(
+            "Reference tokens are enabled, but no reference token store is managed".to_string(),
+        )),
+    }
+}
+
+/// Service token retrieval via the client credentials grant
+#[get("/?<client_param>", rank = 0)]
+fn client_credentials(
+    _https: RequireHttps,
+    authorization: auth::Authorization<auth::Basic>,
+    client_param: ClientCredentialsParam,
+    snapshot: Snapshot,
+    client_authenticator: Option<State<auth::ClientCredentialsAuthenticator>>,
+    private_claims_type: State<PrivateClaimsType>,
+    claims_transform: Option<State<Box<auth::ClaimsTransform>>>,
+    metrics: Option<State<Metrics>>,
+    reference_store: Option<State<Box<ReferenceTokenStore>>>,
+    audit_sink: Option<State<Box<AuditSink>>>,
+    client_ip: Option<ClientIp>,
+) -> Result<Token<PrivateClaim>, ::Error> {
+    let Snapshot { configuration, keys } = snapshot;
+    debug_assert_eq!(client_param.grant_type, GrantType::ClientCredentials);
+    let client_authenticator = client_authenticator.ok_or_else(|| {
+        ::Error::BadRequest("Client credentials grant is not enabled".to_string())
+    })?;
+    let nonce = check_nonce(&client_param.nonce)?;
 
+    let result = authenticate_with_metrics(&metrics, || {
+        client_authenticator.prepare_authentication_response(&authorization, false)
+    });
+    audit_login(&audit_sink, client_ip, Some(authorization.username()), &result);
+    result
+        .map(|result| render_claims_template(&configuration, result))
+        .and_then(|result| transform_claims(claims_transform, result))
+        .and_then(|result| {
+            let private_claims = private_claims_type.conform(result.private_claims)?;
+            let private_claims = restrict_scope(private_claims, &client_param.scope);
+            let private_claims = mark_service_token(private_claims)?;
+            let private_claims = add_nonce(private_claims, nonce)?;
+            let token = Token::<PrivateClaim>::service_token(
+                &configuration,
+                &result.subject,
+                &client_param.service,
+                private_claims,
+            )?;
+            let token = token.encode(&keys.signing)?;
+            token.check_size(&configuration.token_size_limit)?;
+            let token = into_reference_if_enabled(token, &configuration, &reference_store)?;
+            record_token_issued(&metrics, false);
             Ok(token)
         })
 }
 
+/// Add the nonce of the token request, if any, to the private claims as the `nonce` claim. A nonce
+/// in the private claims returned by the authenticator is replaced.
+fn add_nonce(private_claims: PrivateClaim, nonce: Option<&str>) -> Result<PrivateClaim, ::Error> {
+    let nonce = match nonce {
+        Some(nonce) => nonce,
+        None => return Ok(private_claims),
+    };
+    let mut map = match private_claims {
+        ::JsonValue::Object(map) => map,
+        ::JsonValue::Null => ::JsonMap::with_capacity(1),
+        _ => Err(::Error::GenericError(
+            "Private claims must be an object to add a nonce".to_string(),
+        ))?,
+    };
+    let _ = map.insert(token::NONCE_CLAIM.to_string(), From::from(nonce));
+    Ok(::JsonValue::Object(map))
+}
+
+/// Returns whether the private claims have the `token_type` claim of a service token. The claim is
+/// informational, and must not be trusted, since authenticators and claims templates can set it.
+/// Service tokens are told apart by their header instead. See `AccessTokenClaims`.
+fn claims_service_token(private_claims: &PrivateClaim) -> bool {
+    private_claims
+        .get(token::TOKEN_TYPE_HEADER)
+        .and_then(|claim| claim.as_str()) == Some(token::SERVICE_TOKEN_TYPE)
+}
+
+/// Request guard for a valid access token, recording whether it is a service token issued by the
+/// client credentials grant, as marked by its header. See
+/// `token::HeaderFields::is_service_token`.
+///
+/// Tokens whose `token_type` claim says that they are service tokens, without the header to
+/// match, are refused with `401 Unauthorized`.
+struct AccessTokenClaims {
+    service: bool,
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for AccessTokenClaims {
+    type Error = ::Error;
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, ::Error> {
+        match auth::validated_token::<PrivateClaim>(request) {
+            Outcome::Success((header, claims)) => {
+                let service = header.private.is_service_token();
+                if !service && claims_service_token(&claims.private) {
+                    debug_!("Token claims to be a service token, but its header does not");
+                    return Outcome::Failure((
+                        Status::Unauthorized,
+                        ::Error::Auth(auth::Error::AuthenticationFailure),
+                    ));
+                }
+                Outcome::Success(AccessTokenClaims { service })
+            }
+            Outcome::Failure((status, e)) => Outcome::Failure((status, ::Error::Auth(e))),
+            Outcome::Forward(()) => Outcome::Forward(()),
+        }
+    }
+}
+
+/// Remove the `token_type` claim from the private claims of a token issued to a user, since the
+/// claim is reserved for service tokens. Authenticators, claims templates and `ClaimsTransform`s
+/// can otherwise set it.
+fn strip_token_type(private_claims: PrivateClaim) -> PrivateClaim {
+    match private_claims {
+        ::JsonValue::Object(mut map) => {
+            if map.remove(token::TOKEN_TYPE_HEADER).is_some() {
+                warn_!(
+                    "Removed the reserved `{}` claim from the private claims of a user token",
+                    token::TOKEN_TYPE_HEADER
+                );
+            }
+            ::JsonValue::Object(map)
+        }
+        private_claims => private_claims,
+    }
+}
+
+/// Narrow the `scope` claim of the private claims down to the space-delimited `requested` scopes,
+/// keeping its form as a string or an array. Scopes are never added: requested scopes that are not
+/// granted are left out, and private claims without a `scope` claim are unchanged.
+fn restrict_scope(mut private_claims: PrivateClaim, requested: &str) -> PrivateClaim {
+    let requested: Vec<&str> = requested.split_whitespace().collect();
+    let restricted = match private_claims.get("scope") {
+        Some(&::JsonValue::String(ref granted)) => ::JsonValue::String(
+            granted
+                .split_whitespace()
+                .filter(|scope| requested.contains(scope))
+                .collect::<Vec<_>>()
+                .join(" "),
+        ),
+        Some(&::JsonValue::Array(ref granted)) => ::JsonValue::Array(
+            granted
+                .iter()
+                .filter(|scope| scope.as_str().map_or(false, |scope| requested.contains(&scope)))
+                .cloned()
+                .collect(),
+        ),
+        _ => return private_claims,
+    };
+    if let Some(map) = private_claims.as_object_mut() {
+        let _ = map.insert("scope".to_string(), restricted);
+    }
+    private_claims
+}
+
+/// Add the `token_type` claim marking a service token to the private claims
+fn mark_service_token(private_claims: PrivateClaim) -> Result<PrivateClaim, ::Error> {
+    let mut map = match private_claims {
+        ::JsonValue::Object(map) => map,
+        ::JsonValue::Null => ::JsonMap::with_capacity(1),
+        _ => Err(::Error::GenericError(
+            "Private claims of service tokens must be an object".to_string(),
+        ))?,
+    };
+    let _ = map.insert(
+        token::TOKEN_TYPE_HEADER.to_string(),
+        From::from(token::SERVICE_TOKEN_TYPE),
+    );
+    Ok(::JsonValue::Object(map))
+}
+
 /// Access token retrieval via refresh token route
 #[get("/?<auth_param>", rank = 2)]
 fn refresh_token(
+    _https: RequireHttps,
     authorization: auth::Authorization<auth::Bearer>,
     auth_param: AuthParam,
-    configuration: State<Configuration>,
-    keys: State<Keys>,
+    snapshot: Snapshot,
     authenticator: State<Box<auth::BasicAuthenticator>>,
+    private_claims_type: State<PrivateClaimsType>,
+    claims_transform: Option<State<Box<auth::ClaimsTransform>>>,
+    metrics: Option<State<Metrics>>,
+    reference_store: Option<State<Box<ReferenceTokenStore>>>,
+    audit_sink: Option<State<Box<AuditSink>>>,
+    client_ip: Option<ClientIp>,
 ) -> Result<Token<PrivateClaim>, ::Error> {
+    let Snapshot { configuration, keys } = snapshot;
     if !configuration.refresh_token_enabled() {
         return Err(::Error::BadRequest(
             "Refresh token is not enabled".to_string(),
@@ -86,6 +462,8 @@ fn refresh_token(
     let refresh_token_configuration = configuration.refresh_token();
 
     auth_param.verify(&authorization)?;
+    let additional_audiences = auth_param.additional_audiences()?;
+    let nonce = auth_param.nonce()?;
     let refresh_token = RefreshToken::new_encrypted(&authorization.token());
     let refresh_token = refresh_token.decrypt(
         &keys.signature_verification,
@@ -99,26 +477,255 @@ fn refresh_token(
 
     refresh_token.validate(&auth_param.service, &configuration, None)?;
 
-    authenticator
-        .prepare_refresh_response(refresh_token.payload()?)
+    let payload = refresh_token.payload()?;
+    let result =
+        authenticate_with_metrics(&metrics, || authenticator.prepare_refresh_response(payload));
+    audit_login(&audit_sink, client_ip, None, &result);
+    result
+        .map(|result| render_claims_template(&configuration, result))
+        .and_then(|result| transform_claims(claims_transform, result))
         .and_then(|result| {
-            let token = Token::<PrivateClaim>::with_configuration(
+            let private_claims = private_claims_type.conform(result.private_claims.clone())?;
+            let private_claims = strip_token_type(private_claims);
+            let private_claims = add_nonce(private_claims, nonce)?;
+            let token = Token::<PrivateClaim>::with_additional_audiences(
                 &configuration,
                 &result.subject,
                 &auth_param.service,
-                result.private_claims.clone(),
+                &additional_audiences,
+                private_claims,
                 None,
             )?;
             let token = token.encode(&keys.signing)?;
+            token.check_size(&configuration.token_size_limit)?;
+            let token = into_reference_if_enabled(token, &configuration, &reference_store)?;
+            record_token_issued(&metrics, true);
             Ok(token)
         })
 }
 
+/// Invalidate all refresh tokens of the user, signing them out everywhere. The user authenticates
+/// with `Basic` authentication, and is identified by the subject that the authenticator returns,
+/// before any claims template or `ClaimsTransform` is applied. Access tokens that have already
+/// been issued remain valid until they expire.
+#[post("/sessions/revoke")]
+fn revoke_sessions(
+    _https: RequireHttps,
+    _body_limit: BodyLimit,
+    authorization: auth::Authorization<auth::Basic>,
+    authenticator: State<Box<auth::BasicAuthenticator>>,
+    audit_sink: Option<State<Box<AuditSink>>>,
+    client_ip: Option<ClientIp>,
+) -> Result<status::NoContent, ::Error> {
+    let result = authenticator
+        .prepare_authentication_response(&authorization, false)
+        .and_then(|result| authenticator.invalidate_sessions(&result.subject));
+    audit(
+        &audit_sink,
+        AuditEventKind::TokenRevocation,
+        Some(authorization.username()),
+        client_ip,
+        result.is_ok(),
+    );
+    result?;
+    Ok(status::NoContent)
+}
+
 /// Route to catch missing Authorization
 #[get("/?<auth_param>", rank = 3)]
-fn bad_request(auth_param: AuthParam, configuration: State<Configuration>) -> Result<(), ::Error> {
-    let _ = auth_param;
-    auth::missing_authorization(&configuration.issuer.to_string())
+fn bad_request(
+    auth_param: AuthParam,
+    configuration: CurrentConfiguration,
+) -> Result<(), ::Error> {
+    auth::missing_authorization(&configuration.realm_for(&auth_param.service))
+}
+
+/// Returns the `request_body_limit` of the current configuration, and fails if the declared
+/// `Content-Length` of the request exceeds it. There is no limit if there is no configuration.
+fn check_body_limit(request: &Request) -> Result<u64, ::Error> {
+    let limit = request
+        .guard::<CurrentConfiguration>()
+        .succeeded()
+        .map(|configuration| configuration.request_body_limit)
+        .unwrap_or_else(u64::max_value);
+    let length = request
+        .headers()
+        .get_one("Content-Length")
+        .and_then(|length| length.trim().parse::<u64>().ok());
+    match length {
+        Some(length) if length > limit => Err(body_too_large(limit)),
+        _ => Ok(limit),
+    }
+}
+
+/// Error for a request body larger than the `limit`
+fn body_too_large(limit: u64) -> ::Error {
+    ::Error::BadRequest(format!(
+        "The request body is larger than the maximum of {} bytes",
+        limit
+    ))
+}
+
+/// Request guard refusing requests with a declared `Content-Length` above the
+/// `request_body_limit` with `413 Payload Too Large`. For routes that do not read the body.
+struct BodyLimit;
+
+impl<'a, 'r> FromRequest<'a, 'r> for BodyLimit {
+    type Error = ::Error;
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, ::Error> {
+        match check_body_limit(request) {
+            Ok(_) => Outcome::Success(BodyLimit),
+            Err(e) => Outcome::Failure((Status::PayloadTooLarge, e)),
+        }
+    }
+}
+
+/// Form data guard that reads at most `request_body_limit` bytes of the body, and refuses
+/// larger bodies with `413 Payload Too Large`. Otherwise behaves like `Form`.
+struct LimitedForm<T>(T);
+
+impl<T> FromData for LimitedForm<T>
+where
+    T: for<'f> FromForm<'f>,
+{
+    type Error = ::Error;
+
+    fn from_data(request: &Request, data: Data) -> data::Outcome<Self, ::Error> {
+        if !request.content_type().map_or(false, |ct| ct.is_form()) {
+            return Outcome::Forward(data);
+        }
+        let limit = match check_body_limit(request) {
+            Ok(limit) => limit,
+            Err(e) => return Outcome::Failure((Status::PayloadTooLarge, e)),
+        };
+
+        // Read one byte past the limit to tell whether the body exceeds it
+        let mut body = String::new();
+        if let Err(e) = data.open().take(limit.saturating_add(1)).read_to_string(&mut body) {
+            return Outcome::Failure((Status::BadRequest, ::Error::BadRequest(e.to_string())));
+        }
+        if body.len() as u64 > limit {
+            return Outcome::Failure((Status::PayloadTooLarge, body_too_large(limit)));
+        }
+
+        match T::from_form(&mut FormItems::from(body.as_str()), true) {
+            Ok(form) => Outcome::Success(LimitedForm(form)),
+            Err(_) => Outcome::Failure((
+                Status::UnprocessableEntity,
+                ::Error::BadRequest("The form is malformed".to_string()),
+            )),
+        }
+    }
+}
+
+/// Parameters of token introspection requests. See
+/// [RFC 7662](https://tools.ietf.org/html/rfc7662#section-2.1).
+#[derive(FromForm, Debug)]
+struct IntrospectionParam {
+    token: String,
+}
+
+/// Client making a token introspection request, which has to authenticate as required by
+/// [RFC 7662 §2.1](https://tools.ietf.org/html/rfc7662#section-2.1).
+///
+/// Clients authenticate either with their client credentials as HTTP Basic authentication,
+/// verified by the managed `ClientCredentialsAuthenticator`, or with a service token issued to
+/// them by the client credentials grant as `Bearer` authentication. Service tokens are told apart
+/// by their header, which cannot be set by private claims. See `AccessTokenClaims`. Access tokens
+/// issued to users are refused with `403 Forbidden`, so that users cannot introspect the tokens of
+/// others.
+struct IntrospectionClient;
+
+impl<'a, 'r> FromRequest<'a, 'r> for IntrospectionClient {
+    type Error = ::Error;
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, ::Error> {
+        let unauthorized = || {
+            Outcome::Failure((
+                Status::Unauthorized,
+                ::Error::Auth(auth::Error::AuthenticationFailure),
+            ))
+        };
+
+        if let Outcome::Success(authorization) =
+            request.guard::<auth::Authorization<auth::Basic>>()
+        {
+            let client_authenticator =
+                match request.guard::<State<auth::ClientCredentialsAuthenticator>>() {
+                    Outcome::Success(client_authenticator) => client_authenticator,
+                    _ => {
+                        debug_!("Introspection clients cannot authenticate without a client \
+                                 credentials authenticator");
+                        return unauthorized();
+                    }
+                };
+            return match client_authenticator.authenticate(&authorization, false) {
+                Ok(_) => Outcome::Success(IntrospectionClient),
+                Err(e) => {
+                    debug_!("Introspection client failed to authenticate: {}", e);
+                    unauthorized()
+                }
+            };
+        }
+
+        match request.guard::<AccessTokenClaims>() {
+            Outcome::Success(ref access_token) if access_token.service => {
+                Outcome::Success(IntrospectionClient)
+            }
+            Outcome::Success(_) => Outcome::Failure((
+                Status::Forbidden,
+                ::Error::Auth(auth::Error::AuthenticationFailure),
+            )),
+            Outcome::Failure(failure) => Outcome::Failure(failure),
+            Outcome::Forward(()) => Outcome::Forward(()),
+        }
+    }
+}
+
+/// Introspect a token, which is either a JWT, or a reference token issued when
+/// `reference_tokens` is enabled. If the token is valid, responds with its claims, and
+/// `"active": true`. Otherwise, responds with only `"active": false`. See
+/// [RFC 7662](https://tools.ietf.org/html/rfc7662#section-2.2).
+///
+/// The client making the request must authenticate. See `IntrospectionClient`.
+#[post("/introspect", data = "<param>")]
+fn introspect(
+    _https: RequireHttps,
+    _client: IntrospectionClient,
+    param: LimitedForm<IntrospectionParam>,
+    configuration: CurrentConfiguration,
+    reference_store: Option<State<Box<ReferenceTokenStore>>>,
+) -> Result<content::Json<String>, ::Error> {
+    let token = &param.0.token;
+    let resolved = match reference_store {
+        Some(ref store) if configuration.reference_tokens => {
+            store.resolve(token, configuration.clock.now())?
+        }
+        _ => None,
+    };
+    let token = resolved.as_ref().unwrap_or(token);
+
+    let keys = configuration.keys().ok_or_else(|| {
+        ::Error::GenericError("Token keys are not managed".to_string())
+    })?;
+    let (active, mut response) = match configuration.validate_token::<PrivateClaim>(keys, token) {
+        Ok(validated) => {
+            let claims = validated.token.payload().map_err(token::Error::JWTError)?;
+            match serde_json::to_value(claims).map_err(token::Error::TokenSerializationError)? {
+                ::JsonValue::Object(map) => (true, map),
+                _ => (true, ::JsonMap::with_capacity(1)),
+            }
+        }
+        Err(e) => {
+            debug_!("Introspected token is not active: {}", e);
+            (false, ::JsonMap::with_capacity(1))
+        }
+    };
+    let _ = response.insert("active".to_string(), From::from(active));
+
+    let body = serde_json::to_string(&response).map_err(token::Error::TokenSerializationError)?;
+    Ok(content::Json(body))
 }
 
 /// A simple "Ping Pong" route to check the health of the server
@@ -127,9 +734,113 @@ fn ping() -> &'static str {
     "Pong"
 }
 
+/// Health of the server reported by the `/health` route
+#[derive(Serialize)]
+struct Health<'a> {
+    status: &'static str,
+    signing_key_fingerprint: Option<&'a str>,
+}
+
+/// Reports the health of the server, and the fingerprint of the key used to sign tokens so that
+/// operators can tell which key a running instance uses.
+///
+/// The fingerprint is only reported for asymmetric keys. Fingerprints of HMAC secrets would allow
+/// offline guessing of the secret, so they are only logged when the keys are loaded.
+#[get("/health")]
+fn health(snapshot: Snapshot) -> Result<content::Json<String>, ::Error> {
+    let signing_key_fingerprint = if snapshot.configuration.secret.is_asymmetric() {
+        snapshot.keys.signing_key_fingerprint.as_ref().map(String::as_str)
+    } else {
+        None
+    };
+    let health = Health {
+        status: "ok",
+        signing_key_fingerprint,
+    };
+    let body = serde_json::to_string(&health).map_err(token::Error::TokenSerializationError)?;
+    Ok(content::Json(body))
+}
+
+/// Responds with the discovery document of the server. See the `discovery` module.
+#[get("/.well-known/openid-configuration")]
+fn discovery(metadata: State<ServerMetadata>) -> Result<content::Json<String>, ::Error> {
+    let body = serde_json::to_string(&*metadata).map_err(token::Error::TokenSerializationError)?;
+    Ok(content::Json(body))
+}
+
+/// Exposes the managed `Metrics`, followed by those of the managed `Box<MetricsCollector>`, if
+/// any, in the Prometheus text format
+#[cfg(feature = "metrics")]
+#[get("/metrics")]
+fn metrics(
+    metrics: State<Metrics>,
+    collector: Option<State<Box<::metrics::MetricsCollector>>>,
+) -> String {
+    let mut output = metrics.render();
+    if let Some(collector) = collector {
+        collector.render(&mut output);
+    }
+    output
+}
+
+/// Catches `403 Forbidden`. If the request was rejected by CORS, the body names the offending
+/// origin, method, or header. If the request was rejected for being made over plaintext HTTP,
+/// the body says so.
+#[error(403)]
+fn forbidden(request: &Request) -> status::Custom<String> {
+    let reason = request
+        .guard::<State<CorsOptionsHandle>>()
+        .succeeded()
+        .and_then(|cors_options| cors_options.rejection_reason(request))
+        .or_else(|| RequireHttps::rejection_reason(request));
+    status::Custom(
+        Status::Forbidden,
+        reason.unwrap_or_else(|| "Forbidden".to_string()),
+    )
+}
+
+/// Catches `400 Bad Request`. If the `Authorization` header is malformed, the body describes why.
+#[error(400)]
+fn malformed_request(request: &Request) -> status::Custom<String> {
+    status::Custom(
+        Status::BadRequest,
+        auth::malformed_authorization(request).unwrap_or_else(|| "Bad Request".to_string()),
+    )
+}
+
 /// Return routes provided by rowdy
 pub fn routes() -> Vec<Route> {
-    routes![token_getter, refresh_token, bad_request, ping,]
+    routes![
+        client_credentials,
+        token_getter,
+        refresh_token,
+        revoke_sessions,
+        bad_request,
+        introspect,
+        ping,
+        health,
+        discovery,
+    ]
+}
+
+/// Return the route exposing the managed `Metrics`
+#[cfg(feature = "metrics")]
+pub fn metrics_routes() -> Vec<Route> {
+    routes![metrics]
+}
+
+/// Return the error catchers provided by rowdy, which describe why requests were rejected:
+///
+/// - `403 Forbidden` names the origin, method, or header rejected by CORS, or says that the
+///   request was made over plaintext HTTP
+/// - `400 Bad Request` says why the `Authorization` header is malformed
+///
+/// Catchers apply to every route of a rocket, not only to those of rowdy, and replace any catcher
+/// registered earlier for the same status. They are therefore not registered by `ignite` or
+/// `rocket`, only by the `launch` functions. Register them with `rocket.catch(catchers())` if the
+/// rocket only serves rowdy.
+pub fn catchers() -> Vec<Catcher> {
+    errors![forbidden, malformed_request]
 }
 
 #[cfg(test)]
@@ -140,64 +851,90 @@ mod tests {
     use hyper;
     use jwt;
     use rocket::Rocket;
-    use rocket::http::{Header, Status};
+    use rocket::http::{ContentType, Header, Status};
     use rocket::local::Client;
     use serde_json;
 
     use ByteSequence;
     use super::*;
-    use token::{RefreshTokenConfiguration, Secret};
+    use token::{MemoryReferenceTokenStore, RefreshTokenConfiguration, Secret};
 
-    fn ignite() -> Rocket {
-        // Ignite rocket
+    fn make_configuration() -> Configuration {
         let allowed_origins = ["https://www.example.com"];
         let (allowed_origins, _) = ::cors::AllowedOrigins::some(&allowed_origins);
-        let token_configuration = Configuration {
+        Configuration {
             issuer: FromStr::from_str("https://www.acme.com").unwrap(),
             allowed_origins: allowed_origins,
+            allow_credentials: true,
+            cors_max_age: Some(600),
+            cors_request_headers_limit: Default::default(),
             audience: jwt::SingleOrMultiple::Single(
                 not_err!(FromStr::from_str("https://www.example.com")),
             ),
             signature_algorithm: Some(jwt::jwa::SignatureAlgorithm::HS512),
             secret: Secret::ByteSequence(ByteSequence::String("secret".to_string())),
             expiry_duration: Duration::from_secs(120),
+            not_before_offset: Duration::from_secs(0),
+            service_token_duration: Duration::from_secs(60),
             refresh_token: Some(RefreshTokenConfiguration {
                 cek_algorithm: jwt::jwa::KeyManagementAlgorithm::A256GCMKW,
                 enc_algorithm: jwt::jwa::ContentEncryptionAlgorithm::A256GCM,
                 key: Secret::ByteSequence(ByteSequence::Bytes(vec![0; 256 / 8])),
                 expiry_duration: Duration::from_secs(86400),
             }),
-        };
-        let configuration = ::Configuration {
-            token: token_configuration,
-            basic_authenticator: ::auth::tests::MockAuthenticatorConfiguration {},
-        };
+            realm: None,
+            authentication_failure_message: None,
+            services: Default::default(),
+            header: Default::default(),
+            trust_proxy: false,
+            reference_tokens: false,
+            fetch_metadata: Default::default(),
+            token_size_limit: Default::default(),
+            request_body_limit: 16384,
+            introspection_allowed_origins: None,
+            claims_template: None,
+            refresh_soon_threshold: 0.1,
+            https: Default::default(),
+            additional_audiences: None,
+            alternative_signatures: vec![],
+            clock: Default::default(),
+        }
+    }
 
-        let rocket = not_err!(configuration.ignite());
-        rocket.mount("/", routes())
+    /// Configuration of rowdy with the mock authenticator and the `token_configuration`
+    fn make_rowdy_configuration(
+        token_configuration: Configuration,
+    ) -> ::Configuration<auth::tests::MockAuthenticatorConfiguration> {
+        ::Configuration {
+            token: token_configuration,
+            basic_authenticator: auth::tests::MockAuthenticatorConfiguration {},
+            logging: None,
+            base_path: "/".to_string(),
+        }
     }
 
-    #[test]
-    fn ping_pong() {
-        let rocket = ignite();
-        let client = not_err!(Client::new(rocket));
+    fn ignite() -> Rocket {
+        ignite_with(make_configuration())
+    }
 
-        let req = client.get("/ping");
-        let mut response = req.dispatch();
-        let body_str = not_none!(response.body().and_then(|body| body.into_string()));
+    fn ignite_with(token_configuration: Configuration) -> Rocket {
+        // Ignite rocket
+        let configuration = make_rowdy_configuration(token_configuration);
 
-        assert_eq!("Pong", body_str);
+        let client_authenticator =
+            auth::ClientCredentialsAuthenticator::new(auth::tests::MockAuthenticator {});
+        let rocket = not_err!(configuration.ignite());
+        rocket
+            .manage(client_authenticator)
+            .mount("/", routes())
+            .catch(catchers())
     }
 
-    #[test]
-    fn token_getter_options_test() {
-        let rocket = ignite();
+    /// Dispatch a preflight request from the origin and return the `Access-Control-Allow-Origin`
+    fn preflight_allowed_origin(rocket: Rocket, origin: &str) -> String {
         let client = not_err!(Client::new(rocket));
 
-        // Make headers
-        let origin_header = Header::from(not_err!(
-            hyper::header::Origin::from_str("https://www.example.com")
-        ));
+        let origin_header = Header::from(not_err!(hyper::header::Origin::from_str(origin)));
         let method_header = Header::from(hyper::header::AccessControlRequestMethod(
             hyper::method::Method::Get,
         ));
@@ -206,7 +943,6 @@ mod tests {
         );
         let request_headers = Header::from(request_headers);
 
-        // Make and dispatch request
         let req = client
             .options("/?service=https://www.example.com&scope=all")
             .header(origin_header)
@@ -214,25 +950,66 @@ mod tests {
             .header(request_headers);
         let response = req.dispatch();
 
-        // Assert
         assert!(response.status().class().is_success());
-        let origin_header = response
+        response
             .headers()
             .get_one("Access-Control-Allow-Origin")
-            .expect("to exist");
-        assert_eq!("https://www.example.com", origin_header);
+            .expect("to exist")
+            .to_string()
+    }
+
+    #[test]
+    fn health_reports_signing_key_fingerprint() {
+        let mut configuration = make_configuration();
+        configuration.signature_algorithm = Some(jwt::jwa::SignatureAlgorithm::RS256);
+        configuration.secret = Secret::RSAKeyPair {
+            rsa_private: "test/fixtures/rsa_private_key.der".to_string(),
+            rsa_public: "test/fixtures/rsa_public_key.der".to_string(),
+        };
+        let fingerprint = not_none!(not_err!(configuration.signing_key_fingerprint()));
+        let rocket = ignite_with(configuration);
+        let client = not_err!(Client::new(rocket));
+
+        let mut response = client.get("/health").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+
+        let body_str = not_none!(response.body().and_then(|body| body.into_string()));
+        let body: serde_json::Value = not_err!(serde_json::from_str(&body_str));
+        assert_eq!(body["status"], "ok");
+        assert_eq!(body["signing_key_fingerprint"], fingerprint.as_str());
+    }
+
+    #[test]
+    fn health_does_not_report_hmac_secret_fingerprint() {
+        let client = not_err!(Client::new(ignite()));
+
+        let mut response = client.get("/health").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+
+        let body_str = not_none!(response.body().and_then(|body| body.into_string()));
+        let body: serde_json::Value = not_err!(serde_json::from_str(&body_str));
+        assert_eq!(body["status"], "ok");
+        assert_eq!(body["signing_key_fingerprint"], serde_json::Value::Null);
+    }
+
+    /// Ignite a rocket with the routes mounted, keeping the handle to its token configuration
+    fn ignite_reloadable() -> (Rocket, ::reload::ReloadHandle) {
+        let configuration = make_rowdy_configuration(make_configuration());
+
+        let (rocket, handle) = not_err!(configuration.ignite_reloadable());
+        (rocket.mount("/", routes()).catch(catchers()), handle)
     }
 
     #[test]
     #[allow(deprecated)]
-    fn token_getter_get_test() {
-        let rocket = ignite();
+    fn reloaded_keys_are_used_by_the_next_request() {
+        let (rocket, handle) = ignite_reloadable();
         let client = not_err!(Client::new(rocket));
 
-        // Make headers
-        let origin_header = Header::from(not_err!(
-            hyper::header::Origin::from_str("https://www.example.com")
-        ));
+        let mut configuration = make_configuration();
+        configuration.secret = Secret::ByteSequence(ByteSequence::String("rotated".to_string()));
+        not_err!(handle.reload(configuration));
+
         let auth_header = hyper::header::Authorization(auth::Basic {
             username: "mei".to_owned(),
             password: Some("冻住，不许走!".to_string()),
@@ -241,68 +1018,1690 @@ mod tests {
             "Authorization",
             hyper::header::HeaderFormatter(&auth_header).to_string(),
         );
-        // Make and dispatch request
-        let req = client
+        let mut response = client
             .get("/?service=https://www.example.com&scope=all")
-            .header(origin_header)
-            .header(auth_header);
-        let mut response = req.dispatch();
+            .header(auth_header)
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
 
-        // Assert
-        assert!(response.status().class().is_success());
         let body_str = not_none!(response.body().and_then(|body| body.into_string()));
-        let origin_header = response
-            .headers()
-            .get_one("Access-Control-Allow-Origin")
-            .expect("to exist");
-        assert_eq!("https://www.example.com", origin_header);
-
         let deserialized: Token<PrivateClaim> = not_err!(serde_json::from_str(&body_str));
-        let actual_token = not_err!(deserialized.decode(
+        let token = deserialized.decode(
             &jwt::jws::Secret::bytes_from_str("secret"),
             jwt::jwa::SignatureAlgorithm::HS512,
+        );
+        assert!(token.is_err());
+        let deserialized: Token<PrivateClaim> = not_err!(serde_json::from_str(&body_str));
+        let _ = not_err!(deserialized.decode(
+            &jwt::jws::Secret::bytes_from_str("rotated"),
+            jwt::jwa::SignatureAlgorithm::HS512,
         ));
+    }
 
-        assert!(actual_token.refresh_token.is_none());
+    #[test]
+    fn reloaded_allowed_origins_are_used_by_the_next_request() {
+        let (rocket, handle) = ignite_reloadable();
+
+        let mut configuration = make_configuration();
+        let (allowed_origins, _) = ::cors::AllowedOrigins::some(&["https://www.rotated.com"]);
+        configuration.allowed_origins = allowed_origins;
+        not_err!(handle.reload(configuration));
 
-        let registered = not_err!(actual_token.registered_claims());
-        assert_eq!(
-            Some(FromStr::from_str("https://www.acme.com").unwrap()),
-            registered.issuer
-        );
         assert_eq!(
-            Some(jwt::SingleOrMultiple::Single(
-                FromStr::from_str("https://www.example.com").unwrap(),
-            ),),
-            registered.audience
+            preflight_allowed_origin(rocket, "https://www.rotated.com"),
+            "https://www.rotated.com"
         );
+    }
 
-        // TODO: Test private claims
+    #[test]
+    #[cfg(feature = "metrics")]
+    fn metrics_of_the_managed_collector_are_exposed() {
+        struct Collector;
 
-        let header = not_err!(actual_token.header());
-        assert_eq!(
-            header.registered.algorithm,
-            jwt::jwa::SignatureAlgorithm::HS512
-        );
+        impl ::metrics::MetricsCollector for Collector {
+            fn render(&self, output: &mut String) {
+                output.push_str("collected_total 1\n");
+            }
+        }
+
+        let collector: Box<::metrics::MetricsCollector> = Box::new(Collector);
+        let rocket = ignite()
+            .manage(::metrics::Metrics::default())
+            .manage(collector)
+            .mount("/", metrics_routes());
+        let client = not_err!(Client::new(rocket));
+
+        let mut response = client.get("/metrics").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let body = not_none!(response.body().and_then(|body| body.into_string()));
+        assert!(body.contains("rowdy_tokens_issued_total 0\n"));
+        assert!(body.ends_with("collected_total 1\n"));
     }
 
     #[test]
-    #[allow(deprecated)]
-    fn token_getter_get_invalid_credentials() {
-        // Ignite rocket
+    fn ping_pong() {
         let rocket = ignite();
         let client = not_err!(Client::new(rocket));
 
-        // Make headers
-        let origin_header = Header::from(not_err!(
-            hyper::header::Origin::from_str("https://www.example.com")
-        ));
-        let auth_header = hyper::header::Authorization(auth::Basic {
-            username: "Aladin".to_owned(),
-            password: Some("let me in".to_string()),
-        });
-        let auth_header = Header::new(
-            "Authorization",
+        let req = client.get("/ping");
+        let mut response = req.dispatch();
+        let body_str = not_none!(response.body().and_then(|body| body.into_string()));
+
+        assert_eq!("Pong", body_str);
+        assert!(
+            response
+                .headers()
+                .get_one(::request_id::REQUEST_ID_HEADER)
+                .is_some()
+        );
+    }
+
+    #[test]
+    fn token_getter_options_test() {
+        let rocket = ignite();
+        let client = not_err!(Client::new(rocket));
+
+        // Make headers
+        let origin_header = Header::from(not_err!(
+            hyper::header::Origin::from_str("https://www.example.com")
+        ));
+        let method_header = Header::from(hyper::header::AccessControlRequestMethod(
+            hyper::method::Method::Get,
+        ));
+        let request_headers = hyper::header::AccessControlRequestHeaders(
+            vec![FromStr::from_str("Authorization").unwrap()],
+        );
+        let request_headers = Header::from(request_headers);
+
+        // Make and dispatch request
+        let req = client
+            .options("/?service=https://www.example.com&scope=all")
+            .header(origin_header)
+            .header(method_header)
+            .header(request_headers);
+        let response = req.dispatch();
+
+        // Assert
+        assert!(response.status().class().is_success());
+        let origin_header = response
+            .headers()
+            .get_one("Access-Control-Allow-Origin")
+            .expect("to exist");
+        assert_eq!("https://www.example.com", origin_header);
+    }
+
+    #[test]
+    fn token_getter_options_headers_are_sorted() {
+        let rocket = ignite();
+        let client = not_err!(Client::new(rocket));
+
+        let origin_header = Header::from(not_err!(
+            hyper::header::Origin::from_str("https://www.example.com")
+        ));
+        let method_header = Header::from(hyper::header::AccessControlRequestMethod(
+            hyper::method::Method::Get,
+        ));
+
+        let req = client
+            .options("/?service=https://www.example.com&scope=all")
+            .header(origin_header)
+            .header(method_header)
+            .header(Header::new(
+                "Access-Control-Request-Headers",
+                "Authorization, Accept-Language, Accept",
+            ));
+        let response = req.dispatch();
+
+        assert!(response.status().class().is_success());
+        let allow_headers = response
+            .headers()
+            .get_one("Access-Control-Allow-Headers")
+            .expect("to exist");
+        assert_eq!("Accept, Accept-Language, Authorization", allow_headers);
+    }
+
+    #[test]
+    fn token_getter_rejects_cross_site_requests_by_policy() {
+        use fetch_metadata::{SecFetchSite, SEC_FETCH_SITE_HEADER};
+
+        let mut configuration = make_configuration();
+        configuration.fetch_metadata.allowed_sites =
+            Some(vec![SecFetchSite::SameOrigin, SecFetchSite::SameSite]);
+        let rocket = ignite_with(configuration);
+        let client = not_err!(Client::new(rocket));
+
+        let preflight = |site: &'static str| {
+            client
+                .options("/?service=https://www.example.com&scope=all")
+                .header(Header::new("Origin", "https://www.example.com"))
+                .header(Header::new("Access-Control-Request-Method", "GET"))
+                .header(Header::new(SEC_FETCH_SITE_HEADER, site))
+                .dispatch()
+        };
+
+        assert!(preflight("same-site").status().class().is_success());
+
+        let mut response = preflight("cross-site");
+        assert_eq!(response.status(), Status::Forbidden);
+        let body_str = not_none!(response.body().and_then(|body| body.into_string()));
+        assert_eq!("Sec-Fetch-Site `cross-site` is not allowed", body_str);
+    }
+
+    #[test]
+    fn token_getter_options_names_rejected_method() {
+        let rocket = ignite();
+        let client = not_err!(Client::new(rocket));
+
+        let origin_header = Header::from(not_err!(
+            hyper::header::Origin::from_str("https://www.example.com")
+        ));
+        let method_header = Header::from(hyper::header::AccessControlRequestMethod(
+            hyper::method::Method::Post,
+        ));
+
+        let req = client
+            .options("/?service=https://www.example.com&scope=all")
+            .header(origin_header)
+            .header(method_header);
+        let mut response = req.dispatch();
+
+        assert_eq!(response.status(), Status::Forbidden);
+        let body_str = not_none!(response.body().and_then(|body| body.into_string()));
+        assert_eq!("Method `POST` is not allowed", body_str);
+    }
+
+    #[test]
+    fn token_getter_options_rejects_oversized_request_headers() {
+        let rocket = ignite();
+        let client = not_err!(Client::new(rocket));
+
+        let origin_header = Header::from(not_err!(
+            hyper::header::Origin::from_str("https://www.example.com")
+        ));
+        let method_header = Header::from(hyper::header::AccessControlRequestMethod(
+            hyper::method::Method::Get,
+        ));
+        let request_headers = vec!["Authorization"; 1000].join(",");
+
+        let req = client
+            .options("/?service=https://www.example.com&scope=all")
+            .header(origin_header)
+            .header(method_header)
+            .header(Header::new("Access-Control-Request-Headers", request_headers));
+        let mut response = req.dispatch();
+
+        assert_eq!(response.status(), Status::Forbidden);
+        assert!(
+            response
+                .headers()
+                .get_one("Access-Control-Allow-Origin")
+                .is_none()
+        );
+        let body_str = not_none!(response.body().and_then(|body| body.into_string()));
+        assert_eq!(
+            "Access-Control-Request-Headers is longer than 4096 bytes",
+            body_str
+        );
+    }
+
+    #[test]
+    fn token_getter_options_names_rejected_origin() {
+        let rocket = ignite();
+        let client = not_err!(Client::new(rocket));
+
+        let origin_header = Header::from(not_err!(
+            hyper::header::Origin::from_str("https://www.evil.com")
+        ));
+        let method_header = Header::from(hyper::header::AccessControlRequestMethod(
+            hyper::method::Method::Get,
+        ));
+
+        let req = client
+            .options("/?service=https://www.example.com&scope=all")
+            .header(origin_header)
+            .header(method_header);
+        let mut response = req.dispatch();
+
+        assert_eq!(response.status(), Status::Forbidden);
+        let body_str = not_none!(response.body().and_then(|body| body.into_string()));
+        assert_eq!("Origin `https://www.evil.com` is not allowed", body_str);
+    }
+
+    /// `CorsOptionsHandle::rejection_reason` matches requests against the CORS options itself, so
+    /// it must agree with `rocket_cors` on every kind of rejection, and on requests it allows
+    #[test]
+    fn cors_rejection_reasons_agree_with_rocket_cors() {
+        let rocket = ignite();
+        let cors_options = not_none!(rocket.state::<CorsOptionsHandle>()).clone();
+        let client = not_err!(Client::new(rocket));
+
+        // Whether the request is a preflight, its origin, requested method and headers, and the
+        // reason it is rejected for, if any
+        let cases: Vec<(bool, &str, &str, &str, Option<&str>)> = vec![
+            (true, "https://www.example.com", "GET", "Authorization", None),
+            (true, "https://www.example.com", "GET", "content-type", None),
+            (
+                true,
+                "https://www.evil.com",
+                "GET",
+                "Authorization",
+                Some("Origin `https://www.evil.com` is not allowed"),
+            ),
+            (
+                true,
+                "https://www.evil.com",
+                "POST",
+                "X-Evil",
+                Some("Origin `https://www.evil.com` is not allowed"),
+            ),
+            (
+                true,
+                "https://www.example.com",
+                "POST",
+                "Authorization",
+                Some("Method `POST` is not allowed"),
+            ),
+            (
+                true,
+                "https://www.example.com",
+                "GET",
+                "Authorization, X-Evil",
+                Some("Headers `X-Evil` are not allowed"),
+            ),
+            (false, "https://www.example.com", "", "", None),
+            (
+                false,
+                "https://www.evil.com",
+                "",
+                "",
+                Some("Origin `https://www.evil.com` is not allowed"),
+            ),
+        ];
+
+        for (preflight, origin, method, headers, expected_reason) in cases {
+            let request = || {
+                let request = if preflight {
+                    client
+                        .options("/?service=https://www.example.com&scope=all")
+                        .header(Header::new("Access-Control-Request-Method", method))
+                        .header(Header::new("Access-Control-Request-Headers", headers))
+                } else {
+                    client.get("/?service=https://www.example.com&scope=all")
+                };
+                request.header(Header::new("Origin", origin))
+            };
+
+            let reason = cors_options.rejection_reason(request().inner());
+            assert_eq!(reason.as_ref().map(String::as_str), expected_reason);
+
+            let mut response = request().dispatch();
+            match expected_reason {
+                Some(expected_reason) => {
+                    assert_eq!(response.status(), Status::Forbidden);
+                    let body_str = not_none!(response.body().and_then(|body| body.into_string()));
+                    assert_eq!(expected_reason, body_str);
+                }
+                None => assert_ne!(response.status(), Status::Forbidden),
+            }
+        }
+    }
+
+    #[get("/forbidden")]
+    fn host_forbidden() -> ::rocket::response::Failure {
+        ::rocket::response::Failure(Status::Forbidden)
+    }
+
+    #[test]
+    fn catchers_are_not_registered_for_host_applications() {
+        let configuration = make_rowdy_configuration(make_configuration());
+        let rocket = not_err!(::rocket(configuration)).mount("/app", routes![host_forbidden]);
+        let client = not_err!(Client::new(rocket));
+
+        // Rocket's default catcher responds, rather than the one of rowdy
+        let mut response = client.get("/app/forbidden").dispatch();
+        assert_eq!(response.status(), Status::Forbidden);
+        assert_eq!(response.content_type(), Some(ContentType::HTML));
+        let body_str = not_none!(response.body().and_then(|body| body.into_string()));
+        assert_ne!("Forbidden", body_str);
+    }
+
+    #[error(400)]
+    fn host_bad_request() -> &'static str {
+        "Bad request of the host"
+    }
+
+    #[test]
+    fn catchers_of_host_applications_are_kept() {
+        let configuration = make_rowdy_configuration(make_configuration());
+        let rocket = not_err!(::rocket(configuration)).catch(errors![host_bad_request]);
+        let client = not_err!(Client::new(rocket));
+
+        let mut response = client
+            .get("/?service=https://www.example.com&scope=all")
+            .header(Header::new("Authorization", "Basic !!!!"))
+            .dispatch();
+        assert_eq!(response.status(), Status::BadRequest);
+        let body_str = not_none!(response.body().and_then(|body| body.into_string()));
+        assert_eq!("Bad request of the host", body_str);
+    }
+
+    #[test]
+    fn allowed_origins_can_be_changed_at_runtime() {
+        let rocket = ignite();
+        let cors_options = not_none!(rocket.state::<CorsOptionsHandle>()).clone();
+        let client = not_err!(Client::new(rocket));
+
+        let preflight = |origin: &str| {
+            let origin_header = Header::from(not_err!(hyper::header::Origin::from_str(origin)));
+            let method_header = Header::from(hyper::header::AccessControlRequestMethod(
+                hyper::method::Method::Get,
+            ));
+            client
+                .options("/?service=https://www.example.com&scope=all")
+                .header(origin_header)
+                .header(method_header)
+                .dispatch()
+                .status()
+        };
+
+        assert_eq!(preflight("https://www.foobar.com"), Status::Forbidden);
+
+        let (allowed_origins, failed) = ::cors::AllowedOrigins::some(&["https://www.foobar.com"]);
+        assert!(failed.is_empty());
+        cors_options.set_allowed_origins(allowed_origins);
+
+        assert!(preflight("https://www.foobar.com").class().is_success());
+        assert_eq!(preflight("https://www.example.com"), Status::Forbidden);
+    }
+
+    #[test]
+    fn token_getter_options_sets_max_age() {
+        let rocket = ignite();
+        let client = not_err!(Client::new(rocket));
+
+        let origin_header = Header::from(not_err!(
+            hyper::header::Origin::from_str("https://www.example.com")
+        ));
+        let method_header = Header::from(hyper::header::AccessControlRequestMethod(
+            hyper::method::Method::Get,
+        ));
+
+        let req = client
+            .options("/?service=https://www.example.com&scope=all")
+            .header(origin_header)
+            .header(method_header);
+        let response = req.dispatch();
+
+        assert!(response.status().class().is_success());
+        let max_age_header = response
+            .headers()
+            .get_one("Access-Control-Max-Age")
+            .expect("to exist");
+        assert_eq!("600", max_age_header);
+    }
+
+    /// Request headers are matched case-insensitively against the allowed headers, but the
+    /// casing of the requested header is echoed back as is
+    #[test]
+    fn token_getter_options_matches_headers_case_insensitively() {
+        let rocket = ignite();
+        let client = not_err!(Client::new(rocket));
+
+        let origin_header = Header::from(not_err!(
+            hyper::header::Origin::from_str("https://www.example.com")
+        ));
+        let method_header = Header::from(hyper::header::AccessControlRequestMethod(
+            hyper::method::Method::Get,
+        ));
+        let request_headers = hyper::header::AccessControlRequestHeaders(
+            vec![FromStr::from_str("content-type").unwrap()],
+        );
+        let request_headers = Header::from(request_headers);
+
+        let req = client
+            .options("/?service=https://www.example.com&scope=all")
+            .header(origin_header)
+            .header(method_header)
+            .header(request_headers);
+        let response = req.dispatch();
+
+        assert!(response.status().class().is_success());
+        let allow_headers = response
+            .headers()
+            .get_one("Access-Control-Allow-Headers")
+            .expect("to exist");
+        assert_eq!("content-type", allow_headers);
+    }
+
+    #[test]
+    fn all_origins_with_credentials_reflects_origin() {
+        let mut configuration = make_configuration();
+        configuration.allowed_origins = ::cors::AllOrSome::All;
+        configuration.allow_credentials = true;
+
+        let allowed_origin =
+            preflight_allowed_origin(ignite_with(configuration), "https://www.foobar.com");
+        assert_eq!("https://www.foobar.com", allowed_origin);
+    }
+
+    #[test]
+    fn configured_origins_are_normalized() {
+        let mut configuration = make_configuration();
+        let (allowed_origins, _) = ::cors::AllowedOrigins::some(&["https://www.foobar.com/app/"]);
+        configuration.allowed_origins = allowed_origins;
+
+        let allowed_origin =
+            preflight_allowed_origin(ignite_with(configuration), "https://www.foobar.com");
+        assert_eq!("https://www.foobar.com", allowed_origin);
+    }
+
+    #[test]
+    fn ipv6_and_ported_origins_are_allowed() {
+        let mut configuration = make_configuration();
+        let (allowed_origins, _) =
+            ::cors::AllowedOrigins::some(&["http://[::1]:3000/", "http://127.0.0.1:3000"]);
+        configuration.allowed_origins = allowed_origins;
+
+        for origin in &["http://[::1]:3000", "http://127.0.0.1:3000"] {
+            let allowed_origin =
+                preflight_allowed_origin(ignite_with(configuration.clone()), origin);
+            assert_eq!(*origin, allowed_origin);
+        }
+    }
+
+    #[test]
+    fn all_origins_without_credentials_sends_wildcard() {
+        let mut configuration = make_configuration();
+        configuration.allowed_origins = ::cors::AllOrSome::All;
+        configuration.allow_credentials = false;
+
+        let allowed_origin =
+            preflight_allowed_origin(ignite_with(configuration), "https://www.foobar.com");
+        assert_eq!("*", allowed_origin);
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn token_responses_are_not_cached() {
+        let rocket = ignite();
+        let client = not_err!(Client::new(rocket));
+
+        let auth_header = hyper::header::Authorization(auth::Basic {
+            username: "mei".to_owned(),
+            password: Some("冻住，不许走!".to_string()),
+        });
+        let auth_header = Header::new(
+            "Authorization",
+            hyper::header::HeaderFormatter(&auth_header).to_string(),
+        );
+        let response = client
+            .get("/?service=https://www.example.com&scope=all")
+            .header(auth_header)
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+
+        let headers = response.headers();
+        assert_eq!(headers.get_one("Cache-Control"), Some("no-store"));
+        assert_eq!(headers.get_one("Pragma"), Some("no-cache"));
+        assert_eq!(headers.get_one("Content-Type"), Some("application/json"));
+        assert_eq!(headers.get_one("X-Content-Type-Options"), Some("nosniff"));
+
+        // Authentication failures must not be cached either
+        let response = client
+            .get("/?service=https://www.example.com&scope=all")
+            .dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+        assert_eq!(response.headers().get_one("Cache-Control"), Some("no-store"));
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn token_getter_get_test() {
+        let rocket = ignite();
+        let client = not_err!(Client::new(rocket));
+
+        // Make headers
+        let origin_header = Header::from(not_err!(
+            hyper::header::Origin::from_str("https://www.example.com")
+        ));
+        let auth_header = hyper::header::Authorization(auth::Basic {
+            username: "mei".to_owned(),
+            password: Some("冻住，不许走!".to_string()),
+        });
+        let auth_header = Header::new(
+            "Authorization",
+            hyper::header::HeaderFormatter(&auth_header).to_string(),
+        );
+        // Make and dispatch request
+        let req = client
+            .get("/?service=https://www.example.com&scope=all")
+            .header(origin_header)
+            .header(auth_header);
+        let mut response = req.dispatch();
+
+        // Assert
+        assert!(response.status().class().is_success());
+        let body_str = not_none!(response.body().and_then(|body| body.into_string()));
+        let origin_header = response
+            .headers()
+            .get_one("Access-Control-Allow-Origin")
+            .expect("to exist");
+        assert_eq!("https://www.example.com", origin_header);
+
+        let deserialized: Token<PrivateClaim> = not_err!(serde_json::from_str(&body_str));
+        let actual_token = not_err!(deserialized.decode(
+            &jwt::jws::Secret::bytes_from_str("secret"),
+            jwt::jwa::SignatureAlgorithm::HS512,
+        ));
+
+        assert!(actual_token.refresh_token.is_none());
+
+        let registered = not_err!(actual_token.registered_claims());
+        assert_eq!(
+            Some(FromStr::from_str("https://www.acme.com").unwrap()),
+            registered.issuer
+        );
+        assert_eq!(
+            Some(jwt::SingleOrMultiple::Single(
+                FromStr::from_str("https://www.example.com").unwrap(),
+            ),),
+            registered.audience
+        );
+
+        // TODO: Test private claims
+
+        let header = not_err!(actual_token.header());
+        assert_eq!(
+            header.registered.algorithm,
+            jwt::jwa::SignatureAlgorithm::HS512
+        );
+    }
+
+    #[test]
+    fn token_getter_rejects_private_claims_not_matching_claims_type() {
+        #[derive(Serialize, Deserialize)]
+        struct TenantClaims {
+            tenant: String,
+        }
+
+        let configuration = make_rowdy_configuration(make_configuration());
+        let (reloadable, _) =
+            not_err!(configuration.ignite_reloadable_with_claims::<TenantClaims>());
+        let rockets = vec![
+            not_err!(configuration.ignite_with_claims::<TenantClaims>()),
+            reloadable,
+        ];
+
+        for rocket in rockets {
+            let client = not_err!(Client::new(rocket.mount("/", routes())));
+
+            let req = client
+                .get("/?service=https://www.example.com&scope=all")
+                .header(client_header());
+            let response = req.dispatch();
+
+            // The mock authenticator does not provide a `tenant` claim
+            assert_eq!(response.status(), Status::InternalServerError);
+        }
+    }
+
+    #[test]
+    fn token_getter_applies_claims_transform() {
+        let transform = |mut result: auth::AuthenticationResult| -> Result<_, ::Error> {
+            result.subject = format!("user:{}", result.subject);
+            Ok(result)
+        };
+        let transform: Box<auth::ClaimsTransform> = Box::new(transform);
+        let rocket = ignite().manage(transform);
+        let client = not_err!(Client::new(rocket));
+
+        let req = client
+            .get("/?service=https://www.example.com&scope=all")
+            .header(client_header());
+        let mut response = req.dispatch();
+
+        assert!(response.status().class().is_success());
+        let body_str = not_none!(response.body().and_then(|body| body.into_string()));
+        let deserialized: Token<PrivateClaim> = not_err!(serde_json::from_str(&body_str));
+        let actual_token = not_err!(deserialized.decode(
+            &jwt::jws::Secret::bytes_from_str("secret"),
+            jwt::jwa::SignatureAlgorithm::HS512,
+        ));
+
+        let registered = not_err!(actual_token.registered_claims());
+        assert_eq!(
+            Some(FromStr::from_str("user:mei").unwrap()),
+            registered.subject
+        );
+    }
+
+    #[test]
+    fn client_credentials_issues_service_token() {
+        let client = not_err!(Client::new(ignite()));
+
+        let req = client
+            .get("/?grant_type=client_credentials&service=https://www.example.com&scope=all")
+            .header(client_header());
+        let mut response = req.dispatch();
+
+        assert!(response.status().class().is_success());
+        let body_str = not_none!(response.body().and_then(|body| body.into_string()));
+        let deserialized: Token<PrivateClaim> = not_err!(serde_json::from_str(&body_str));
+        assert!(deserialized.refresh_token.is_none());
+
+        let actual_token = not_err!(deserialized.decode(
+            &jwt::jws::Secret::bytes_from_str("secret"),
+            jwt::jwa::SignatureAlgorithm::HS512,
+        ));
+        let private_claims = not_err!(actual_token.private_claims());
+        assert_eq!(private_claims["token_type"], "service");
+        assert!(not_err!(actual_token.header()).private.is_service_token());
+
+        // Service tokens expire after the shorter `service_token_duration`
+        assert_eq!(actual_token.expires_in, Duration::from_secs(60));
+        let registered = not_err!(actual_token.registered_claims());
+        let issued_at = not_none!(registered.issued_at.as_ref()).timestamp();
+        let expiry = not_none!(registered.expiry.as_ref()).timestamp();
+        assert_eq!(expiry - issued_at, 60);
+    }
+
+    #[test]
+    fn client_credentials_requires_client_authenticator() {
+        let configuration = make_rowdy_configuration(make_configuration());
+        let rocket = not_err!(configuration.ignite()).mount("/", routes());
+        let client = not_err!(Client::new(rocket));
+
+        let req = client
+            .get("/?grant_type=client_credentials&service=https://www.example.com&scope=all")
+            .header(client_header());
+        let response = req.dispatch();
+
+        assert_eq!(response.status(), Status::BadRequest);
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn authentication_outcomes_are_recorded_in_metrics() {
+        let rocket = ignite().manage(::metrics::Metrics::default());
+        let client = not_err!(Client::new(rocket));
+
+        for &(password, status) in &[
+            ("冻住，不许走!", Status::Ok),
+            ("let me in", Status::Unauthorized),
+        ] {
+            let auth_header = hyper::header::Authorization(auth::Basic {
+                username: "mei".to_owned(),
+                password: Some(password.to_string()),
+            });
+            let auth_header = Header::new(
+                "Authorization",
+                hyper::header::HeaderFormatter(&auth_header).to_string(),
+            );
+            let req = client
+                .get("/?service=https://www.example.com&scope=all")
+                .header(auth_header);
+            assert_eq!(req.dispatch().status(), status);
+        }
+
+        let metrics = not_none!(client.rocket().state::<::metrics::Metrics>());
+        assert_eq!(metrics.authentication_successes(), 1);
+        assert_eq!(metrics.authentication_failures(), 1);
+        assert_eq!(metrics.tokens_issued(), 1);
+        assert_eq!(metrics.token_refreshes(), 0);
+    }
+
+    #[get("/claims")]
+    fn claims(claims: auth::AuthenticatedClaims<PrivateClaim>) -> String {
+        claims.registered.subject.as_ref().unwrap().to_string()
+    }
+
+    struct Admin;
+
+    impl auth::Scope for Admin {
+        fn scope() -> &'static str {
+            "admin"
+        }
+    }
+
+    #[get("/admin")]
+    fn admin(claims: auth::ScopedClaims<PrivateClaim, Admin>) -> String {
+        claims.registered.subject.as_ref().unwrap().to_string()
+    }
+
+    struct RepoRead;
+
+    impl auth::Scope for RepoRead {
+        fn scope() -> &'static str {
+            "repo:read"
+        }
+
+        fn hierarchical() -> bool {
+            true
+        }
+    }
+
+    #[get("/repo")]
+    fn repo(claims: auth::ScopedClaims<PrivateClaim, RepoRead>) -> String {
+        claims.registered.subject.as_ref().unwrap().to_string()
+    }
+
+    struct ExactRepoRead;
+
+    impl auth::Scope for ExactRepoRead {
+        fn scope() -> &'static str {
+            "repo:read"
+        }
+    }
+
+    #[get("/repo/exact")]
+    fn exact_repo(claims: auth::ScopedClaims<PrivateClaim, ExactRepoRead>) -> String {
+        claims.registered.subject.as_ref().unwrap().to_string()
+    }
+
+    /// Responds with a CORS header already set
+    #[get("/preset_cors")]
+    fn preset_cors() -> ::rocket::response::Response<'static> {
+        ::rocket::response::Response::build()
+            .raw_header("Access-Control-Allow-Origin", "https://www.foobar.com")
+            .finalize()
+    }
+
+    #[test]
+    fn cors_headers_set_by_responders_are_replaced() {
+        let client = not_err!(Client::new(ignite().mount("/", routes![preset_cors])));
+        let origin_header = Header::from(not_err!(
+            hyper::header::Origin::from_str("https://www.example.com")
+        ));
+
+        let response = client.get("/preset_cors").header(origin_header).dispatch();
+        assert!(response.status().class().is_success());
+        let origin_headers: Vec<_> = response
+            .headers()
+            .get("Access-Control-Allow-Origin")
+            .collect();
+        assert_eq!(origin_headers, vec!["https://www.example.com"]);
+    }
+
+    /// Issue an access token for `mei` with the Basic authentication route
+    #[allow(deprecated)]
+    fn issue_token(client: &Client) -> String {
+        let auth_header = hyper::header::Authorization(auth::Basic {
+            username: "mei".to_owned(),
+            password: Some("冻住，不许走!".to_string()),
+        });
+        let auth_header = Header::new(
+            "Authorization",
+            hyper::header::HeaderFormatter(&auth_header).to_string(),
+        );
+        let req = client
+            .get("/?service=https://www.example.com&scope=all")
+            .header(auth_header);
+        let mut response = req.dispatch();
+        assert!(response.status().class().is_success());
+        let body_str = not_none!(response.body().and_then(|body| body.into_string()));
+        let token: Token<PrivateClaim> = not_err!(serde_json::from_str(&body_str));
+        not_err!(token.encoded_token())
+    }
+
+    #[allow(deprecated)]
+    fn bearer_header(token: String) -> Header<'static> {
+        let auth_header = hyper::header::Authorization(auth::Bearer { token: token });
+        Header::new(
+            "Authorization",
+            hyper::header::HeaderFormatter(&auth_header).to_string(),
+        )
+    }
+
+    #[test]
+    fn authenticated_claims_guard_validates_bearer_tokens() {
+        let rocket = ignite().mount("/", routes![claims]);
+        let client = not_err!(Client::new(rocket));
+        let token = issue_token(&client);
+
+        for (token, status) in vec![
+            (token, Status::Ok),
+            ("这样可以挡住他们。".to_string(), Status::Unauthorized),
+        ] {
+            let mut response = client.get("/claims").header(bearer_header(token)).dispatch();
+            assert_eq!(response.status(), status);
+            if status == Status::Ok {
+                let body_str = not_none!(response.body().and_then(|body| body.into_string()));
+                assert_eq!(body_str, "mei");
+            }
+        }
+
+        let response = client.get("/claims").dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+    }
+
+    #[test]
+    fn authenticated_claims_guard_accepts_alternative_signatures() {
+        use jwt::jwa::SignatureAlgorithm::{HS256, RS256};
+
+        let mut configuration = make_configuration();
+        configuration.alternative_signatures = vec![
+            token::AlternativeSignature {
+                signature_algorithm: RS256,
+                secret: Secret::RSAKeyPair {
+                    rsa_private: "test/fixtures/rsa_private_key.der".to_string(),
+                    rsa_public: "test/fixtures/rsa_public_key.der".to_string(),
+                },
+            },
+        ];
+        let keys = not_err!(configuration.keys());
+        let make_encoded_token = |signature_algorithm| -> String {
+            let token = not_err!(Token::<PrivateClaim>::with_signature_algorithm(
+                &configuration,
+                "mei",
+                "https://www.example.com",
+                &[],
+                Default::default(),
+                None,
+                Some(signature_algorithm),
+            ));
+            let key = not_err!(configuration.signing_key(&keys, Some(signature_algorithm)));
+            not_err!(not_err!(token.encode(key)).encoded_token())
+        };
+        let alternative = make_encoded_token(RS256);
+        // Signed with the HMAC `secret`, but with an algorithm that is not configured
+        let unconfigured = make_encoded_token(HS256);
+
+        let rocket = ignite_with(configuration.clone()).mount("/", routes![claims]);
+        let client = not_err!(Client::new(rocket));
+        let mut response = client
+            .get("/claims")
+            .header(bearer_header(alternative))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let body_str = not_none!(response.body().and_then(|body| body.into_string()));
+        assert_eq!(body_str, "mei");
+
+        let response = client
+            .get("/claims")
+            .header(bearer_header(unconfigured))
+            .dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+    }
+
+    #[test]
+    fn authenticated_claims_guard_uses_directly_managed_keys() {
+        let token = issue_token(&not_err!(Client::new(ignite())));
+        let configuration = make_configuration();
+        let keys = not_err!(configuration.keys());
+
+        let rocket = ::rocket::ignite()
+            .manage(configuration.clone())
+            .manage(keys)
+            .mount("/", routes![claims]);
+        let client = not_err!(Client::new(rocket));
+        let response = client
+            .get("/claims")
+            .header(bearer_header(token.clone()))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+
+        // The key files are never read by the guard, so it fails without prepared keys
+        let rocket = ::rocket::ignite()
+            .manage(configuration)
+            .mount("/", routes![claims]);
+        let client = not_err!(Client::new(rocket));
+        let response = client.get("/claims").header(bearer_header(token)).dispatch();
+        assert_eq!(response.status(), Status::InternalServerError);
+    }
+
+    #[test]
+    fn tokens_signed_with_rotated_out_keys_are_rejected_after_reloading() {
+        let configuration = make_rowdy_configuration(make_configuration());
+        let (rocket, handle) = not_err!(configuration.ignite_reloadable());
+        let client = not_err!(Client::new(rocket.mount("/", routes()).mount("/", routes![claims])));
+        let token = issue_token(&client);
+
+        let response = client
+            .get("/claims")
+            .header(bearer_header(token.clone()))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+
+        let mut rotated = make_configuration();
+        rotated.secret = Secret::ByteSequence(ByteSequence::String("rotated".to_string()));
+        not_err!(handle.reload(rotated));
+
+        let response = client
+            .get("/claims")
+            .header(bearer_header(token.clone()))
+            .dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+
+        // Tokens issued after the reload are signed with the new key
+        let token = issue_token(&client);
+        let response = client.get("/claims").header(bearer_header(token)).dispatch();
+        assert_eq!(response.status(), Status::Ok);
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn logins_and_revocations_are_audited() {
+        use std::sync::mpsc;
+        use audit::{AuditOutcome, ChannelAuditSink};
+
+        let (sender, receiver) = mpsc::channel();
+        let audit_sink: Box<AuditSink> = Box::new(ChannelAuditSink::new(sender));
+        let client = not_err!(Client::new(ignite().manage(audit_sink)));
+        issue_token(&client);
+
+        let event = not_err!(receiver.try_recv());
+        assert_eq!(event.event, AuditEventKind::Login);
+        assert_eq!(event.subject, Some("mei".to_string()));
+        assert_eq!(event.outcome, AuditOutcome::Success);
+
+        let auth_header = hyper::header::Authorization(auth::Basic {
+            username: "mei".to_owned(),
+            password: Some("wrong".to_string()),
+        });
+        let response = client
+            .get("/?service=https://www.example.com&scope=all")
+            .header(Header::new(
+                "Authorization",
+                hyper::header::HeaderFormatter(&auth_header).to_string(),
+            ))
+            .dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+        let event = not_err!(receiver.try_recv());
+        assert_eq!(event.event, AuditEventKind::Login);
+        assert_eq!(event.subject, Some("mei".to_string()));
+        assert_eq!(event.outcome, AuditOutcome::Failure);
+
+        let response = client
+            .post("/sessions/revoke")
+            .header(client_header())
+            .dispatch();
+        assert_eq!(response.status(), Status::NoContent);
+        let event = not_err!(receiver.try_recv());
+        assert_eq!(event.event, AuditEventKind::TokenRevocation);
+        assert_eq!(event.subject, Some("mei".to_string()));
+        assert_eq!(event.outcome, AuditOutcome::Success);
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn sessions_are_revoked_with_credentials() {
+        let client = not_err!(Client::new(ignite()));
+
+        let response = client
+            .post("/sessions/revoke")
+            .header(client_header())
+            .dispatch();
+        assert_eq!(response.status(), Status::NoContent);
+        assert_eq!(auth::tests::invalidated_sessions(), vec!["mei".to_string()]);
+
+        let auth_header = hyper::header::Authorization(auth::Basic {
+            username: "mei".to_owned(),
+            password: Some("wrong".to_string()),
+        });
+        let response = client
+            .post("/sessions/revoke")
+            .header(Header::new(
+                "Authorization",
+                hyper::header::HeaderFormatter(&auth_header).to_string(),
+            ))
+            .dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+
+        let response = client.post("/sessions/revoke").dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+        assert_eq!(auth::tests::invalidated_sessions(), vec!["mei".to_string()]);
+    }
+
+    #[test]
+    fn sessions_are_revoked_for_the_username_when_the_subject_is_transformed() {
+        let transform = |mut result: auth::AuthenticationResult| -> Result<_, ::Error> {
+            result.subject = format!("user:{}", result.subject);
+            Ok(result)
+        };
+        let transform: Box<auth::ClaimsTransform> = Box::new(transform);
+        let client = not_err!(Client::new(ignite().manage(transform)));
+
+        let response = client
+            .post("/sessions/revoke")
+            .header(client_header())
+            .dispatch();
+        assert_eq!(response.status(), Status::NoContent);
+        assert_eq!(auth::tests::invalidated_sessions(), vec!["mei".to_string()]);
+    }
+
+    /// `Basic` authentication with the client credentials accepted by the mock authenticator
+    #[allow(deprecated)]
+    fn client_header() -> Header<'static> {
+        let auth_header = hyper::header::Authorization(auth::Basic {
+            username: "mei".to_owned(),
+            password: Some("冻住，不许走!".to_string()),
+        });
+        Header::new(
+            "Authorization",
+            hyper::header::HeaderFormatter(&auth_header).to_string(),
+        )
+    }
+
+    /// Introspect a token as an authenticated client, and return the response
+    fn introspect_token(client: &Client, token: &str) -> serde_json::Value {
+        let mut response = client
+            .post("/introspect")
+            .header(ContentType::Form)
+            .header(client_header())
+            .body(format!("token={}", token))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let body_str = not_none!(response.body().and_then(|body| body.into_string()));
+        not_err!(serde_json::from_str(&body_str))
+    }
+
+    #[test]
+    fn tokens_are_introspected() {
+        let client = not_err!(Client::new(ignite()));
+        let token = issue_token(&client);
+
+        let introspection = introspect_token(&client, &token);
+        assert_eq!(introspection["active"], true);
+        assert_eq!(introspection["sub"], "mei");
+        assert_eq!(introspection["aud"], "https://www.example.com");
+
+        let introspection = introspect_token(&client, "not-a-token");
+        assert_eq!(introspection["active"], false);
+        assert_eq!(not_none!(introspection.as_object()).len(), 1);
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn introspection_requires_client_authentication() {
+        let client = not_err!(Client::new(ignite()));
+        let token = issue_token(&client);
+        let introspect = |authorization: Option<Header<'static>>| {
+            let mut request = client
+                .post("/introspect")
+                .header(ContentType::Form)
+                .body(format!("token={}", token));
+            if let Some(authorization) = authorization {
+                request.add_header(authorization);
+            }
+            request.dispatch().status()
+        };
+
+        assert_eq!(introspect(None), Status::Unauthorized);
+        assert_eq!(introspect(Some(client_header())), Status::Ok);
+
+        let wrong_secret = hyper::header::Authorization(auth::Basic {
+            username: "mei".to_owned(),
+            password: Some("wrong".to_string()),
+        });
+        let wrong_secret = Header::new(
+            "Authorization",
+            hyper::header::HeaderFormatter(&wrong_secret).to_string(),
+        );
+        assert_eq!(introspect(Some(wrong_secret)), Status::Unauthorized);
+
+        // Users cannot introspect tokens with their access tokens
+        assert_eq!(introspect(Some(bearer_header(token.clone()))), Status::Forbidden);
+
+        // Clients can with their service tokens
+        let mut response = client
+            .get("/?grant_type=client_credentials&service=https://www.example.com&scope=all")
+            .header(client_header())
+            .dispatch();
+        let body_str = not_none!(response.body().and_then(|body| body.into_string()));
+        let service_token: Token<PrivateClaim> = not_err!(serde_json::from_str(&body_str));
+        let service_token = not_err!(service_token.encoded_token());
+        assert_eq!(introspect(Some(bearer_header(service_token))), Status::Ok);
+    }
+
+    #[test]
+    fn user_tokens_with_service_token_claims_cannot_introspect() {
+        let client = not_err!(Client::new(ignite()));
+        let token = issue_token(&client);
+
+        // The claims of a user token can say anything, such as when set by a claims template
+        let configuration = make_configuration();
+        let private_claims = not_err!(serde_json::from_str(r#"{"token_type":"service"}"#));
+        let forged = not_err!(Token::<PrivateClaim>::with_configuration(
+            &configuration,
+            "mei",
+            "https://www.example.com",
+            private_claims,
+            None,
+        ));
+        let forged = not_err!(forged.encode(&jwt::jws::Secret::bytes_from_str("secret")));
+        let forged = not_err!(forged.encoded_token());
+
+        let response = client
+            .post("/introspect")
+            .header(ContentType::Form)
+            .header(bearer_header(forged))
+            .body(format!("token={}", token))
+            .dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+    }
+
+    #[test]
+    fn oversized_request_bodies_are_refused() {
+        let mut configuration = make_configuration();
+        configuration.request_body_limit = 64;
+        let client = not_err!(Client::new(ignite_with(configuration)));
+
+        let token = "a".repeat(128);
+        let response = client
+            .post("/introspect")
+            .header(ContentType::Form)
+            .header(client_header())
+            .body(format!("token={}", token))
+            .dispatch();
+        assert_eq!(response.status(), Status::PayloadTooLarge);
+
+        let response = client
+            .post("/introspect")
+            .header(ContentType::Form)
+            .header(client_header())
+            .header(Header::new("Content-Length", "1048576"))
+            .body("token=a")
+            .dispatch();
+        assert_eq!(response.status(), Status::PayloadTooLarge);
+
+        let response = client
+            .post("/sessions/revoke")
+            .header(Header::new("Content-Length", "1048576"))
+            .dispatch();
+        assert_eq!(response.status(), Status::PayloadTooLarge);
+
+        // Bodies within the limit are read
+        let introspection = introspect_token(&client, "not-a-token");
+        assert_eq!(introspection["active"], false);
+    }
+
+    #[test]
+    fn nonces_are_echoed_in_tokens() {
+        let client = not_err!(Client::new(ignite()));
+        let request_token = |query: &str| {
+            let mut response = client
+                .get(format!("/?service=https://www.example.com&scope=all{}", query))
+                .header(client_header())
+                .dispatch();
+            let status = response.status();
+            let body = response.body().and_then(|body| body.into_string());
+            (status, body)
+        };
+
+        let (status, body) = request_token("&nonce=n-0S6_WzA2Mj");
+        assert_eq!(status, Status::Ok);
+        let token: Token<PrivateClaim> = not_err!(serde_json::from_str(&not_none!(body)));
+        let encoded = not_err!(token.encoded_token());
+        let token = not_err!(token.decode(
+            &jwt::jws::Secret::bytes_from_str("secret"),
+            jwt::jwa::SignatureAlgorithm::HS512,
+        ));
+        assert_eq!(not_err!(token.nonce()), Some("n-0S6_WzA2Mj".to_string()));
+
+        let introspection = introspect_token(&client, &encoded);
+        assert_eq!(introspection["nonce"], "n-0S6_WzA2Mj");
+
+        // Tokens requested without a nonce do not have the claim
+        let (status, body) = request_token("");
+        assert_eq!(status, Status::Ok);
+        let token: Token<PrivateClaim> = not_err!(serde_json::from_str(&not_none!(body)));
+        let token = not_err!(token.decode(
+            &jwt::jws::Secret::bytes_from_str("secret"),
+            jwt::jwa::SignatureAlgorithm::HS512,
+        ));
+        assert_eq!(not_err!(token.nonce()), None);
+
+        // Nonces are size limited
+        let nonce = "a".repeat(token::MAX_NONCE_LENGTH + 1);
+        let (status, _) = request_token(&format!("&nonce={}", nonce));
+        assert_eq!(status, Status::BadRequest);
+        let (status, _) = request_token("&nonce=");
+        assert_eq!(status, Status::BadRequest);
+    }
+
+    #[test]
+    fn plaintext_token_requests_are_rejected_when_https_is_enforced() {
+        let mut configuration = make_configuration();
+        configuration.https = ::https::HttpsPolicy::Enforce;
+        configuration.trust_proxy = true;
+        let client = not_err!(Client::new(ignite_with(configuration)));
+
+        let mut response = client
+            .get("/?service=https://www.example.com&scope=all")
+            .header(client_header())
+            .dispatch();
+        assert_eq!(response.status(), Status::Forbidden);
+        let body_str = not_none!(response.body().and_then(|body| body.into_string()));
+        assert!(body_str.contains("HTTPS is required"), "{}", body_str);
+
+        let response = client
+            .get("/?service=https://www.example.com&scope=all")
+            .header(client_header())
+            .header(Header::new(::https::X_FORWARDED_PROTO_HEADER, "https"))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+    }
+
+    #[test]
+    fn plaintext_session_revocations_are_rejected_when_https_is_enforced() {
+        let mut configuration = make_configuration();
+        configuration.https = ::https::HttpsPolicy::Enforce;
+        configuration.trust_proxy = true;
+        let client = not_err!(Client::new(ignite_with(configuration)));
+
+        let mut response = client
+            .post("/sessions/revoke")
+            .header(client_header())
+            .dispatch();
+        assert_eq!(response.status(), Status::Forbidden);
+        let body_str = not_none!(response.body().and_then(|body| body.into_string()));
+        assert!(body_str.contains("HTTPS is required"), "{}", body_str);
+        assert!(auth::tests::invalidated_sessions().is_empty());
+
+        let response = client
+            .post("/sessions/revoke")
+            .header(client_header())
+            .header(Header::new(::https::X_FORWARDED_PROTO_HEADER, "https"))
+            .dispatch();
+        assert_eq!(response.status(), Status::NoContent);
+    }
+
+    #[test]
+    fn plaintext_introspection_requests_are_rejected_when_https_is_enforced() {
+        let mut configuration = make_configuration();
+        configuration.https = ::https::HttpsPolicy::Enforce;
+        configuration.trust_proxy = true;
+        let client = not_err!(Client::new(ignite_with(configuration)));
+
+        let mut response = client
+            .post("/introspect")
+            .header(ContentType::Form)
+            .header(client_header())
+            .body("token=not-a-token")
+            .dispatch();
+        assert_eq!(response.status(), Status::Forbidden);
+        let body_str = not_none!(response.body().and_then(|body| body.into_string()));
+        assert!(body_str.contains("HTTPS is required"), "{}", body_str);
+
+        let response = client
+            .post("/introspect")
+            .header(ContentType::Form)
+            .header(client_header())
+            .header(Header::new(::https::X_FORWARDED_PROTO_HEADER, "https"))
+            .body("token=not-a-token")
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+    }
+
+    #[test]
+    fn additional_audiences_are_requested() {
+        let mut configuration = make_configuration();
+        configuration.additional_audiences =
+            Some(vec![not_err!(FromStr::from_str("https://api.example.com"))]);
+        let client = not_err!(Client::new(ignite_with(configuration)));
+
+        let request_token = |audience: &str| {
+            client
+                .get(format!(
+                    "/?service=https://www.example.com&scope=all&audience={}",
+                    audience
+                ))
+                .header(client_header())
+                .dispatch()
+        };
+
+        let mut response = request_token("https://api.example.com");
+        assert_eq!(response.status(), Status::Ok);
+        let body_str = not_none!(response.body().and_then(|body| body.into_string()));
+        let token: Token<PrivateClaim> = not_err!(serde_json::from_str(&body_str));
+        let introspection = introspect_token(&client, &not_err!(token.encoded_token()));
+        assert_eq!(introspection["active"], true);
+        assert_eq!(
+            introspection["aud"],
+            json_array(&["https://www.example.com", "https://api.example.com"])
+        );
+
+        let response = request_token("https://www.evil.com");
+        assert_eq!(response.status(), Status::Forbidden);
+    }
+
+    fn json_array(values: &[&str]) -> serde_json::Value {
+        serde_json::Value::Array(values.iter().map(|value| From::from(*value)).collect())
+    }
+
+    #[test]
+    fn private_claims_are_rendered_from_the_claims_template() {
+        let mut configuration = make_configuration();
+        configuration.claims_template = Some(not_err!(serde_json::from_str(
+            r#"{
+                "user": "{{username}}",
+                "profile": "https://www.example.com/users/{{subject}}",
+                "team": "{{claims.team}}"
+            }"#
+        )));
+        let client = not_err!(Client::new(ignite_with(configuration)));
+        let token = issue_token(&client);
+
+        let introspection = introspect_token(&client, &token);
+        assert_eq!(introspection["user"], "mei");
+        assert_eq!(introspection["profile"], "https://www.example.com/users/mei");
+        // Missing claims are rendered as `null`, which is omitted from the token
+        assert!(introspection.get("team").is_none());
+    }
+
+    #[test]
+    fn user_tokens_cannot_claim_a_token_type() {
+        let mut configuration = make_configuration();
+        configuration.claims_template = Some(not_err!(serde_json::from_str(
+            r#"{ "user": "{{username}}", "token_type": "service" }"#
+        )));
+        let client = not_err!(Client::new(ignite_with(configuration)));
+        let token = issue_token(&client);
+
+        let introspection = introspect_token(&client, &token);
+        assert_eq!(introspection["user"], "mei");
+        assert!(introspection.get("token_type").is_none());
+    }
+
+    #[test]
+    fn service_token_scope_is_narrowed_to_the_requested_scope() {
+        let mut configuration = make_configuration();
+        configuration.claims_template = Some(not_err!(serde_json::from_str(
+            r#"{ "scope": "read write admin" }"#
+        )));
+        let client = not_err!(Client::new(ignite_with(configuration)));
+
+        let query = "grant_type=client_credentials&service=https://www.example.com\
+                     &scope=read%20admin%20all";
+        let mut response = client
+            .get(format!("/?{}", query))
+            .header(client_header())
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let body_str = not_none!(response.body().and_then(|body| body.into_string()));
+        let service_token: Token<PrivateClaim> = not_err!(serde_json::from_str(&body_str));
+        let service_token = not_err!(service_token.encoded_token());
+
+        let introspection = introspect_token(&client, &service_token);
+        assert_eq!(introspection["scope"], "read admin");
+        assert_eq!(introspection["token_type"], "service");
+    }
+
+    #[test]
+    fn introspection_is_not_cross_origin_by_default() {
+        let client = not_err!(Client::new(ignite()));
+        let token = issue_token(&client);
+
+        let response = client
+            .post("/introspect")
+            .header(ContentType::Form)
+            .header(client_header())
+            .header(Header::new("Origin", "https://www.example.com"))
+            .body(format!("token={}", token))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert!(!response.headers().contains("Access-Control-Allow-Origin"));
+
+        let response = client
+            .options("/introspect")
+            .header(Header::new("Origin", "https://www.example.com"))
+            .header(Header::new("Access-Control-Request-Method", "POST"))
+            .dispatch();
+        assert!(!response.status().class().is_success());
+        assert!(!response.headers().contains("Access-Control-Allow-Origin"));
+    }
+
+    #[test]
+    fn introspection_has_its_own_cors_options() {
+        let mut configuration = make_configuration();
+        configuration.introspection_allowed_origins = Some(::cors::AllOrSome::All);
+        let client = not_err!(Client::new(ignite_with(configuration)));
+
+        let response = client
+            .options("/introspect")
+            .header(Header::new("Origin", "https://www.foobar.com"))
+            .header(Header::new("Access-Control-Request-Method", "POST"))
+            .header(Header::new("Access-Control-Request-Headers", "Content-Type"))
+            .dispatch();
+        assert!(response.status().class().is_success());
+        assert_eq!(
+            Some("*"),
+            response.headers().get_one("Access-Control-Allow-Origin")
+        );
+        assert!(!response.headers().contains("Access-Control-Allow-Credentials"));
+
+        // The token getter keeps its own options
+        let response = client
+            .options("/?service=https://www.example.com&scope=all")
+            .header(Header::new("Origin", "https://www.foobar.com"))
+            .header(Header::new("Access-Control-Request-Method", "GET"))
+            .dispatch();
+        assert_eq!(response.status(), Status::Forbidden);
+    }
+
+    #[test]
+    fn discovery_document_is_served_to_any_origin() {
+        let client = not_err!(Client::new(ignite()));
+
+        let mut response = client
+            .get("/.well-known/openid-configuration")
+            .header(Header::new("Origin", "https://www.foobar.com"))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(
+            Some("*"),
+            response.headers().get_one("Access-Control-Allow-Origin")
+        );
+
+        let body_str = not_none!(response.body().and_then(|body| body.into_string()));
+        let metadata: serde_json::Value = not_err!(serde_json::from_str(&body_str));
+        assert_eq!(metadata["issuer"], "https://www.acme.com/");
+        assert_eq!(metadata["token_endpoint"], "https://www.acme.com/");
+        assert_eq!(
+            metadata["introspection_endpoint"],
+            "https://www.acme.com/introspect"
+        );
+        assert_eq!(
+            metadata["grant_types_supported"],
+            json_array(&["client_credentials", "refresh_token"])
+        );
+        assert_eq!(
+            metadata["access_token_signing_alg_values_supported"],
+            json_array(&["HS512"])
+        );
+        assert!(metadata.get("jwks_uri").is_none());
+    }
+
+    #[test]
+    fn reference_tokens_are_resolved_from_the_store() {
+        let mut configuration = make_configuration();
+        configuration.reference_tokens = true;
+        let store: Box<ReferenceTokenStore> = Box::new(MemoryReferenceTokenStore::default());
+        let rocket = ignite_with(configuration)
+            .manage(store)
+            .mount("/", routes![claims]);
+        let client = not_err!(Client::new(rocket));
+
+        let reference = issue_token(&client);
+        assert_eq!(36, reference.len());
+        assert!(!reference.contains('.'));
+
+        let introspection = introspect_token(&client, &reference);
+        assert_eq!(introspection["active"], true);
+        assert_eq!(introspection["sub"], "mei");
+
+        // Reference tokens are accepted by the request guards
+        let mut response = client
+            .get("/claims")
+            .header(bearer_header(reference.clone()))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let body_str = not_none!(response.body().and_then(|body| body.into_string()));
+        assert_eq!(body_str, "mei");
+
+        // Unknown references are not active
+        let introspection = introspect_token(&client, "00000000-0000-0000-0000-000000000000");
+        assert_eq!(introspection["active"], false);
+        assert_eq!(not_none!(introspection.as_object()).len(), 1);
+    }
+
+    #[test]
+    fn reference_tokens_expire_by_the_configured_clock() {
+        use std::sync::Arc;
+        use chrono::{self, TimeZone, Utc};
+        use clock::{ClockHandle, FixedClock};
+
+        let clock = Arc::new(FixedClock::new(Utc.ymd(2017, 10, 11).and_hms(12, 0, 0)));
+        let mut configuration = make_configuration();
+        configuration.reference_tokens = true;
+        configuration.clock = ClockHandle::new(clock.clone());
+        let store: Box<ReferenceTokenStore> = Box::new(MemoryReferenceTokenStore::default());
+        let client = not_err!(Client::new(ignite_with(configuration).manage(store)));
+
+        // The token expired long ago by the system clock, but not by the configured one
+        let reference = issue_token(&client);
+        let introspection = introspect_token(&client, &reference);
+        assert_eq!(introspection["active"], true);
+
+        clock.advance(chrono::Duration::seconds(121));
+        let introspection = introspect_token(&client, &reference);
+        assert_eq!(introspection["active"], false);
+    }
+
+    #[test]
+    fn reference_tokens_are_not_resolved_unless_enabled() {
+        use chrono::{self, Utc};
+
+        let store = MemoryReferenceTokenStore::default();
+        let client = not_err!(Client::new(ignite()));
+        let token = issue_token(&client);
+        let reference = "00000000-0000-0000-0000-000000000000";
+        let now = Utc::now();
+        not_err!(store.store(reference, &token, now + chrono::Duration::hours(1), now));
+
+        let store: Box<ReferenceTokenStore> = Box::new(store);
+        let rocket = ignite().manage(store).mount("/", routes![claims]);
+        let client = not_err!(Client::new(rocket));
+
+        let response = client
+            .get("/claims")
+            .header(bearer_header(reference.to_string()))
+            .dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+        let introspection = introspect_token(&client, reference);
+        assert_eq!(introspection["active"], false);
+    }
+
+    #[test]
+    fn reference_tokens_require_a_store() {
+        let mut configuration = make_configuration();
+        configuration.reference_tokens = true;
+        let client = not_err!(Client::new(ignite_with(configuration)));
+
+        let response = client
+            .get("/?service=https://www.example.com&scope=all")
+            .header(client_header())
+            .dispatch();
+        assert_eq!(response.status(), Status::InternalServerError);
+    }
+
+    #[test]
+    fn oversized_tokens_are_not_issued() {
+        let mut configuration = make_configuration();
+        configuration.token_size_limit.max_length = 64;
+        let client = not_err!(Client::new(ignite_with(configuration)));
+
+        let response = client
+            .get("/?service=https://www.example.com&scope=all")
+            .header(client_header())
+            .dispatch();
+        assert_eq!(response.status(), Status::InternalServerError);
+    }
+
+    #[test]
+    fn scoped_claims_guard_requires_scope() {
+        // Tokens issued without the scope are forbidden
+        let client = not_err!(Client::new(ignite().mount("/", routes![admin])));
+        let token = issue_token(&client);
+        let response = client.get("/admin").header(bearer_header(token)).dispatch();
+        assert_eq!(response.status(), Status::Forbidden);
+
+        let response = client.get("/admin").dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+
+        // Tokens issued with the scope are allowed
+        let transform = |mut result: auth::AuthenticationResult| -> Result<_, ::Error> {
+            let mut private_claims = ::JsonMap::new();
+            let _ = private_claims.insert("scope".to_string(), From::from("read admin"));
+            result.private_claims = ::JsonValue::Object(private_claims);
+            Ok(result)
+        };
+        let transform: Box<auth::ClaimsTransform> = Box::new(transform);
+        let rocket = ignite().manage(transform).mount("/", routes![admin]);
+        let client = not_err!(Client::new(rocket));
+        let token = issue_token(&client);
+        let mut response = client.get("/admin").header(bearer_header(token)).dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let body_str = not_none!(response.body().and_then(|body| body.into_string()));
+        assert_eq!(body_str, "mei");
+    }
+
+    #[test]
+    fn scoped_claims_guard_accepts_implied_scopes_only_if_hierarchical() {
+        let client_with_scopes = |scopes: &'static str| {
+            let transform = move |mut result: auth::AuthenticationResult| -> Result<_, ::Error> {
+                let mut private_claims = ::JsonMap::new();
+                let _ = private_claims.insert("scope".to_string(), From::from(scopes));
+                result.private_claims = ::JsonValue::Object(private_claims);
+                Ok(result)
+            };
+            let transform: Box<auth::ClaimsTransform> = Box::new(transform);
+            let rocket = ignite().manage(transform).mount("/", routes![repo, exact_repo]);
+            not_err!(Client::new(rocket))
+        };
+
+        for &(scopes, status, exact_status) in &[
+            ("repo:read", Status::Ok, Status::Ok),
+            ("gist repo:read", Status::Ok, Status::Ok),
+            ("repo:admin", Status::Ok, Status::Forbidden),
+            ("repo", Status::Ok, Status::Forbidden),
+            ("gist repo:write", Status::Ok, Status::Forbidden),
+            ("admin", Status::Forbidden, Status::Forbidden),
+            ("repo:issues:admin", Status::Forbidden, Status::Forbidden),
+            ("repository:admin", Status::Forbidden, Status::Forbidden),
+        ] {
+            let client = client_with_scopes(scopes);
+            let token = issue_token(&client);
+            let response = client
+                .get("/repo")
+                .header(bearer_header(token.clone()))
+                .dispatch();
+            assert_eq!(response.status(), status, "{}", scopes);
+
+            // Exact matching is the default
+            let response = client.get("/repo/exact").header(bearer_header(token)).dispatch();
+            assert_eq!(response.status(), exact_status, "{}", scopes);
+        }
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn token_getter_get_invalid_credentials() {
+        // Ignite rocket
+        let rocket = ignite();
+        let client = not_err!(Client::new(rocket));
+
+        // Make headers
+        let origin_header = Header::from(not_err!(
+            hyper::header::Origin::from_str("https://www.example.com")
+        ));
+        let auth_header = hyper::header::Authorization(auth::Basic {
+            username: "Aladin".to_owned(),
+            password: Some("let me in".to_string()),
+        });
+        let auth_header = Header::new(
+            "Authorization",
             hyper::header::HeaderFormatter(&auth_header).to_string(),
         );
         // Make and dispatch request
@@ -319,6 +2718,9 @@ mod tests {
             .get_one("Access-Control-Allow-Origin")
             .expect("to exist");
         assert_eq!("https://www.example.com", origin_header);
+
+        let www_header: Vec<_> = response.headers().get("WWW-Authenticate").collect();
+        assert_eq!(www_header, vec![r#"Basic realm="https://www.acme.com/""#]);
     }
 
     #[test]
@@ -348,7 +2750,85 @@ mod tests {
         assert_eq!("https://www.example.com", origin_header);
 
         let www_header: Vec<_> = response.headers().get("WWW-Authenticate").collect();
-        assert_eq!(www_header, vec!["Basic realm=https://www.acme.com/"]);
+        assert_eq!(www_header, vec![r#"Basic realm="https://www.acme.com/""#]);
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn authentication_failures_use_service_realm_and_message() {
+        let mut configuration = make_configuration();
+        configuration.authentication_failure_message = Some("Authentication failed".to_string());
+        let _ = configuration.services.insert(
+            "https://www.example.com".to_string(),
+            ::token::ServiceConfiguration {
+                realm: Some("https://www.example.com/login".to_string()),
+                authentication_failure_message: Some("Sign in with Example".to_string()),
+            },
+        );
+        let rocket = ignite_with(configuration);
+        let client = not_err!(Client::new(rocket));
+
+        let auth_header = hyper::header::Authorization(auth::Basic {
+            username: "Aladin".to_owned(),
+            password: Some("let me in".to_string()),
+        });
+        let auth_header = Header::new(
+            "Authorization",
+            hyper::header::HeaderFormatter(&auth_header).to_string(),
+        );
+        let mut response = client
+            .get("/?service=https://www.example.com&scope=all")
+            .header(auth_header)
+            .dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+        let www_header: Vec<_> = response.headers().get("WWW-Authenticate").collect();
+        assert_eq!(www_header, vec![r#"Basic realm="https://www.example.com/login""#]);
+        let body_str = not_none!(response.body().and_then(|body| body.into_string()));
+        assert_eq!(body_str, "Sign in with Example");
+
+        let mut response = client
+            .get("/?service=https://www.example.com&scope=all")
+            .dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+        let www_header: Vec<_> = response.headers().get("WWW-Authenticate").collect();
+        assert_eq!(www_header, vec![r#"Basic realm="https://www.example.com/login""#]);
+        let body_str = not_none!(response.body().and_then(|body| body.into_string()));
+        assert_eq!(body_str, "Sign in with Example");
+
+        // Other services fall back to the global realm and message
+        let mut response = client.get("/?service=foobar&scope=all").dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+        let www_header: Vec<_> = response.headers().get("WWW-Authenticate").collect();
+        assert_eq!(www_header, vec![r#"Basic realm="https://www.acme.com/""#]);
+        let body_str = not_none!(response.body().and_then(|body| body.into_string()));
+        assert_eq!(body_str, "Authentication failed");
+    }
+
+    #[test]
+    fn token_getter_get_malformed_credentials() {
+        let rocket = ignite();
+        let client = not_err!(Client::new(rocket));
+
+        for header in &["Basic 这样可以挡住他们。", "Basic !!!!"] {
+            let mut response = client
+                .get("/?service=https://www.example.com&scope=all")
+                .header(Header::new("Authorization", *header))
+                .dispatch();
+            assert_eq!(response.status(), Status::BadRequest);
+            let body_str = not_none!(response.body().and_then(|body| body.into_string()));
+            assert!(
+                body_str.starts_with("The `Authorization` header is malformed"),
+                "{}",
+                body_str
+            );
+        }
+
+        // Headers of other schemes are not malformed Basic headers
+        let response = client
+            .get("/?service=https://www.example.com&scope=all")
+            .header(Header::new("Authorization", "Digest foobar"))
+            .dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
     }
 
     #[test]
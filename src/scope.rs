@@ -0,0 +1,252 @@
+//! Hierarchical scopes
+//!
+//! A [`Scope`] is a `:`-separated path of resource segments, optionally ending with an access
+//! level of `read`, `write`, or `admin`. For example, `repo:issues:write` is write access to the
+//! issues of `repo`, and `repo` is full access to `repo`. A scope may also be a bare access level,
+//! such as `admin`.
+//!
+//! A granted scope [satisfies](Scope::satisfies) a required scope if both of the following hold:
+//!
+//! - The resource of the granted scope is the resource of the required scope, or one of its
+//!   ancestors. `repo:write` applies to `repo` and to `repo:issues`, but not to `gist`. Bare access
+//!   levels only apply to other bare access levels, so `admin` does not imply `repo:read`.
+//! - The access level of the granted scope is at least that of the required scope. The levels are
+//!   ordered `read` < `write` < `admin`, and a scope without a level grants full access. So
+//!   `admin` implies `write` and `read`, `repo` implies `repo:admin`, and `repo:admin` does not
+//!   imply `repo`.
+//!
+//! | Granted            | Required             | Satisfied |
+//! |--------------------|----------------------|-----------|
+//! | `admin`            | `read`               | Yes       |
+//! | `read`             | `write`              | No        |
+//! | `repo:admin`       | `repo:read`          | Yes       |
+//! | `repo:write`       | `repo:issues:read`   | Yes       |
+//! | `repo`             | `repo:issues:admin`  | Yes       |
+//! | `repo:admin`       | `repo`               | No        |
+//! | `repo:issues`      | `repo:read`          | No        |
+//! | `admin`            | `repo:read`          | No        |
+//!
+//! The [`ScopedClaims`](::auth::ScopedClaims) request guard parses the scopes granted by a token
+//! and checks them against the scope required by the route with these rules, if the required
+//! [`Scope`](::auth::Scope::hierarchical) opts in to them. Otherwise, the required scope must be
+//! granted exactly.
+use std::fmt;
+use std::str::FromStr;
+
+use Error;
+
+/// Separator of the segments of a scope
+const SEPARATOR: char = ':';
+
+/// Access level at the end of a scope. Levels are ordered from `Read` to `Admin`, and each level
+/// implies the levels below it.
+#[derive(Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
+pub enum Access {
+    /// `read`
+    Read,
+    /// `write`
+    Write,
+    /// `admin`
+    Admin,
+}
+
+impl Access {
+    fn as_str(&self) -> &'static str {
+        match *self {
+            Access::Read => "read",
+            Access::Write => "write",
+            Access::Admin => "admin",
+        }
+    }
+
+    fn from_segment(segment: &str) -> Option<Self> {
+        match segment {
+            "read" => Some(Access::Read),
+            "write" => Some(Access::Write),
+            "admin" => Some(Access::Admin),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Access {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// A scope parsed into its resource and access level. See the module documentation for the
+/// implication rules.
+///
+/// # Examples
+/// ```
+/// use rowdy::scope::Scope;
+///
+/// let granted: Scope = "repo:admin".parse().unwrap();
+/// assert!(granted.satisfies(&"repo:issues:read".parse().unwrap()));
+/// assert!(!granted.satisfies(&"gist:read".parse().unwrap()));
+/// ```
+#[derive(Clone, Eq, PartialEq, Hash, Debug)]
+pub struct Scope {
+    resource: Vec<String>,
+    access: Option<Access>,
+}
+
+impl Scope {
+    /// The resource segments of the scope, which are empty for bare access levels
+    pub fn resource(&self) -> &[String] {
+        &self.resource
+    }
+
+    /// The access level of the scope. `None` grants full access to the resource.
+    pub fn access(&self) -> Option<Access> {
+        self.access
+    }
+
+    /// Returns whether this scope, when granted, implies the `required` scope
+    pub fn satisfies(&self, required: &Scope) -> bool {
+        if self.resource.is_empty() != required.resource.is_empty() {
+            return false;
+        }
+        if !required.resource.starts_with(&self.resource) {
+            return false;
+        }
+        match (self.access, required.access) {
+            (None, _) => true,
+            (Some(_), None) => false,
+            (Some(granted), Some(required)) => granted >= required,
+        }
+    }
+
+    /// Parse a space-delimited list of scopes, like the `scope` parameter of OAuth2 requests
+    pub fn parse_list(scopes: &str) -> Result<Vec<Scope>, Error> {
+        scopes.split_whitespace().map(FromStr::from_str).collect()
+    }
+}
+
+impl FromStr for Scope {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        let invalid = || Error::GenericError(format!("Invalid scope `{}`", s));
+        if s.is_empty() || s.chars().any(char::is_whitespace) {
+            Err(invalid())?;
+        }
+
+        let mut resource: Vec<String> = s.split(SEPARATOR).map(ToString::to_string).collect();
+        if resource.iter().any(String::is_empty) {
+            Err(invalid())?;
+        }
+        let access = resource
+            .last()
+            .and_then(|segment| Access::from_segment(segment));
+        if access.is_some() {
+            let _ = resource.pop();
+        }
+        Ok(Scope {
+            resource: resource,
+            access: access,
+        })
+    }
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let separator = SEPARATOR.to_string();
+        write!(f, "{}", self.resource.join(&separator))?;
+        if let Some(access) = self.access {
+            if !self.resource.is_empty() {
+                write!(f, "{}", SEPARATOR)?;
+            }
+            write!(f, "{}", access)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scope(s: &str) -> Scope {
+        not_err!(s.parse())
+    }
+
+    fn satisfies(granted: &str, required: &str) -> bool {
+        scope(granted).satisfies(&scope(required))
+    }
+
+    #[test]
+    fn scopes_are_parsed() {
+        let parsed = scope("repo:issues:write");
+        assert_eq!(parsed.resource(), &["repo".to_string(), "issues".to_string()]);
+        assert_eq!(parsed.access(), Some(Access::Write));
+
+        let parsed = scope("repo");
+        assert_eq!(parsed.resource(), &["repo".to_string()]);
+        assert_eq!(parsed.access(), None);
+
+        let parsed = scope("admin");
+        assert!(parsed.resource().is_empty());
+        assert_eq!(parsed.access(), Some(Access::Admin));
+
+        // Access levels are only recognised at the end
+        let parsed = scope("read:repo");
+        assert_eq!(parsed.resource(), &["read".to_string(), "repo".to_string()]);
+        assert_eq!(parsed.access(), None);
+    }
+
+    #[test]
+    fn invalid_scopes_are_rejected() {
+        for invalid in &["", "repo:", ":repo", "repo::read", "repo read"] {
+            assert!(invalid.parse::<Scope>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn scopes_are_displayed_as_parsed() {
+        for s in &["repo:issues:write", "repo", "admin", "read:repo"] {
+            assert_eq!(scope(s).to_string(), *s);
+        }
+    }
+
+    #[test]
+    fn scope_lists_are_parsed() {
+        let scopes = not_err!(Scope::parse_list(" read  repo:admin "));
+        assert_eq!(scopes, vec![scope("read"), scope("repo:admin")]);
+        assert!(Scope::parse_list("read repo:").is_err());
+    }
+
+    #[test]
+    fn higher_access_levels_imply_lower_ones() {
+        assert!(satisfies("admin", "read"));
+        assert!(satisfies("admin", "write"));
+        assert!(satisfies("write", "read"));
+        assert!(satisfies("read", "read"));
+        assert!(!satisfies("read", "write"));
+        assert!(!satisfies("write", "admin"));
+
+        assert!(satisfies("repo:admin", "repo:read"));
+        assert!(!satisfies("repo:read", "repo:admin"));
+    }
+
+    #[test]
+    fn resources_imply_their_descendants() {
+        assert!(satisfies("repo", "repo"));
+        assert!(satisfies("repo", "repo:admin"));
+        assert!(satisfies("repo", "repo:issues:admin"));
+        assert!(satisfies("repo:write", "repo:issues:read"));
+        assert!(!satisfies("repo:write", "repo:issues:admin"));
+        assert!(!satisfies("repo:issues", "repo:read"));
+        assert!(!satisfies("repo:issues", "repo"));
+        assert!(!satisfies("repo:admin", "repo"));
+        assert!(!satisfies("repository", "repo:read"));
+        assert!(!satisfies("repo", "gist:read"));
+    }
+
+    #[test]
+    fn bare_access_levels_do_not_apply_to_resources() {
+        assert!(!satisfies("admin", "repo:read"));
+        assert!(!satisfies("repo:admin", "admin"));
+    }
+}
@@ -0,0 +1,114 @@
+//! Custom serializer and deserializer for allowed CORS origins. Serialization is unchanged.
+//!
+//! In addition to `"All"` and `{ "Some": [...] }`, the origins can be deserialized from a plain
+//! array of origins, or from a single comma-separated string like
+//! `"https://www.example.com,https://www.foobar.com"`. The latter is convenient when the value is
+//! read from an environment variable.
+use std::collections::HashSet;
+use std::str::FromStr;
+
+use cors::{AllOrSome, AllowedOrigins};
+use cors::headers::Url;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde::de;
+
+/// The forms allowed origins can be deserialized from
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Origins {
+    /// `"All"` or `{ "Some": [...] }`
+    AllOrSome(AllowedOrigins),
+    /// An array of origins
+    Array(HashSet<Url>),
+    /// A comma-separated list of origins
+    String(String),
+}
+
+/// Serialize the allowed origins as is
+pub fn serialize<S>(origins: &AllowedOrigins, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    origins.serialize(serializer)
+}
+
+/// Deserialize the allowed origins from any of the supported forms
+pub fn deserialize<'de, D>(deserializer: D) -> Result<AllowedOrigins, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match Origins::deserialize(deserializer)? {
+        Origins::AllOrSome(origins) => Ok(origins),
+        Origins::Array(origins) => Ok(AllOrSome::Some(origins)),
+        Origins::String(origins) => origins
+            .split(',')
+            .map(str::trim)
+            .filter(|origin| !origin.is_empty())
+            .map(|origin| {
+                Url::from_str(origin).map_err(|e| {
+                    de::Error::custom(format!("`{}` is not a valid origin: {}", origin, e))
+                })
+            })
+            .collect::<Result<_, _>>()
+            .map(AllOrSome::Some),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cors::{AllOrSome, AllowedOrigins};
+    use serde_json;
+
+    #[derive(Serialize, Deserialize, Debug)]
+    struct TestStruct {
+        #[serde(with = "super")] allowed_origins: AllowedOrigins,
+    }
+
+    fn deserialize(json: &str) -> AllowedOrigins {
+        let deserialized: TestStruct = not_err!(serde_json::from_str(json));
+        deserialized.allowed_origins
+    }
+
+    #[test]
+    fn deserializes_from_all_forms() {
+        let (expected, _) =
+            AllowedOrigins::some(&["https://www.example.com", "https://www.foobar.com"]);
+
+        assert_eq!(deserialize(r#"{"allowed_origins":"All"}"#), AllOrSome::All);
+        assert_eq!(
+            deserialize(
+                r#"{"allowed_origins":
+                    {"Some":["https://www.example.com","https://www.foobar.com"]}}"#
+            ),
+            expected
+        );
+        assert_eq!(
+            deserialize(
+                r#"{"allowed_origins":["https://www.example.com","https://www.foobar.com"]}"#
+            ),
+            expected
+        );
+        assert_eq!(
+            deserialize(r#"{"allowed_origins":"https://www.example.com, https://www.foobar.com"}"#),
+            expected
+        );
+    }
+
+    #[test]
+    fn serialization_round_trip() {
+        let (allowed_origins, _) = AllowedOrigins::some(&["https://www.example.com"]);
+        let structure = TestStruct {
+            allowed_origins: allowed_origins.clone(),
+        };
+        let serialized = not_err!(serde_json::to_string(&structure));
+        assert_eq!(deserialize(&serialized), allowed_origins);
+    }
+
+    #[test]
+    #[should_panic(expected = "is not a valid origin")]
+    fn rejects_invalid_origins_in_strings() {
+        let _: TestStruct =
+            serde_json::from_str(r#"{"allowed_origins":"https://www.example.com,foobar"}"#)
+                .unwrap();
+    }
+}
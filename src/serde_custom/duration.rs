@@ -1,7 +1,14 @@
 //! Custom serializer and deserializer for `std::time::Duration`. Serializes to seconds,
 //! and deserializes from seconds.
+//!
+//! In addition to an integer number of seconds, durations can be deserialized from strings with
+//! a unit suffix: `s` for seconds, `m` for minutes, `h` for hours, and `d` for days.
+//! For example, `"30m"`, `"24h"`, or `"7d"`.
+use std::fmt;
 use std::time::Duration;
-use serde::{Deserialize, Deserializer, Serializer};
+
+use serde::{Deserializer, Serializer};
+use serde::de;
 
 /// Serialize a `Duration` into a `u64` representing the seconds
 pub fn serialize<S>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error>
@@ -11,13 +18,73 @@ where
     serializer.serialize_u64(duration.as_secs())
 }
 
-/// From a `u64`, deserialize into a `Duration` with the `u64` in seconds
+/// From a `u64` in seconds, or a string with a unit suffix, deserialize into a `Duration`
 pub fn deserialize<'de, D>(deserializer: D) -> Result<Duration, D::Error>
 where
     D: Deserializer<'de>,
 {
-    let duration = u64::deserialize(deserializer)?;
-    Ok(Duration::from_secs(duration))
+    struct DurationVisitor;
+
+    impl<'de> de::Visitor<'de> for DurationVisitor {
+        type Value = Duration;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("a number of seconds, or a string like \"30m\", \"24h\" or \"7d\"")
+        }
+
+        fn visit_u64<E>(self, value: u64) -> Result<Self::Value, E>
+        where
+            E: de::Error,
+        {
+            Ok(Duration::from_secs(value))
+        }
+
+        fn visit_i64<E>(self, value: i64) -> Result<Self::Value, E>
+        where
+            E: de::Error,
+        {
+            if value < 0 {
+                Err(E::custom("duration cannot be negative"))
+            } else {
+                Ok(Duration::from_secs(value as u64))
+            }
+        }
+
+        fn visit_str<E>(self, value: &str) -> Result<Self::Value, E>
+        where
+            E: de::Error,
+        {
+            parse(value).map_err(E::custom)
+        }
+    }
+
+    deserializer.deserialize_any(DurationVisitor)
+}
+
+/// Parse a string of the form `<number><unit>` into a `Duration`. The unit is optional, and
+/// defaults to seconds.
+fn parse(value: &str) -> Result<Duration, String> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !c.is_digit(10))
+        .unwrap_or_else(|| value.len());
+    let (number, unit) = value.split_at(split);
+
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("invalid duration `{}`", value))?;
+    let multiplier = match unit.trim() {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        unit => Err(format!("unknown unit `{}` in duration `{}`", unit, value))?,
+    };
+
+    let seconds = number
+        .checked_mul(multiplier)
+        .ok_or_else(|| format!("duration `{}` is too large", value))?;
+    Ok(Duration::from_secs(seconds))
 }
 
 #[cfg(test)]
@@ -43,4 +110,39 @@ mod tests {
         let deserialized_struct: TestStruct = not_err!(serde_json::from_str(&actual_json));
         assert_eq!(structure, deserialized_struct);
     }
+
+    #[test]
+    fn deserializes_from_strings_with_units() {
+        let cases = [
+            ("\"3600\"", 3600),
+            ("\"3600s\"", 3600),
+            ("\"30m\"", 30 * 60),
+            ("\"24h\"", 24 * 60 * 60),
+            ("\"7d\"", 7 * 24 * 60 * 60),
+        ];
+
+        for &(duration, expected_seconds) in cases.iter() {
+            let json = format!("{{\"duration\":{}}}", duration);
+            let deserialized: TestStruct = not_err!(serde_json::from_str(&json));
+            assert_eq!(Duration::from_secs(expected_seconds), deserialized.duration);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "unknown unit")]
+    fn rejects_unknown_units() {
+        let _: TestStruct = serde_json::from_str("{\"duration\":\"24y\"}").unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid duration")]
+    fn rejects_missing_number() {
+        let _: TestStruct = serde_json::from_str("{\"duration\":\"h\"}").unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "cannot be negative")]
+    fn rejects_negative_durations() {
+        let _: TestStruct = serde_json::from_str("{\"duration\":-1}").unwrap();
+    }
 }
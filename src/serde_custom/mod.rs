@@ -1,2 +1,4 @@
 //! Custom serde serialization and deserialization.
+pub mod allowed_origins;
 pub mod duration;
+pub mod uri;
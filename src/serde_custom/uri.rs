@@ -0,0 +1,53 @@
+//! Custom serializer and deserializer for `jwt::StringOrUri` fields that must be URIs.
+//! Serialization is unchanged, but deserialization fails if the value is not a URI.
+use jwt::StringOrUri;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde::de;
+
+/// Serialize a `StringOrUri` as is
+pub fn serialize<S>(uri: &StringOrUri, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    uri.serialize(serializer)
+}
+
+/// Deserialize a `StringOrUri`, rejecting values that are not URIs
+pub fn deserialize<'de, D>(deserializer: D) -> Result<StringOrUri, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match StringOrUri::deserialize(deserializer)? {
+        uri @ StringOrUri::Uri(_) => Ok(uri),
+        StringOrUri::String(string) => Err(de::Error::custom(
+            format!("`{}` is not a valid URI", string),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use jwt::StringOrUri;
+    use serde_json;
+
+    #[derive(Serialize, Deserialize, Debug)]
+    struct TestStruct {
+        #[serde(with = "super")] uri: StringOrUri,
+    }
+
+    #[test]
+    fn serialization_round_trip() {
+        let json = r#"{"uri":"https://www.acme.com/"}"#;
+        let deserialized: TestStruct = not_err!(serde_json::from_str(json));
+        assert_matches_non_debug!(deserialized.uri, StringOrUri::Uri(_));
+
+        let serialized = not_err!(serde_json::to_string(&deserialized));
+        assert_eq!(json, serialized);
+    }
+
+    #[test]
+    #[should_panic(expected = "is not a valid URI")]
+    fn rejects_strings() {
+        let _: TestStruct = serde_json::from_str(r#"{"uri":"www.acme.com"}"#).unwrap();
+    }
+}
@@ -0,0 +1,329 @@
+//! Helpers for testing code that depends on rowdy
+//!
+//! Requires the `test-util` feature, which should only be enabled for your tests:
+//!
+//! ```toml
+//! [dev-dependencies]
+//! rowdy = { version = "0.0.8", features = ["test-util"] }
+//! ```
+//!
+//! The helpers build a token [`Configuration`](::token::Configuration) that signs tokens with a
+//! known HMAC secret, an [`InMemoryAuthenticator`] seeded with a test user, and a Rocket with the
+//! routes provided by rowdy mounted. Tokens can be minted and validated directly, without going
+//! through the routes. _DO NOT USE ANY OF THIS IN PRODUCTION_.
+//!
+//! # Examples
+//! ```rust,ignore
+//! use rocket::local::Client;
+//! use rowdy::test_support;
+//!
+//! let client = Client::new(test_support::rocket()?)?;
+//! let response = client
+//!     .get(format!("/?service={}&scope=all", test_support::TEST_AUDIENCE))
+//!     .header(test_support::basic_auth_header(
+//!         test_support::TEST_USERNAME,
+//!         test_support::TEST_PASSWORD,
+//!     ))
+//!     .dispatch();
+//!
+//! let token = test_support::mint_token("mei", Default::default())?;
+//! let validated = test_support::validate_token(&token)?;
+//! ```
+use std::collections::HashMap;
+
+use hyper::header;
+use ring::constant_time::verify_slices_are_equal;
+use rocket::Rocket;
+use rocket::http::Header;
+use serde_json;
+
+use {Error, JsonMap, JsonValue};
+use auth::{self, AuthenticationResult, Authenticator, AuthenticatorConfiguration, Authorization,
+           Basic};
+use https::HttpsPolicy;
+use token::{self, Token};
+
+/// Issuer of the tokens signed with the test configuration
+pub const TEST_ISSUER: &str = "https://rowdy.test";
+/// Audience of the tokens signed with the test configuration, and the service to request tokens
+/// for
+pub const TEST_AUDIENCE: &str = "https://service.rowdy.test";
+/// HMAC secret that the test configuration signs tokens with
+pub const TEST_SECRET: &str = "rowdy test secret, not for production";
+/// Username of the user seeded in the test authenticator
+pub const TEST_USERNAME: &str = "mei";
+/// Password of the user seeded in the test authenticator
+pub const TEST_PASSWORD: &str = "冻住，不许走!";
+
+/// Build a token configuration that signs tokens with `TEST_SECRET` using `HS256`. Tokens are
+/// issued by `TEST_ISSUER` for `TEST_AUDIENCE`, and all origins are allowed. HTTPS is not
+/// required, so that tests can make plaintext requests. Refresh tokens are disabled.
+pub fn configuration() -> token::Configuration {
+    let json = format!(
+        r#"{{
+            "issuer": "{}",
+            "allowed_origins": "All",
+            "audience": "{}",
+            "signature_algorithm": "HS256",
+            "secret": "{}"
+        }}"#,
+        TEST_ISSUER,
+        TEST_AUDIENCE,
+        TEST_SECRET
+    );
+    let mut configuration: token::Configuration =
+        serde_json::from_str(&json).expect("the test configuration to be valid");
+    configuration.https = HttpsPolicy::Off;
+    configuration
+}
+
+/// Build the application configuration from the token configuration of `configuration`, with an
+/// authenticator seeded with the test user
+pub fn application_configuration() -> ::Configuration<InMemoryAuthenticatorConfiguration> {
+    ::Configuration {
+        token: configuration(),
+        basic_authenticator: InMemoryAuthenticatorConfiguration::seeded(),
+        logging: None,
+        base_path: "/".to_string(),
+    }
+}
+
+/// Ignite a rocket with `application_configuration`, with the routes provided by rowdy mounted at
+/// `/`
+pub fn rocket() -> Result<Rocket, Error> {
+    ::rocket(application_configuration())
+}
+
+/// Make an `Authorization` header for HTTP Basic authentication with the credentials
+#[allow(deprecated)]
+pub fn basic_auth_header(username: &str, password: &str) -> Header<'static> {
+    let authorization = header::Authorization(Basic {
+        username: username.to_string(),
+        password: Some(password.to_string()),
+    });
+    Header::new(
+        "Authorization",
+        header::HeaderFormatter(&authorization).to_string(),
+    )
+}
+
+/// Mint an encoded access token for the subject with the test configuration, without going
+/// through the routes
+pub fn mint_token(subject: &str, private_claims: JsonValue) -> Result<String, Error> {
+    let configuration = configuration();
+    let keys = configuration.keys()?;
+    let token = Token::with_configuration(
+        &configuration,
+        subject,
+        TEST_AUDIENCE,
+        private_claims,
+        None,
+    )?;
+    token.encode(&keys.signing)?.encoded_token()
+}
+
+/// Decode and validate an encoded access token with the test configuration
+pub fn validate_token(token: &str) -> Result<Token<JsonValue>, Error> {
+    let configuration = configuration();
+    let keys = configuration.keys()?;
+    Ok(configuration.validate_token(&keys, token)?)
+}
+
+/// A user of the `InMemoryAuthenticator`
+#[derive(Serialize, Deserialize, Clone, Eq, PartialEq, Debug)]
+pub struct InMemoryUser {
+    /// The password of the user, in plaintext
+    pub password: String,
+    /// The private claims returned when the user authenticates. Defaults to an empty object.
+    #[serde(default = "empty_claims")]
+    pub private_claims: JsonValue,
+}
+
+fn empty_claims() -> JsonValue {
+    JsonValue::Object(JsonMap::new())
+}
+
+/// An authenticator whose users are kept in memory, with plaintext passwords. Supports refresh
+/// tokens, whose payload is the username.
+#[derive(Clone, Debug, Default)]
+pub struct InMemoryAuthenticator {
+    users: HashMap<String, InMemoryUser>,
+}
+
+impl InMemoryAuthenticator {
+    /// Create an authenticator without users
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Create an authenticator with the test user `TEST_USERNAME`
+    pub fn seeded() -> Self {
+        Self::new().with_user(TEST_USERNAME, TEST_PASSWORD)
+    }
+
+    /// Add a user without private claims
+    pub fn with_user(self, username: &str, password: &str) -> Self {
+        self.with_user_claims(username, password, empty_claims())
+    }
+
+    /// Add a user whose authentication returns the private claims
+    pub fn with_user_claims(
+        mut self,
+        username: &str,
+        password: &str,
+        private_claims: JsonValue,
+    ) -> Self {
+        let user = InMemoryUser {
+            password: password.to_string(),
+            private_claims: private_claims,
+        };
+        let _ = self.users.insert(username.to_string(), user);
+        self
+    }
+
+    fn verify(
+        &self,
+        username: &str,
+        password: Option<&str>,
+        include_refresh_payload: bool,
+    ) -> Result<AuthenticationResult, Error> {
+        let user = self.users
+            .get(username)
+            .ok_or_else(|| auth::Error::AuthenticationFailure)?;
+        if let Some(password) = password {
+            if verify_slices_are_equal(password.as_bytes(), user.password.as_bytes()).is_err() {
+                Err(auth::Error::AuthenticationFailure)?;
+            }
+        }
+
+        let refresh_payload = if include_refresh_payload {
+            let mut map = JsonMap::with_capacity(1);
+            let _ = map.insert("user".to_string(), From::from(username));
+            Some(JsonValue::Object(map))
+        } else {
+            None
+        };
+        Ok(AuthenticationResult {
+            subject: username.to_string(),
+            private_claims: user.private_claims.clone(),
+            refresh_payload,
+        })
+    }
+}
+
+impl Authenticator<Basic> for InMemoryAuthenticator {
+    fn authenticate(
+        &self,
+        authorization: &Authorization<Basic>,
+        include_refresh_payload: bool,
+    ) -> Result<AuthenticationResult, Error> {
+        let username = authorization.username();
+        let password = authorization.password().unwrap_or_else(|| "".to_string());
+        self.verify(&username, Some(&password), include_refresh_payload)
+    }
+
+    fn authenticate_refresh_token(
+        &self,
+        refresh_payload: &JsonValue,
+    ) -> Result<AuthenticationResult, Error> {
+        let username = refresh_payload
+            .get("user")
+            .and_then(JsonValue::as_str)
+            .ok_or_else(|| auth::Error::AuthenticationFailure)?;
+        self.verify(username, None, false)
+    }
+}
+
+/// Configuration for the `InMemoryAuthenticator`, with the users keyed by username
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct InMemoryAuthenticatorConfiguration {
+    /// The users of the authenticator
+    #[serde(default)]
+    pub users: HashMap<String, InMemoryUser>,
+}
+
+impl InMemoryAuthenticatorConfiguration {
+    /// Configure the test user `TEST_USERNAME`
+    pub fn seeded() -> Self {
+        InMemoryAuthenticatorConfiguration {
+            users: InMemoryAuthenticator::seeded().users,
+        }
+    }
+}
+
+impl AuthenticatorConfiguration<Basic> for InMemoryAuthenticatorConfiguration {
+    type Authenticator = InMemoryAuthenticator;
+
+    fn make_authenticator(&self) -> Result<Self::Authenticator, Error> {
+        Ok(InMemoryAuthenticator {
+            users: self.users.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use rocket::http::Status;
+    use rocket::local::Client;
+
+    use super::*;
+
+    fn request_token(client: &Client, username: &str, password: &str) -> Status {
+        client
+            .get(format!("/?service={}&scope=all", TEST_AUDIENCE))
+            .header(basic_auth_header(username, password))
+            .dispatch()
+            .status()
+    }
+
+    #[test]
+    fn seeded_user_is_issued_tokens() {
+        let client = not_err!(Client::new(not_err!(rocket())));
+        assert_eq!(
+            request_token(&client, TEST_USERNAME, TEST_PASSWORD),
+            Status::Ok
+        );
+        assert_eq!(
+            request_token(&client, TEST_USERNAME, "wrong"),
+            Status::Unauthorized
+        );
+        assert_eq!(
+            request_token(&client, "unknown", TEST_PASSWORD),
+            Status::Unauthorized
+        );
+    }
+
+    #[test]
+    fn minted_tokens_are_validated() {
+        let mut claims = JsonMap::new();
+        let _ = claims.insert("role".to_string(), From::from("admin"));
+        let token = not_err!(mint_token("mei", JsonValue::Object(claims)));
+
+        let token = not_err!(validate_token(&token));
+        let registered = not_err!(token.registered_claims());
+        assert_eq!(registered.subject, Some(not_err!(FromStr::from_str("mei"))));
+        assert_eq!(not_err!(token.private_claims())["role"], "admin");
+
+        assert!(validate_token("not a token").is_err());
+    }
+
+    #[test]
+    fn users_can_be_added_with_private_claims() {
+        let mut claims = JsonMap::new();
+        let _ = claims.insert("team".to_string(), From::from("weather"));
+        let authenticator = InMemoryAuthenticator::seeded().with_user_claims(
+            "ling",
+            "password",
+            JsonValue::Object(claims),
+        );
+
+        let result = not_err!(authenticator.verify("ling", Some("password"), true));
+        assert_eq!(result.private_claims["team"], "weather");
+        let refresh_payload = not_none!(result.refresh_payload);
+        let refreshed = not_err!(authenticator.authenticate_refresh_token(&refresh_payload));
+        assert_eq!(refreshed.subject, "ling");
+        assert!(authenticator.verify("ling", Some("wrong"), false).is_err());
+    }
+}